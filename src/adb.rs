@@ -1,13 +1,20 @@
+use crate::{is_verbose, logv, logw};
 use std::error::Error;
 use std::ffi::OsStr;
 use std::fmt::{Debug, Display};
-use std::io::BufRead;
-use std::io::BufReader;
-use std::io::BufWriter;
+use std::io::Read;
 use std::io::Write;
-use std::process::{ChildStdin, ChildStdout};
+use std::net::TcpStream;
+use std::process::{Child, Output, Stdio};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 use std::{io, process::Command};
 
+mod client;
+pub mod sync;
+#[cfg(feature = "usb")]
+pub mod usb;
+
 #[derive(Debug)]
 pub enum AdbErr {
     IO(io::Error),
@@ -30,65 +37,449 @@ impl From<Box<str>> for AdbErr {
         Self::Adb(e)
     }
 }
+impl From<&str> for AdbErr {
+    fn from(e: &str) -> Self {
+        Self::Adb(e.into())
+    }
+}
 impl From<String> for AdbErr {
     fn from(e: String) -> Self {
         Self::Adb(e.into_boxed_str())
     }
 }
 
+// extra global flags put in front of every adb invocation (--adb-arg)
+pub static ADB_ARGS: OnceLock<Vec<String>> = OnceLock::new();
+
+fn adb_args() -> &'static [String] {
+    ADB_ARGS.get().map_or(&[], Vec::as_slice)
+}
+
+// a connection to one service on the device
+pub trait AdbStream: Read + Write + Send {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl AdbStream for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+// how services on the device are reached: through the adb server, or with `--usb` straight
+// over usb
+pub trait Transport: Send + Sync {
+    // a connection to `service`, like `shell,v2,raw:ls` or `sync:`
+    fn open(&self, service: &str) -> Result<Box<dyn AdbStream>, AdbErr>;
+    // the features of the device's adbd, comma separated
+    fn features(&self) -> Result<String, AdbErr>;
+}
+
+pub struct ServerTransport {
+    pub host: String,
+    pub port: u16,
+    // e.g. `host:transport-any`, picks the device like the adb client would
+    pub transport: String,
+}
+
+impl ServerTransport {
+    pub fn new() -> Result<Self, AdbErr> {
+        let (host, port, transport) = server_target()?;
+        Ok(Self {
+            host,
+            port,
+            transport,
+        })
+    }
+}
+
+impl Transport for ServerTransport {
+    fn open(&self, service: &str) -> Result<Box<dyn AdbStream>, AdbErr> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port))?;
+        AdbShell::request(&mut stream, &self.transport)?;
+        AdbShell::request(&mut stream, service)?;
+        Ok(Box::new(stream))
+    }
+
+    // the server answers this without talking to the device
+    fn features(&self) -> Result<String, AdbErr> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port))?;
+        AdbShell::request(&mut stream, &host_request(&self.transport, "features"))?;
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len)?;
+        let len = std::str::from_utf8(&len)
+            .ok()
+            .and_then(|l| usize::from_str_radix(l, 16).ok())
+            .ok_or("malformed features length")?;
+        let mut features = vec![0u8; len];
+        stream.read_exact(&mut features)?;
+        Ok(String::from_utf8_lossy(&features).into_owned())
+    }
+}
+
+// the transport of this run, the usb one with `--usb`
+pub fn transport() -> Result<Box<dyn Transport>, AdbErr> {
+    #[cfg(feature = "usb")]
+    if let Some(usb) = usb::device() {
+        return Ok(Box::new(usb.clone()));
+    }
+    Ok(Box::new(ServerTransport::new()?))
+}
+
+// whether `--usb` replaces the adb server, and with it everything that needs the binary
+#[cfg(feature = "usb")]
+pub fn over_usb() -> bool {
+    usb::device().is_some()
+}
+
+#[cfg(not(feature = "usb"))]
+pub fn over_usb() -> bool {
+    false
+}
+
+// talks to the device through adbd's `shell,v2:` service. every command gets its own
+// connection with separate stdout/stderr streams and a real exit code
 pub struct AdbShell {
-    pub si: BufWriter<ChildStdin>,
-    pub so: BufReader<ChildStdout>,
+    transport: Box<dyn Transport>,
+    // restart the adb server when reconnecting does not help
+    pub recover_server: bool,
+}
+
+#[derive(Debug)]
+pub struct ShellOutput {
+    pub stdout: String,
+    pub stderr: String,
+    pub status: u8,
 }
 
-macro_rules! CMD_END {
-    () => {
-        "ADBSYNCEND"
+// shell v2 packet ids
+const ID_STDOUT: u8 = 1;
+const ID_STDERR: u8 = 2;
+const ID_EXIT: u8 = 3;
+const ID_CLOSE_STDIN: u8 = 4;
+
+// how many times opening a command's session is retried after it drops (device reboot, adb restart)
+const SHELL_RETRIES: u32 = 3;
+
+// errors of a dropped session, see `exec` for when they are retried
+fn is_disconnect(e: &AdbErr) -> bool {
+    match e {
+        AdbErr::IO(_) => true,
+        AdbErr::Adb(msg) => {
+            msg.contains("device offline")
+                || msg.contains("no devices")
+                || (msg.contains("device '") && msg.contains("not found"))
+        }
+    }
+}
+
+// the adb server's host and port and the `host:transport...` request picking the device,
+// from the environment and the --adb-arg flags like the adb client would
+fn server_target() -> Result<(String, u16, String), AdbErr> {
+    let mut host = "127.0.0.1".to_string();
+    let mut port = std::env::var("ANDROID_ADB_SERVER_PORT").ok();
+    let mut transport = match std::env::var("ANDROID_SERIAL") {
+        Ok(serial) => format!("host:transport:{serial}"),
+        Err(_) => "host:transport-any".to_string(),
     };
+    // the --adb-arg flags that change which server or device is talked to
+    let mut args = adb_args().iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or_else(|| format!("adb flag '{arg}' needs a value"))
+        };
+        match arg.as_str() {
+            "-s" => transport = format!("host:transport:{}", value()?),
+            "-t" => transport = format!("host:transport-id:{}", value()?),
+            "-d" => transport = "host:transport-usb".to_string(),
+            "-e" => transport = "host:transport-local".to_string(),
+            "-H" => host = value()?,
+            "-P" => port = Some(value()?),
+            _ => {}
+        }
+    }
+    let port = match port {
+        Some(p) => p
+            .parse()
+            .map_err(|_| format!("invalid adb server port '{p}'"))?,
+        None => 5037,
+    };
+    Ok((host, port, transport))
 }
 
 impl AdbShell {
     pub fn new() -> Result<Self, AdbErr> {
-        let mut c = Command::new("adb")
-            .arg("shell")
-            .stdin(::std::process::Stdio::piped())
-            .stdout(::std::process::Stdio::piped())
-            .spawn()?;
-        Ok(Self {
-            si: BufWriter::new(c.stdin.take().expect("si piped")),
-            so: BufReader::new(c.stdout.take().expect("so piped")),
-        })
+        let mut shell = Self {
+            transport: transport()?,
+            recover_server: false,
+        };
+        // fails early on devices without shell v2 (before android 7)
+        shell.exec(["true"])?;
+        Ok(shell)
     }
 
-    pub fn run<I, S>(&mut self, args: I) -> Result<String, AdbErr>
+    // a session over `transport` as it is, without the check of `new`
+    pub(crate) fn over(transport: Box<dyn Transport>) -> Self {
+        Self {
+            transport,
+            recover_server: false,
+        }
+    }
+
+    // sends one length prefixed request to the server and waits for OKAY
+    fn request(stream: &mut TcpStream, req: &str) -> Result<(), AdbErr> {
+        stream.write_all(format!("{:04x}{}", req.len(), req).as_bytes())?;
+        let mut status = [0u8; 4];
+        stream.read_exact(&mut status)?;
+        match &status {
+            b"OKAY" => Ok(()),
+            b"FAIL" => {
+                let mut len = [0u8; 4];
+                stream.read_exact(&mut len)?;
+                let len = std::str::from_utf8(&len)
+                    .ok()
+                    .and_then(|l| usize::from_str_radix(l, 16).ok())
+                    .ok_or("malformed FAIL length")?;
+                let mut msg = vec![0u8; len];
+                stream.read_exact(&mut msg)?;
+                Err(format!("adb: error: {}", String::from_utf8_lossy(&msg)).into())
+            }
+            s => Err(format!(
+                "unexpected adb server reply {:?}",
+                String::from_utf8_lossy(s)
+            )
+            .into()),
+        }
+    }
+
+    pub fn exec<I, S>(&mut self, args: I) -> Result<ShellOutput, AdbErr>
     where
         I: IntoIterator<Item = S>,
         S: AsRef<str>,
     {
-        print!("[ABD SHELL] ");
-        for arg in args {
-            print!("{:?} ", &arg.as_ref());
-            self.si.write_all(arg.as_ref().as_bytes())?;
-        }
-        println!();
-        self.si
-            .write_all(concat!(";echo ", CMD_END!(), "\n").as_bytes())?;
-        self.si.flush()?;
-
-        let mut buf = ::std::string::String::new();
-        while {
-            self.so.read_line(&mut buf)?;
-            let buf = buf.trim_end();
-            if CMD_END!().len() > buf.len() {
-                true
-            } else {
-                buf.get(buf.len() - CMD_END!().len()..)
-                    .is_none_or(|b| b != CMD_END!())
+        let cmd = args
+            .into_iter()
+            .map(|a| a.as_ref().to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        crate::log_line("[ABD SHELL] ", format_args!("{:?}", cmd));
+
+        // the command starts as soon as the service is open, so only failing to open it is
+        // retried. after that it may already have run, and touch, chown or rm must not run twice
+        let service = format!("shell,v2,raw:{cmd}");
+        let mut attempt = 0;
+        let mut recoveries = 0;
+        let stream = loop {
+            match self.transport.open(&service) {
+                Err(e) if attempt < SHELL_RETRIES && is_disconnect(&e) => {
+                    attempt += 1;
+                    logw!(
+                        W014: "shell session lost ({e}), reconnecting ({attempt}/{SHELL_RETRIES})"
+                    );
+                    Self::reconnect(attempt);
+                }
+                Err(e)
+                    if self.recover_server
+                        && recoveries < SERVER_RECOVERIES
+                        && is_server_wedged(&e) =>
+                {
+                    recoveries += 1;
+                    logw!(W014: "adb server is not responding ({e}), restarting it ({recoveries}/{SERVER_RECOVERIES})");
+                    restart_server()?;
+                }
+                r => break r?,
+            }
+        };
+        Self::output(stream)
+    }
+
+    // waits for the server and device to come back after a dropped session.
+    // errors are ignored here, the retried open reports them
+    fn reconnect(attempt: u32) {
+        std::thread::sleep(std::time::Duration::from_secs(1 << attempt));
+        let _ = AdbCmd::run_v(["start-server"]);
+        let _ = AdbCmd::run_v(["reconnect", "offline"]);
+    }
+
+    fn exec_once(&mut self, cmd: &str) -> Result<ShellOutput, AdbErr> {
+        let stream = self.transport.open(&format!("shell,v2,raw:{cmd}"))?;
+        Self::output(stream)
+    }
+
+    // the command's output and exit code, from an opened `shell,v2:` service
+    fn output(mut stream: Box<dyn AdbStream>) -> Result<ShellOutput, AdbErr> {
+        // nothing is ever written to the command's stdin
+        stream.write_all(&[ID_CLOSE_STDIN, 0, 0, 0, 0])?;
+
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let mut header = [0u8; 5];
+        loop {
+            stream.read_exact(&mut header)?;
+            let len = u32::from_le_bytes(header[1..].try_into().expect("4 bytes")) as usize;
+            let mut data = vec![0u8; len];
+            stream.read_exact(&mut data)?;
+            match header[0] {
+                ID_STDOUT => stdout.extend_from_slice(&data),
+                ID_STDERR => stderr.extend_from_slice(&data),
+                ID_EXIT => {
+                    return Ok(ShellOutput {
+                        stdout: String::from_utf8_lossy(&stdout).into_owned(),
+                        stderr: String::from_utf8_lossy(&stderr).into_owned(),
+                        status: data.first().copied().unwrap_or(0),
+                    })
+                }
+                _ => {}
             }
-        } {}
-        buf.truncate(buf.len() - CMD_END!().len());
-        Ok(buf)
+        }
+    }
+
+    // stdout of a command that is expected to succeed
+    pub fn run<I, S>(&mut self, args: I) -> Result<String, AdbErr>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let op = self.exec(args)?;
+        if op.status != 0 {
+            return Err(format!(
+                "shell command exited with {}: {}",
+                op.status,
+                op.stderr.trim_end()
+            )
+            .into());
+        }
+        if !op.stderr.is_empty() {
+            logv!("{}", op.stderr.trim_end());
+        }
+        Ok(op.stdout)
+    }
+}
+
+// the device's features, asked once per run
+static FEATURES: OnceLock<String> = OnceLock::new();
+
+// `host:transport-usb` and `features` -> `host-usb:features`, the server answers those without
+// the device
+fn host_request(transport: &str, service: &str) -> String {
+    let rest = transport.strip_prefix("host:transport").unwrap_or("-any");
+    match rest.strip_prefix(':') {
+        Some(serial) => format!("host-serial:{serial}:{service}"),
+        None => match rest.trim_start_matches('-') {
+            "any" => format!("host:{service}"),
+            // `usb`, `local` and `id:N`
+            kind => format!("host-{kind}:{service}").replace("host-id:", "host-transport-id:"),
+        },
+    }
+}
+
+// whether the device's adbd has `feature`, like `ls_v2` or `stat_v2`
+pub fn has_feature(feature: &str) -> bool {
+    let features = FEATURES.get_or_init(|| {
+        transport().and_then(|t| t.features()).unwrap_or_else(|e| {
+            logv!("Could not read the device's features ({e}), using the oldest requests");
+            String::new()
+        })
+    });
+    features.split(',').any(|f| f.trim() == feature)
+}
+
+// whether the device's adbd lists directories with LIS2
+pub fn has_ls_v2() -> bool {
+    has_feature("ls_v2")
+}
+
+// what a wire errno from the device means, they are linux's
+pub fn errno_name(errno: u32) -> String {
+    match errno {
+        1 => "operation not permitted".to_string(),
+        2 => "no such file or directory".to_string(),
+        5 => "I/O error".to_string(),
+        13 => "permission denied".to_string(),
+        40 => "too many levels of symbolic links".to_string(),
+        75 => "value too large".to_string(),
+        e => format!("errno {e}"),
+    }
+}
+
+// single-quotes `s` so the device shell sees it as one literal argument
+pub fn quote(s: &str) -> String {
+    let mut q = String::with_capacity(s.len() + 2);
+    q.push('\'');
+    for c in s.chars() {
+        if c == '\'' {
+            q.push_str("'\\''");
+        } else {
+            q.push(c);
+        }
+    }
+    q.push('\'');
+    q
+}
+
+// how many kill-server/start-server cycles are tried on a stuck server before giving up
+pub const SERVER_RECOVERIES: u32 = 2;
+// `adb devices` and friends answer instantly on a healthy server
+pub const SERVER_TIMEOUT: Duration = Duration::from_secs(10);
+
+// protocol errors and hangs that restarting the adb server usually fixes
+pub fn is_server_wedged(e: &AdbErr) -> bool {
+    match e {
+        AdbErr::IO(e) => matches!(
+            e.kind(),
+            io::ErrorKind::TimedOut
+                | io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+        ),
+        AdbErr::Adb(msg) => {
+            msg.contains("protocol fault")
+                || msg.contains("failed to check server version")
+                || msg.contains("cannot connect to daemon")
+        }
+    }
+}
+
+pub fn restart_server() -> Result<(), AdbErr> {
+    AdbCmd::new()
+        .arg("kill-server")
+        .output_timeout(SERVER_TIMEOUT)?;
+    match AdbCmd::new()
+        .arg("start-server")
+        .output_timeout(SERVER_TIMEOUT)
+    {
+        // start-server reports on stderr even when it succeeds
+        Ok(_) => Ok(()),
+        Err(AdbErr::Adb(e)) if e.starts_with("* daemon") => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+// best `adb push -z` algorithm that both the host adb and the device support
+pub fn push_compression() -> Option<&'static str> {
+    // the binary compresses, it would go through a server
+    if over_usb() {
+        return None;
     }
+    // "Version 34.0.5-10900879"
+    let version = AdbCmd::run_v(["version"]).ok()?;
+    let major: u32 = version
+        .lines()
+        .find_map(|l| l.strip_prefix("Version "))?
+        .split('.')
+        .next()?
+        .parse()
+        .ok()?;
+    let features = AdbCmd::run_v(["features"]).ok()?;
+    [
+        ("zstd", "sendrecv_v2_zstd", 31),
+        ("lz4", "sendrecv_v2_lz4", 31),
+        ("brotli", "sendrecv_v2_brotli", 30),
+    ]
+    .into_iter()
+    .find(|(_, feature, since)| major >= *since && features.lines().any(|l| l.trim() == *feature))
+    .map(|(algo, _, _)| algo)
 }
 
 pub struct AdbCmd {
@@ -118,7 +509,8 @@ impl AdbCmd {
 
     pub fn new() -> Self {
         let mut cmd = Command::new("adb");
-        cmd.stdout(::std::process::Stdio::piped())
+        cmd.args(adb_args())
+            .stdout(::std::process::Stdio::piped())
             .stderr(::std::process::Stdio::piped());
         Self { cmd }
     }
@@ -138,25 +530,108 @@ impl AdbCmd {
     }
 
     pub fn output(&mut self) -> Result<String, AdbErr> {
-        println!("[ADB] {:?}", self.cmd);
+        crate::log_line("[ADB] ", format_args!("{:?}", self.cmd));
         self._output()
     }
 
     pub fn output_v(&mut self) -> Result<String, AdbErr> {
         if crate::is_verbose() {
-            println!("[ADB] {:?}", self.cmd);
+            crate::log_line("[ADB] ", format_args!("{:?}", self.cmd));
         }
         self._output()
     }
 
+    // what the command reads, for `spawn`
+    pub fn stdin(&mut self, cfg: Stdio) -> &mut Self {
+        self.cmd.stdin(cfg);
+        self
+    }
+
+    // where the output goes instead of being captured, for `spawn`
+    pub fn stdout(&mut self, cfg: Stdio) -> &mut Self {
+        self.cmd.stdout(cfg);
+        self
+    }
+
+    // spawns without waiting, for streaming the command's stdout
+    pub fn spawn(&mut self) -> Result<Child, AdbErr> {
+        if crate::is_verbose() {
+            crate::log_line("[ADB] ", format_args!("{:?}", self.cmd));
+        }
+        Ok(self.cmd.spawn()?)
+    }
+
+    // like `output_v`, but kills the command if it does not finish within `timeout`.
+    // only for commands that are expected to return quickly
+    pub fn output_timeout(&mut self, timeout: Duration) -> Result<String, AdbErr> {
+        if crate::is_verbose() {
+            crate::log_line("[ADB] ", format_args!("{:?}", self.cmd));
+        }
+        if let Some(r) = client::run(&self.arg_strings(), Some(timeout)) {
+            return r.and_then(Self::check);
+        }
+        let mut child = self.cmd.spawn()?;
+        let start = Instant::now();
+        while child.try_wait()?.is_none() {
+            if start.elapsed() > timeout {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(
+                    io::Error::new(io::ErrorKind::TimedOut, "adb command timed out").into(),
+                );
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        Self::parse_output(child.wait_with_output()?)
+    }
+
+    // like `output`, but kills the command once the file `watch` it writes has not grown for
+    // `stall`, for transfers that can take long but should never stop moving
+    pub fn output_stalled(&mut self, watch: &str, stall: Duration) -> Result<String, AdbErr> {
+        crate::log_line("[ADB] ", format_args!("{:?}", self.cmd));
+        let mut child = self.cmd.spawn()?;
+        let (mut size, mut moved) = (0, Instant::now());
+        while child.try_wait()?.is_none() {
+            let now = std::fs::metadata(watch).map_or(0, |m| m.len());
+            if now != size {
+                (size, moved) = (now, Instant::now());
+            } else if moved.elapsed() > stall {
+                let _ = child.kill();
+                let _ = child.wait();
+                let msg = format!("no progress for {}s", stall.as_secs());
+                return Err(io::Error::new(io::ErrorKind::TimedOut, msg).into());
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        Self::parse_output(child.wait_with_output()?)
+    }
+
+    // through the server's socket where the client can, else the binary
     fn _output(&mut self) -> Result<String, AdbErr> {
+        if let Some(r) = client::run(&self.arg_strings(), None) {
+            return r.and_then(Self::check);
+        }
         let op = self.cmd.output()?;
+        Self::parse_output(op)
+    }
+
+    fn arg_strings(&self) -> Vec<String> {
+        self.cmd
+            .get_args()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn parse_output(op: Output) -> Result<String, AdbErr> {
         let op = if !op.stderr.is_empty() {
             op.stderr
         } else {
             op.stdout
         };
-        let op = String::from_utf8(op).expect("utf8 output");
+        Self::check(String::from_utf8(op).expect("utf8 output"))
+    }
+
+    fn check(op: String) -> Result<String, AdbErr> {
         if op.starts_with("adb: error:") {
             Err(AdbErr::from(op))
         } else {
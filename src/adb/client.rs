@@ -0,0 +1,203 @@
+// the adb server's host services spoken over its socket, so `AdbCmd` runs without an `adb`
+// binary on PATH as long as a server is running. what is not covered here, or when the server
+// cannot be reached, still spawns the binary: starting the server, whole directories,
+// compressed pushes, port forwards and streaming `exec-out`. with `--usb` there is no server,
+// what a sync needs is answered over the usb transport
+//   host:devices, host:connect:<addr>      the server itself
+//   host-serial:<serial>:features          about one device, without talking to it
+//   host:transport:<serial>, then shell,v2: or tcpip:<port>   a service on the device
+
+use super::sync;
+use super::{adb_args, has_feature, host_request, server_target, AdbErr, AdbShell};
+use super::{ServerTransport, Transport};
+use crate::{is_verbose, logv};
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::TcpStream;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+// the adb flags that only pick the server or the device
+const FLAGS: [&str; 6] = ["-s", "-t", "-d", "-e", "-H", "-P"];
+
+// what `adb <args>` prints, None when it needs the binary. `timeout` bounds each read
+pub(super) fn run(args: &[String], timeout: Option<Duration>) -> Option<Result<String, AdbErr>> {
+    // other flags, like -L, may point somewhere else
+    if adb_args()
+        .iter()
+        .any(|a| a.starts_with('-') && !FLAGS.contains(&a.as_str()))
+    {
+        return None;
+    }
+    let (host, port, default) = server_target().ok()?;
+    let mut transport = default.clone();
+    let mut rest: Vec<&str> = args[adb_args().len()..]
+        .iter()
+        .map(String::as_str)
+        .collect();
+    // `-s <serial>` and friends in front of the command
+    loop {
+        match rest.as_slice() {
+            ["-s", serial, ..] => transport = format!("host:transport:{serial}"),
+            ["-t", id, ..] => transport = format!("host:transport-id:{id}"),
+            ["-d", ..] => transport = "host:transport-usb".to_string(),
+            ["-e", ..] => transport = "host:transport-local".to_string(),
+            [flag, ..] if flag.starts_with('-') => return None,
+            _ => break,
+        }
+        let flag_len = if matches!(rest[0], "-s" | "-t") { 2 } else { 1 };
+        rest.drain(..flag_len);
+    }
+    #[cfg(feature = "usb")]
+    if let Some(usb) = super::usb::device() {
+        let this = transport == default || transport == format!("host:transport:{}", usb.serial());
+        return run_usb(usb, &rest, this);
+    }
+    let mut stream = match TcpStream::connect((host.as_str(), port)) {
+        Ok(s) => s,
+        Err(e) => {
+            logv!("The adb server is not reachable ({}), running adb", e);
+            return None;
+        }
+    };
+    if let Err(e) = stream.set_read_timeout(timeout) {
+        return Some(Err(e.into()));
+    }
+    let r =
+        match rest.as_slice() {
+            // a dying server may still accept the connection, a request tells
+            ["start-server"] => match query(&mut stream, "host:version") {
+                Ok(_) => Ok(String::new()),
+                Err(_) => return None,
+            },
+            ["kill-server"] => AdbShell::request(&mut stream, "host:kill").map(|()| String::new()),
+            ["devices"] => query(&mut stream, "host:devices")
+                .map(|d| format!("List of devices attached\n{d}\n")),
+            ["devices", "-l"] => query(&mut stream, "host:devices-l")
+                .map(|d| format!("List of devices attached\n{d}\n")),
+            ["connect", addr] => query(&mut stream, &format!("host:connect:{addr}")),
+            ["disconnect", addr] => query(&mut stream, &format!("host:disconnect:{addr}")),
+            ["reconnect", "offline"] => query(&mut stream, "host:reconnect-offline"),
+            ["features"] => query(&mut stream, &host_request(&transport, "features")),
+            ["get-state"] => query(&mut stream, &host_request(&transport, "get-state")),
+            ["get-serialno"] => query(&mut stream, &host_request(&transport, "get-serialno")),
+            ["shell", cmd @ ..] if !cmd.is_empty() => {
+                let server = ServerTransport {
+                    host,
+                    port,
+                    transport,
+                };
+                shell(Box::new(server), &cmd.join(" "))
+            }
+            ["tcpip", p] => service(&mut stream, &transport, &format!("tcpip:{p}")),
+            // the sync connections go to the default device
+            ["push", local, remote] if transport == default && Path::new(local).is_file() => {
+                push(local, remote)
+            }
+            ["pull", remote, local] if transport == default && !Path::new(local).is_dir() => {
+                pull_file(remote, local)?
+            }
+            _ => return None,
+        };
+    Some(r.map_err(|e| match e {
+        // unix reports a read timeout as EAGAIN
+        AdbErr::IO(e) if e.kind() == io::ErrorKind::WouldBlock => {
+            io::Error::new(io::ErrorKind::TimedOut, "adb command timed out").into()
+        }
+        e => e,
+    }))
+}
+
+// `this_device` when the command is for the usb device, not another one picked with `-s`
+#[cfg(feature = "usb")]
+fn run_usb(
+    usb: &super::usb::UsbTransport,
+    rest: &[&str],
+    this_device: bool,
+) -> Option<Result<String, AdbErr>> {
+    let r = match rest {
+        ["start-server"] | ["kill-server"] | ["reconnect", "offline"] => Ok(String::new()),
+        ["devices"] => Ok(format!(
+            "List of devices attached\n{}\tdevice\n\n",
+            usb.serial()
+        )),
+        _ if !this_device => return None,
+        ["features"] => usb.features(),
+        ["get-state"] => Ok("device".to_string()),
+        ["get-serialno"] => Ok(usb.serial().to_string()),
+        ["shell", cmd @ ..] if !cmd.is_empty() => shell(Box::new(usb.clone()), &cmd.join(" ")),
+        ["push", local, remote] if Path::new(local).is_file() => push(local, remote),
+        ["pull", remote, local] if !Path::new(local).is_dir() => pull_file(remote, local)?,
+        _ => return None,
+    };
+    Some(r)
+}
+
+// a host request whose answer is a length prefixed string, or nothing
+fn query(stream: &mut TcpStream, req: &str) -> Result<String, AdbErr> {
+    AdbShell::request(stream, req)?;
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply)?;
+    let prefixed = std::str::from_utf8(reply.get(..4).unwrap_or_default())
+        .ok()
+        .and_then(|l| usize::from_str_radix(l, 16).ok());
+    let text = match prefixed {
+        Some(len) if len == reply.len() - 4 => &reply[4..],
+        _ => &reply[..],
+    };
+    Ok(String::from_utf8_lossy(text).into_owned())
+}
+
+// a device service whose output ends with the connection
+fn service(stream: &mut TcpStream, transport: &str, service: &str) -> Result<String, AdbErr> {
+    AdbShell::request(stream, transport)?;
+    AdbShell::request(stream, service)?;
+    let mut out = Vec::new();
+    stream.read_to_end(&mut out)?;
+    Ok(String::from_utf8_lossy(&out).into_owned())
+}
+
+// like the binary, what the command wrote to stderr if it wrote anything, else its stdout
+fn shell(transport: Box<dyn Transport>, cmd: &str) -> Result<String, AdbErr> {
+    if !has_feature("shell_v2") {
+        let mut out = Vec::new();
+        transport
+            .open(&format!("shell:{cmd}"))?
+            .read_to_end(&mut out)?;
+        return Ok(String::from_utf8_lossy(&out).into_owned());
+    }
+    let mut shell = AdbShell::over(transport);
+    let op = shell.exec_once(cmd)?;
+    Ok(if op.stderr.is_empty() {
+        op.stdout
+    } else {
+        op.stderr
+    })
+}
+
+fn push(local: &str, remote: &str) -> Result<String, AdbErr> {
+    let mut file = File::open(local)?;
+    let meta = file.metadata()?;
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs() as u32);
+    sync::with(|s| s.send(&mut file, remote, sync::local_mode(&meta), mtime))?;
+    Ok(String::new())
+}
+
+// only regular files, adb pulls directories recursively
+fn pull_file(remote: &str, local: &str) -> Option<Result<String, AdbErr>> {
+    match sync::with(|s| s.stat(remote)) {
+        Ok(Some(st)) if st.mode >> 12 == 0o10 => Some(pull(remote, local)),
+        _ => None,
+    }
+}
+
+fn pull(remote: &str, local: &str) -> Result<String, AdbErr> {
+    let mut out = io::BufWriter::new(File::create(local)?);
+    sync::with(|s| s.recv(remote, &mut out, None))?;
+    out.into_inner().map_err(|e| e.into_error())?;
+    Ok(String::new())
+}
@@ -0,0 +1,277 @@
+// adbd's file sync service, spoken directly instead of through `adb push`, `pull`
+// and `ls`: no adb process per file, and the exact mode, size and mtime the device reports.
+// a request is an id and a length, the reply comes on the same connection
+//   LIST, LIS2  the entries of a directory     STAT, LST2  one path
+//   RECV        a file from the device         SEND        a file to the device
+// a connection is kept for the next request, one per thread doing transfers
+
+use super::{has_feature, transport, AdbErr, AdbStream};
+use std::io::{self, BufReader, Read, Write};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// the most one DATA packet may carry
+const MAX_DATA: usize = 64 * 1024;
+
+// one entry of a directory listing
+pub struct DirEntry {
+    pub name: String,
+    pub mode: u32,
+    pub size: u64,
+    pub mtime: i64,
+    // errno of the lstat that failed on the device, the other fields are then zero. LIST
+    // leaves those entries out instead
+    pub error: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stat {
+    pub mode: u32,
+    pub size: u64,
+    pub mtime: i64,
+}
+
+pub struct SyncConn {
+    r: BufReader<Box<dyn AdbStream>>,
+}
+
+// the connection of the last request, for the next one
+static IDLE: Mutex<Option<SyncConn>> = Mutex::new(None);
+
+// runs `f` on an idle connection, or a new one when there is none. a failed request leaves
+// the connection in an unknown state, it is closed then
+pub fn with<T>(f: impl FnOnce(&mut SyncConn) -> Result<T, AdbErr>) -> Result<T, AdbErr> {
+    let idle = IDLE.lock().unwrap().take();
+    let mut conn = match idle {
+        Some(conn) => conn,
+        None => SyncConn::open()?,
+    };
+    let r = f(&mut conn)?;
+    IDLE.lock().unwrap().get_or_insert(conn);
+    Ok(r)
+}
+
+impl SyncConn {
+    pub fn open() -> Result<Self, AdbErr> {
+        Ok(Self {
+            r: BufReader::new(transport()?.open("sync:")?),
+        })
+    }
+
+    fn send_request(&mut self, id: &[u8; 4], payload: &[u8]) -> Result<(), AdbErr> {
+        let mut req = Vec::with_capacity(8 + payload.len());
+        req.extend(id);
+        req.extend((payload.len() as u32).to_le_bytes());
+        req.extend(payload);
+        self.r.get_mut().write_all(&req)?;
+        Ok(())
+    }
+
+    fn read_u32(&mut self) -> Result<u32, AdbErr> {
+        let mut b = [0u8; 4];
+        self.r.read_exact(&mut b)?;
+        Ok(u32::from_le_bytes(b))
+    }
+
+    // the id of the next reply, the message of a FAIL as the error
+    fn reply(&mut self) -> Result<[u8; 4], AdbErr> {
+        let mut id = [0u8; 4];
+        self.r.read_exact(&mut id)?;
+        if &id == b"FAIL" {
+            let mut msg = vec![0u8; self.read_u32()? as usize];
+            self.r.read_exact(&mut msg)?;
+            return Err(format!("adb: error: {}", String::from_utf8_lossy(&msg)).into());
+        }
+        Ok(id)
+    }
+
+    fn unexpected(id: &[u8; 4]) -> AdbErr {
+        format!("unexpected sync reply {:?}", String::from_utf8_lossy(id)).into()
+    }
+
+    // LIST has 32 bit sizes and leaves out what the device cannot stat
+    pub fn list(&mut self, path: &str) -> Result<Vec<DirEntry>, AdbErr> {
+        self.send_request(b"LIST", path.as_bytes())?;
+        let mut entries = Vec::new();
+        loop {
+            let id = self.reply()?;
+            // mode, size, mtime, namelen. the DONE at the end has them too
+            let mut dent = [0u8; 16];
+            self.r.read_exact(&mut dent)?;
+            match &id {
+                b"DONE" => return Ok(entries),
+                b"DENT" => {}
+                id => return Err(Self::unexpected(id)),
+            }
+            let u32_at = |i: usize| u32::from_le_bytes(dent[i..i + 4].try_into().expect("4 bytes"));
+            let mut name = vec![0u8; u32_at(12) as usize];
+            self.r.read_exact(&mut name)?;
+            entries.push(DirEntry {
+                name: String::from_utf8_lossy(&name).into_owned(),
+                mode: u32_at(0),
+                size: u32_at(4) as u64,
+                mtime: u32_at(8) as i64,
+                error: 0,
+            });
+        }
+    }
+
+    // needs the `ls_v2` feature
+    pub fn list_v2(&mut self, path: &str) -> Result<Vec<DirEntry>, AdbErr> {
+        self.send_request(b"LIS2", path.as_bytes())?;
+        let mut entries = Vec::new();
+        // the dent_v2 struct after its id, the DONE at the end is one too
+        let mut dent = [0u8; 72];
+        loop {
+            let id = self.reply()?;
+            self.r.read_exact(&mut dent)?;
+            match &id {
+                b"DONE" => return Ok(entries),
+                b"DNT2" => {}
+                id => return Err(Self::unexpected(id)),
+            }
+            let u32_at = |i: usize| u32::from_le_bytes(dent[i..i + 4].try_into().expect("4 bytes"));
+            let u64_at = |i: usize| u64::from_le_bytes(dent[i..i + 8].try_into().expect("8 bytes"));
+            // error, dev, ino, mode, nlink, uid, gid, size, atime, mtime, ctime, namelen
+            let mut name = vec![0u8; u32_at(68) as usize];
+            self.r.read_exact(&mut name)?;
+            entries.push(DirEntry {
+                name: String::from_utf8_lossy(&name).into_owned(),
+                mode: u32_at(20),
+                size: u64_at(36),
+                mtime: u64_at(52) as i64,
+                error: u32_at(0),
+            });
+        }
+    }
+
+    // None if `path` does not exist. symlinks are not followed. LST2 where the device has it,
+    // for sizes over 4 GiB
+    pub fn stat(&mut self, path: &str) -> Result<Option<Stat>, AdbErr> {
+        if !has_feature("stat_v2") {
+            self.send_request(b"STAT", path.as_bytes())?;
+            let id = self.reply()?;
+            if &id != b"STAT" {
+                return Err(Self::unexpected(&id));
+            }
+            let (mode, size, mtime) = (self.read_u32()?, self.read_u32()?, self.read_u32()?);
+            // all zero for a path that does not exist
+            return Ok((mode != 0).then_some(Stat {
+                mode,
+                size: size as u64,
+                mtime: mtime as i64,
+            }));
+        }
+        self.send_request(b"LST2", path.as_bytes())?;
+        let id = self.reply()?;
+        if &id != b"LST2" {
+            return Err(Self::unexpected(&id));
+        }
+        // error, dev, ino, mode, nlink, uid, gid, size, atime, mtime, ctime
+        let mut st = [0u8; 68];
+        self.r.read_exact(&mut st)?;
+        let u32_at = |i: usize| u32::from_le_bytes(st[i..i + 4].try_into().expect("4 bytes"));
+        let u64_at = |i: usize| u64::from_le_bytes(st[i..i + 8].try_into().expect("8 bytes"));
+        match u32_at(0) {
+            0 => Ok(Some(Stat {
+                mode: u32_at(20),
+                size: u64_at(36),
+                mtime: u64_at(52) as i64,
+            })),
+            // ENOENT and ENOTDIR
+            2 | 20 => Ok(None),
+            e => Err(format!("adb: error: stat '{path}': {}", super::errno_name(e)).into()),
+        }
+    }
+
+    // writes the device file `path` to `out`, returns how many bytes it had. with `stall`, gives
+    // up once no data came for that long
+    pub fn recv(
+        &mut self,
+        path: &str,
+        out: &mut impl Write,
+        stall: Option<Duration>,
+    ) -> Result<u64, AdbErr> {
+        self.r.get_ref().set_read_timeout(stall)?;
+        let r = self.recv_data(path, out);
+        self.r.get_ref().set_read_timeout(None)?;
+        r.map_err(|e| match (e, stall) {
+            // unix reports a read timeout as EAGAIN
+            (AdbErr::IO(e), Some(stall))
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                let msg = format!("no progress for {}s", stall.as_secs());
+                io::Error::new(io::ErrorKind::TimedOut, msg).into()
+            }
+            (e, _) => e,
+        })
+    }
+
+    fn recv_data(&mut self, path: &str, out: &mut impl Write) -> Result<u64, AdbErr> {
+        self.send_request(b"RECV", path.as_bytes())?;
+        let mut buf = vec![0u8; MAX_DATA];
+        let mut total = 0;
+        loop {
+            let id = self.reply()?;
+            let len = self.read_u32()? as usize;
+            match &id {
+                b"DONE" => return Ok(total),
+                b"DATA" if len <= MAX_DATA => {}
+                id => return Err(Self::unexpected(id)),
+            }
+            self.r.read_exact(&mut buf[..len])?;
+            out.write_all(&buf[..len])?;
+            total += len as u64;
+        }
+    }
+
+    // writes `data` to the device file `path` with permission bits `mode` and `mtime`. adbd
+    // creates missing parent directories and replaces the file once it is complete
+    pub fn send(
+        &mut self,
+        data: &mut impl Read,
+        path: &str,
+        mode: u32,
+        mtime: u32,
+    ) -> Result<(), AdbErr> {
+        self.send_request(b"SEND", format!("{path},{mode}").as_bytes())?;
+        let mut buf = vec![0u8; MAX_DATA];
+        loop {
+            let n = data.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            self.send_request(b"DATA", &buf[..n])?;
+        }
+        // DONE carries the mtime where the length would be
+        let mut done = b"DONE".to_vec();
+        done.extend(mtime.to_le_bytes());
+        self.r.get_mut().write_all(&done)?;
+        let id = self.reply()?;
+        if &id != b"OKAY" {
+            return Err(Self::unexpected(&id));
+        }
+        self.read_u32()?;
+        Ok(())
+    }
+}
+
+// the mode SEND gets for a local file, its own like `adb push`
+#[cfg(unix)]
+pub fn local_mode(meta: &std::fs::Metadata) -> u32 {
+    std::os::unix::fs::PermissionsExt::mode(&meta.permissions())
+}
+
+#[cfg(not(unix))]
+pub fn local_mode(_meta: &std::fs::Metadata) -> u32 {
+    0o100644
+}
+
+impl Drop for SyncConn {
+    fn drop(&mut self) {
+        let _ = self.r.get_mut().write_all(b"QUIT\0\0\0\0");
+    }
+}
@@ -0,0 +1,683 @@
+// adb's own protocol spoken to the device over usb, for `--usb`: no adb server or binary is
+// needed, the device's adb interface is claimed directly. the handshake is the server's, with
+// the user's adb key so a device that trusts the adb client trusts this too
+//   CNXN(version, max data, "host::")   ->  AUTH(TOKEN) or CNXN(.., "device::..features=..")
+//   AUTH(SIGNATURE, token signed)       ->  CNXN, or AUTH(TOKEN) when the key is not trusted
+//   AUTH(RSAPUBLICKEY, key)             ->  CNXN once the user accepted it on the device
+// every service is a stream on the one connection, told apart by the ids of both ends
+//   OPEN(local, 0, service)  ->  OKAY(remote, local), or CLSE when the device refuses it
+//   WRTE(local, remote, data)  ->  OKAY, before the next WRTE on that stream
+//   CLSE(local, remote)  from either end closes it
+// whichever thread waits on its stream reads the next message and queues it for its stream
+
+use super::{server_target, AdbErr, AdbStream, Transport};
+use crate::{is_verbose, logi, logv};
+use base64::Engine;
+use rsa::pkcs1v15::SigningKey;
+use rsa::pkcs8::{DecodePrivateKey, EncodePrivateKey, LineEnding};
+use rsa::signature::hazmat::PrehashSigner;
+use rsa::signature::SignatureEncoding;
+use rsa::traits::PublicKeyParts;
+use rsa::{BigUint, RsaPrivateKey};
+use rusb::{DeviceHandle, GlobalContext};
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u32)]
+enum Cmd {
+    CNXN = 1314410051,
+    AUTH = 1213486401,
+    CLSE = 1163086915,
+    OKAY = 1497451343,
+    OPEN = 1313165391,
+    SYNC = 1129208147,
+    WRTE = 1163154007,
+}
+impl TryFrom<u32> for Cmd {
+    type Error = ();
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            v if Self::CNXN as u32 == v => Ok(Self::CNXN),
+            v if Self::AUTH as u32 == v => Ok(Self::AUTH),
+            v if Self::CLSE as u32 == v => Ok(Self::CLSE),
+            v if Self::OKAY as u32 == v => Ok(Self::OKAY),
+            v if Self::OPEN as u32 == v => Ok(Self::OPEN),
+            v if Self::SYNC as u32 == v => Ok(Self::SYNC),
+            v if Self::WRTE as u32 == v => Ok(Self::WRTE),
+            _ => Err(()),
+        }
+    }
+}
+
+// from this version on neither side checksums the data
+const VERSION: u32 = 0x01000001;
+const VERSION_CHECKSUMS: u32 = 0x01000000;
+const MAX_ADB_DATA: u32 = 256 * 1024;
+const MSG_SIZE: usize = 4 * 6;
+
+const AUTH_TOKEN: u32 = 1;
+const AUTH_SIGNATURE: u32 = 2;
+const AUTH_RSAPUBLICKEY: u32 = 3;
+
+// adb keys are 2048 bit, in 32 bit words
+const KEY_WORDS: usize = 64;
+
+// how long one bulk read waits, a thread waiting longer than that reads again
+const POLL: Duration = Duration::from_millis(200);
+const TIMEOUT: Duration = Duration::from_secs(10);
+// for the user to allow usb debugging on the device
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(60);
+
+impl From<rusb::Error> for AdbErr {
+    fn from(e: rusb::Error) -> Self {
+        match e {
+            rusb::Error::Timeout => io::Error::new(io::ErrorKind::TimedOut, "usb timed out").into(),
+            rusb::Error::NoDevice => {
+                io::Error::new(io::ErrorKind::ConnectionAborted, "usb device is gone").into()
+            }
+            e => format!("usb: {e}").into(),
+        }
+    }
+}
+
+fn io_err(e: AdbErr) -> io::Error {
+    match e {
+        AdbErr::IO(e) => e,
+        AdbErr::Adb(msg) => io::Error::other(msg.into_string()),
+    }
+}
+
+#[derive(Debug)]
+struct AdbMsg {
+    cmd: Cmd,
+    arg0: u32,
+    arg1: u32,
+    data: Vec<u8>,
+}
+
+fn pack(cmd: Cmd, arg0: u32, arg1: u32, data: &[u8]) -> [u8; MSG_SIZE] {
+    let cmd = cmd as u32;
+    let fields = [
+        cmd,
+        arg0,
+        arg1,
+        data.len() as u32,
+        checksum(data),
+        cmd ^ 0xFFFFFFFF,
+    ];
+    let mut buf = [0u8; MSG_SIZE];
+    for (i, f) in fields.iter().enumerate() {
+        buf[i * 4..i * 4 + 4].copy_from_slice(&f.to_le_bytes());
+    }
+    buf
+}
+
+// the command, both args, the data length and its checksum
+fn unpack(buf: &[u8; MSG_SIZE]) -> Result<(Cmd, u32, u32, u32, u32), AdbErr> {
+    let u32_at = |i: usize| u32::from_le_bytes(buf[i * 4..i * 4 + 4].try_into().expect("4 bytes"));
+    let cmd = Cmd::try_from(u32_at(0))
+        .ok()
+        .filter(|&c| u32_at(5) == c as u32 ^ 0xFFFFFFFF)
+        .ok_or_else(|| format!("malformed adb message {:08x}", u32_at(0)))?;
+    Ok((cmd, u32_at(1), u32_at(2), u32_at(3), u32_at(4)))
+}
+
+fn checksum(data: &[u8]) -> u32 {
+    data.iter().map(|&b| b as u32).sum::<u32>()
+}
+
+// the claimed adb interface of the device
+struct Usb {
+    handle: DeviceHandle<GlobalContext>,
+    read_endpoint: u8,
+    write_endpoint: u8,
+    // the write endpoint's packet size, data filling whole packets ends with an empty one
+    packet_size: usize,
+}
+
+impl Usb {
+    fn send(&self, cmd: Cmd, arg0: u32, arg1: u32, data: &[u8]) -> Result<(), AdbErr> {
+        let header = pack(cmd, arg0, arg1, data);
+        self.handle
+            .write_bulk(self.write_endpoint, &header, TIMEOUT)?;
+        if data.is_empty() {
+            return Ok(());
+        }
+        let mut written = 0;
+        while written < data.len() {
+            written += self
+                .handle
+                .write_bulk(self.write_endpoint, &data[written..], TIMEOUT)?;
+        }
+        if data.len() % self.packet_size == 0 {
+            self.handle.write_bulk(self.write_endpoint, &[], TIMEOUT)?;
+        }
+        Ok(())
+    }
+
+    // the next message, None when none came within `wait`
+    fn recv(&self, wait: Duration, checksums: bool) -> Result<Option<AdbMsg>, AdbErr> {
+        let mut header = [0u8; MSG_SIZE];
+        match self.handle.read_bulk(self.read_endpoint, &mut header, wait) {
+            Err(rusb::Error::Timeout) => return Ok(None),
+            Err(e) => return Err(e.into()),
+            Ok(MSG_SIZE) => {}
+            Ok(n) => return Err(format!("short adb message header of {n} bytes").into()),
+        }
+        let (cmd, arg0, arg1, len, sum) = unpack(&header)?;
+        let mut data = vec![0u8; len as usize];
+        let mut read = 0;
+        while read < data.len() {
+            read += self
+                .handle
+                .read_bulk(self.read_endpoint, &mut data[read..], TIMEOUT)?;
+        }
+        if checksums && checksum(&data) != sum {
+            return Err("adb message checksum mismatch".into());
+        }
+        Ok(Some(AdbMsg {
+            cmd,
+            arg0,
+            arg1,
+            data,
+        }))
+    }
+
+    // drops what a previous session left unread
+    fn flush(&self) -> Result<(), AdbErr> {
+        let mut buf = [0u8; 64 * 1024];
+        loop {
+            match self
+                .handle
+                .read_bulk(self.read_endpoint, &mut buf, Duration::from_millis(100))
+            {
+                Err(rusb::Error::Timeout) | Ok(0) => return Ok(()),
+                Err(e) => return Err(e.into()),
+                Ok(_) => {}
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct StreamState {
+    // the device's id for the stream, 0 until it accepted the OPEN
+    remote: u32,
+    // the device OKAYed the last message, the next WRTE may go
+    ready: bool,
+    // WRTEs from the device, each OKAYed once it is taken
+    data: VecDeque<Vec<u8>>,
+    closed: bool,
+}
+
+#[derive(Default)]
+struct Streams {
+    last_id: u32,
+    open: HashMap<u32, StreamState>,
+    // a thread is reading from the device, the others wait for what it queues
+    reading: bool,
+}
+
+struct Device {
+    usb: Usb,
+    serial: String,
+    // the CNXN banner, `device::ro.product.name=..;..;features=shell_v2,cmd,..`
+    banner: String,
+    max_data: usize,
+    checksums: bool,
+    // a message's header and data go out together
+    write: Mutex<()>,
+    streams: Mutex<Streams>,
+    changed: Condvar,
+}
+
+impl Device {
+    fn send(&self, cmd: Cmd, arg0: u32, arg1: u32, data: &[u8]) -> Result<(), AdbErr> {
+        let _write = self.write.lock().unwrap();
+        self.usb.send(cmd, arg0, arg1, data)
+    }
+
+    // waits until `take` gets something out of stream `id`, reading from the device meanwhile
+    fn wait<T>(
+        &self,
+        id: u32,
+        deadline: Option<Instant>,
+        mut take: impl FnMut(&mut StreamState) -> Option<T>,
+    ) -> Result<T, AdbErr> {
+        let mut streams = self.streams.lock().unwrap();
+        loop {
+            let state = streams.open.get_mut(&id).ok_or("adb stream is closed")?;
+            if let Some(t) = take(state) {
+                return Ok(t);
+            }
+            if deadline.is_some_and(|d| Instant::now() > d) {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "adb stream timed out").into());
+            }
+            if streams.reading {
+                streams = self.changed.wait_timeout(streams, POLL).unwrap().0;
+                continue;
+            }
+            streams.reading = true;
+            drop(streams);
+            let msg = self.usb.recv(POLL, self.checksums);
+            streams = self.streams.lock().unwrap();
+            streams.reading = false;
+            self.changed.notify_all();
+            if let Some(msg) = msg? {
+                self.dispatch(&mut streams, msg)?;
+            }
+        }
+    }
+
+    fn dispatch(&self, streams: &mut Streams, msg: AdbMsg) -> Result<(), AdbErr> {
+        let (remote, local) = (msg.arg0, msg.arg1);
+        let Some(state) = streams.open.get_mut(&local) else {
+            // a stream dropped here that the device still writes to
+            if msg.cmd == Cmd::WRTE {
+                self.send(Cmd::CLSE, local, remote, &[])?;
+            }
+            return Ok(());
+        };
+        match msg.cmd {
+            Cmd::OKAY => {
+                state.remote = remote;
+                state.ready = true;
+            }
+            Cmd::WRTE if msg.data.is_empty() => self.send(Cmd::OKAY, local, remote, &[])?,
+            Cmd::WRTE => state.data.push_back(msg.data),
+            Cmd::CLSE => state.closed = true,
+            Cmd::CNXN | Cmd::AUTH => return Err("the device restarted its adb connection".into()),
+            _ => logv!("Ignoring adb message {:?}", msg.cmd),
+        }
+        Ok(())
+    }
+}
+
+// the device `--usb` opened, for the run
+#[derive(Clone)]
+pub struct UsbTransport(Arc<Device>);
+
+static DEVICE: OnceLock<UsbTransport> = OnceLock::new();
+
+// the usb transport when `--usb` is on
+pub fn device() -> Option<&'static UsbTransport> {
+    DEVICE.get()
+}
+
+// opens the device `-s`/ANDROID_SERIAL picks, or the only one, for everything after this
+pub fn open() -> Result<(), AdbErr> {
+    let (_, _, transport) = server_target()?;
+    let serial = transport.strip_prefix("host:transport:");
+    let (usb, serial) = find(serial)?;
+    usb.flush()?;
+    let (version, max_data, banner) = connect(&usb)?;
+    logi!("Using device {} over usb", serial);
+    logv!("{}", banner);
+    let device = Device {
+        usb,
+        serial,
+        banner,
+        max_data: max_data.min(MAX_ADB_DATA) as usize,
+        checksums: version <= VERSION_CHECKSUMS,
+        write: Mutex::new(()),
+        streams: Mutex::new(Streams::default()),
+        changed: Condvar::new(),
+    };
+    let _ = DEVICE.set(UsbTransport(Arc::new(device)));
+    Ok(())
+}
+
+impl UsbTransport {
+    pub fn serial(&self) -> &str {
+        &self.0.serial
+    }
+}
+
+impl Transport for UsbTransport {
+    fn open(&self, service: &str) -> Result<Box<dyn AdbStream>, AdbErr> {
+        let dev = &self.0;
+        let local = {
+            let mut streams = dev.streams.lock().unwrap();
+            streams.last_id += 1;
+            let id = streams.last_id;
+            streams.open.insert(id, StreamState::default());
+            id
+        };
+        let mut stream = UsbStream {
+            dev: self.0.clone(),
+            local,
+            remote: 0,
+            buf: Vec::new(),
+            pos: 0,
+            timeout: Cell::new(None),
+        };
+        let mut name = service.as_bytes().to_vec();
+        name.push(0);
+        dev.send(Cmd::OPEN, local, 0, &name)?;
+        let deadline = Instant::now() + TIMEOUT;
+        stream.remote = dev.wait(local, Some(deadline), |st| {
+            (st.remote != 0 || st.closed).then_some(st.remote)
+        })?;
+        if stream.remote == 0 {
+            return Err(format!("adb: error: the device refused '{service}'").into());
+        }
+        Ok(Box::new(stream))
+    }
+
+    fn features(&self) -> Result<String, AdbErr> {
+        let features = self
+            .0
+            .banner
+            .split(';')
+            .find_map(|p| p.strip_prefix("features="));
+        Ok(features.unwrap_or_default().to_string())
+    }
+}
+
+pub struct UsbStream {
+    dev: Arc<Device>,
+    local: u32,
+    remote: u32,
+    // the rest of the last WRTE
+    buf: Vec<u8>,
+    pos: usize,
+    timeout: Cell<Option<Duration>>,
+}
+
+impl UsbStream {
+    fn deadline(&self) -> Option<Instant> {
+        self.timeout.get().map(|t| Instant::now() + t)
+    }
+}
+
+impl Read for UsbStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buf.len() {
+            // empty at the end of the stream
+            let data = self
+                .dev
+                .wait(self.local, self.deadline(), |st| {
+                    st.data.pop_front().or_else(|| st.closed.then(Vec::new))
+                })
+                .map_err(io_err)?;
+            if data.is_empty() {
+                return Ok(0);
+            }
+            self.dev
+                .send(Cmd::OKAY, self.local, self.remote, &[])
+                .map_err(io_err)?;
+            (self.buf, self.pos) = (data, 0);
+        }
+        let n = buf.len().min(self.buf.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl Write for UsbStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.dev.max_data);
+        let open = self
+            .dev
+            .wait(self.local, self.deadline(), |st| {
+                if st.closed {
+                    Some(false)
+                } else {
+                    std::mem::take(&mut st.ready).then_some(true)
+                }
+            })
+            .map_err(io_err)?;
+        if !open {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        self.dev
+            .send(Cmd::WRTE, self.local, self.remote, &buf[..n])
+            .map_err(io_err)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AdbStream for UsbStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.timeout.set(timeout);
+        Ok(())
+    }
+}
+
+impl Drop for UsbStream {
+    fn drop(&mut self) {
+        self.dev.streams.lock().unwrap().open.remove(&self.local);
+        let _ = self.dev.send(Cmd::CLSE, self.local, self.remote, &[]);
+    }
+}
+
+// the first device with an adb interface, or the one with `serial`. returns its serial
+fn find(serial: Option<&str>) -> Result<(Usb, String), AdbErr> {
+    let mut denied = 0;
+    for device in rusb::devices()?.iter() {
+        let Ok(config) = device.active_config_descriptor() else {
+            continue;
+        };
+        let adb = config.interfaces().find_map(|interface| {
+            let idesc = interface.descriptors().find(|d| {
+                (d.class_code(), d.sub_class_code(), d.protocol_code()) == (255, 66, 1)
+            })?;
+            let bulk = |dir| {
+                idesc
+                    .endpoint_descriptors()
+                    .find(|e| e.transfer_type() == rusb::TransferType::Bulk && e.direction() == dir)
+            };
+            let (read, write) = (bulk(rusb::Direction::In)?, bulk(rusb::Direction::Out)?);
+            Some((
+                interface.number(),
+                read.address(),
+                write.address(),
+                write.max_packet_size() as usize,
+            ))
+        });
+        let Some((number, read_endpoint, write_endpoint, packet_size)) = adb else {
+            continue;
+        };
+        let desc = device.device_descriptor()?;
+        let handle = match device.open() {
+            Ok(h) => h,
+            Err(e) => {
+                logv!(
+                    "Could not open usb device {:04x}:{:04x} ({})",
+                    desc.vendor_id(),
+                    desc.product_id(),
+                    e
+                );
+                denied += 1;
+                continue;
+            }
+        };
+        let found = handle
+            .read_serial_number_string_ascii(&desc)
+            .unwrap_or_default();
+        if serial.is_some_and(|s| s != found) {
+            continue;
+        }
+        handle.claim_interface(number).map_err(|e| {
+            format!("Could not claim the adb interface of {found} ({e}), is an adb server running? `adb kill-server` stops it")
+        })?;
+        let usb = Usb {
+            handle,
+            read_endpoint,
+            write_endpoint,
+            packet_size: packet_size.max(1),
+        };
+        return Ok((usb, found));
+    }
+    let which = serial.map_or("No usb device".to_string(), |s| {
+        format!("No usb device {s}")
+    });
+    if denied > 0 {
+        return Err(format!(
+            "{which} found, {denied} could not be opened. On linux, a udev rule has to give you access"
+        )
+        .into());
+    }
+    Err(format!("{which} found").into())
+}
+
+// the handshake, returns the device's version, the most data it takes in one message and its
+// banner
+fn connect(usb: &Usb) -> Result<(u32, u32, String), AdbErr> {
+    usb.send(Cmd::CNXN, VERSION, MAX_ADB_DATA, b"host::adb-sink\0")?;
+    let mut key = None;
+    let mut offered = false;
+    let mut deadline = Instant::now() + TIMEOUT;
+    loop {
+        let Some(msg) = usb.recv(POLL, false)? else {
+            if Instant::now() > deadline {
+                let msg = if offered {
+                    "USB debugging was not allowed on the device"
+                } else {
+                    "the device did not answer over usb"
+                };
+                return Err(msg.into());
+            }
+            continue;
+        };
+        match (msg.cmd, msg.arg0) {
+            (Cmd::CNXN, _) => {
+                let banner = String::from_utf8_lossy(&msg.data);
+                return Ok((
+                    msg.arg0,
+                    msg.arg1,
+                    banner.trim_end_matches('\0').to_string(),
+                ));
+            }
+            // the first token is signed, a second one means the device does not know the key
+            (Cmd::AUTH, AUTH_TOKEN) if key.is_none() => {
+                let k = load_key()?;
+                let signed = SigningKey::<sha1::Sha1>::new(k.clone())
+                    .sign_prehash(&msg.data)
+                    .map_err(|e| format!("could not sign the adb token: {e}"))?
+                    .to_bytes();
+                usb.send(Cmd::AUTH, AUTH_SIGNATURE, 0, &signed)?;
+                key = Some(k);
+            }
+            (Cmd::AUTH, AUTH_TOKEN) if !offered => {
+                let mut public = public_key(key.as_ref().expect("signed before")).into_bytes();
+                public.extend(b" adb-sink\0");
+                usb.send(Cmd::AUTH, AUTH_RSAPUBLICKEY, 0, &public)?;
+                logi!("Allow USB debugging from this computer on the device");
+                offered = true;
+                deadline = Instant::now() + ACCEPT_TIMEOUT;
+            }
+            (Cmd::AUTH, _) => return Err("the device rejected the adb key".into()),
+            (cmd, _) => logv!("Ignoring adb message {:?} before the connection", cmd),
+        }
+    }
+}
+
+// adb's key, so devices that allowed the adb client allow this too:
+// $ANDROID_USER_HOME/adbkey, else ~/.android/adbkey
+fn key_path() -> Option<PathBuf> {
+    if let Some(d) = std::env::var_os("ANDROID_USER_HOME") {
+        return Some(PathBuf::from(d).join("adbkey"));
+    }
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+    Some(PathBuf::from(home).join(".android").join("adbkey"))
+}
+
+// the adb key, made like adb would when there is none yet
+fn load_key() -> Result<RsaPrivateKey, AdbErr> {
+    let path = key_path().ok_or("Could not find a home directory for the adb key")?;
+    let pem = match std::fs::read_to_string(&path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return new_key(&path),
+        r => r?,
+    };
+    let key = RsaPrivateKey::from_pkcs8_pem(&pem)
+        .map_err(|e| format!("could not read the adb key {}: {e}", path.display()))?;
+    if key.size() != KEY_WORDS * 4 {
+        return Err(format!("the adb key {} is not 2048 bit", path.display()).into());
+    }
+    Ok(key)
+}
+
+fn new_key(path: &Path) -> Result<RsaPrivateKey, AdbErr> {
+    logi!("Creating the adb key {}", path.display());
+    let key = RsaPrivateKey::new(&mut rsa::rand_core::OsRng, KEY_WORDS * 32)
+        .map_err(|e| format!("could not create an adb key: {e}"))?;
+    let pem = key
+        .to_pkcs8_pem(LineEnding::LF)
+        .map_err(|e| format!("could not encode the adb key: {e}"))?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut opts = std::fs::OpenOptions::new();
+    opts.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut opts, 0o600);
+    opts.open(path)?.write_all(pem.as_bytes())?;
+    std::fs::write(
+        path.with_extension("pub"),
+        format!("{} adb-sink\n", public_key(&key)),
+    )?;
+    Ok(key)
+}
+
+// the public key like adbkey.pub has it: android's RSAPublicKey struct in base64
+//   words, -1/n mod 2^32, n, 2^4096 mod n, e   little endian
+fn public_key(key: &RsaPrivateKey) -> String {
+    let words = |v: &BigUint| {
+        let mut b = v.to_bytes_le();
+        b.resize(KEY_WORDS * 4, 0);
+        b
+    };
+    let n = words(key.n());
+    let n0 = u32::from_le_bytes(n[..4].try_into().expect("4 bytes"));
+    let rr = (BigUint::from(1u8) << (KEY_WORDS * 64)) % key.n();
+    let mut e = key.e().to_bytes_le();
+    e.resize(4, 0);
+    let mut buf = Vec::with_capacity(KEY_WORDS * 8 + 12);
+    buf.extend((KEY_WORDS as u32).to_le_bytes());
+    buf.extend(n0inv(n0).to_le_bytes());
+    buf.extend(n);
+    buf.extend(words(&rr));
+    buf.extend(&e[..4]);
+    base64::engine::general_purpose::STANDARD.encode(buf)
+}
+
+// -1 / n0 mod 2^32 for the odd n0. an odd number is its own inverse mod 8, and each of
+// newton's steps doubles the correct low bits
+fn n0inv(n0: u32) -> u32 {
+    let mut inv = n0;
+    for _ in 0..4 {
+        inv = inv.wrapping_mul(2u32.wrapping_sub(n0.wrapping_mul(inv)));
+    }
+    inv.wrapping_neg()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usb_messages() {
+        let header = pack(Cmd::OPEN, 1, 0, b"sync:\0");
+        let (cmd, arg0, arg1, len, sum) = unpack(&header).unwrap();
+        assert_eq!((cmd, arg0, arg1, len), (Cmd::OPEN, 1, 0, 6));
+        assert_eq!(sum, checksum(b"sync:\0"));
+        let mut bad = header;
+        bad[20] ^= 1;
+        assert!(unpack(&bad).is_err());
+        for n0 in [1u32, 3, 0xFFFF_FFFF, 0x8000_0001, 0x1234_5679] {
+            assert_eq!(n0.wrapping_mul(n0inv(n0)), 0xFFFF_FFFF);
+        }
+    }
+}
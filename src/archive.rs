@@ -0,0 +1,1094 @@
+// tar and zip archives: one-shot pulls into an archive, and archives as push sources
+
+use crate::adb::{quote, AdbCmd};
+use crate::filter::Filter;
+use crate::fs::{hex, AnyFileSystem, FileMode, FileSystem, LocalFS, SyncFile, Transport, Unpack};
+use crate::tree::{build_tree, Node, VirtualTree};
+use crate::{human_size, is_verbose, logi, logv, logw, unix_time, utc, CResult};
+use chainerror::Context;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::rc::Rc;
+use std::thread::JoinHandle;
+use typed_path::{Utf8UnixPath as UnixPath, Utf8UnixPathBuf as UnixPathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Tar,
+    Zip,
+}
+
+// the format and external compressor of an archive, by its file name
+fn kind(path: &Path) -> Option<(Format, Option<&'static str>)> {
+    let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+    let kind = if name.ends_with(".zip") {
+        (Format::Zip, None)
+    } else if name.ends_with(".tar") {
+        (Format::Tar, None)
+    } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+        (Format::Tar, Some("zstd"))
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        (Format::Tar, Some("gzip"))
+    } else {
+        return None;
+    };
+    Some(kind)
+}
+
+pub fn is_archive(path: &Path) -> bool {
+    kind(path).is_some()
+}
+
+fn not_archive(path: &Path) -> Box<dyn std::error::Error + Send + Sync> {
+    format!(
+        "'{}' is not a .tar, .tar.zst, .tar.gz or .zip file",
+        path.display()
+    )
+    .into()
+}
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 {
+                0xedb88320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &b in data {
+        crc = CRC_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+// ustar numeric fields are zero padded octal followed by a NUL
+fn octal(field: &mut [u8], v: u64) {
+    let w = field.len() - 1;
+    field[..w].copy_from_slice(format!("{v:0w$o}").as_bytes());
+    field[w] = 0;
+}
+
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let s = std::str::from_utf8(field).ok()?;
+    let s = s.trim_matches(|c: char| c == '\0' || c == ' ');
+    if s.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(s, 8).ok()
+}
+
+// "<len> key=value\n", where len counts the whole record including itself
+fn pax_record(key: &str, value: &str) -> String {
+    let rest = format!(" {key}={value}\n");
+    let mut len = rest.len() + 1;
+    while len != rest.len() + len.to_string().len() {
+        len = rest.len() + len.to_string().len();
+    }
+    format!("{len}{rest}")
+}
+
+fn padding(size: u64) -> u64 {
+    (512 - size % 512) % 512
+}
+
+// (time, date) in MS-DOS format, which has no time zone and starts in 1980
+fn dos_time(mtime: u32) -> (u16, u16) {
+    let (year, month, day, hour, min, sec) = utc(mtime as u64);
+    if year < 1980 {
+        return (0, 1 << 5 | 1);
+    }
+    let date = ((year - 1980).min(127) as u16) << 9 | (month as u16) << 5 | day as u16;
+    let time = (hour as u16) << 11 | (min as u16) << 5 | (sec / 2) as u16;
+    (time, date)
+}
+
+fn from_dos_time(time: u16, date: u16) -> u32 {
+    let (year, month, day) = (
+        1980 + (date >> 9) as i64,
+        (date >> 5 & 0xf) as u32,
+        (date & 0x1f) as u32,
+    );
+    let (hour, min, sec) = (
+        (time >> 11) as u32,
+        (time >> 5 & 0x3f) as u32,
+        (time & 0x1f) as u32 * 2,
+    );
+    unix_time(year, month.max(1), day.max(1), hour, min, sec) as u32
+}
+
+// the "UT" extra field, which carries the unix mtime
+fn ut_extra(mtime: u32) -> [u8; 9] {
+    let mut e = [0; 9];
+    e[..2].copy_from_slice(&0x5455u16.to_le_bytes());
+    e[2..4].copy_from_slice(&5u16.to_le_bytes());
+    e[4] = 1;
+    e[5..].copy_from_slice(&mtime.to_le_bytes());
+    e
+}
+
+// general purpose flags: sizes and crc in a data descriptor after the data, utf-8 names
+const ZIP_FLAGS: u16 = 1 << 3 | 1 << 11;
+const ZIP_LIMITS: &str = "zip archives are limited to 4 GiB and 65535 entries here, use a .tar";
+// what the 11 octal digits of the ustar size field hold, larger sizes go in a pax header
+const TAR_MAX_SIZE: u64 = 0o77777777777;
+
+struct ZipEntry {
+    name: String,
+    offset: u32,
+    crc: u32,
+    size: u32,
+    mtime: u32,
+    dir: bool,
+}
+
+// `<archive>.001`, `.002`, .. of a split archive
+fn part_path(base: &Path, part: u32) -> PathBuf {
+    let mut path = base.as_os_str().to_owned();
+    path.push(format!(".{part:03}"));
+    PathBuf::from(path)
+}
+
+fn index_path(base: &Path) -> PathBuf {
+    let mut path = base.as_os_str().to_owned();
+    path.push(".index");
+    PathBuf::from(path)
+}
+
+// whether `path` is an archive, whole or split
+pub fn exists(path: &Path) -> bool {
+    is_archive(path) && (path.is_file() || part_path(path, 1).is_file())
+}
+
+// a file that continues in the next part every `size` bytes
+struct SplitWriter {
+    base: PathBuf,
+    size: u64,
+    part: u32,
+    written: u64,
+    file: File,
+}
+
+impl SplitWriter {
+    fn create(base: &Path, size: u64) -> CResult<Self> {
+        Ok(Self {
+            base: base.to_path_buf(),
+            size,
+            part: 1,
+            written: 0,
+            file: File::create(part_path(base, 1)).annotate()?,
+        })
+    }
+}
+
+impl Write for SplitWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.written == self.size {
+            self.part += 1;
+            self.file = File::create(part_path(&self.base, self.part))?;
+            self.written = 0;
+        }
+        let n = buf.len().min((self.size - self.written) as usize);
+        let n = self.file.write(&buf[..n])?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+// the index of a split archive: the part size, then a line per member with its offset in the
+// whole (uncompressed) archive
+fn write_index(base: &Path, part_size: u64, members: &Members) -> CResult<()> {
+    let mut out = format!("split-size\t{part_size}\n");
+    for (name, mode, size, mtime, offset) in members {
+        let mode = if *mode == FileMode::Dir { 'd' } else { 'f' };
+        out.push_str(&format!("{offset}\t{size}\t{mtime}\t{mode}\t{name}\n"));
+    }
+    std::fs::write(index_path(base), out).annotate()?;
+    Ok(())
+}
+
+fn read_index(base: &Path) -> CResult<(u64, Members)> {
+    let path = index_path(base);
+    let s = std::fs::read_to_string(&path).annotate()?;
+    let malformed = |i: usize| format!("'{}' line {} is malformed", path.display(), i + 1);
+    let mut lines = s.lines().enumerate();
+    let part_size = lines
+        .next()
+        .and_then(|(_, l)| l.strip_prefix("split-size\t")?.parse().ok())
+        .ok_or_else(|| malformed(0))?;
+    let mut members = Vec::new();
+    for (i, line) in lines {
+        let mut f = line.splitn(5, '\t');
+        let member = (|| {
+            let (offset, size, mtime) = (f.next()?, f.next()?, f.next()?);
+            let mode = match f.next()? {
+                "d" => FileMode::Dir,
+                "f" => FileMode::File,
+                _ => return None,
+            };
+            Some((
+                f.next()?.to_string(),
+                mode,
+                size.parse().ok()?,
+                mtime.parse().ok()?,
+                offset.parse().ok()?,
+            ))
+        })();
+        members.push(member.ok_or_else(|| malformed(i))?);
+    }
+    Ok((part_size, members))
+}
+
+// writes an archive sequentially, so it can be piped through a compressor
+pub struct ArchiveWriter {
+    format: Format,
+    out: BufWriter<Box<dyn Write>>,
+    // the compressor and the thread copying its output into the archive
+    compressor: Option<(Child, JoinHandle<std::io::Result<()>>)>,
+    // bytes written so far, zip records where every entry starts
+    offset: u64,
+    zip_entries: Vec<ZipEntry>,
+    // (archive, part size, members) of a split archive
+    index: Option<(PathBuf, u64, Members)>,
+}
+
+impl ArchiveWriter {
+    // `split_size` writes parts of at most that many bytes instead of a single file,
+    // plus an index of where the members are
+    pub fn create(path: &Path, split_size: Option<u64>) -> CResult<Self> {
+        let Some((format, compressor)) = kind(path) else {
+            return Err(not_archive(path));
+        };
+        let mut file: Box<dyn Write + Send> = match split_size {
+            Some(size) => Box::new(SplitWriter::create(path, size).annotate()?),
+            None => Box::new(File::create(path).annotate()?),
+        };
+        let mut pump = None;
+        let out: Box<dyn Write> = match compressor {
+            None => file,
+            Some(tool) => {
+                let mut child = Command::new(tool)
+                    .args(["-q", "-c"])
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .spawn()
+                    .map_err(|e| format!("Could not run `{tool}`, is it installed? {e}"))?;
+                let stdin = child.stdin.take().expect("stdin piped");
+                let mut stdout = child.stdout.take().expect("stdout piped");
+                let copy = std::thread::spawn(move || {
+                    std::io::copy(&mut stdout, &mut file)?;
+                    file.flush()
+                });
+                pump = Some((child, copy));
+                Box::new(stdin)
+            }
+        };
+        Ok(Self {
+            format,
+            out: BufWriter::with_capacity(1 << 20, out),
+            compressor: pump,
+            offset: 0,
+            zip_entries: Vec::new(),
+            index: split_size.map(|size| (path.to_path_buf(), size, Vec::new())),
+        })
+    }
+
+    fn record(&mut self, name: &str, mode: FileMode, size: u64, mtime: u32, offset: u64) {
+        if let Some((_, _, members)) = &mut self.index {
+            let name = name.trim_end_matches('/').to_string();
+            members.push((name, mode, size, mtime, offset));
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> CResult<()> {
+        self.out.write_all(buf).annotate()?;
+        self.offset += buf.len() as u64;
+        Ok(())
+    }
+
+    // adds a directory, `path` is relative to the archive root
+    pub fn add_dir(&mut self, path: &str, mtime: u32) -> CResult<()> {
+        let path = format!("{path}/");
+        match self.format {
+            Format::Tar => {
+                self.tar_header(&path, 0, mtime, b'5').annotate()?;
+                self.record(&path, FileMode::Dir, 0, mtime, self.offset);
+                Ok(())
+            }
+            Format::Zip => self.zip_entry(&path, 0, mtime, true, &mut std::io::empty()),
+        }
+    }
+
+    // adds a file of `size` bytes read from `data`
+    pub fn add_file(
+        &mut self,
+        path: &str,
+        size: u64,
+        mtime: u32,
+        data: &mut dyn Read,
+    ) -> CResult<()> {
+        match self.format {
+            Format::Tar => {
+                self.tar_header(path, size, mtime, b'0').annotate()?;
+                self.record(path, FileMode::File, size, mtime, self.offset);
+                self.copy_data(path, data, size).annotate()?;
+                self.write(&vec![0; padding(size) as usize])
+            }
+            Format::Zip => {
+                let size = u32::try_from(size).map_err(|_| ZIP_LIMITS)?;
+                self.zip_entry(path, size, mtime, false, data)
+            }
+        }
+    }
+
+    // copies exactly `size` bytes, the headers already promised that many. returns their crc
+    fn copy_data(&mut self, path: &str, data: &mut dyn Read, size: u64) -> CResult<u32> {
+        let mut buf = vec![0; 1 << 16];
+        let (mut left, mut crc, mut short) = (size, 0, false);
+        while left > 0 {
+            let want = left.min(buf.len() as u64) as usize;
+            let mut n = data.read(&mut buf[..want]).annotate()?;
+            if n == 0 {
+                if !short {
+                    logw!(
+                        W011: "'{}' shrank while being archived, padding it with zeros",
+                        path
+                    );
+                    short = true;
+                }
+                buf[..want].fill(0);
+                n = want;
+            }
+            crc = crc32(crc, &buf[..n]);
+            self.write(&buf[..n]).annotate()?;
+            left -= n as u64;
+        }
+        Ok(crc)
+    }
+
+    fn tar_header(&mut self, path: &str, size: u64, mtime: u32, kind: u8) -> CResult<()> {
+        // the ustar name field holds 100 bytes, longer paths go in a pax header before it
+        let mut records = String::new();
+        if path.len() > 100 {
+            records.push_str(&pax_record("path", path));
+        }
+        if size > TAR_MAX_SIZE {
+            records.push_str(&pax_record("size", &size.to_string()));
+        }
+        if !records.is_empty() {
+            self.tar_block(b"././@PaxHeader", records.len() as u64, mtime, b'x')
+                .annotate()?;
+            self.write(records.as_bytes()).annotate()?;
+            self.write(&vec![0; padding(records.len() as u64) as usize])
+                .annotate()?;
+        }
+        self.tar_block(path.as_bytes(), size.min(TAR_MAX_SIZE), mtime, kind)
+    }
+
+    fn tar_block(&mut self, name: &[u8], size: u64, mtime: u32, kind: u8) -> CResult<()> {
+        let mut h = [0u8; 512];
+        let name = &name[..name.len().min(100)];
+        h[..name.len()].copy_from_slice(name);
+        octal(&mut h[100..108], if kind == b'5' { 0o755 } else { 0o644 });
+        octal(&mut h[108..116], 0);
+        octal(&mut h[116..124], 0);
+        octal(&mut h[124..136], size);
+        octal(&mut h[136..148], mtime as u64);
+        h[148..156].fill(b' ');
+        h[156] = kind;
+        h[257..263].copy_from_slice(b"ustar\0");
+        h[263..265].copy_from_slice(b"00");
+        let sum: u32 = h.iter().map(|&b| b as u32).sum();
+        octal(&mut h[148..155], sum as u64);
+        self.write(&h)
+    }
+
+    fn zip_offset(&self) -> CResult<u32> {
+        u32::try_from(self.offset).map_err(|_| ZIP_LIMITS.into())
+    }
+
+    fn zip_entry(
+        &mut self,
+        path: &str,
+        size: u32,
+        mtime: u32,
+        dir: bool,
+        data: &mut dyn Read,
+    ) -> CResult<()> {
+        let offset = self.zip_offset().annotate()?;
+        let (time, date) = dos_time(mtime);
+        let mut h = Vec::with_capacity(30 + path.len() + 9);
+        h.extend(0x04034b50u32.to_le_bytes());
+        h.extend(20u16.to_le_bytes());
+        h.extend(ZIP_FLAGS.to_le_bytes());
+        // stored, media is compressed already
+        h.extend(0u16.to_le_bytes());
+        h.extend(time.to_le_bytes());
+        h.extend(date.to_le_bytes());
+        // crc and sizes, in the data descriptor instead
+        h.extend([0; 12]);
+        h.extend((path.len() as u16).to_le_bytes());
+        h.extend(9u16.to_le_bytes());
+        h.extend(path.as_bytes());
+        h.extend(ut_extra(mtime));
+        self.write(&h).annotate()?;
+        let mode = if dir { FileMode::Dir } else { FileMode::File };
+        self.record(path, mode, size as u64, mtime, self.offset);
+        let crc = self.copy_data(path, data, size as u64).annotate()?;
+        let mut d = Vec::with_capacity(16);
+        d.extend(0x08074b50u32.to_le_bytes());
+        d.extend(crc.to_le_bytes());
+        d.extend(size.to_le_bytes());
+        d.extend(size.to_le_bytes());
+        self.write(&d).annotate()?;
+        self.zip_entries.push(ZipEntry {
+            name: path.to_string(),
+            offset,
+            crc,
+            size,
+            mtime,
+            dir,
+        });
+        Ok(())
+    }
+
+    fn zip_central_directory(&mut self) -> CResult<()> {
+        let start = self.zip_offset().annotate()?;
+        let entries = std::mem::take(&mut self.zip_entries);
+        let count = u16::try_from(entries.len()).map_err(|_| ZIP_LIMITS)?;
+        for e in &entries {
+            let (time, date) = dos_time(e.mtime);
+            let mode: u32 = if e.dir { 0o40755 } else { 0o100644 };
+            // unix mode in the high half, the MS-DOS directory bit in the low one
+            let attrs = mode << 16 | if e.dir { 0x10 } else { 0 };
+            let mut h = Vec::with_capacity(46 + e.name.len() + 9);
+            h.extend(0x02014b50u32.to_le_bytes());
+            // made by unix, zip 2.0
+            h.extend((3u16 << 8 | 20).to_le_bytes());
+            h.extend(20u16.to_le_bytes());
+            h.extend(ZIP_FLAGS.to_le_bytes());
+            h.extend(0u16.to_le_bytes());
+            h.extend(time.to_le_bytes());
+            h.extend(date.to_le_bytes());
+            h.extend(e.crc.to_le_bytes());
+            h.extend(e.size.to_le_bytes());
+            h.extend(e.size.to_le_bytes());
+            h.extend((e.name.len() as u16).to_le_bytes());
+            h.extend(9u16.to_le_bytes());
+            // comment length, disk, internal attributes
+            h.extend([0; 6]);
+            h.extend(attrs.to_le_bytes());
+            h.extend(e.offset.to_le_bytes());
+            h.extend(e.name.as_bytes());
+            h.extend(ut_extra(e.mtime));
+            self.write(&h).annotate()?;
+        }
+        let size = self.zip_offset().annotate()? - start;
+        let mut end = Vec::with_capacity(22);
+        end.extend(0x06054b50u32.to_le_bytes());
+        end.extend([0; 4]);
+        end.extend(count.to_le_bytes());
+        end.extend(count.to_le_bytes());
+        end.extend(size.to_le_bytes());
+        end.extend(start.to_le_bytes());
+        end.extend([0; 2]);
+        self.write(&end)
+    }
+
+    pub fn finish(mut self) -> CResult<()> {
+        match self.format {
+            Format::Tar => self.write(&[0; 1024]).annotate()?,
+            Format::Zip => self.zip_central_directory().annotate()?,
+        }
+        let Self {
+            out,
+            compressor,
+            index,
+            ..
+        } = self;
+        // closes the compressor's stdin
+        drop(out.into_inner().map_err(|e| e.into_error()).annotate()?);
+        if let Some((mut child, pump)) = compressor {
+            let copied = pump.join().expect("archive writer thread");
+            if !child.wait().annotate()?.success() {
+                return Err("compressing the archive failed".into());
+            }
+            copied.annotate()?;
+        }
+        if let Some((path, part_size, members)) = index {
+            write_index(&path, part_size, &members).annotate()?;
+        }
+        Ok(())
+    }
+}
+
+// the content of `path` on `src`, and the process to wait for after reading it
+fn open_source(src: Transport, path: &UnixPath) -> CResult<(Box<dyn Read>, Option<Child>)> {
+    match src {
+        Transport::Local => Ok((Box::new(File::open(path.as_str()).annotate()?), None)),
+        Transport::Adb => {
+            let mut child = AdbCmd::new()
+                .args(["exec-out", "cat", quote(path.as_str()).as_str()])
+                .spawn()
+                .annotate()?;
+            let stdout = child.stdout.take().expect("stdout piped");
+            Ok((Box::new(stdout), Some(child)))
+        }
+        src => Err(format!("cannot read {src:?} files into an archive").into()),
+    }
+}
+
+fn archive_file(w: &mut ArchiveWriter, src: Transport, sf: &SyncFile, name: &str) -> CResult<()> {
+    logv!("ARCHIVE FILE: {} -> {}", sf.path, name);
+    let (mut data, child) = open_source(src, &sf.path).annotate()?;
+    w.add_file(name, sf.size, sf.timestamp, &mut data)
+        .annotate()?;
+    drop(data);
+    if let Some(mut child) = child {
+        // the pipe may have been closed before cat was done, when the file grew meanwhile
+        let _ = child.wait();
+    }
+    Ok(())
+}
+
+// pulls `src_path` into a new archive, which has the source directory at its root like
+// `tar -C <parent> -cf archive <name>` would
+pub fn pull_to_archive(
+    src_fs: &mut Box<dyn AnyFileSystem>,
+    src_path: &UnixPath,
+    archive: &Path,
+    split_size: Option<u64>,
+    filter: &Filter,
+) -> CResult<()> {
+    let Some(src_sf) = src_fs.stat(src_path).annotate()? else {
+        return Err(format!("Source '{}' does not exist", src_path).into());
+    };
+    let src = src_fs.transport();
+    let top = src_path.file_name().unwrap_or("root").to_string();
+    let mut w = ArchiveWriter::create(archive, split_size).annotate()?;
+    let (mut files, mut bytes) = (0, 0);
+    if src_sf.mode == FileMode::File {
+        archive_file(&mut w, src, &src_sf, &top).annotate()?;
+        (files, bytes) = (1, src_sf.size);
+    } else {
+        let root = build_tree(src_fs, src_sf, src_path, filter).annotate()?;
+        w.add_dir(&top, root.sf.timestamp).annotate()?;
+        // a pre-order walk of the sorted tree, parents come before their children
+        let mut nodes: Vec<&Node> = Vec::new();
+        fn walk<'n>(n: &'n Node, nodes: &mut Vec<&'n Node>) {
+            for e in n.entries.values() {
+                nodes.push(e);
+                walk(e, nodes);
+            }
+        }
+        walk(&root, &mut nodes);
+        for n in nodes {
+            let name = format!("{top}/{}", n.strip_path);
+            match n.sf.mode {
+                FileMode::File => {
+                    archive_file(&mut w, src, &n.sf, &name).annotate()?;
+                    files += 1;
+                    bytes += n.sf.size;
+                }
+                FileMode::Dir => w.add_dir(&name, n.sf.timestamp).annotate()?,
+                FileMode::Symlink => logw!(W002: "Skipping symlink {}", n.sf.path),
+            }
+        }
+    }
+    w.finish().annotate()?;
+    logi!(
+        "Archived {} files ({}) into '{}'",
+        files,
+        human_size(bytes),
+        archive.display()
+    );
+    Ok(())
+}
+
+// (name, mode, size, mtime, data offset) of every member
+type Members = Vec<(String, FileMode, u64, u32, u64)>;
+
+fn read_tar(f: &mut BufReader<File>) -> CResult<Members> {
+    let mut members = Vec::new();
+    let mut offset = 0;
+    // from a pax or GNU long name header, for the member after it
+    let (mut long_name, mut long_size) = (None, None);
+    loop {
+        let mut h = [0u8; 512];
+        if f.read_exact(&mut h).is_err() || h.iter().all(|&b| b == 0) {
+            break;
+        }
+        offset += 512;
+        let (Some(size), Some(mtime)) = (parse_octal(&h[124..136]), parse_octal(&h[136..148]))
+        else {
+            return Err(format!("Malformed tar header at byte {}", offset - 512).into());
+        };
+        let field = |b: &[u8]| {
+            let end = b.iter().position(|&c| c == 0).unwrap_or(b.len());
+            String::from_utf8_lossy(&b[..end]).into_owned()
+        };
+        let mut name = field(&h[..100]);
+        if &h[257..262] == b"ustar" {
+            let prefix = field(&h[345..500]);
+            if !prefix.is_empty() {
+                name = format!("{prefix}/{name}");
+            }
+        }
+        let mode = match h[156] {
+            b'x' | b'L' => {
+                let mut data = vec![0; size as usize];
+                f.read_exact(&mut data).annotate()?;
+                let data = String::from_utf8_lossy(&data);
+                if h[156] == b'L' {
+                    long_name = Some(data.trim_end_matches('\0').to_string());
+                } else {
+                    let value = |key: &str| {
+                        data.lines()
+                            .find_map(|r| r.split_once(' ')?.1.strip_prefix(key))
+                            .map(str::to_string)
+                    };
+                    long_name = value("path=");
+                    long_size = value("size=").and_then(|s| s.parse::<u64>().ok());
+                }
+                f.seek_relative(padding(size) as i64).annotate()?;
+                offset += size + padding(size);
+                continue;
+            }
+            b'0' | 0 => Some(FileMode::File),
+            b'5' => Some(FileMode::Dir),
+            // links, devices, global pax headers
+            _ => None,
+        };
+        let name = long_name.take().unwrap_or(name);
+        let size = long_size.take().unwrap_or(size);
+        if let Some(mode) = mode {
+            members.push((name, mode, size, mtime as u32, offset));
+        } else {
+            logv!("SKIP (NOT A FILE OR DIRECTORY): {}", name);
+        }
+        let skip = size + padding(size);
+        f.seek_relative(skip as i64).annotate()?;
+        offset += skip;
+    }
+    Ok(members)
+}
+
+fn u16_at(b: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes([b[i], b[i + 1]])
+}
+
+fn u32_at(b: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]])
+}
+
+fn read_zip(f: &mut BufReader<File>, path: &Path) -> CResult<Members> {
+    let len = f.seek(SeekFrom::End(0)).annotate()?;
+    // the end of central directory record, followed by a comment of up to 64 KiB
+    let tail_len = len.min(22 + 0xffff);
+    f.seek(SeekFrom::Start(len - tail_len)).annotate()?;
+    let mut tail = vec![0; tail_len as usize];
+    f.read_exact(&mut tail).annotate()?;
+    let Some(end) = tail.windows(4).rposition(|w| w == b"PK\x05\x06") else {
+        return Err(not_archive(path));
+    };
+    let (count, size, start) = (
+        u16_at(&tail, end + 10),
+        u32_at(&tail, end + 12),
+        u32_at(&tail, end + 16),
+    );
+    let mut cd = vec![0; size as usize];
+    f.seek(SeekFrom::Start(start as u64)).annotate()?;
+    f.read_exact(&mut cd).annotate()?;
+
+    let mut members = Vec::with_capacity(count as usize);
+    let mut p = 0;
+    for _ in 0..count {
+        if cd.get(p..p + 4) != Some(&b"PK\x01\x02"[..]) {
+            return Err(format!("'{}' has a malformed central directory", path.display()).into());
+        }
+        let method = u16_at(&cd, p + 10);
+        let size = u32_at(&cd, p + 24);
+        let (name_len, extra_len, comment_len) = (
+            u16_at(&cd, p + 28) as usize,
+            u16_at(&cd, p + 30) as usize,
+            u16_at(&cd, p + 32) as usize,
+        );
+        let local = u32_at(&cd, p + 42) as u64;
+        let name = String::from_utf8_lossy(&cd[p + 46..p + 46 + name_len]).into_owned();
+        let mut extra = &cd[p + 46 + name_len..p + 46 + name_len + extra_len];
+        let mut mtime = from_dos_time(u16_at(&cd, p + 12), u16_at(&cd, p + 14));
+        while extra.len() >= 4 {
+            let (id, len) = (u16_at(extra, 0), u16_at(extra, 2) as usize);
+            let data = &extra[4..(4 + len).min(extra.len())];
+            if id == 0x5455 && data.len() >= 5 && data[0] & 1 != 0 {
+                mtime = u32_at(data, 1);
+            }
+            extra = &extra[(4 + len).min(extra.len())..];
+        }
+        p += 46 + name_len + extra_len + comment_len;
+
+        let mode = if name.ends_with('/') {
+            FileMode::Dir
+        } else {
+            FileMode::File
+        };
+        if mode == FileMode::File && method != 0 {
+            return Err(format!(
+                "'{}' in '{}' is compressed, only stored zip members can be pushed",
+                name,
+                path.display()
+            )
+            .into());
+        }
+        // the data starts after the local header, whose extra field may differ
+        let mut h = [0; 30];
+        f.seek(SeekFrom::Start(local)).annotate()?;
+        f.read_exact(&mut h).annotate()?;
+        let offset = local + 30 + u16_at(&h, 26) as u64 + u16_at(&h, 28) as u64;
+        members.push((name, mode, size as u64, mtime, offset));
+    }
+    Ok(members)
+}
+
+// where the members' data is
+enum Data {
+    File(PathBuf),
+    // the parts of a split archive, each `size` bytes but the last
+    Parts { base: PathBuf, size: u64 },
+}
+
+// concatenates the parts of a split archive, or decompresses a compressed one, into a
+// temporary file
+fn to_temp(path: &Path, parts: Option<u32>, tool: Option<&str>) -> CResult<PathBuf> {
+    let name = format!("adb-sink-{}.archive", std::process::id());
+    let tmp = std::env::temp_dir().join(name);
+    let inputs: Vec<PathBuf> = match parts {
+        Some(n) => (1..=n).map(|i| part_path(path, i)).collect(),
+        None => vec![path.to_path_buf()],
+    };
+    let mut out = File::create(&tmp).annotate()?;
+    let r = (|| -> CResult<()> {
+        let Some(tool) = tool else {
+            for input in &inputs {
+                std::io::copy(&mut File::open(input).annotate()?, &mut out).annotate()?;
+            }
+            return Ok(());
+        };
+        logi!("Decompressing '{}'", path.display());
+        let mut child = Command::new(tool)
+            .args(["-d", "-c"])
+            .stdin(Stdio::piped())
+            .stdout(out)
+            .spawn()
+            .map_err(|e| format!("Could not run `{tool}`, is it installed? {e}"))?;
+        let mut stdin = child.stdin.take().expect("stdin piped");
+        for input in &inputs {
+            std::io::copy(&mut File::open(input).annotate()?, &mut stdin).annotate()?;
+        }
+        drop(stdin);
+        if !child.wait().annotate()?.success() {
+            return Err(format!("Decompressing '{}' failed", path.display()).into());
+        }
+        Ok(())
+    })();
+    if let Err(e) = r {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(e);
+    }
+    Ok(tmp)
+}
+
+// the members of a tar or zip archive, indexed for random access. compressed tars are
+// decompressed into a temporary file first, uncompressed split archives with an index are
+// read from just the parts that hold the wanted members
+pub struct Archive {
+    data: Data,
+    tmp: Option<PathBuf>,
+    // members with their data offsets, under the archive's own path
+    members: VirtualTree<u64>,
+}
+
+impl Archive {
+    pub fn open(path: &Path) -> CResult<Self> {
+        let Some((format, compressor)) = kind(path) else {
+            return Err(not_archive(path));
+        };
+        let parts = if path.is_file() {
+            None
+        } else {
+            Some((1..).take_while(|&i| part_path(path, i).is_file()).count() as u32)
+        };
+        if parts == Some(0) {
+            return Err(format!("Archive '{}' does not exist", path.display()).into());
+        }
+        let index = match parts {
+            Some(_) if index_path(path).is_file() => Some(read_index(path).annotate()?),
+            _ => None,
+        };
+        let (data, tmp) = match (compressor, parts, &index) {
+            (None, None, _) => (Data::File(path.to_path_buf()), None),
+            (None, Some(_), Some((size, _))) => {
+                let base = path.to_path_buf();
+                (Data::Parts { base, size: *size }, None)
+            }
+            (tool, parts, _) => {
+                let tmp = to_temp(path, parts, tool).annotate()?;
+                (Data::File(tmp.clone()), Some(tmp))
+            }
+        };
+        let root = UnixPathBuf::from(path.to_str().unwrap());
+        let first = match parts {
+            Some(_) => part_path(path, 1),
+            None => path.to_path_buf(),
+        };
+        let first = UnixPathBuf::from(first.to_str().unwrap());
+        let mtime = LocalFS
+            .stat(&first)
+            .annotate()?
+            .map_or(0, |sf| sf.timestamp);
+        let mut archive = Self {
+            data,
+            tmp,
+            members: VirtualTree::new(root, mtime),
+        };
+        let members = match (index, &archive.data) {
+            (Some((_, members)), _) => members,
+            (None, Data::File(data)) => {
+                let mut f = BufReader::new(File::open(data).annotate()?);
+                match format {
+                    Format::Tar => read_tar(&mut f).annotate()?,
+                    Format::Zip => read_zip(&mut f, path).annotate()?,
+                }
+            }
+            (None, Data::Parts { .. }) => unreachable!("parts are only read with an index"),
+        };
+        for (name, mode, size, mtime, offset) in members {
+            let name = name.trim_start_matches("./").trim_matches('/');
+            if !name.is_empty() {
+                archive.members.insert(name, mode, size, mtime, offset);
+            }
+        }
+        Ok(archive)
+    }
+
+    // `archive/<dir>` when the archive holds a single directory, as `pull --to-archive` makes them
+    pub fn top_dir(&self) -> CResult<PathBuf> {
+        match self.members.single_dir() {
+            Some(top) => Ok(PathBuf::from(top.as_str())),
+            None => {
+                let root = &self.members.root;
+                Err(format!(
+                    "'{root}' does not hold a single directory, push one of its directories like '{root}/DCIM'"
+                )
+                .into())
+            }
+        }
+    }
+
+    fn reader(&self, path: &UnixPath) -> CResult<Box<dyn Read>> {
+        let Some((sf, offset)) = self.members.get(path) else {
+            return Err(format!("'{path}' is not in the archive").into());
+        };
+        match &self.data {
+            Data::File(data) => {
+                let mut f = File::open(data).annotate()?;
+                f.seek(SeekFrom::Start(*offset)).annotate()?;
+                Ok(Box::new(f.take(sf.size)))
+            }
+            Data::Parts { base, size } => {
+                // a member can span several parts
+                let mut r: Box<dyn Read> = Box::new(std::io::empty());
+                let (mut offset, mut left) = (*offset, sf.size);
+                while left > 0 {
+                    let within = offset % size;
+                    let n = left.min(size - within);
+                    let mut f =
+                        File::open(part_path(base, (offset / size) as u32 + 1)).annotate()?;
+                    f.seek(SeekFrom::Start(within)).annotate()?;
+                    r = Box::new(r.chain(f.take(n)));
+                    offset += n;
+                    left -= n;
+                }
+                Ok(r)
+            }
+        }
+    }
+}
+
+impl Drop for Archive {
+    fn drop(&mut self) {
+        if let Some(tmp) = &self.tmp {
+            let _ = std::fs::remove_file(tmp);
+        }
+    }
+}
+
+// read-only file system view of an archive, for pushing from it
+pub struct ArchiveFS(pub Rc<Archive>);
+
+fn read_only() -> CResult<()> {
+    Err("archives are read-only".into())
+}
+
+impl FileSystem for ArchiveFS {
+    fn mkdir(&mut self, _path: &UnixPath) -> CResult<()> {
+        read_only()
+    }
+
+    fn list_dir(&mut self, path: &UnixPath) -> CResult<Vec<SyncFile>> {
+        Ok(self.0.members.list_dir(path))
+    }
+
+    fn stat(&mut self, path: &UnixPath) -> CResult<Option<SyncFile>> {
+        Ok(self.0.members.stat(path))
+    }
+
+    fn rm(&mut self, _path: &UnixPath) -> CResult<()> {
+        read_only()
+    }
+
+    fn rm_dir(&mut self, _path: &UnixPath) -> CResult<()> {
+        read_only()
+    }
+
+    fn set_mtime(&mut self, _path: &UnixPath, _timestamp: u32) -> CResult<()> {
+        read_only()
+    }
+
+    fn sha256(&mut self, path: &UnixPath) -> CResult<String> {
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut self.0.reader(path).annotate()?, &mut hasher).annotate()?;
+        Ok(hex(&hasher.finalize()))
+    }
+}
+
+impl AnyFileSystem for ArchiveFS {
+    fn transport(&self) -> Transport {
+        Transport::Local
+    }
+
+    fn copy_from(
+        &mut self,
+        _src: Transport,
+        _from: &UnixPath,
+        _to: &UnixPath,
+        _timestamp: Option<u32>,
+    ) -> CResult<()> {
+        read_only()
+    }
+
+    fn copy_dir_from(
+        &mut self,
+        _src: Transport,
+        _from: &UnixPath,
+        _to: &UnixPath,
+        _timestamp: Option<u32>,
+    ) -> CResult<()> {
+        read_only()
+    }
+}
+
+impl Unpack for ArchiveFS {
+    fn unpack(&mut self, from: &UnixPath, to: &UnixPath) -> CResult<()> {
+        let mut out = File::create(to.as_str()).annotate()?;
+        std::io::copy(&mut self.0.reader(from).annotate()?, &mut out).annotate()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(0, b"123456789"), 0xcbf43926);
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = std::env::temp_dir().join(format!("adb-sink-archive-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let long = format!("DCIM/{}/b.jpg", "x".repeat(120));
+        for name in ["a.tar", "a.zip"] {
+            let path = dir.join(name);
+            let mut w = ArchiveWriter::create(&path, None).unwrap();
+            w.add_dir("DCIM", 1_700_000_000).unwrap();
+            w.add_file("DCIM/a.jpg", 3, 1_700_000_002, &mut &b"abc"[..])
+                .unwrap();
+            // shorter than promised, gets padded
+            w.add_file(&long, 4, 1_700_000_004, &mut &b"de"[..])
+                .unwrap();
+            w.finish().unwrap();
+
+            let archive = Rc::new(Archive::open(&path).unwrap());
+            let top = archive.top_dir().unwrap();
+            assert_eq!(top, path.join("DCIM"));
+            let mut fs = ArchiveFS(archive);
+            let a = fs
+                .stat(&archive_path(&path, "DCIM/a.jpg"))
+                .unwrap()
+                .unwrap();
+            assert_eq!((a.size, a.timestamp), (3, 1_700_000_002));
+            let b = archive_path(&path, &long);
+            let out = UnixPathBuf::from(dir.join("out").to_str().unwrap());
+            fs.unpack(&b, &out).unwrap();
+            assert_eq!(std::fs::read(out.as_str()).unwrap(), b"de\0\0");
+            assert_eq!(fs.list_dir(&archive_path(&path, "DCIM")).unwrap().len(), 2);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn split_round_trip() {
+        let dir = std::env::temp_dir().join(format!("adb-sink-split-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.tar");
+        let data: Vec<u8> = (0..3000u32).map(|i| i as u8).collect();
+        let mut w = ArchiveWriter::create(&path, Some(1000)).unwrap();
+        w.add_dir("DCIM", 1_700_000_000).unwrap();
+        w.add_file("DCIM/a.bin", 3000, 1_700_000_002, &mut &data[..])
+            .unwrap();
+        w.finish().unwrap();
+        assert!(!path.exists() && part_path(&path, 5).is_file());
+        assert!(exists(&path));
+
+        let mut fs = ArchiveFS(Rc::new(Archive::open(&path).unwrap()));
+        let out = UnixPathBuf::from(dir.join("out").to_str().unwrap());
+        fs.unpack(&archive_path(&path, "DCIM/a.bin"), &out).unwrap();
+        assert_eq!(std::fs::read(out.as_str()).unwrap(), data);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn archive_path(archive: &Path, name: &str) -> UnixPathBuf {
+        UnixPathBuf::from(archive.to_str().unwrap()).join(name)
+    }
+
+    #[test]
+    fn dos_times() {
+        let t = 1_700_000_002;
+        let (time, date) = dos_time(t);
+        assert_eq!(from_dos_time(time, date), t);
+    }
+}
@@ -1,19 +1,168 @@
-use clap::{Args, Parser, Subcommand};
+use crate::bwlimit::Schedule;
+use crate::config::Profile;
+use crate::dedup::DedupMode;
+use crate::filter::FilterRule;
+use crate::fs::Transport;
+use crate::perms::{Chmod, Chown};
+use crate::preflight::Thermal;
+use crate::transform::{Case, Transform};
+use crate::warnings;
+use crate::winnames::TrailingDot;
+use crate::{OnConflict, Order, TimeOffset, VerifySample};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use std::path::PathBuf;
 
+// options shared by push and pull
+#[derive(Args, Debug)]
+pub struct SyncArgs {
+    /// delete files on target that does not exist in source
+    #[arg(short = 'd', long)]
+    pub delete_if_dne: bool,
+
+    /// also delete files on target that are excluded by the filter rules
+    #[arg(long, requires = "delete_if_dne")]
+    pub delete_excluded: bool,
+
+    /// ignore a path and everything under it, relative to the source. globs like
+    /// `Android/data/*/cache` or `**/node_modules` are allowed
+    #[arg(short, long, value_parser = FilterRule::exclude_path)]
+    pub ignore_dir: Vec<FilterRule>,
+
+    /// skip the contents of directories that have a file of this name, e.g. CACHEDIR.TAG
+    #[arg(long, value_name = "NAME")]
+    pub exclude_if_present: Vec<String>,
+
+    /// rsync style '+ pattern' / '- pattern' rules, the first matching rule wins
+    #[arg(long, value_name = "RULE")]
+    pub filter: Vec<FilterRule>,
+
+    /// skip files and directories matching this glob anywhere in the tree, e.g. `*.log` or
+    /// `build/`, after the --filter rules
+    #[arg(long, value_name = "GLOB", value_parser = FilterRule::exclude)]
+    pub exclude: Vec<FilterRule>,
+
+    /// skip files with these extensions, e.g. `mp4,mkv,tmp`, in any case
+    #[arg(long, value_name = "EXTS", value_delimiter = ',', value_parser = FilterRule::exclude_ext)]
+    pub exclude_ext: Vec<FilterRule>,
+
+    /// only copy files with these extensions, e.g. `pdf,docx`, in any case
+    #[arg(long, value_name = "EXTS", value_delimiter = ',', value_parser = FilterRule::include_ext)]
+    pub include_ext: Vec<FilterRule>,
+
+    /// match --ignore-dir, --filter and --exclude patterns regardless of case
+    #[arg(long)]
+    pub ignore_case_filters: bool,
+
+    /// do not skip MediaStore's half written `.pending-*` and trashed `.trashed-*` files
+    #[arg(long)]
+    pub include_pending: bool,
+
+    /// rewrite destination paths with sed style 's/pattern/replacement/[gi]' rules
+    #[arg(long)]
+    pub transform: Vec<Transform>,
+
+    /// copy all files directly into the destination directory, suffixing colliding names
+    #[arg(long)]
+    pub flatten: bool,
+
+    /// also copy `user.*` extended attributes of copied files
+    #[arg(long)]
+    pub xattrs: bool,
+
+    /// recreate hard links between source files on the destination instead of copying each
+    #[arg(short = 'H', long)]
+    pub hard_links: bool,
+
+    /// copy files in this order instead of in directory batches
+    #[arg(long, value_enum)]
+    pub order: Option<Order>,
+
+    /// copy files matching this pattern, e.g. 'Documents/**', before all others
+    #[arg(long, value_name = "PATTERN", value_parser = FilterRule::pattern)]
+    pub first: Vec<FilterRule>,
+
+    /// copy files matching this pattern, e.g. 'Movies/**', after all others
+    #[arg(long, value_name = "PATTERN", value_parser = FilterRule::pattern)]
+    pub last: Vec<FilterRule>,
+
+    /// refuse to run when more than N files would be copied or deleted, showing where they are
+    #[arg(long, value_name = "N")]
+    pub max_files: Option<usize>,
+
+    /// change nothing, write the copies and deletes the sync would do to FILE for `adb-sink apply`
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["metadata_only", "xattrs", "hard_links", "owner", "time_offset"])]
+    pub plan_out: Option<PathBuf>,
+
+    /// only print the copies and deletes the sync would do, changing nothing
+    #[arg(short = 'n', long, conflicts_with_all = ["plan_out", "metadata_only"])]
+    pub dry_run: bool,
+
+    /// do not create directories that have no files in them
+    #[arg(long)]
+    pub prune_empty_dirs: bool,
+
+    /// also compare the first and last KIB KiB of files whose size and mtime match by hash
+    #[arg(
+        long,
+        value_name = "KIB",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "64"
+    )]
+    pub spot_check: Option<u64>,
+
+    /// afterwards hash N, or P% (`5%`), of the copied files picked at random on both ends
+    #[arg(long, value_name = "N|P%", conflicts_with_all = ["plan_out", "metadata_only"])]
+    pub verify_sample: Option<VerifySample>,
+
+    /// what to do with destination files that changed and are newer than their source
+    #[arg(long, value_enum, value_name = "POLICY", default_value_t = OnConflict::Overwrite, conflicts_with = "plan_out")]
+    pub on_conflict: OnConflict,
+
+    /// copy nothing, only give files whose size matches the mtime of their source
+    #[arg(long, conflicts_with = "delete_if_dne")]
+    pub metadata_only: bool,
+
+    /// recreate the source's parent directories under the destination, starting after a `/./`
+    /// in the source or the storage root
+    #[arg(short = 'R', long)]
+    pub relative: bool,
+
+    /// source mtimes are this far ahead of the destination's (`+HH:MM`, `-HH:MM`), or `auto` to
+    /// detect a timezone skew from files on both sides; corrected before comparing and copying
+    #[arg(long, value_name = "OFFSET", allow_hyphen_values = true)]
+    pub time_offset: Option<TimeOffset>,
+
+    /// keep file owners: pulls record the uid and gid in DEST/.adb-sink-owners, pushes chown
+    /// to them (needs root on the device)
+    #[arg(long)]
+    pub owner: bool,
+
+    /// report how long each phase and the slowest operations took
+    #[arg(long)]
+    pub timings: bool,
+}
+
 #[derive(Args, Debug)]
 #[command(arg_required_else_help(true))]
 pub struct PushArgs {
     pub source: PathBuf,
     pub dest: PathBuf,
 
-    /// delete files on target that does not exist in source
-    #[arg(short = 'd', long)]
-    pub delete_if_dne: bool,
+    /// do not compress pushes even if adb and the device support it
+    #[arg(long)]
+    pub no_compress: bool,
 
-    /// ignore dirs starting with specified string
-    #[arg(short, long)]
-    pub ignore_dir: Vec<Box<str>>,
+    /// the source was pulled with --encrypt, decrypt its `.age` files with this age identity file
+    #[arg(long, value_name = "IDENTITY")]
+    pub decrypt: Option<PathBuf>,
+
+    /// after syncing, keep watching the source and push what changes until interrupted
+    #[arg(long)]
+    pub watch: bool,
+
+    #[command(flatten)]
+    pub sync: SyncArgs,
 }
 
 #[derive(Args, Debug)]
@@ -22,23 +171,463 @@ pub struct PullArgs {
     pub source: PathBuf,
     pub dest: Option<PathBuf>,
 
-    /// delete files on target that does not exist in source
-    #[arg(short = 'd', long)]
-    pub delete_if_dne: bool,
-
-    /// ignore dirs starting with specified string
-    #[arg(short, long)]
-    pub ignore_dir: Vec<Box<str>>,
+    #[command(flatten)]
+    pub sync: SyncArgs,
 
     /// set modified time of files
     #[arg(short = 't', long)]
     pub set_times: bool,
+
+    /// convert destination names to lower or upper case
+    #[arg(long, value_enum, default_value_t = Case::Preserve)]
+    pub case: Case,
+
+    /// skip, hard link or locally copy files whose content already exists anywhere under the destination
+    #[arg(long, value_enum)]
+    pub dedup: Option<DedupMode>,
+
+    /// list device files through MediaStore instead of `adb ls`, optionally with an extra SQL selection
+    #[arg(long, value_name = "WHERE", require_equals = true)]
+    pub mediastore: Option<Option<String>>,
+
+    /// record the sha256 of pulled files in DEST/.adb-sink-hashes, for bitrot-check
+    #[arg(long)]
+    pub hash_db: bool,
+
+    /// write a sha256sum compatible DEST/<dir>.sha256sums, with sizes and mtimes, of the pulled directory
+    #[arg(long, conflicts_with_all = ["metadata_only", "encrypt"])]
+    pub write_manifest: bool,
+
+    /// rsync style permission rules for created files and directories, e.g. `D755,F644` or `go-w`
+    #[arg(long, value_name = "RULES")]
+    pub chmod: Option<Chmod>,
+
+    /// give created files and directories this owner, `user:group`, `user` or `:group`
+    #[arg(long, value_name = "USER:GROUP")]
+    pub chown: Option<Chown>,
+
+    /// give names with characters Windows does not allow, like `:` in `10:30:00.png`, this one
+    /// instead. done on Windows or with --windows-names, DEST/.adb-sink-names keeps the
+    /// original names and a push from DEST restores them
+    #[arg(long, value_name = "CHAR", default_value_t = '_')]
+    pub invalid_char_replacement: char,
+
+    /// also give destination files Windows compatible names elsewhere, e.g. on exFAT or SMB mounts
+    #[arg(long)]
+    pub windows_names: bool,
+
+    /// rename names ending in dots or spaces, which Windows drops, by appending the replacement
+    /// character or by stripping them
+    #[arg(long, value_enum, value_name = "POLICY", default_value_t = TrailingDot::Marker)]
+    pub trailing_dot: TrailingDot,
+
+    /// give photos and videos the time they were taken, from Exif or the movie header, as mtime
+    #[arg(long, conflicts_with_all = ["store", "encrypt", "to_archive", "plan_out"])]
+    pub capture_times: bool,
+
+    /// keep DEST as a content addressed store, recording each run as a snapshot of hash-named blobs
+    #[arg(long, conflicts_with_all = ["hash_db", "write_manifest", "dedup", "transform", "flatten", "delete_if_dne", "owner", "chmod", "chown", "verify_sample"])]
+    pub store: bool,
+
+    /// store pulled files encrypted with `age` as `<name>.age`, for this public key or file of them
+    #[arg(
+        long,
+        value_name = "RECIPIENT",
+        conflicts_with_all = ["hash_db", "dedup", "store", "spot_check", "xattrs"]
+    )]
+    pub encrypt: Vec<String>,
+
+    /// write everything into a new .tar, .tar.zst, .tar.gz or .zip archive instead of syncing to DEST
+    #[arg(
+        long,
+        value_name = "ARCHIVE",
+        conflicts_with_all = ["dest", "hash_db", "write_manifest", "dedup", "store", "encrypt", "delete_if_dne", "metadata_only", "owner", "chmod", "chown", "verify_sample"]
+    )]
+    pub to_archive: Option<PathBuf>,
+
+    /// split the archive into ARCHIVE.001, .002, .. of at most SIZE bytes (K, M, G suffixes), with an index
+    #[arg(long, value_name = "SIZE", requires = "to_archive", value_parser = parse_size)]
+    pub split_size: Option<u64>,
+
+    /// afterwards delete device files older than DAYS whose pulled copy hashes the same, asking first
+    #[arg(
+        long,
+        value_name = "DAYS",
+        conflicts_with_all = ["delete_if_dne", "relative", "flatten", "transform", "metadata_only", "plan_out", "store", "encrypt", "to_archive"]
+    )]
+    pub expire_source: Option<u64>,
+
+    /// delete each device file once its pulled copy hashes the same, moving it off the device
+    #[arg(
+        long,
+        conflicts_with_all = ["delete_if_dne", "dedup", "metadata_only", "plan_out", "store", "encrypt", "to_archive", "expire_source"]
+    )]
+    pub remove_source_files: bool,
+
+    /// give up on a file once its pull has written nothing for SECS, and go on with the others
+    #[arg(long, value_name = "SECS", conflicts_with = "store")]
+    pub file_timeout: Option<u64>,
+
+    /// pull the files --file-timeout gave up on once more at the end of the run
+    #[arg(long, requires = "file_timeout")]
+    pub retry_stalled: bool,
+
+    /// only list the device files --expire-source would delete
+    #[arg(long, requires = "expire_source")]
+    pub expire_dry_run: bool,
+
+    /// let --expire-source delete without asking, for unattended runs
+    #[arg(long, requires = "expire_source", conflicts_with = "expire_dry_run")]
+    pub yes: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Direction {
+    Pull,
+    Push,
+}
+
+// exact one-way copy: pull or push with --delete-if-dne, --set-times and --prune-empty-dirs
+#[derive(Args, Debug)]
+#[command(arg_required_else_help(true))]
+pub struct MirrorArgs {
+    #[arg(value_enum)]
+    pub direction: Direction,
+    pub source: PathBuf,
+    pub dest: PathBuf,
+
+    #[command(flatten)]
+    pub sync: SyncArgs,
+}
+
+impl MirrorArgs {
+    pub fn into_subcmd(self) -> SubCmds {
+        let sync = SyncArgs {
+            delete_if_dne: true,
+            prune_empty_dirs: true,
+            ..self.sync
+        };
+        sync_subcmd(self.direction, self.source, self.dest, sync, true)
+    }
+}
+
+// "4096", "512K", "3900M", "4G", powers of 1024
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim_end_matches(['B', 'b']).trim_end_matches('i');
+    let (num, unit) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&s[..i], c.to_ascii_uppercase()),
+        _ => (s, ' '),
+    };
+    let shift = match unit {
+        ' ' => 0,
+        'K' => 10,
+        'M' => 20,
+        'G' => 30,
+        'T' => 40,
+        _ => return Err(format!("unknown size unit '{unit}'")),
+    };
+    let n: u64 = num
+        .trim()
+        .parse()
+        .map_err(|_| format!("'{s}' is not a size"))?;
+    match n.checked_mul(1 << shift) {
+        Some(0) => Err("size must not be 0".to_string()),
+        Some(n) => Ok(n),
+        None => Err(format!("'{s}' is too large")),
+    }
+}
+
+// a plain pull or push with the defaults of the options only they have
+fn sync_subcmd(
+    direction: Direction,
+    source: PathBuf,
+    dest: PathBuf,
+    sync: SyncArgs,
+    set_times: bool,
+) -> SubCmds {
+    match direction {
+        Direction::Pull => SubCmds::Pull(PullArgs {
+            source,
+            dest: Some(dest),
+            sync,
+            set_times,
+            case: Case::Preserve,
+            dedup: None,
+            mediastore: None,
+            hash_db: false,
+            write_manifest: false,
+            chmod: None,
+            chown: None,
+            invalid_char_replacement: '_',
+            windows_names: false,
+            trailing_dot: TrailingDot::Marker,
+            capture_times: false,
+            store: false,
+            encrypt: Vec::new(),
+            to_archive: None,
+            split_size: None,
+            expire_source: None,
+            remove_source_files: false,
+            file_timeout: None,
+            retry_stalled: false,
+            expire_dry_run: false,
+            yes: false,
+        }),
+        // adb push keeps modification times on its own
+        Direction::Push => SubCmds::Push(PushArgs {
+            source,
+            dest,
+            no_compress: false,
+            decrypt: None,
+            watch: false,
+            sync,
+        }),
+    }
+}
+
+// a `[profile.<name>]` from the config file, the command line adding to it
+#[derive(Args, Debug)]
+#[command(arg_required_else_help(true))]
+pub struct RunArgs {
+    pub profile: String,
+
+    #[command(flatten)]
+    pub sync: SyncArgs,
+}
+
+impl RunArgs {
+    pub fn into_subcmd(self, profile: Profile) -> SubCmds {
+        let mut sync = SyncArgs {
+            delete_if_dne: self.sync.delete_if_dne || profile.delete,
+            ..self.sync
+        };
+        sync.filter.extend(profile.filter);
+        sync.exclude.extend(profile.exclude);
+        sync_subcmd(
+            profile.direction,
+            profile.source,
+            profile.dest,
+            sync,
+            profile.set_times,
+        )
+    }
+}
+
+// pull or push, whichever way round the paths are
+#[derive(Args, Debug)]
+#[command(arg_required_else_help(true))]
+pub struct AutoArgs {
+    pub source: PathBuf,
+    pub dest: PathBuf,
+
+    #[command(flatten)]
+    pub sync: SyncArgs,
+}
+
+impl AutoArgs {
+    pub fn into_subcmd(self, direction: Direction) -> SubCmds {
+        sync_subcmd(direction, self.source, self.dest, self.sync, false)
+    }
+}
+
+// what to run over the wireless connection
+#[derive(Debug, Subcommand)]
+pub enum WirelessThen {
+    Pull(PullArgs),
+    Push(PushArgs),
+    Mirror(MirrorArgs),
+}
+
+impl From<WirelessThen> for SubCmds {
+    fn from(then: WirelessThen) -> Self {
+        match then {
+            WirelessThen::Pull(pa) => Self::Pull(pa),
+            WirelessThen::Push(pa) => Self::Push(pa),
+            WirelessThen::Mirror(ma) => Self::Mirror(ma),
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct WirelessArgs {
+    /// port adbd listens on in TCP mode
+    #[arg(long, default_value_t = 5555)]
+    pub port: u16,
+
+    #[command(subcommand)]
+    pub then: Option<WirelessThen>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCmd {
+    /// write a starter config file for the connected device
+    Init,
+    /// check a config file, the default one without a path
+    Validate { path: Option<PathBuf> },
+}
+
+#[derive(Args, Debug)]
+pub struct BitrotCheckArgs {
+    pub backup_dir: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct SpeedtestArgs {
+    /// size of the test file (K, M, G suffixes)
+    #[arg(long, default_value = "64M", value_parser = parse_size)]
+    pub size: u64,
+    /// how many shell commands to average the latency over
+    #[arg(long, default_value_t = 20)]
+    pub rounds: u32,
+}
+
+#[derive(Args, Debug)]
+pub struct VerifyManifestArgs {
+    /// directory the manifest's paths are relative to
+    pub dir: PathBuf,
+    /// a sha256sum file, like the ones `pull --write-manifest` writes
+    pub manifest: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct CpArgs {
+    /// a file or directory, on the device or local
+    pub source: PathBuf,
+    pub dest: PathBuf,
+    /// do not hash the copied files on both ends afterwards
+    #[arg(long)]
+    pub no_verify: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct ApplyArgs {
+    /// a plan written by --plan-out
+    pub plan: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct ScanArgs {
+    /// device directory to scan
+    pub source: PathBuf,
+    /// write the scan here instead of to stdout
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct SnapshotDiffArgs {
+    /// the older scan, written by `adb-sink scan`
+    pub a: PathBuf,
+    pub b: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct CheckoutArgs {
+    /// a destination of `pull --store`
+    pub store: PathBuf,
+    pub dest: PathBuf,
+    /// snapshot to check out, the latest by default
+    #[arg(long)]
+    pub snapshot: Option<String>,
+    /// hard link files to the store's blobs instead of copying them, the tree must then not be modified
+    #[arg(long)]
+    pub link: bool,
+}
+
+#[derive(Args, Debug)]
+#[command(group(clap::ArgGroup::new("keep").required(true).multiple(true)))]
+pub struct PruneArgs {
+    /// a destination of `pull --store`
+    pub store: PathBuf,
+    /// keep the latest snapshot of each of the last N days
+    #[arg(long, value_name = "N", group = "keep")]
+    pub keep_daily: Option<usize>,
+    /// keep the latest snapshot of each of the last N weeks
+    #[arg(long, value_name = "N", group = "keep")]
+    pub keep_weekly: Option<usize>,
+    /// keep the latest snapshot of each of the last N months
+    #[arg(long, value_name = "N", group = "keep")]
+    pub keep_monthly: Option<usize>,
+    /// only list what would be deleted
+    #[arg(short = 'n', long)]
+    pub dry_run: bool,
+}
+
+#[derive(Args, Debug)]
+#[command(arg_required_else_help(true))]
+pub struct InstallArgs {
+    /// an APK, or a directory of APKs and of split APK sets, each in a directory with its base.apk
+    pub path: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct HistoryArgs {
+    /// only the runs of this --job
+    pub job: Option<String>,
+    /// how many of the latest runs to list
+    #[arg(short = 'n', long, value_name = "N", default_value_t = 20)]
+    pub limit: usize,
+}
+
+#[derive(Args, Debug)]
+#[command(arg_required_else_help(true))]
+pub struct RestoreArgs {
+    /// a destination of `pull --store`, or an archive made with `pull --to-archive`
+    pub source: PathBuf,
+    /// device directory the backed up directory is restored as
+    pub dest: PathBuf,
+    /// snapshot of the store to restore, the latest by default
+    #[arg(long)]
+    pub snapshot: Option<String>,
+    /// hash every restored file on the device afterwards and compare it with the backup
+    #[arg(long)]
+    pub verify: bool,
+
+    #[command(flatten)]
+    pub sync: SyncArgs,
 }
 
 #[derive(Debug, Subcommand)]
 pub enum SubCmds {
     Pull(PullArgs),
     Push(PushArgs),
+    /// mirror the source exactly, deleting everything else on the destination
+    Mirror(MirrorArgs),
+    /// pull or push, depending on whether the source exists on the device or locally
+    Auto(AutoArgs),
+    /// sync a profile from the config file
+    Run(RunArgs),
+    /// copy a file or directory to or from the device as it is, without comparing anything
+    Cp(CpArgs),
+    /// do exactly what a plan written by --plan-out lists, if nothing changed since
+    Apply(ApplyArgs),
+    /// switch a USB connected device to adb over WiFi, then optionally sync over it
+    Wireless(WirelessArgs),
+    /// list connected devices with their nicknames
+    Devices,
+    /// measure shell latency and transfer rates to the device
+    Speedtest(SpeedtestArgs),
+    /// re-hash a backup made with --hash-db to find files that silently changed
+    BitrotCheck(BitrotCheckArgs),
+    /// re-hash a backup against a manifest, reporting missing, extra and corrupted files
+    VerifyManifest(VerifyManifestArgs),
+    /// save the files of a device directory with their sizes and mtimes as json
+    Scan(ScanArgs),
+    /// list the files added, removed and changed between two saved scans
+    SnapshotDiff(SnapshotDiffArgs),
+    /// write a snapshot of a `pull --store` destination out as a normal directory tree
+    Checkout(CheckoutArgs),
+    /// delete old snapshots of a `pull --store` destination, and the blobs only they had
+    Prune(PruneArgs),
+    /// push a snapshot or archive back to the device with its mtimes
+    Restore(RestoreArgs),
+    /// list past runs and how each job has been doing
+    History(HistoryArgs),
+    /// install APKs and split APK sets, skipping apps the device has at the same version
+    Install(InstallArgs),
+    /// create or check the config file
+    #[command(subcommand)]
+    Config(ConfigCmd),
 }
 
 #[derive(Parser, Debug)]
@@ -54,4 +643,153 @@ pub struct Cli {
 
     #[arg(short = 'v', long)]
     pub verbose: bool,
+
+    /// exit with 1 if source and destination differed, 0 if they matched and 2 on errors
+    #[arg(long, global = true)]
+    pub exit_code: bool,
+
+    /// extra global flag for every adb invocation, e.g. `--adb-arg=-d` or `--adb-arg=-t --adb-arg=3`
+    #[arg(long, value_name = "ARG", allow_hyphen_values = true, global = true)]
+    pub adb_arg: Vec<String>,
+
+    /// `adb connect` to this address before syncing, and use that device
+    #[arg(long, value_name = "HOST:PORT", global = true)]
+    pub connect: Option<String>,
+
+    /// talk to the USB connected device directly instead of through an adb server, no adb needed
+    #[cfg(feature = "usb")]
+    #[arg(long, conflicts_with = "connect", global = true)]
+    pub usb: bool,
+
+    /// serial of the device to use, like `adb -s`, or its `[devices]` nickname from the config file
+    #[arg(
+        short = 's',
+        long,
+        visible_alias = "serial",
+        value_name = "SERIAL",
+        conflicts_with = "connect",
+        global = true
+    )]
+    pub device: Option<String>,
+
+    /// name of this run in the history, the command line by default
+    #[arg(long, value_name = "NAME", global = true)]
+    pub job: Option<String>,
+
+    /// `adb disconnect` from the --connect address when done
+    #[arg(long, requires = "connect", global = true)]
+    pub disconnect: bool,
+
+    /// refuse to start while the device battery is below PERCENT and not charging
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        value_parser = clap::value_parser!(u8).range(0..=100),
+        global = true
+    )]
+    pub min_battery: Option<u8>,
+
+    /// refuse to start while the device thermal status is above this
+    #[arg(long, value_enum, value_name = "STATUS", global = true)]
+    pub max_thermal: Option<Thermal>,
+
+    /// refuse to start while the device is not charging, for scheduled runs
+    #[arg(long, global = true)]
+    pub only_when_charging: bool,
+
+    /// refuse to start while the device's default network is not WiFi, e.g. mobile data
+    #[arg(long, global = true)]
+    pub only_on_wifi: bool,
+
+    /// wait until the preflight checks (--min-battery, --max-thermal, --only-*) pass instead of refusing
+    #[arg(long, global = true)]
+    pub preflight_wait: bool,
+
+    /// post a notification on the device when the sync finished or failed
+    #[arg(long, global = true)]
+    pub device_notify: bool,
+
+    /// never modify the device: no pushes, deletes, mtime or setting changes, whatever else is given
+    #[arg(
+        long,
+        conflicts_with_all = ["device_notify", "stay_awake", "forward"],
+        global = true
+    )]
+    pub no_write_device: bool,
+
+    /// keep the device screen on while plugged in until the sync is done
+    #[arg(long, global = true)]
+    pub stay_awake: bool,
+
+    /// restart the adb server (kill-server + start-server) when it stops responding
+    #[arg(long, global = true)]
+    pub recover_server: bool,
+
+    /// limit transfers to RATE bytes per second, or by local time of day: `01:00-07:00=0,2M` is
+    /// unlimited at night and 2 MiB/s otherwise
+    #[arg(long, value_name = "SCHEDULE", value_parser = Schedule::parse, global = true)]
+    pub bwlimit: Option<Schedule>,
+
+    /// update large changed files by moving only the blocks that differ instead of the whole file
+    #[arg(long, global = true)]
+    pub delta: bool,
+
+    /// show a progress line with the transfer rate and time left while copying, on a terminal
+    #[arg(long, global = true)]
+    pub progress: bool,
+
+    /// print a one line status every SECS, for CI logs of long transfers
+    #[arg(
+        long,
+        value_name = "SECS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        global = true
+    )]
+    pub heartbeat: Option<u64>,
+
+    /// fail the run when it had warnings, or only warnings of these codes, e.g. `W003,W008`
+    #[arg(
+        long,
+        value_name = "CODES",
+        num_args = 0..,
+        require_equals = true,
+        value_delimiter = ',',
+        value_parser = warnings::parse_code,
+        global = true
+    )]
+    pub fail_on_warning: Option<Vec<&'static str>>,
+
+    /// transfer files through a helper served by toybox `nc` on the device behind `adb forward`,
+    /// instead of an adb push or pull per file
+    #[arg(long, global = true)]
+    pub forward: bool,
+
+    /// with --forward, transfer this many files at a time over separate connections
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        requires = "forward",
+        value_parser = clap::value_parser!(u8).range(1..=16),
+        global = true
+    )]
+    pub channels: u8,
+
+    /// instead of running, write a systemd user service and timer NAME that run this command
+    #[arg(long, value_name = "NAME", global = true)]
+    pub generate_unit: Option<String>,
+
+    /// when the --generate-unit timer starts the sync, in systemd.time(7) calendar syntax
+    #[arg(
+        long,
+        value_name = "CALENDAR",
+        default_value = "daily",
+        requires = "generate_unit",
+        global = true
+    )]
+    pub on_calendar: String,
+
+    /// backend used to reach the device side
+    #[arg(long, value_enum, default_value_t = Transport::Adb, global = true)]
+    pub transport: Transport,
 }
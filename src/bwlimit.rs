@@ -0,0 +1,164 @@
+// `--bwlimit`: a transfer rate limit that can depend on the time of day, so a sync left running
+// all day, or started by a timer, stays out of the way of the network and the device while
+// they are in use
+//   --bwlimit 2M                          2 MiB/s at any time
+//   --bwlimit 01:00-07:00=0,2M            unlimited at night, 2 MiB/s otherwise
+//   --bwlimit 09:00-18:00=512K,22:00-06:00=0
+// times are local, a window ending before it starts runs over midnight. adb copies a file in
+// one go, so the limit is kept per file: after each one the sync waits until the file took as
+// long as it would have at the rate. the schedule is looked at after every file
+
+use crate::args::parse_size;
+use crate::{human_size, logi};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Window {
+    // minutes since midnight
+    from: u32,
+    to: u32,
+    rate: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    windows: Vec<Window>,
+    // outside of the windows
+    rate: Option<u64>,
+}
+
+impl Schedule {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut schedule = Self {
+            windows: Vec::new(),
+            rate: None,
+        };
+        for part in s.split(',').map(str::trim) {
+            match part.split_once('=') {
+                Some((span, rate)) => {
+                    let (from, to) = span
+                        .split_once('-')
+                        .ok_or_else(|| format!("'{span}' is not a time span like 01:00-07:00"))?;
+                    schedule.windows.push(Window {
+                        from: minutes(from)?,
+                        to: minutes(to)?,
+                        rate: parse_rate(rate)?,
+                    });
+                }
+                None => schedule.rate = parse_rate(part)?,
+            }
+        }
+        Ok(schedule)
+    }
+
+    // bytes per second at `minute` of the day, None for no limit. the first matching window wins
+    fn rate_at(&self, minute: u32) -> Option<u64> {
+        self.windows
+            .iter()
+            .find(|w| {
+                if w.from <= w.to {
+                    (w.from..w.to).contains(&minute)
+                } else {
+                    minute >= w.from || minute < w.to
+                }
+            })
+            .map_or(self.rate, |w| w.rate)
+    }
+}
+
+// "07:30"
+fn minutes(s: &str) -> Result<u32, String> {
+    let err = || format!("'{s}' is not a time like 07:30");
+    let (h, m) = s.trim().split_once(':').ok_or_else(err)?;
+    let (h, m): (u32, u32) = (h.parse().map_err(|_| err())?, m.parse().map_err(|_| err())?);
+    // 24:00 ends a window at midnight
+    if h > 24 || m > 59 || (h == 24 && m > 0) {
+        return Err(err());
+    }
+    Ok(h * 60 + m)
+}
+
+// "2M" bytes per second, "0" or "unlimited" for none
+fn parse_rate(s: &str) -> Result<Option<u64>, String> {
+    match s.trim() {
+        "0" | "unlimited" => Ok(None),
+        s => parse_size(s).map(Some),
+    }
+}
+
+static SCHEDULE: OnceLock<Schedule> = OnceLock::new();
+// the rate last announced, to log when a window starts or ends
+static ANNOUNCED: Mutex<Option<Option<u64>>> = Mutex::new(None);
+
+pub fn set(schedule: Schedule) {
+    let _ = SCHEDULE.set(schedule);
+}
+
+pub(crate) fn is_set() -> bool {
+    SCHEDULE.get().is_some()
+}
+
+// waits after a file of `bytes` that took `took` until it would have at the current rate
+pub(crate) fn pace(bytes: u64, took: Duration) {
+    let Some(schedule) = SCHEDULE.get() else {
+        return;
+    };
+    let rate = schedule.rate_at(local_minute());
+    let mut announced = ANNOUNCED.lock().unwrap();
+    if *announced != Some(rate) {
+        match rate {
+            Some(r) => logi!("Bandwidth limit {}/s", human_size(r)),
+            None => logi!("No bandwidth limit"),
+        }
+        *announced = Some(rate);
+    }
+    drop(announced);
+    if let Some(rate) = rate {
+        let should_take = Duration::from_secs_f64(bytes as f64 / rate as f64);
+        if let Some(wait) = should_take.checked_sub(took) {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn local_minute() -> u32 {
+    let now = crate::history::now() as libc::time_t;
+    // SAFETY: localtime_r only writes to `tm`
+    let tm = unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        tm
+    };
+    (tm.tm_hour * 60 + tm.tm_min) as u32
+}
+
+// UTC where the local timezone is not known
+#[cfg(not(unix))]
+fn local_minute() -> u32 {
+    (crate::history::now() / 60 % (24 * 60)) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bandwidth_schedule() {
+        let s = Schedule::parse("01:00-07:00=0,2M").unwrap();
+        assert_eq!(s.rate_at(0), Some(2 << 20));
+        assert_eq!(s.rate_at(60), None);
+        assert_eq!(s.rate_at(7 * 60), Some(2 << 20));
+
+        let s = Schedule::parse("09:00-18:00=512K, 22:00-06:00=unlimited").unwrap();
+        assert_eq!(s.rate_at(12 * 60), Some(512 << 10));
+        assert_eq!(s.rate_at(23 * 60), None);
+        assert_eq!(s.rate_at(5 * 60 + 59), None);
+        assert_eq!(s.rate_at(20 * 60), None);
+
+        assert!(Schedule::parse("01:00=2M").is_err());
+        assert!(Schedule::parse("01:00-25:00=2M").is_err());
+        assert!(Schedule::parse("2X").is_err());
+    }
+}
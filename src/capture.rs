@@ -0,0 +1,293 @@
+// `pull --capture-times`: photos and videos get the time they were taken as their local mtime,
+// read from the pulled file, instead of the device file's mtime, which is whenever the file was
+// last moved, restored or re-downloaded. understood are the Exif DateTimeOriginal of JPEG, HEIF
+// and DNG files and the movie header creation time of MP4, MOV and 3GP files
+//
+// Exif times are local to the camera. with an OffsetTimeOriginal, as recent phones write, they
+// are converted to UTC, without one they are taken as UTC. movie headers are in UTC
+
+use crate::fs::FileSystem;
+use crate::{is_verbose, logv, unix_time, CResult};
+use chainerror::Context;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicBool, Ordering};
+use typed_path::Utf8UnixPath as UnixPath;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+const EXTENSIONS: [&str; 9] = [
+    "jpg", "jpeg", "heic", "heif", "dng", "mp4", "m4v", "mov", "3gp",
+];
+
+// whether the mtime of `path` is its capture time rather than that of the source, so a source
+// file with a later mtime is no sign of a change
+pub(crate) fn keeps_time(path: &UnixPath) -> bool {
+    ENABLED.load(Ordering::Relaxed) && is_media(path)
+}
+
+fn is_media(path: &UnixPath) -> bool {
+    path.extension()
+        .is_some_and(|e| EXTENSIONS.iter().any(|m| e.eq_ignore_ascii_case(m)))
+}
+
+// sets the mtime of the pulled file `path` to its capture time, if it has one
+pub(crate) fn apply<DEST: FileSystem>(dest_fs: &mut DEST, path: &UnixPath) -> CResult<()> {
+    if !keeps_time(path) {
+        return Ok(());
+    }
+    let mut f = File::open(path.as_str()).annotate()?;
+    match capture_time(&mut f) {
+        Some(t) => {
+            logv!("CAPTURE TIME {}: {}", t, path);
+            dest_fs.set_mtime(path, t).annotate()?;
+        }
+        None => logv!("NO CAPTURE TIME: {}", path),
+    }
+    Ok(())
+}
+
+// seconds since the epoch the photo or video in `r` was taken at
+pub fn capture_time<R: Read + Seek>(r: &mut R) -> Option<u32> {
+    let mut head = [0u8; 12];
+    r.read_exact(&mut head).ok()?;
+    r.seek(SeekFrom::Start(0)).ok()?;
+    let t = if head.starts_with(&[0xff, 0xd8]) {
+        jpeg(r)
+    } else if head.starts_with(b"II*\0") || head.starts_with(b"MM\0*") {
+        let mut buf = Vec::new();
+        r.take(1 << 20).read_to_end(&mut buf).ok()?;
+        exif(&buf)
+    } else if &head[4..8] == b"ftyp" {
+        let brand = &head[8..12];
+        if [b"heic", b"heix", b"mif1", b"msf1"]
+            .iter()
+            .any(|b| brand == *b)
+        {
+            heif(r)
+        } else {
+            movie(r)
+        }
+    } else {
+        None
+    }?;
+    u32::try_from(t).ok().filter(|&t| t > 0)
+}
+
+// the Exif APP1 segment comes before the image data
+fn jpeg<R: Read>(r: &mut R) -> Option<i64> {
+    let mut marker = [0u8; 4];
+    r.read_exact(&mut marker[..2]).ok()?;
+    loop {
+        r.read_exact(&mut marker).ok()?;
+        if marker[0] != 0xff || marker[1] == 0xda || marker[1] == 0xd9 {
+            return None;
+        }
+        let len = u16::from_be_bytes([marker[2], marker[3]]) as usize;
+        let mut seg = vec![0u8; len.checked_sub(2)?];
+        r.read_exact(&mut seg).ok()?;
+        if marker[1] == 0xe1 && seg.starts_with(b"Exif\0\0") {
+            return exif(&seg[6..]);
+        }
+    }
+}
+
+// the Exif item of a HEIF file is somewhere in its first megabyte, prefixed as in a JPEG
+fn heif<R: Read>(r: &mut R) -> Option<i64> {
+    let mut buf = Vec::new();
+    r.take(1 << 20).read_to_end(&mut buf).ok()?;
+    let at = buf
+        .windows(8)
+        .position(|w| w.starts_with(b"Exif\0\0") && (&w[6..] == b"II" || &w[6..] == b"MM"))?;
+    exif(&buf[at + 6..])
+}
+
+// the TIFF structure of Exif data: the DateTimeOriginal and OffsetTimeOriginal of the Exif
+// IFD, falling back to the DateTime of IFD0
+fn exif(tiff: &[u8]) -> Option<i64> {
+    let be = match tiff.get(..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let u16_at = |at: usize| {
+        let b: [u8; 2] = tiff.get(at..at + 2)?.try_into().ok()?;
+        Some(if be {
+            u16::from_be_bytes(b)
+        } else {
+            u16::from_le_bytes(b)
+        })
+    };
+    let u32_at = |at: usize| {
+        let b: [u8; 4] = tiff.get(at..at + 4)?.try_into().ok()?;
+        let v = if be {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        };
+        Some(v as usize)
+    };
+    // the entry of `tag` in the IFD at `ifd`: its value offset and count
+    let find = |ifd: usize, tag: u16| {
+        let n = u16_at(ifd)? as usize;
+        (0..n).map(|i| ifd + 2 + i * 12).find_map(|e| {
+            (u16_at(e)? == tag).then_some(())?;
+            let count = u32_at(e + 4)?;
+            // values of up to 4 bytes are stored in the entry itself
+            let at = if count <= 4 { e + 8 } else { u32_at(e + 8)? };
+            Some((at, count))
+        })
+    };
+    let ascii = |(at, count): (usize, usize)| {
+        let s = tiff.get(at..at + count)?;
+        std::str::from_utf8(s)
+            .ok()
+            .map(|s| s.trim_end_matches('\0'))
+    };
+
+    let ifd0 = u32_at(4)?;
+    let exif_ifd = find(ifd0, 0x8769).and_then(|(at, _)| u32_at(at));
+    let from_exif = exif_ifd.and_then(|ifd| {
+        let date = find(ifd, 0x9003).and_then(ascii)?;
+        let offset = find(ifd, 0x9011).and_then(ascii).and_then(utc_offset);
+        Some((date, offset))
+    });
+    let (date, offset) = match from_exif {
+        Some(d) => d,
+        None => (find(ifd0, 0x0132).and_then(ascii)?, None),
+    };
+    Some(exif_date(date)? - offset.unwrap_or(0))
+}
+
+// "YYYY:MM:DD HH:MM:SS"
+fn exif_date(s: &str) -> Option<i64> {
+    let (date, time) = s.trim().split_once(' ')?;
+    let n = |s: &str| s.parse::<u32>().ok();
+    let d: Vec<u32> = date.split(':').map(n).collect::<Option<_>>()?;
+    let t: Vec<u32> = time.split(':').map(n).collect::<Option<_>>()?;
+    let (&[year, month, day], &[hour, min, sec]) = (&d[..], &t[..]) else {
+        return None;
+    };
+    // unset dates are written as zeros
+    if year < 1970 || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    Some(unix_time(year as i64, month, day, hour, min, sec) as i64)
+}
+
+// "+02:00" in seconds
+fn utc_offset(s: &str) -> Option<i64> {
+    let sign = match s.get(..1)? {
+        "+" => 1,
+        "-" => -1,
+        _ => return None,
+    };
+    let (h, m) = s[1..].split_once(':')?;
+    Some(sign * (h.parse::<i64>().ok()? * 3600 + m.parse::<i64>().ok()? * 60))
+}
+
+// the creation time of the `mvhd` box in `moov`, which recorders often write last
+fn movie<R: Read + Seek>(r: &mut R) -> Option<i64> {
+    let end = r.seek(SeekFrom::End(0)).ok()?;
+    let (start, end) = find_box(r, 0, end, b"moov")?;
+    let (start, _) = find_box(r, start, end, b"mvhd")?;
+    r.seek(SeekFrom::Start(start)).ok()?;
+    let mut hd = [0u8; 12];
+    r.read_exact(&mut hd).ok()?;
+    let created = match hd[0] {
+        0 => u32::from_be_bytes(hd[4..8].try_into().ok()?) as i64,
+        1 => i64::try_from(u64::from_be_bytes(hd[4..12].try_into().ok()?)).ok()?,
+        _ => return None,
+    };
+    // seconds since 1904, zero when the recorder did not know the time
+    (created != 0).then(|| created - 2082844800)
+}
+
+// the content range of the first box of `kind` between `start` and `end`
+fn find_box<R: Read + Seek>(
+    r: &mut R,
+    mut start: u64,
+    end: u64,
+    kind: &[u8],
+) -> Option<(u64, u64)> {
+    while start + 8 <= end {
+        r.seek(SeekFrom::Start(start)).ok()?;
+        let mut hdr = [0u8; 8];
+        r.read_exact(&mut hdr).ok()?;
+        let (size, content) = match u32::from_be_bytes(hdr[..4].try_into().ok()?) {
+            0 => (end - start, start + 8),
+            1 => {
+                let mut large = [0u8; 8];
+                r.read_exact(&mut large).ok()?;
+                (u64::from_be_bytes(large), start + 16)
+            }
+            size => (size as u64, start + 8),
+        };
+        if size < content - start {
+            return None;
+        }
+        if &hdr[4..] == kind {
+            return Some((content, (start + size).min(end)));
+        }
+        start += size;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // a big endian TIFF with IFD0 pointing at an Exif IFD holding `date` and `offset`
+    fn tiff(date: &str, offset: &str) -> Vec<u8> {
+        let mut t = b"MM\0*\0\0\0\x08".to_vec();
+        // IFD0 at 8: one entry, the Exif IFD pointer, then no next IFD
+        t.extend([0, 1, 0x87, 0x69, 0, 4, 0, 0, 0, 1, 0, 0, 0, 26]);
+        t.extend([0, 0, 0, 0]);
+        // the Exif IFD at 26: two ASCII entries with their values after it
+        let date_at = 26 + 2 + 2 * 12 + 4;
+        let offset_at = date_at + date.len() + 1;
+        t.extend([0, 2]);
+        t.extend([0x90, 0x03, 0, 2]);
+        t.extend((date.len() as u32 + 1).to_be_bytes());
+        t.extend((date_at as u32).to_be_bytes());
+        t.extend([0x90, 0x11, 0, 2]);
+        t.extend((offset.len() as u32 + 1).to_be_bytes());
+        t.extend((offset_at as u32).to_be_bytes());
+        t.extend([0, 0, 0, 0]);
+        t.extend(date.as_bytes());
+        t.push(0);
+        t.extend(offset.as_bytes());
+        t.push(0);
+        t
+    }
+
+    #[test]
+    fn capture_times() {
+        // 2024-05-01 12:30:05 at UTC+02:00
+        let exif_data = tiff("2024:05:01 14:30:05", "+02:00");
+        let mut jpeg = vec![0xff, 0xd8, 0xff, 0xe1];
+        jpeg.extend((exif_data.len() as u16 + 8).to_be_bytes());
+        jpeg.extend(b"Exif\0\0");
+        jpeg.extend(&exif_data);
+        jpeg.extend([0xff, 0xda, 0, 2]);
+        assert_eq!(capture_time(&mut Cursor::new(&jpeg)), Some(1714566605));
+        assert_eq!(capture_time(&mut Cursor::new(&exif_data)), Some(1714566605));
+        let unset = tiff("0000:00:00 00:00:00", "+02:00");
+        assert_eq!(capture_time(&mut Cursor::new(&unset)), None);
+
+        // ftyp, an mdat and then moov with a version 0 mvhd
+        let mut mp4 = b"\0\0\0\x10ftypisom\0\0\0\0".to_vec();
+        mp4.extend(b"\0\0\0\x0cmdat\0\0\0\0");
+        mp4.extend(b"\0\0\0\x1cmoov\0\0\0\x14mvhd\0\0\0\0");
+        mp4.extend((1714566605u32 + 2082844800).to_be_bytes());
+        mp4.extend([0, 0, 0, 0]);
+        assert_eq!(capture_time(&mut Cursor::new(&mp4)), Some(1714566605));
+        assert_eq!(capture_time(&mut Cursor::new(b"not a photo")), None);
+    }
+}
@@ -0,0 +1,481 @@
+use crate::adb::AdbCmd;
+use crate::args::Direction;
+use crate::filter::FilterRule;
+use crate::{logi, CResult};
+use chainerror::Context;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+pub static CONFIG: OnceLock<Config> = OnceLock::new();
+
+// the loaded config, empty if main did not load one
+pub fn config() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+// the small toml subset adb-sink needs: `[section]` headers, `key = "value"` and
+// `key = ["a", "b"]` lines. bare values (numbers, booleans) are kept as written.
+// every value is stored as a list, a plain value being a list of one
+#[derive(Debug, Default)]
+pub struct Config {
+    sections: BTreeMap<String, BTreeMap<String, Vec<String>>>,
+    // (section, key) -> line number, for error messages
+    lines: BTreeMap<(String, String), usize>,
+}
+
+// defaults of one device, from its `[device.<serial or nickname>]` section
+#[derive(Debug, Default)]
+pub struct DeviceSettings {
+    // appended after the command line's --filter rules
+    pub filter: Vec<FilterRule>,
+    // pull destination when none is given on the command line
+    pub pull_dest: Option<PathBuf>,
+    // appended after the command line's --first and --last patterns
+    pub first: Vec<FilterRule>,
+    pub last: Vec<FilterRule>,
+}
+
+// a recurring sync from its `[profile.<name>]` section, run with `adb-sink run <name>`
+#[derive(Debug)]
+pub struct Profile {
+    pub direction: Direction,
+    pub source: PathBuf,
+    pub dest: PathBuf,
+    // appended after the command line's --filter rules and --exclude globs
+    pub filter: Vec<FilterRule>,
+    pub exclude: Vec<FilterRule>,
+    pub delete: bool,
+    pub set_times: bool,
+}
+
+impl Config {
+    // $ADB_SINK_CONFIG, else adb-sink/config.toml in the user's config directory
+    pub fn path() -> Option<PathBuf> {
+        if let Some(p) = std::env::var_os("ADB_SINK_CONFIG") {
+            return Some(PathBuf::from(p));
+        }
+        let dir = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))
+            .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))?;
+        Some(dir.join("adb-sink").join("config.toml"))
+    }
+
+    // a missing config file is an empty config
+    pub fn load() -> CResult<Self> {
+        match Self::path() {
+            Some(path) => Self::load_from(&path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn load_from(path: &Path) -> CResult<Self> {
+        let s = match std::fs::read_to_string(path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            r => r.annotate()?,
+        };
+        Self::parse(&s).map_err(|e| format!("Config '{}': {}", path.display(), e).into())
+    }
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut sections: BTreeMap<String, BTreeMap<String, Vec<String>>> = BTreeMap::new();
+        let mut lines = BTreeMap::new();
+        let mut section = String::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = name.trim().to_string();
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(format!("line {}: expected `key = value`", i + 1));
+            };
+            let (key, _) = scalar(key.trim()).map_err(|e| format!("line {}: {}", i + 1, e))?;
+            let value = values(value.trim()).map_err(|e| format!("line {}: {}", i + 1, e))?;
+            lines.insert((section.clone(), key.clone()), i + 1);
+            sections
+                .entry(section.clone())
+                .or_default()
+                .insert(key, value);
+        }
+        Ok(Self { sections, lines })
+    }
+
+    pub fn section(&self, name: &str) -> Option<&BTreeMap<String, Vec<String>>> {
+        self.sections.get(name)
+    }
+
+    // the value of `key`, the first element for lists
+    pub fn get(&self, section: &str, key: &str) -> Option<&str> {
+        self.section(section)?.get(key)?.first().map(String::as_str)
+    }
+
+    // the serial behind a `[devices]` nickname, `device` itself if it is not one
+    pub fn serial<'a>(&'a self, device: &'a str) -> &'a str {
+        self.get("devices", device).unwrap_or(device)
+    }
+
+    pub fn nickname(&self, serial: &str) -> Option<&str> {
+        self.section("devices")?
+            .iter()
+            .find(|(_, s)| s.first().is_some_and(|s| s == serial))
+            .map(|(n, _)| n.as_str())
+    }
+
+    // "pixel7 (1A2B3C4D)", or just the serial without a nickname
+    pub fn describe(&self, serial: &str) -> String {
+        match self.nickname(serial) {
+            Some(n) => format!("{n} ({serial})"),
+            None => serial.to_string(),
+        }
+    }
+
+    pub fn device_settings(&self, serial: &str) -> CResult<DeviceSettings> {
+        let name = Some(format!("device.{serial}"))
+            .into_iter()
+            .chain(self.nickname(serial).map(|n| format!("device.{n}")))
+            .find(|name| self.sections.contains_key(name));
+        match name {
+            Some(name) => Ok(self.settings_in(&name)?),
+            None => Ok(DeviceSettings::default()),
+        }
+    }
+
+    fn settings_in(&self, section: &str) -> Result<DeviceSettings, String> {
+        let mut settings = DeviceSettings::default();
+        for (key, value) in &self.sections[section] {
+            let at = |e: String| format!("{}: {}", self.location(section, key), e);
+            match key.as_str() {
+                "filter" => {
+                    for rule in value {
+                        settings.filter.push(rule.parse().map_err(at)?);
+                    }
+                }
+                "pull-dest" => settings.pull_dest = value.first().map(PathBuf::from),
+                "first" | "last" => {
+                    for pattern in value {
+                        let rule = FilterRule::pattern(pattern).map_err(at)?;
+                        match key.as_str() {
+                            "first" => settings.first.push(rule),
+                            _ => settings.last.push(rule),
+                        }
+                    }
+                }
+                _ => return Err(at(format!("unknown key '{key}'"))),
+            }
+        }
+        Ok(settings)
+    }
+
+    pub fn profile(&self, name: &str) -> Result<Profile, String> {
+        let section = format!("profile.{name}");
+        if !self.sections.contains_key(&section) {
+            let names: Vec<&str> = self
+                .sections
+                .keys()
+                .filter_map(|s| s.strip_prefix("profile."))
+                .collect();
+            return Err(match names.as_slice() {
+                [] => format!("No profile '{name}', the config has none"),
+                names => format!("No profile '{name}', there are: {}", names.join(", ")),
+            });
+        }
+        self.profile_in(&section)
+    }
+
+    fn profile_in(&self, section: &str) -> Result<Profile, String> {
+        let (mut direction, mut source, mut dest) = (None, None, None);
+        let (mut filter, mut exclude) = (Vec::new(), Vec::new());
+        let (mut delete, mut set_times) = (false, false);
+        for (key, value) in &self.sections[section] {
+            let at = |e: String| format!("{}: {}", self.location(section, key), e);
+            match key.as_str() {
+                "direction" => {
+                    direction = Some(match value.first().map(String::as_str) {
+                        Some("pull") => Direction::Pull,
+                        Some("push") => Direction::Push,
+                        _ => return Err(at("expected \"pull\" or \"push\"".to_string())),
+                    })
+                }
+                "source" => source = value.first().map(PathBuf::from),
+                "dest" => dest = value.first().map(PathBuf::from),
+                "filter" => {
+                    for rule in value {
+                        filter.push(rule.parse().map_err(at)?);
+                    }
+                }
+                "exclude" => {
+                    for glob in value {
+                        exclude.push(FilterRule::exclude(glob).map_err(at)?);
+                    }
+                }
+                "delete" => delete = boolean(value).map_err(at)?,
+                "set-times" => set_times = boolean(value).map_err(at)?,
+                _ => return Err(at(format!("unknown key '{key}'"))),
+            }
+        }
+        let missing = |key: &str| format!("[{section}]: missing '{key}'");
+        Ok(Profile {
+            direction: direction.ok_or_else(|| missing("direction"))?,
+            source: source.ok_or_else(|| missing("source"))?,
+            dest: dest.ok_or_else(|| missing("dest"))?,
+            filter,
+            exclude,
+            delete,
+            set_times,
+        })
+    }
+
+    // "line 3, [device.pixel7] filter"
+    fn location(&self, section: &str, key: &str) -> String {
+        let line = self.lines[&(section.to_string(), key.to_string())];
+        format!("line {line}, [{section}] {key}")
+    }
+
+    // every problem of the config, with its location
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (name, section) in &self.sections {
+            if name.starts_with("device.") {
+                if let Err(e) = self.settings_in(name) {
+                    problems.push(e);
+                }
+                continue;
+            }
+            if name.starts_with("profile.") {
+                if let Err(e) = self.profile_in(name) {
+                    problems.push(e);
+                }
+                continue;
+            }
+            for key in section.keys() {
+                match name.as_str() {
+                    "devices" if self.get(name, key).is_some_and(|s| !s.is_empty()) => {}
+                    "devices" => {
+                        problems.push(format!("{}: empty serial", self.location(name, key)))
+                    }
+                    _ => problems.push(format!("{}: unknown setting", self.location(name, key))),
+                }
+            }
+        }
+        problems
+    }
+}
+
+// `true` or `false`
+fn boolean(value: &[String]) -> Result<bool, String> {
+    match value {
+        [v] if v == "true" => Ok(true),
+        [v] if v == "false" => Ok(false),
+        _ => Err("expected true or false".to_string()),
+    }
+}
+
+// a toml string of `s`
+fn quoted(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn ask(question: &str, default: &str) -> CResult<String> {
+    print!("{question} [{default}]: ");
+    std::io::stdout().flush().annotate()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).annotate()?;
+    Ok(match answer.trim() {
+        "" => default.to_string(),
+        a => a.to_string(),
+    })
+}
+
+// `config init`: asks about the connected device and writes a starter config to `path`
+pub fn init(path: &Path) -> CResult<()> {
+    if path.exists() {
+        return Err(format!("'{}' already exists", path.display()).into());
+    }
+    let serial = crate::adb_device_list(false)
+        .annotate()?
+        .into_iter()
+        .find(|(_, state)| state == "device")
+        .map(|(serial, _)| serial);
+    let mut out = String::from("# adb-sink configuration\n");
+    match serial {
+        Some(serial) => {
+            let model = AdbCmd::run_v(["-s", &serial, "shell", "getprop", "ro.product.model"])
+                .annotate()?;
+            let suggested: String = model
+                .trim()
+                .to_lowercase()
+                .chars()
+                .filter(|c| c.is_ascii_alphanumeric())
+                .collect();
+            logi!("Found device {} ({})", serial, model.trim());
+            let nick = ask("Nickname for it", &suggested).annotate()?;
+            let home = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+            let dest = ask("Pull destination", &format!("{home}/adb-sink/{nick}")).annotate()?;
+            let skip_junk =
+                ask("Skip thumbnails, caches and Android/data? (y/n)", "y").annotate()?;
+
+            out.push_str(&format!(
+                "\n[devices]\n{} = {}\n",
+                quoted(&nick),
+                quoted(&serial)
+            ));
+            out.push_str(&format!(
+                "\n[device.{nick}]\npull-dest = {}\n",
+                quoted(&dest)
+            ));
+            if skip_junk.starts_with(['y', 'Y']) {
+                out.push_str("filter = [\"- .thumbnails/\", \"- cache/\", \"- /Android/data/\"]\n");
+            }
+            out.push_str(&format!(
+                "\n# then: adb-sink --device {nick} pull /sdcard/DCIM\n"
+            ));
+        }
+        None => {
+            logi!("No device connected, writing an example config");
+            out.push_str(
+                "\n[devices]\n# pixel7 = \"1A2B3C4D\"\n\n[device.pixel7]\n\
+                 # pull-dest = \"/home/me/backups/pixel7\"\n\
+                 # filter = [\"- .thumbnails/\", \"- /Android/data/\"]\n",
+            );
+        }
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).annotate()?;
+    }
+    std::fs::write(path, out).annotate()?;
+    logi!("Wrote '{}'", path.display());
+    Ok(())
+}
+
+// a plain value or a `[..]` list of them
+fn values(s: &str) -> Result<Vec<String>, String> {
+    let Some(mut rest) = s.strip_prefix('[') else {
+        let (v, rest) = scalar(s)?;
+        end(rest)?;
+        return Ok(vec![v]);
+    };
+    let mut out = Vec::new();
+    loop {
+        rest = rest.trim_start();
+        if let Some(r) = rest.strip_prefix(']') {
+            end(r)?;
+            return Ok(out);
+        }
+        let (v, r) = scalar(rest)?;
+        out.push(v);
+        rest = r.trim_start();
+        rest = rest.strip_prefix(',').unwrap_or(rest);
+    }
+}
+
+// returns the value and what follows it
+fn scalar(s: &str) -> Result<(String, &str), String> {
+    let Some(inner) = s.strip_prefix('"') else {
+        let len = s.find([',', ']', '#']).unwrap_or(s.len());
+        if len == 0 {
+            return Err(format!("expected a value: '{s}'"));
+        }
+        return Ok((s[..len].trim().to_string(), &s[len..]));
+    };
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Ok((out, chars.as_str())),
+            '\\' => match chars.next() {
+                Some('n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                Some(c @ ('"' | '\\')) => out.push(c),
+                c => return Err(format!("unknown escape '\\{}'", c.unwrap_or(' '))),
+            },
+            c => out.push(c),
+        }
+    }
+    Err(format!("unterminated string: {s}"))
+}
+
+// only a comment may follow a value
+fn end(rest: &str) -> Result<(), String> {
+    let rest = rest.trim();
+    if rest.is_empty() || rest.starts_with('#') {
+        Ok(())
+    } else {
+        Err(format!("unexpected '{rest}' after value"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let c = Config::parse(
+            "# phones\n[devices]\npixel7 = \"1A2B3C4D\" # mine\n\"old tab\" = \"R58\\\"X\"\n\n\
+            [device.pixel7]\nfilter = [\"- Android/\", \"- *.tmp\"] # junk\npull-dest = \"/bak/p7\"\n\
+            [misc]\nn = 3\n",
+        )
+        .unwrap();
+        assert_eq!(c.serial("pixel7"), "1A2B3C4D");
+        assert_eq!(c.serial("emulator-5554"), "emulator-5554");
+        assert_eq!(c.nickname("R58\"X"), Some("old tab"));
+        assert_eq!(c.describe("1A2B3C4D"), "pixel7 (1A2B3C4D)");
+        assert_eq!(c.get("misc", "n"), Some("3"));
+        let s = c.device_settings("1A2B3C4D").unwrap();
+        assert_eq!(s.filter.len(), 2);
+        assert_eq!(s.pull_dest, Some(PathBuf::from("/bak/p7")));
+        assert!(c.device_settings("R58\"X").unwrap().filter.is_empty());
+        assert_eq!(c.validate(), ["line 10, [misc] n: unknown setting"]);
+        let bad = Config::parse("[devices]\np = \"\"\n[device.p]\nfilter = \"x\"\n[misc]\nn = 1\n")
+            .unwrap();
+        assert_eq!(
+            bad.validate(),
+            [
+                "line 4, [device.p] filter: filter rule must look like '+ pattern' or '- pattern': 'x'",
+                "line 2, [devices] p: empty serial",
+                "line 6, [misc] n: unknown setting",
+            ]
+        );
+        assert!(Config::parse("[devices]\npixel7\n").is_err());
+        assert!(Config::parse("a = \"b").is_err());
+        assert!(Config::parse("a = [\"b\"] c").is_err());
+    }
+
+    #[test]
+    fn profiles() {
+        let c = Config::parse(
+            "[profile.photos]\ndirection = \"pull\"\nsource = \"/sdcard/DCIM\"\n\
+            dest = \"/bak/dcim\"\nexclude = [\".thumbnails/\", \"*.tmp\"]\ndelete = true\n\
+            set-times = true\n[profile.bad]\ndirection = \"sideways\"\n[profile.half]\n\
+            direction = \"push\"\nsource = \"a\"\n",
+        )
+        .unwrap();
+        let p = c.profile("photos").unwrap();
+        assert_eq!(p.direction, Direction::Pull);
+        assert_eq!(p.dest, PathBuf::from("/bak/dcim"));
+        assert_eq!(p.exclude.len(), 2);
+        assert!(p.delete && p.set_times);
+        assert_eq!(
+            c.profile("nope").unwrap_err(),
+            "No profile 'nope', there are: bad, half, photos"
+        );
+        assert_eq!(
+            c.validate(),
+            [
+                "line 9, [profile.bad] direction: expected \"pull\" or \"push\"",
+                "[profile.half]: missing 'dest'",
+            ]
+        );
+        let bad = Config::parse("[profile.p]\ndelete = yes\n").unwrap();
+        assert_eq!(
+            bad.validate(),
+            ["line 2, [profile.p] delete: expected true or false"]
+        );
+    }
+}
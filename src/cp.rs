@@ -0,0 +1,120 @@
+// `adb-sink cp`: copies a file or directory to or from the device as it is, without scanning
+// or comparing the destination, through the same transfers a sync uses
+
+use crate::args::Direction;
+use crate::fs::{AnyFileSystem, FileMode, FileSystem, LocalFS};
+use crate::{history, human_size, logi, logw, CResult};
+use chainerror::Context;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::time::Instant;
+use typed_path::{Utf8UnixPath as UnixPath, Utf8UnixPathBuf as UnixPathBuf};
+
+// local paths may be relative
+fn unix_path(path: &Path, local: bool) -> CResult<UnixPathBuf> {
+    let path = if local && path.is_relative() {
+        std::env::current_dir().annotate()?.join(path)
+    } else {
+        path.to_path_buf()
+    };
+    Ok(UnixPathBuf::from(path.to_str().unwrap()))
+}
+
+// like `cp -r`: into `dest` when that is a directory, else as `dest`. mtimes are kept and,
+// with `verify`, every copied file is hashed on both ends afterwards
+pub fn cp(
+    device_fs: &mut Box<dyn AnyFileSystem>,
+    direction: Direction,
+    source: &Path,
+    dest: &Path,
+    verify: bool,
+) -> CResult<()> {
+    let mut local_fs: Box<dyn AnyFileSystem> = Box::new(LocalFS);
+    let pull = direction == Direction::Pull;
+    let source = unix_path(source, !pull).annotate()?;
+    let dest = unix_path(dest, pull).annotate()?;
+    let (src_fs, dest_fs) = if pull {
+        (device_fs, &mut local_fs)
+    } else {
+        (&mut local_fs, device_fs)
+    };
+
+    let Some(src_sf) = src_fs.stat(&source).annotate()? else {
+        return Err(format!("Source '{}' does not exist", source).into());
+    };
+    let target = match dest_fs.stat(&dest).annotate()? {
+        Some(d) if d.mode == FileMode::Dir => dest.join(&*src_sf.name),
+        _ => dest,
+    };
+    let (files, empty_dirs) = match src_sf.mode {
+        FileMode::File => (vec![src_sf], Vec::new()),
+        FileMode::Dir => src_fs.get_all_files(&source).annotate()?,
+        FileMode::Symlink => return Err(format!("'{}' is a symlink", source).into()),
+    };
+    let target_of = |from: &UnixPath| match from.strip_prefix(&source) {
+        Ok(rel) if !rel.as_str().is_empty() => target.join(rel),
+        _ => target.clone(),
+    };
+    let mut files: Vec<_> = files
+        .into_iter()
+        .map(|sf| (target_of(&sf.path), sf))
+        .collect();
+    files.sort_unstable_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
+
+    let dirs: BTreeSet<UnixPathBuf> = files
+        .iter()
+        .filter_map(|(to, _)| to.parent().map(UnixPath::to_path_buf))
+        .chain(empty_dirs.iter().map(|d| target_of(&d.path)))
+        .collect();
+    for dir in &dirs {
+        dest_fs.mkdir(dir).annotate()?;
+    }
+
+    let total: u64 = files.iter().map(|(_, sf)| sf.size).sum();
+    let (mut done, start) = (0, Instant::now());
+    let src_transport = src_fs.transport();
+    for (i, (to, sf)) in files.iter().enumerate() {
+        dest_fs
+            .copy_from(src_transport, &sf.path, to, Some(sf.timestamp))
+            .annotate()?;
+        history::count_copies(1, sf.size);
+        done += sf.size;
+        let rate = done as f64 / start.elapsed().as_secs_f64().max(0.001);
+        logi!(
+            "[{}/{}] {}% {}/s {} -> {}",
+            i + 1,
+            files.len(),
+            done * 100 / total.max(1),
+            human_size(rate as u64),
+            sf.path,
+            to
+        );
+    }
+    logi!(
+        "Copied {} files ({}) in {:.1}s",
+        files.len(),
+        human_size(total),
+        start.elapsed().as_secs_f64()
+    );
+
+    if verify {
+        let from: Vec<&UnixPath> = files.iter().map(|(_, sf)| &*sf.path).collect();
+        let to: Vec<&UnixPath> = files.iter().map(|(to, _)| to.as_path()).collect();
+        let want = src_fs.sha256_many(&from).annotate()?;
+        let got = dest_fs.sha256_many(&to).annotate()?;
+        let bad: Vec<_> = to
+            .iter()
+            .zip(want.iter().zip(&got))
+            .filter(|(_, (w, g))| w != g)
+            .map(|(to, _)| to)
+            .collect();
+        for to in &bad {
+            logw!(W005: "DIFFERS: {}", to);
+        }
+        if !bad.is_empty() {
+            return Err(format!("{} copied files differ from their source", bad.len()).into());
+        }
+        logi!("Verified {} files", files.len());
+    }
+    Ok(())
+}
@@ -0,0 +1,298 @@
+// encryption at rest through the `age` command line tool
+
+use crate::adb::{quote, AdbCmd};
+use crate::fs::{
+    hex, unsupported_pair, AndroidFS, AnyFileSystem, FileMode, FileSystem, LocalFS, SyncFile,
+    Transport, Unpack,
+};
+use crate::{is_verbose, logv, CResult};
+use chainerror::Context;
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use typed_path::{Utf8UnixPath as UnixPath, Utf8UnixPathBuf as UnixPathBuf};
+
+// encrypted files are stored as `<name>.age`, other files of an encrypted tree are ignored
+const SUFFIX: &str = ".age";
+
+// the age payload is a 16 byte nonce, then 64 KiB chunks that each carry a 16 byte tag
+const CHUNK: u64 = 64 * 1024;
+const TAG: u64 = 16;
+
+fn age_path(path: &UnixPath) -> UnixPathBuf {
+    UnixPathBuf::from(format!("{path}{SUFFIX}"))
+}
+
+// the `.age` file of `path`, or `path` itself for directories
+fn stored_path(path: &UnixPath) -> UnixPathBuf {
+    let age = age_path(path);
+    if Path::new(age.as_str()).exists() {
+        age
+    } else {
+        path.to_path_buf()
+    }
+}
+
+fn run(cmd: &mut Command) -> CResult<()> {
+    logv!("[AGE] {:?}", cmd);
+    let out = cmd
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .map_err(|e| format!("Could not run `age`, is it installed? {e}"))?;
+    if !out.status.success() {
+        return Err(format!("age: {}", String::from_utf8_lossy(&out.stderr).trim()).into());
+    }
+    Ok(())
+}
+
+fn wait(mut child: Child, what: &str) -> CResult<()> {
+    if !child.wait().annotate()?.success() {
+        return Err(format!("{what} failed").into());
+    }
+    Ok(())
+}
+
+// the plaintext size of an age file of `size` bytes, so that encrypted files compare by size
+// with their source. the header length depends on the recipients and has to be read
+fn plain_size(path: &UnixPath, size: u64) -> CResult<u64> {
+    let mut head = Vec::new();
+    std::fs::File::open(path.as_str())
+        .annotate()?
+        .take(CHUNK)
+        .read_to_end(&mut head)
+        .annotate()?;
+    // the header ends with a `--- <mac>` line
+    let end = head
+        .windows(4)
+        .position(|w| w == b"\n---")
+        .and_then(|mac| Some(mac + 2 + head[mac + 1..].iter().position(|&b| b == b'\n')?));
+    let Some(end) = end else {
+        return Err(format!("'{path}' is not an age file").into());
+    };
+    let payload = size.saturating_sub(end as u64 + TAG);
+    let chunks = payload.div_ceil(CHUNK + TAG).max(1);
+    Ok(payload.saturating_sub(chunks * TAG))
+}
+
+// a local directory whose files are stored encrypted as `<name>.age`. listings show the
+// plaintext names and sizes, so it syncs like a plain tree
+pub struct EncryptedDir {
+    // age or ssh public keys, or files of them
+    recipients: Vec<String>,
+    // needed to read files back, i.e. for hashing
+    identity: Option<PathBuf>,
+}
+
+impl EncryptedDir {
+    pub fn new(recipients: Vec<String>, identity: Option<PathBuf>) -> Self {
+        Self {
+            recipients,
+            identity,
+        }
+    }
+
+    fn plain(&self, sf: SyncFile) -> CResult<Option<SyncFile>> {
+        if sf.mode != FileMode::File {
+            return Ok(Some(sf));
+        }
+        let Some(path) = sf.path.as_str().strip_suffix(SUFFIX) else {
+            logv!("SKIP (NOT ENCRYPTED): {}", sf.path);
+            return Ok(None);
+        };
+        let size = plain_size(&sf.path, sf.size).annotate()?;
+        Ok(Some(SyncFile {
+            size,
+            name: sf.name[..sf.name.len() - SUFFIX.len()].into(),
+            path: UnixPathBuf::from(path).into_boxed_path(),
+            ..sf
+        }))
+    }
+
+    // `age -d` of the encrypted `path`, writing the plaintext to `out` or stdout
+    fn decrypt_cmd(&self, path: &UnixPath, out: Option<&UnixPath>) -> CResult<Command> {
+        let Some(identity) = &self.identity else {
+            return Err("Reading encrypted files needs --decrypt".into());
+        };
+        let mut cmd = Command::new("age");
+        cmd.arg("-d").arg("-i").arg(identity);
+        if let Some(out) = out {
+            cmd.args(["-o", out.as_str()]);
+        }
+        // age stops reading flags at the input file
+        cmd.arg(age_path(path).as_str());
+        Ok(cmd)
+    }
+
+    fn encrypt(
+        &mut self,
+        src: Transport,
+        from: &UnixPath,
+        to: &UnixPath,
+        timestamp: Option<u32>,
+    ) -> CResult<()> {
+        let to = age_path(to);
+        // so an interrupted copy does not leave a truncated file that lists as complete
+        let tmp = format!("{to}.tmp");
+        let mut age = Command::new("age");
+        for r in &self.recipients {
+            age.args([if Path::new(r).is_file() { "-R" } else { "-r" }, r.as_str()]);
+        }
+        age.args(["-o", tmp.as_str()]);
+        let r = match src {
+            Transport::Local => run(age.arg(from.as_str())),
+            Transport::Adb => {
+                // streamed, so the plaintext never touches the destination disk
+                let mut cat = AdbCmd::new()
+                    .args(["exec-out", "cat", quote(from.as_str()).as_str()])
+                    .spawn()
+                    .annotate()?;
+                age.stdin(cat.stdout.take().expect("stdout piped"));
+                run(&mut age).and_then(|()| wait(cat, "adb exec-out cat"))
+            }
+            src => return unsupported_pair(src, Transport::Local),
+        };
+        if let Err(e) = r {
+            let _ = std::fs::remove_file(&tmp);
+            return Err(e);
+        }
+        std::fs::rename(&tmp, to.as_str()).annotate()?;
+        if let Some(timestamp) = timestamp {
+            LocalFS.set_mtime(&to, timestamp).annotate()?;
+        }
+        Ok(())
+    }
+}
+
+impl FileSystem for EncryptedDir {
+    fn mkdir(&mut self, path: &UnixPath) -> CResult<()> {
+        LocalFS.mkdir(path)
+    }
+
+    fn list_dir(&mut self, path: &UnixPath) -> CResult<Vec<SyncFile>> {
+        let mut files = Vec::new();
+        for sf in LocalFS.list_dir(path).annotate()? {
+            files.extend(self.plain(sf).annotate()?);
+        }
+        Ok(files)
+    }
+
+    fn stat(&mut self, path: &UnixPath) -> CResult<Option<SyncFile>> {
+        if let Some(sf) = LocalFS.stat(&age_path(path)).annotate()? {
+            return self.plain(sf);
+        }
+        Ok(LocalFS
+            .stat(path)
+            .annotate()?
+            .filter(|sf| sf.mode == FileMode::Dir))
+    }
+
+    fn rm(&mut self, path: &UnixPath) -> CResult<()> {
+        LocalFS.rm(&age_path(path))
+    }
+
+    fn rm_dir(&mut self, path: &UnixPath) -> CResult<()> {
+        LocalFS.rm_dir(path)
+    }
+
+    fn set_mtime(&mut self, path: &UnixPath, timestamp: u32) -> CResult<()> {
+        LocalFS.set_mtime(&age_path(path), timestamp)
+    }
+
+    fn set_owner(&mut self, path: &UnixPath, uid: Option<u32>, gid: Option<u32>) -> CResult<()> {
+        LocalFS.set_owner(&stored_path(path), uid, gid)
+    }
+
+    fn get_mode(&mut self, path: &UnixPath) -> CResult<u32> {
+        LocalFS.get_mode(&stored_path(path))
+    }
+
+    fn set_mode(&mut self, path: &UnixPath, mode: u32) -> CResult<()> {
+        LocalFS.set_mode(&stored_path(path), mode)
+    }
+
+    fn sha256(&mut self, path: &UnixPath) -> CResult<String> {
+        let mut child = self
+            .decrypt_cmd(path, None)
+            .annotate()?
+            .stdout(Stdio::piped())
+            .spawn()
+            .annotate()?;
+        let mut hasher = Sha256::new();
+        std::io::copy(child.stdout.as_mut().expect("stdout piped"), &mut hasher).annotate()?;
+        wait(child, "age -d").annotate()?;
+        Ok(hex(&hasher.finalize()))
+    }
+}
+
+impl AnyFileSystem for EncryptedDir {
+    fn transport(&self) -> Transport {
+        Transport::Local
+    }
+
+    fn copy_from(
+        &mut self,
+        src: Transport,
+        from: &UnixPath,
+        to: &UnixPath,
+        timestamp: Option<u32>,
+    ) -> CResult<()> {
+        self.encrypt(src, from, to, timestamp)
+    }
+
+    // file by file, a directory pulled as a whole would land unencrypted
+    fn copy_dir_from(
+        &mut self,
+        src: Transport,
+        from: &UnixPath,
+        to: &UnixPath,
+        timestamp: Option<u32>,
+    ) -> CResult<()> {
+        let entries = match src {
+            Transport::Local => LocalFS.list_dir(from).annotate()?,
+            Transport::Adb => AndroidFS::ls(from).annotate()?,
+            src => return unsupported_pair(src, Transport::Local),
+        };
+        self.mkdir(to).annotate()?;
+        for e in entries {
+            let to = to.join(&*e.name);
+            match e.mode {
+                FileMode::File => {
+                    let timestamp = timestamp.map(|_| e.timestamp);
+                    self.encrypt(src, &e.path, &to, timestamp).annotate()?
+                }
+                FileMode::Dir => self
+                    .copy_dir_from(src, &e.path, &to, timestamp)
+                    .annotate()?,
+                FileMode::Symlink => logv!("SKIP (SYMLINK): {}", e.path),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Unpack for EncryptedDir {
+    fn unpack(&mut self, from: &UnixPath, to: &UnixPath) -> CResult<()> {
+        run(&mut self.decrypt_cmd(from, Some(to)).annotate()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plaintext_sizes() {
+        let tmp = std::env::temp_dir().join(format!("adb-sink-age-{}", std::process::id()));
+        let path = UnixPathBuf::from(tmp.to_str().unwrap());
+        let header = b"age-encryption.org/v1\n-> X25519 abc\ndef\n--- mac\n";
+        for (plain, chunks) in [(0, 1), (5, 1), (CHUNK, 1), (CHUNK + 1, 2)] {
+            let mut data = header.to_vec();
+            data.resize(data.len() + (TAG + plain + chunks * TAG) as usize, 0);
+            std::fs::write(&tmp, &data).unwrap();
+            assert_eq!(plain_size(&path, data.len() as u64).unwrap(), plain);
+        }
+        std::fs::remove_file(&tmp).unwrap();
+    }
+}
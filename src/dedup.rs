@@ -0,0 +1,71 @@
+use crate::fs::{FileSystem, SyncFile};
+use crate::CResult;
+use chainerror::Context;
+use std::collections::HashMap;
+use typed_path::{Utf8UnixPath as UnixPath, Utf8UnixPathBuf as UnixPathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DedupMode {
+    // do not copy a file whose content already exists somewhere under the destination
+    Skip,
+    // hard link the existing copy into place instead of transferring it again
+    Hardlink,
+    // copy the existing copy into place on the destination, without transferring it again
+    Copy,
+}
+
+// size -> files index of a destination tree. hashes are only computed when sizes collide
+pub struct DedupIndex {
+    pub mode: DedupMode,
+    by_size: HashMap<u64, Vec<UnixPathBuf>>,
+    hashes: HashMap<UnixPathBuf, String>,
+}
+
+impl DedupIndex {
+    pub fn build<FS: FileSystem>(fs: &mut FS, root: &UnixPath, mode: DedupMode) -> CResult<Self> {
+        let (files, _) = fs.get_all_files(root).annotate()?;
+        let mut by_size: HashMap<u64, Vec<UnixPathBuf>> = HashMap::new();
+        for f in files {
+            by_size
+                .entry(f.size)
+                .or_default()
+                .push(f.path.to_path_buf());
+        }
+        Ok(Self {
+            mode,
+            by_size,
+            hashes: HashMap::new(),
+        })
+    }
+
+    // an existing destination file with the same content as the source file `sf`
+    pub fn find<SRC: FileSystem, DEST: FileSystem>(
+        &mut self,
+        src_fs: &mut SRC,
+        dest_fs: &mut DEST,
+        sf: &SyncFile,
+    ) -> CResult<Option<UnixPathBuf>> {
+        let Some(candidates) = self.by_size.get(&sf.size) else {
+            return Ok(None);
+        };
+        let src_hash = src_fs.sha256(&sf.path).annotate()?;
+        for c in candidates {
+            if !self.hashes.contains_key(c) {
+                let h = dest_fs.sha256(c).annotate()?;
+                self.hashes.insert(c.clone(), h);
+            }
+            if self.hashes[c] == src_hash {
+                return Ok(Some(c.clone()));
+            }
+        }
+        Ok(None)
+    }
+
+    // registers a file that was just written to the destination
+    pub fn add(&mut self, path: &UnixPath, size: u64) {
+        self.by_size
+            .entry(size)
+            .or_default()
+            .push(path.to_path_buf());
+    }
+}
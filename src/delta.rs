@@ -0,0 +1,434 @@
+// `--delta`: a changed file that both sides have is updated by moving only the blocks that
+// differ, for large files that change a little, like an appended log or a remuxed video. the
+// device sums fixed blocks of its copy with `dd`, `cksum` and `sha256sum`, and adb-sink rolls
+// the same CRC over the local copy to find those blocks at any offset, so a few inserted bytes
+// do not turn every following block into a mismatch
+//   pull: the blocks of the device file that the old local file lacks are fetched with `dd`
+//   push: the blocks of the old device file found in the local file stay on the device, the
+//         rest is pushed and the file put together there with `dd`, `tail` and `head`
+// whenever that does not work out, like on a device without `cksum`, the file is copied whole
+
+use crate::adb::{self, quote, AdbCmd};
+use crate::fs::{hex, FileSystem, LocalFS};
+use crate::{heartbeat, human_size, is_verbose, logv, CResult};
+use chainerror::Context;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicBool, Ordering};
+use typed_path::Utf8UnixPath as UnixPath;
+
+const BLOCK: u64 = 1 << 20;
+// below this a whole copy takes about as long as summing the blocks on the device
+const MIN_SIZE: u64 = 16 * BLOCK;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub(crate) fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BlockSum {
+    // what `cksum` prints for the block
+    weak: u32,
+    len: u64,
+    // hex sha256
+    strong: String,
+}
+
+// the CRC of POSIX `cksum`: most significant bit first, starting from 0
+const TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc_byte(crc: u32, b: u8) -> u32 {
+    (crc << 8) ^ TABLE[((crc >> 24) as u8 ^ b) as usize]
+}
+
+// `cksum` of data of `len` bytes whose CRC is `crc`: the length goes in too, low bytes first
+fn cksum(mut crc: u32, mut len: u64) -> u32 {
+    while len > 0 {
+        crc = crc_byte(crc, len as u8);
+        len >>= 8;
+    }
+    !crc
+}
+
+// what each byte value adds to the CRC of a window once `len` more bytes followed it, to take
+// the byte out again when the window slides past it
+fn leaving(len: usize) -> [u32; 256] {
+    let bits: [u32; 8] =
+        std::array::from_fn(|bit| (0..len).fold(crc_byte(0, 1 << bit), |c, _| crc_byte(c, 0)));
+    // the CRC is linear, a byte adds what its bits add
+    std::array::from_fn(|b| {
+        (0..8)
+            .filter(|bit| b >> bit & 1 == 1)
+            .fold(0, |c, bit| c ^ bits[bit])
+    })
+}
+
+// the sums of each `block` bytes of `data`
+fn block_sums(mut data: impl Read, block: usize) -> io::Result<Vec<BlockSum>> {
+    let mut sums = Vec::new();
+    let mut buf = vec![0; block];
+    loop {
+        let mut len = 0;
+        while len < block {
+            match data.read(&mut buf[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        if len == 0 {
+            return Ok(sums);
+        }
+        let b = &buf[..len];
+        sums.push(BlockSum {
+            weak: cksum(b.iter().fold(0, |c, &b| crc_byte(c, b)), len as u64),
+            len: len as u64,
+            strong: hex(&Sha256::digest(b)),
+        });
+    }
+}
+
+// the part of the data the rolling checksum is at
+struct Window<R> {
+    data: R,
+    buf: Vec<u8>,
+    // offset of buf[0] in the data
+    base: u64,
+    at: usize,
+}
+
+impl<R: Read> Window<R> {
+    // makes `n` bytes from `at` on available, false when the data ends before
+    fn fill(&mut self, n: usize) -> io::Result<bool> {
+        // what the window slid past is not needed again
+        if self.at >= n.max(1 << 22) {
+            self.buf.drain(..self.at);
+            self.base += self.at as u64;
+            self.at = 0;
+        }
+        while self.buf.len() < self.at + n {
+            let len = self.buf.len();
+            self.buf.resize(len + n.max(1 << 16), 0);
+            let read = self.data.read(&mut self.buf[len..])?;
+            self.buf.truncate(len + read);
+            if read == 0 {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+// (offset in `data`, index in `sums`) of the whole blocks of `sums` found in `data`, in order
+// and not overlapping
+fn find_blocks(data: impl Read, sums: &[BlockSum], block: usize) -> io::Result<Vec<(u64, usize)>> {
+    let mut by_weak: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (i, s) in sums.iter().enumerate() {
+        if s.len == block as u64 {
+            by_weak.entry(s.weak).or_default().push(i);
+        }
+    }
+    let leaving = leaving(block);
+    let mut w = Window {
+        data,
+        buf: Vec::new(),
+        base: 0,
+        at: 0,
+    };
+    let mut found = Vec::new();
+    'scan: while w.fill(block)? {
+        let mut crc = w.buf[w.at..w.at + block]
+            .iter()
+            .fold(0, |c, &b| crc_byte(c, b));
+        loop {
+            if let Some(candidates) = by_weak.get(&cksum(crc, block as u64)) {
+                let strong = hex(&Sha256::digest(&w.buf[w.at..w.at + block]));
+                if let Some(&i) = candidates.iter().find(|&&i| sums[i].strong == strong) {
+                    found.push((w.base + w.at as u64, i));
+                    w.at += block;
+                    continue 'scan;
+                }
+            }
+            if !w.fill(block + 1)? {
+                break 'scan;
+            }
+            let (out, next) = (w.buf[w.at], w.buf[w.at + block]);
+            crc = crc_byte(crc, next) ^ leaving[out as usize];
+            w.at += 1;
+        }
+    }
+    Ok(found)
+}
+
+// one line per block, `<cksum> <len> <sha256> -`. an empty list for a missing file
+fn parse_sums(out: &str) -> Option<Vec<BlockSum>> {
+    out.lines()
+        .map(|l| {
+            let mut f = l.split_whitespace();
+            Some(BlockSum {
+                weak: f.next()?.parse().ok()?,
+                len: f.next()?.parse().ok()?,
+                strong: f.next().filter(|h| h.len() == 64)?.to_string(),
+            })
+        })
+        .collect()
+}
+
+// the sums of the device file `path`, None when the device could not make them
+fn device_sums(path: &UnixPath) -> Option<Vec<BlockSum>> {
+    let script = format!(
+        "p={}; [ -f \"$p\" ] || exit 0; n=$(( ($(stat -c %s \"$p\") + {BLOCK} - 1) / {BLOCK} )); \
+         i=0; while [ $i -lt $n ]; do \
+         echo \"$(dd if=\"$p\" bs={BLOCK} skip=$i count=1 2>/dev/null | cksum) \
+         $(dd if=\"$p\" bs={BLOCK} skip=$i count=1 2>/dev/null | sha256sum)\"; \
+         i=$((i + 1)); done",
+        quote(path.as_str())
+    );
+    let out = match AdbCmd::run_v(["shell", script.as_str()]) {
+        Ok(out) => out,
+        Err(e) => {
+            logv!("DELTA: could not sum the blocks of '{}': {}", path, e);
+            return None;
+        }
+    };
+    let sums = parse_sums(&out);
+    if sums.is_none() {
+        logv!("DELTA: the device cannot sum blocks: {}", out.trim());
+    }
+    sums
+}
+
+// pulls `from` over the older local copy at `to`. false when there is none worth it, or when
+// the device cannot sum blocks, and the file is to be pulled whole
+pub(crate) fn pull(from: &UnixPath, to: &UnixPath, timestamp: Option<u32>) -> CResult<bool> {
+    match std::fs::metadata(to.as_str()) {
+        Ok(m) if m.len() >= MIN_SIZE => {}
+        _ => return Ok(false),
+    }
+    let Some(sums) = device_sums(from) else {
+        return Ok(false);
+    };
+    let found: HashMap<usize, u64> =
+        find_blocks(File::open(to.as_str()).annotate()?, &sums, BLOCK as usize)
+            .annotate()?
+            .into_iter()
+            .map(|(offset, i)| (i, offset))
+            .collect();
+
+    let part = format!("{to}.adb-sink-part");
+    heartbeat::watch(&part);
+    let mut old = File::open(to.as_str()).annotate()?;
+    let mut out = BufWriter::new(File::create(&part).annotate()?);
+    let mut fetched = 0;
+    let mut i = 0;
+    while i < sums.len() {
+        if let Some(&offset) = found.get(&i) {
+            old.seek(SeekFrom::Start(offset)).annotate()?;
+            io::copy(&mut (&mut old).take(sums[i].len), &mut out).annotate()?;
+            i += 1;
+            continue;
+        }
+        // the blocks up to the next one the local copy has, with one `dd`
+        let run = sums[i..]
+            .iter()
+            .enumerate()
+            .take_while(|(j, _)| !found.contains_key(&(i + j)))
+            .count();
+        let dd = format!(
+            "dd if={} bs={BLOCK} skip={i} count={run} 2>/dev/null",
+            quote(from.as_str())
+        );
+        let mut child = AdbCmd::new()
+            .args(["exec-out", dd.as_str()])
+            .spawn()
+            .annotate()?;
+        let mut stdout = child.stdout.take().expect("stdout piped");
+        fetched += io::copy(&mut stdout, &mut out).annotate()?;
+        child.wait().annotate()?;
+        i += run;
+    }
+    out.into_inner().map_err(|e| e.into_error()).annotate()?;
+
+    // fetched blocks are from the device file as it is now, which may not be what was summed
+    if block_sums(File::open(&part).annotate()?, BLOCK as usize).annotate()? != sums {
+        logv!(
+            "DELTA: '{}' changed while it was pulled, pulling it whole",
+            from
+        );
+        let _ = std::fs::remove_file(&part);
+        return Ok(false);
+    }
+    std::fs::rename(&part, to.as_str()).annotate()?;
+    if let Some(timestamp) = timestamp {
+        LocalFS.set_mtime(to, timestamp).annotate()?;
+    }
+    logv!(
+        "DELTA: {}, fetched {} of {}",
+        to,
+        human_size(fetched),
+        human_size(sums.iter().map(|s| s.len).sum())
+    );
+    Ok(true)
+}
+
+// a piece of the new file
+enum Piece {
+    // `count` blocks of the old device file from block `start` on
+    Old(usize, usize),
+    // `len` bytes from `offset` of the pushed literals
+    New(u64, u64),
+}
+
+// pushes `from` over the older device copy at `to`. false when there is none worth it, or when
+// the device cannot sum blocks, and the file is to be pushed whole
+pub(crate) fn push(from: &UnixPath, to: &UnixPath, timestamp: Option<u32>) -> CResult<bool> {
+    let size = std::fs::metadata(from.as_str()).annotate()?.len();
+    if size < MIN_SIZE {
+        return Ok(false);
+    }
+    let sums = match device_sums(to) {
+        Some(sums) if !sums.is_empty() => sums,
+        _ => return Ok(false),
+    };
+    let found =
+        find_blocks(File::open(from.as_str()).annotate()?, &sums, BLOCK as usize).annotate()?;
+
+    // what the device does not have goes into one file, pushed next to `to`
+    let tmp = std::env::temp_dir().join(format!("adb-sink-delta-{}", std::process::id()));
+    let mut literals = BufWriter::new(File::create(&tmp).annotate()?);
+    let mut src = File::open(from.as_str()).annotate()?;
+    let mut pieces: Vec<Piece> = Vec::new();
+    let (mut pos, mut sent) = (0, 0);
+    let mut new = |pieces: &mut Vec<Piece>, offset: u64, len: u64| -> CResult<()> {
+        src.seek(SeekFrom::Start(offset)).annotate()?;
+        io::copy(&mut (&mut src).take(len), &mut literals).annotate()?;
+        pieces.push(Piece::New(sent, len));
+        sent += len;
+        Ok(())
+    };
+    for (offset, i) in found {
+        if offset > pos {
+            new(&mut pieces, pos, offset - pos).annotate()?;
+        }
+        match pieces.last_mut() {
+            Some(Piece::Old(start, count)) if *start + *count == i => *count += 1,
+            _ => pieces.push(Piece::Old(i, 1)),
+        }
+        pos = offset + BLOCK;
+    }
+    if pos < size {
+        new(&mut pieces, pos, size - pos).annotate()?;
+    }
+    literals
+        .into_inner()
+        .map_err(|e| e.into_error())
+        .annotate()?;
+
+    let hash = LocalFS.sha256(from).annotate()?;
+    let lit = format!("{to}.adb-sink-delta");
+    let mut literals = File::open(&tmp).annotate()?;
+    let pushed = adb::sync::with(|s| s.send(&mut literals, &lit, 0o100600, 0));
+    drop(literals);
+    let _ = std::fs::remove_file(&tmp);
+    pushed.annotate()?;
+
+    let parts: Vec<String> = pieces
+        .iter()
+        .map(|p| match p {
+            Piece::Old(start, count) => {
+                format!("dd if=\"$o\" bs={BLOCK} skip={start} count={count} 2>/dev/null")
+            }
+            Piece::New(offset, len) => format!("tail -c +{} \"$l\" | head -c {len}", offset + 1),
+        })
+        .collect();
+    let touch = match timestamp {
+        Some(t) => format!(" && touch -m -d @{t} \"$o\""),
+        None => String::new(),
+    };
+    // the old copy is only replaced once the new one hashes the same as the local file
+    let script = format!(
+        "o={}; l={}; t=\"$o.adb-sink-part\"; {{ {}; }} > \"$t\"; rm -f \"$l\"; \
+         set -- $(sha256sum \"$t\"); \
+         if [ \"$1\" = {hash} ]; then mv \"$t\" \"$o\"{touch} && echo OK; else rm -f \"$t\"; fi",
+        quote(to.as_str()),
+        quote(&lit),
+        parts.join("; ")
+    );
+    let out = AdbCmd::run_v(["shell", script.as_str()]).annotate()?;
+    if out.trim() != "OK" {
+        logv!("DELTA: putting '{}' together failed, pushing it whole", to);
+        return Ok(false);
+    }
+    logv!(
+        "DELTA: {}, sent {} of {}",
+        to,
+        human_size(sent),
+        human_size(size)
+    );
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delta_blocks() {
+        // what `printf abc | cksum` prints
+        let abc = b"abc".iter().fold(0, |c, &b| crc_byte(c, b));
+        assert_eq!(cksum(abc, 3), 1219131554);
+        assert_eq!(cksum(0, 0), 4294967295);
+
+        let data: Vec<u8> = (0u32..4096)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        let sums = block_sums(&data[..], 64).unwrap();
+        assert_eq!(sums.len(), 64);
+
+        // a few bytes inserted and one changed: everything else is found where it moved to
+        let mut changed = data.clone();
+        changed.splice(100..100, [1, 2, 3]);
+        changed[3000] ^= 0xff;
+        let found = find_blocks(&changed[..], &sums, 64).unwrap();
+        let missing: Vec<usize> = (0..64)
+            .filter(|i| !found.iter().any(|(_, j)| j == i))
+            .collect();
+        assert_eq!(missing, [1, 46]);
+        assert!(found.contains(&(2 * 64 + 3, 2)));
+        for (offset, i) in found {
+            let at = offset as usize;
+            assert_eq!(changed[at..at + 64], data[i * 64..i * 64 + 64]);
+        }
+
+        let out =
+            "1219131554 3 ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad -\n";
+        assert_eq!(parse_sums(out).unwrap()[0].weak, 1219131554);
+        assert_eq!(parse_sums(""), Some(Vec::new()));
+        assert!(parse_sums("cksum: not found").is_none());
+    }
+}
@@ -0,0 +1,120 @@
+// `pull --expire-source DAYS`: camera roll housekeeping. once the pull is done, device files
+// older than DAYS are deleted from the device, but only those whose local copy exists and
+// hashes the same. the files are listed first and nothing goes without a yes
+
+use crate::filter::Filter;
+use crate::fs::{FileMode, FileSystem, SyncFile};
+use crate::tree::build_tree;
+use crate::{history, human_size, is_verbose, logi, logv, logw, CResult};
+use chainerror::Context;
+use std::io::{IsTerminal, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+use typed_path::{Utf8UnixPath as UnixPath, Utf8UnixPathBuf as UnixPathBuf};
+
+pub struct Expire {
+    pub days: u64,
+    // only list what would be deleted
+    pub dry_run: bool,
+    // delete without asking, for unattended runs
+    pub yes: bool,
+}
+
+// returns how many device files were deleted. `local_path` is where `src_path` was pulled to
+pub fn expire_source<DEV: FileSystem, LOCAL: FileSystem>(
+    device_fs: &mut DEV,
+    local_fs: &mut LOCAL,
+    src_path: &UnixPath,
+    local_path: &UnixPath,
+    filter: &Filter,
+    expire: &Expire,
+) -> CResult<usize> {
+    let Some(src_sf) = device_fs.stat(src_path).annotate()? else {
+        return Err(format!("Source '{}' does not exist", src_path).into());
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let cutoff = now.saturating_sub(expire.days * 24 * 3600);
+    let old = |sf: &SyncFile| u64::from(sf.timestamp) < cutoff;
+
+    let candidates: Vec<(SyncFile, UnixPathBuf)> = if src_sf.mode == FileMode::File {
+        [(src_sf, local_path.to_path_buf())]
+            .into_iter()
+            .filter(|(sf, _)| old(sf))
+            .collect()
+    } else {
+        let root = build_tree(device_fs, src_sf, src_path, filter).annotate()?;
+        root.files()
+            .into_iter()
+            .filter(|n| old(&n.sf))
+            .map(|n| (n.sf.clone(), local_path.join(&n.strip_path)))
+            .collect()
+    };
+
+    let mut pulled = Vec::new();
+    for (sf, local) in candidates {
+        match local_fs.stat(&local).annotate()? {
+            Some(l) if l.size == sf.size => pulled.push((sf, local)),
+            Some(_) => logw!(W015: "KEEP (SIZE DIFFERS): {}", sf.path),
+            None => logw!(W015: "KEEP (NOT PULLED): {}", sf.path),
+        }
+    }
+    let on_device: Vec<&UnixPath> = pulled.iter().map(|(sf, _)| &*sf.path).collect();
+    let local: Vec<&UnixPath> = pulled.iter().map(|(_, l)| l.as_path()).collect();
+    let want = device_fs.sha256_many(&on_device).annotate()?;
+    let got = local_fs.sha256_many(&local).annotate()?;
+    let mut expired = Vec::new();
+    for ((sf, _), (w, g)) in pulled.iter().zip(want.iter().zip(&got)) {
+        if w == g {
+            logi!("EXPIRE: {}", sf.path);
+            expired.push(sf);
+        } else {
+            logw!(W005: "KEEP (DIFFERS): {}", sf.path);
+        }
+    }
+    if expired.is_empty() {
+        logi!("No pulled device files are older than {} days", expire.days);
+        return Ok(0);
+    }
+    let size = human_size(expired.iter().map(|sf| sf.size).sum());
+    logi!(
+        "{} device files ({}) older than {} days have an intact local copy",
+        expired.len(),
+        size,
+        expire.days
+    );
+    if expire.dry_run {
+        logi!("Dry run, nothing was deleted from the device");
+        return Ok(0);
+    }
+    if !expire.yes && !confirm(&format!("Delete them ({size}) from the device?")).annotate()? {
+        logi!("Deleted nothing from the device");
+        return Ok(0);
+    }
+    // only what the device really deleted is counted
+    let mut deleted = 0;
+    for sf in &expired {
+        logv!("DEL FILE: '{}'", sf.path);
+        match device_fs.rm(&sf.path) {
+            Ok(()) => {
+                history::count_delete();
+                deleted += 1;
+            }
+            Err(e) => logw!(W015: "KEEP (NOT DELETED): {}: {}", sf.path, e),
+        }
+    }
+    logi!("Deleted {} expired files from the device", deleted);
+    Ok(deleted)
+}
+
+fn confirm(question: &str) -> CResult<bool> {
+    if !std::io::stdin().is_terminal() {
+        return Err("--expire-source asks before deleting, pass --yes to run it unattended".into());
+    }
+    print!("{question} [y/N]: ");
+    std::io::stdout().flush().annotate()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).annotate()?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
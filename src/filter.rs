@@ -0,0 +1,349 @@
+use regex::Regex;
+use std::str::FromStr;
+
+// one rsync style filter rule: `+ pattern` includes, `- pattern` excludes.
+// a leading `/` anchors the pattern at the root of the transfer, a trailing `/` only matches
+// directories. without a `/` (or `**`) the pattern matches a path of any depth.
+// `*` matches within a path component, `**` across components, `dir/***` a directory and
+// everything in it
+#[derive(Debug, Clone)]
+pub struct FilterRule {
+    include: bool,
+    dir_only: bool,
+    // only --include-ext and --exclude-ext rules, which never match directories
+    file_only: bool,
+    re: Regex,
+}
+
+impl FromStr for FilterRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (include, pattern) = match s.split_once(' ') {
+            Some(("+", p)) => (true, p),
+            Some(("-", p)) => (false, p),
+            _ => {
+                return Err(format!(
+                    "filter rule must look like '+ pattern' or '- pattern': '{s}'"
+                ))
+            }
+        };
+        if pattern.is_empty() {
+            return Err(format!("empty filter pattern: '{s}'"));
+        }
+        let (pattern, dir_only) = match pattern.strip_suffix('/') {
+            Some(p) if !p.ends_with("/***") => (p, true),
+            _ => (pattern, false),
+        };
+        let (pattern, anchored) = match pattern.strip_prefix('/') {
+            Some(p) => (p, true),
+            None => (pattern, false),
+        };
+        let (pattern, with_contents) = match pattern.strip_suffix("/***") {
+            Some(p) => (p, true),
+            None => (pattern, false),
+        };
+
+        let mut re = String::from(if anchored { "^" } else { "(^|/)" });
+        re.push_str(&glob_to_regex(pattern));
+        if with_contents {
+            re.push_str("(/.*)?");
+        }
+        re.push('$');
+        Ok(Self {
+            include,
+            dir_only,
+            file_only: false,
+            re: Regex::new(&re).map_err(|e| e.to_string())?,
+        })
+    }
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let mut re = String::with_capacity(glob.len() * 2);
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                // `**/` may also match no directory at all
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    re.push_str("(.*/)?");
+                } else {
+                    re.push_str(".*");
+                }
+            }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            '[' => {
+                re.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    re.push('^');
+                }
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                    if c == '\\' || c == '[' {
+                        re.push('\\');
+                    }
+                    re.push(c);
+                }
+                re.push(']');
+            }
+            '\\' => {
+                if let Some(c) = chars.next() {
+                    re.push_str(&regex::escape(&c.to_string()));
+                }
+            }
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re
+}
+
+impl FilterRule {
+    // excludes `path` (relative to the transfer root) for `--ignore-dir`. a path with glob
+    // characters is matched like an anchored rule, anything else literally
+    pub fn exclude_path(path: &str) -> Result<Self, String> {
+        let path = path.trim_matches('/');
+        let re = if path.contains(['*', '?', '[']) {
+            glob_to_regex(path)
+        } else {
+            regex::escape(path)
+        };
+        Ok(Self {
+            include: false,
+            dir_only: false,
+            file_only: false,
+            re: Regex::new(&format!("^{re}$")).map_err(|e| e.to_string())?,
+        })
+    }
+
+    // `--exclude`: a glob matched like the pattern of a `- pattern` rule, so anywhere in the
+    // tree unless it starts with `/`
+    pub fn exclude(glob: &str) -> Result<Self, String> {
+        if glob.is_empty() {
+            return Err("empty pattern".to_string());
+        }
+        format!("- {glob}").parse()
+    }
+
+    // files ending in `.ext`, in any case. `ext` may have more dots, like `tar.gz`
+    fn extension(ext: &str, include: bool) -> Result<Self, String> {
+        let bare = ext.trim().trim_start_matches('.');
+        if bare.is_empty() || bare.contains('/') {
+            return Err(format!("bad extension '{ext}'"));
+        }
+        Ok(Self {
+            include,
+            dir_only: false,
+            file_only: true,
+            re: Regex::new(&format!("(?i)\\.{}$", regex::escape(bare)))
+                .map_err(|e| e.to_string())?,
+        })
+    }
+
+    pub fn exclude_ext(ext: &str) -> Result<Self, String> {
+        Self::extension(ext, false)
+    }
+
+    pub fn include_ext(ext: &str) -> Result<Self, String> {
+        Self::extension(ext, true)
+    }
+
+    // excludes every file, after the --include-ext rules
+    pub fn other_files() -> Self {
+        Self {
+            include: false,
+            dir_only: false,
+            file_only: true,
+            re: Regex::new("").expect("valid pattern"),
+        }
+    }
+
+    // a bare pattern for --first and --last, matched like the pattern of a filter rule
+    pub fn pattern(pattern: &str) -> Result<Self, String> {
+        if pattern.is_empty() {
+            return Err("empty pattern".to_string());
+        }
+        format!("+ {pattern}").parse()
+    }
+
+    // the same rule, matching regardless of case
+    pub fn ignore_case(self) -> Self {
+        Self {
+            re: Regex::new(&format!("(?i){}", self.re.as_str())).expect("valid before"),
+            ..self
+        }
+    }
+
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        (is_dir || !self.dir_only) && !(is_dir && self.file_only) && self.re.is_match(path)
+    }
+}
+
+// ordered rule list, the first matching rule decides. paths no rule matches are included
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub rules: Vec<FilterRule>,
+    // --exclude-if-present: the contents of directories with a file of one of these names,
+    // like CACHEDIR.TAG, are left out
+    pub markers: Vec<String>,
+    // when not empty, only these paths relative to the root and the directories leading to
+    // them are in the transfer, for `push --watch` syncing what changed
+    pub only: Vec<String>,
+}
+
+impl Filter {
+    // `path` is relative to the root of the transfer
+    pub fn is_excluded(&self, path: &str, is_dir: bool) -> bool {
+        let in_scope = |o: &String| {
+            let under = |a: &str, b: &str| a.strip_prefix(b).is_some_and(|r| r.starts_with('/'));
+            path == o || under(path, o) || (is_dir && under(o, path))
+        };
+        if !self.only.is_empty() && !self.only.iter().any(in_scope) {
+            return true;
+        }
+        self.rules
+            .iter()
+            .find(|r| r.matches(path, is_dir))
+            .is_some_and(|r| !r.include)
+    }
+
+    pub fn is_marker(&self, name: &str) -> bool {
+        self.markers.iter().any(|m| m == name)
+    }
+}
+
+// --first and --last patterns. files are copied in the order of the first pattern they
+// match: those matching a --first pattern before the rest, those matching a --last pattern
+// after it, patterns in the order they were given
+#[derive(Debug, Clone, Default)]
+pub struct Priority {
+    pub first: Vec<FilterRule>,
+    pub last: Vec<FilterRule>,
+}
+
+impl Priority {
+    pub fn is_empty(&self) -> bool {
+        self.first.is_empty() && self.last.is_empty()
+    }
+
+    // sort key of the file `path`, relative to the root of the transfer
+    pub fn rank(&self, path: &str) -> usize {
+        if let Some(i) = self.first.iter().position(|r| r.matches(path, false)) {
+            return i;
+        }
+        match self.last.iter().position(|r| r.matches(path, false)) {
+            Some(i) => self.first.len() + 1 + i,
+            None => self.first.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(rules: &[&str]) -> Filter {
+        Filter {
+            rules: rules.iter().map(|r| r.parse().unwrap()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn filter_rules() {
+        let f = filter(&["+ keep.tmp", "- *.tmp", "- /cache/", "- .thumbnails/***"]);
+        assert!(f.is_excluded("a.tmp", false));
+        assert!(f.is_excluded("dir/b.tmp", false));
+        assert!(!f.is_excluded("dir/keep.tmp", false));
+        assert!(f.is_excluded("cache", true));
+        assert!(!f.is_excluded("cache", false));
+        assert!(!f.is_excluded("sub/cache", true));
+        assert!(f.is_excluded("DCIM/.thumbnails", true));
+        assert!(f.is_excluded("DCIM/.thumbnails/1.jpg", false));
+        assert!(!f.is_excluded("DCIM/a.jpg", false));
+
+        let f = filter(&["- /DCIM/**/*.mp4", "- Camera/[!I]*"]);
+        assert!(f.is_excluded("DCIM/a/b/c.mp4", false));
+        assert!(!f.is_excluded("Movies/c.mp4", false));
+        assert!(f.is_excluded("DCIM/Camera/VID_1.jpg", false));
+        assert!(!f.is_excluded("DCIM/Camera/IMG_1.jpg", false));
+
+        assert!("x pattern".parse::<FilterRule>().is_err());
+        assert!(FilterRule::exclude_path("a/b")
+            .unwrap()
+            .matches("a/b", true));
+        let f = Filter {
+            rules: vec![
+                FilterRule::exclude_path("Android/data/*/cache").unwrap(),
+                FilterRule::exclude_path("**/node_modules").unwrap(),
+            ],
+            ..Default::default()
+        };
+        assert!(f.is_excluded("Android/data/com.app/cache", true));
+        assert!(!f.is_excluded("Android/data/com.app/files", true));
+        assert!(f.is_excluded("node_modules", true));
+        assert!(f.is_excluded("src/web/node_modules", true));
+        let f = Filter {
+            rules: vec![
+                FilterRule::exclude("*.log").unwrap(),
+                FilterRule::exclude("build/").unwrap(),
+            ],
+            ..Default::default()
+        };
+        assert!(f.is_excluded("a/b/debug.log", false));
+        assert!(f.is_excluded("app/build", true));
+        assert!(!f.is_excluded("app/build", false));
+        assert!(FilterRule::exclude("").is_err());
+
+        let p = Priority {
+            first: vec![
+                FilterRule::pattern("/Documents/**").unwrap(),
+                FilterRule::pattern("*.kdbx").unwrap(),
+            ],
+            last: vec![FilterRule::pattern("/Movies/**").unwrap()],
+        };
+        assert_eq!(p.rank("Documents/a/b.pdf"), 0);
+        assert_eq!(p.rank("Download/keys.kdbx"), 1);
+        assert_eq!(p.rank("DCIM/a.jpg"), 2);
+        assert_eq!(p.rank("Movies/a.mp4"), 3);
+        assert!(FilterRule::pattern("").is_err());
+
+        let f = Filter {
+            rules: vec![
+                FilterRule::exclude_ext("mp4").unwrap(),
+                FilterRule::include_ext(".tar.gz").unwrap(),
+                FilterRule::include_ext("pdf").unwrap(),
+                FilterRule::other_files(),
+            ],
+            ..Default::default()
+        };
+        assert!(f.is_excluded("Movies/a.MP4", false));
+        assert!(!f.is_excluded("Movies/a.mp4", true));
+        assert!(!f.is_excluded("Documents/b.pdf", false));
+        assert!(!f.is_excluded("src.tar.gz", false));
+        assert!(f.is_excluded("Documents/pdf", false));
+        assert!(!f.is_excluded("Documents", true));
+        assert!(FilterRule::exclude_ext(".").is_err());
+
+        let rule: FilterRule = "- /Download/".parse().unwrap();
+        assert!(!rule.matches("download", true));
+        assert!(rule.ignore_case().matches("download", true));
+
+        let f = Filter {
+            only: vec!["img/icons".to_string()],
+            ..filter(&["- *.tmp"])
+        };
+        assert!(!f.is_excluded("img", true));
+        assert!(!f.is_excluded("img/icons/a.png", false));
+        assert!(f.is_excluded("img/icons/a.tmp", false));
+        assert!(f.is_excluded("img/logo.png", false));
+        assert!(f.is_excluded("img/icons2", true));
+        assert!(f.is_excluded("img", false));
+    }
+}
@@ -0,0 +1,317 @@
+// file transfers over a TCP connection through `adb forward`, to a shell helper that toybox's
+// `nc` serves on the device. a connection costs no new adb process and no sync handshake, which
+// dominates the transfer time of many small files
+
+use crate::adb::AdbCmd;
+use crate::fs::{check_device_writable, FileSystem, LocalFS};
+use crate::{is_verbose, logi, logv, logw, CResult};
+use chainerror::Context;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpStream};
+use std::process::{Child, ChildStdout};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use typed_path::{Utf8UnixPath as UnixPath, Utf8UnixPathBuf as UnixPathBuf};
+
+// local port of the forward, set while a `Forward` is running
+static PORT: OnceLock<u16> = OnceLock::new();
+// the secret every request starts with, see `Forward::start`
+static TOKEN: OnceLock<String> = OnceLock::new();
+// set when files are striped over several connections
+static CHANNELS: OnceLock<Channels> = OnceLock::new();
+
+const HELPER_PATH: &str = "/data/local/tmp/adb-sink-forward.sh";
+
+// one request per connection, `<token> <request>`, answered with `OK ...` or `ERR <reason>`:
+//   PING
+//   GET <path>               -> OK <size>, then the file
+//   PUT <mtime or -> <path>  <- the file until the client shuts down its side -> OK
+// the token comes in $ADB_SINK_TOKEN, which other apps cannot read
+const HELPER: &str = r#"exec 2>/dev/null
+read -r token op rest
+[ -n "$ADB_SINK_TOKEN" ] && [ "$token" = "$ADB_SINK_TOKEN" ] || { echo "ERR bad token"; exit; }
+case "$op" in
+PING) echo OK ;;
+GET)
+  [ -f "$rest" ] || { echo "ERR no such file"; exit; }
+  echo "OK $(stat -c %s "$rest")"
+  exec cat "$rest" ;;
+PUT)
+  mtime=${rest%% *}; path=${rest#* }
+  mkdir -p "${path%/*}" && cat > "$path.adb-sink-part" && mv "$path.adb-sink-part" "$path" &&
+    { [ "$mtime" = - ] || touch -m -d "@$mtime" "$path"; } && echo OK ||
+    { rm -f "$path.adb-sink-part"; echo "ERR could not write $path"; } ;;
+*) echo "ERR unknown request" ;;
+esac
+"#;
+
+// the port of the running forward, if any
+pub fn port() -> Option<u16> {
+    PORT.get().copied()
+}
+
+fn connect(path: &UnixPath, request: &str) -> CResult<TcpStream> {
+    if path.as_str().contains('\n') {
+        return Err(format!("'{path}' has a newline in its name, it cannot be forwarded").into());
+    }
+    let port = port().expect("forward running");
+    let mut s = TcpStream::connect(("127.0.0.1", port)).annotate()?;
+    s.write_all(format!("{} {request}\n", token()).as_bytes())
+        .annotate()?;
+    Ok(s)
+}
+
+fn token() -> &'static str {
+    TOKEN.get_or_init(|| {
+        // the std hasher's keys are random per process
+        let part = |i: u64| {
+            let mut h = RandomState::new().build_hasher();
+            h.write_u64(i);
+            format!("{:016x}", h.finish())
+        };
+        format!("{}{}", part(0), part(1))
+    })
+}
+
+fn status(r: &mut impl BufRead) -> CResult<String> {
+    let mut line = String::new();
+    r.read_line(&mut line).annotate()?;
+    match line.trim_end().split_once(' ') {
+        _ if line.trim_end() == "OK" => Ok(String::new()),
+        Some(("OK", rest)) => Ok(rest.to_string()),
+        Some(("ERR", reason)) => Err(reason.to_string().into()),
+        // the helper is gone, adb still accepts the connection and closes it
+        _ => Err("the forward helper on the device did not answer".into()),
+    }
+}
+
+fn ping(port: u16) -> CResult<()> {
+    let mut s = TcpStream::connect(("127.0.0.1", port)).annotate()?;
+    s.write_all(format!("{} PING\n", token()).as_bytes())
+        .annotate()?;
+    status(&mut BufReader::new(s)).annotate()?;
+    Ok(())
+}
+
+enum Job {
+    Pull(UnixPathBuf, UnixPathBuf, Option<u32>),
+    Push(UnixPathBuf, UnixPathBuf, Option<u32>),
+}
+
+// a queue of transfers worked off by one thread per connection. a single stream rarely fills
+// USB 3 or fast WiFi, least of all with many small files
+struct Channels {
+    queue: Mutex<SyncSender<Job>>,
+    // transfers queued and not done yet
+    pending: Mutex<usize>,
+    done: Condvar,
+    // the first failure, reported by the next `queue` or `flush`
+    error: Mutex<Option<String>>,
+}
+
+impl Channels {
+    fn start(n: usize) -> &'static Self {
+        // a short queue, so a failure stops the run soon after it happened
+        let (tx, rx) = sync_channel(n);
+        let channels = CHANNELS.get_or_init(|| Channels {
+            queue: Mutex::new(tx),
+            pending: Mutex::new(0),
+            done: Condvar::new(),
+            error: Mutex::new(None),
+        });
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..n {
+            let rx: Arc<Mutex<Receiver<Job>>> = rx.clone();
+            std::thread::spawn(move || loop {
+                let job = rx.lock().unwrap().recv();
+                let Ok(job) = job else {
+                    return;
+                };
+                let r = match &job {
+                    Job::Pull(from, to, ts) => get(from, to, *ts),
+                    Job::Push(from, to, ts) => put(from, to, *ts),
+                };
+                if let Err(e) = r {
+                    channels.error.lock().unwrap().get_or_insert(e.to_string());
+                }
+                *channels.pending.lock().unwrap() -= 1;
+                channels.done.notify_all();
+            });
+        }
+        channels
+    }
+
+    fn queue(&self, job: Job) -> CResult<()> {
+        if let Some(e) = self.error.lock().unwrap().take() {
+            return Err(e.into());
+        }
+        *self.pending.lock().unwrap() += 1;
+        self.queue.lock().unwrap().send(job).annotate()?;
+        Ok(())
+    }
+}
+
+// waits for the queued transfers, so that their files can be read or changed
+pub fn flush() -> CResult<()> {
+    let Some(channels) = CHANNELS.get() else {
+        return Ok(());
+    };
+    let mut pending = channels.pending.lock().unwrap();
+    while *pending > 0 {
+        pending = channels.done.wait(pending).unwrap();
+    }
+    match channels.error.lock().unwrap().take() {
+        Some(e) => Err(e.into()),
+        None => Ok(()),
+    }
+}
+
+// queued when striping over several connections, call `flush` before using the file
+pub fn pull(from: &UnixPath, to: &UnixPath, timestamp: Option<u32>) -> CResult<()> {
+    match CHANNELS.get() {
+        Some(c) => c.queue(Job::Pull(from.to_path_buf(), to.to_path_buf(), timestamp)),
+        None => get(from, to, timestamp),
+    }
+}
+
+// queued when striping over several connections, call `flush` before using the file
+pub fn push(from: &UnixPath, to: &UnixPath, timestamp: Option<u32>) -> CResult<()> {
+    match CHANNELS.get() {
+        Some(c) => c.queue(Job::Push(from.to_path_buf(), to.to_path_buf(), timestamp)),
+        None => put(from, to, timestamp),
+    }
+}
+
+fn get(from: &UnixPath, to: &UnixPath, timestamp: Option<u32>) -> CResult<()> {
+    logv!("[FORWARD] GET {}", from);
+    let mut r = BufReader::new(connect(from, &format!("GET {from}")).annotate()?);
+    let size: u64 = status(&mut r)
+        .annotate()?
+        .parse()
+        .map_err(|_| format!("bad size from the forward helper for '{from}'"))?;
+    if let Some(parent) = std::path::Path::new(to.as_str()).parent() {
+        std::fs::create_dir_all(parent).annotate()?;
+    }
+    let mut out = std::fs::File::create(to.as_str()).annotate()?;
+    let n = std::io::copy(&mut r, &mut out).annotate()?;
+    if n != size {
+        drop(out);
+        let _ = std::fs::remove_file(to.as_str());
+        return Err(format!("'{from}' changed size while it was being pulled").into());
+    }
+    drop(out);
+    if let Some(timestamp) = timestamp {
+        LocalFS.set_mtime(to, timestamp).annotate()?;
+    }
+    Ok(())
+}
+
+fn put(from: &UnixPath, to: &UnixPath, timestamp: Option<u32>) -> CResult<()> {
+    logv!("[FORWARD] PUT {}", to);
+    check_device_writable("push", to).annotate()?;
+    let mtime = timestamp.map_or("-".to_string(), |t| t.to_string());
+    let mut s = connect(to, &format!("PUT {mtime} {to}")).annotate()?;
+    std::io::copy(&mut std::fs::File::open(from.as_str()).annotate()?, &mut s).annotate()?;
+    s.shutdown(Shutdown::Write).annotate()?;
+    status(&mut BufReader::new(s)).annotate()?;
+    Ok(())
+}
+
+// the helper listening on the device and the forward to it, both removed when dropped
+pub struct Forward {
+    local: u16,
+    // pid of the device side `nc`
+    pid: String,
+    shell: Child,
+    // kept open, so the shell does not die of a closed stdout
+    _out: BufReader<ChildStdout>,
+}
+
+impl Forward {
+    // `channels` connections at a time
+    pub fn start(channels: usize) -> CResult<Self> {
+        check_device_writable("push the forward helper to", UnixPath::new(HELPER_PATH))
+            .annotate()?;
+        let tmp = std::env::temp_dir().join(format!("adb-sink-forward-{}.sh", std::process::id()));
+        std::fs::write(&tmp, HELPER).annotate()?;
+        let pushed = AdbCmd::run_v(["push", tmp.to_str().unwrap(), HELPER_PATH]);
+        let _ = std::fs::remove_file(&tmp);
+        pushed.annotate()?;
+
+        // several runs against one device each get their own port
+        let device_port = 27183 + (std::process::id() % 1000) as u16;
+        let remote = format!("tcp:{device_port}");
+        let local = AdbCmd::run_v(["forward", "tcp:0", remote.as_str()])
+            .annotate()?
+            .trim()
+            .parse()
+            .map_err(|_| "adb forward did not give a port")?;
+        // loopback is still open to every app on the device, so the helper only answers
+        // requests with this run's token. it goes in the environment rather than on the
+        // command line of the long-running `nc`, and only the shell user can read that
+        let serve = format!(
+            "echo $$; ADB_SINK_TOKEN={} exec toybox nc -s 127.0.0.1 -p {device_port} -L sh {HELPER_PATH}",
+            token()
+        );
+        let mut shell = AdbCmd::new()
+            .args(["shell", "-n", serve.as_str()])
+            .spawn()
+            .annotate()?;
+        let mut out = BufReader::new(shell.stdout.take().expect("stdout piped"));
+        let mut pid = String::new();
+        out.read_line(&mut pid).annotate()?;
+        let forward = Self {
+            local,
+            pid: pid.trim().to_string(),
+            shell,
+            _out: out,
+        };
+        let _ = PORT.set(local);
+
+        // adb accepts connections to the forward before anything listens on the device
+        let start = Instant::now();
+        loop {
+            match ping(local) {
+                Ok(_) => break,
+                Err(_) if start.elapsed() < Duration::from_secs(5) => {
+                    std::thread::sleep(Duration::from_millis(100))
+                }
+                Err(e) => {
+                    return Err(format!(
+                        "The forward helper did not start, does the device have toybox nc? {e}"
+                    )
+                    .into())
+                }
+            }
+        }
+        if channels > 1 {
+            Channels::start(channels);
+            logi!(
+                "Transferring through adb forward on port {} over {} channels",
+                local,
+                channels
+            );
+        } else {
+            logi!("Transferring through adb forward on port {}", local);
+        }
+        Ok(forward)
+    }
+}
+
+impl Drop for Forward {
+    fn drop(&mut self) {
+        if !self.pid.is_empty() {
+            let _ = AdbCmd::run_v(["shell", "kill", self.pid.as_str()]);
+        }
+        let _ = self.shell.kill();
+        let _ = self.shell.wait();
+        let local = format!("tcp:{}", self.local);
+        if let Err(e) = AdbCmd::run_v(["forward", "--remove", local.as_str()]) {
+            logw!(W013: "Could not remove the adb forward {}: {}", local, e);
+        }
+        let _ = AdbCmd::run_v(["shell", "rm", "-f", HELPER_PATH]);
+    }
+}
@@ -1,14 +1,22 @@
-use crate::adb::AdbCmd;
-use crate::adb::AdbShell;
-use crate::logw;
+use crate::adb::{self, quote, AdbCmd, AdbErr, AdbShell};
+use crate::tools::DeviceTools;
 use crate::CResult;
+use crate::{delta, forward, heartbeat, scoped, utc};
+use crate::{is_verbose, logv, logw};
 use chainerror::Context;
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
 use std::{
+    collections::HashMap,
+    error::Error,
     fmt::Debug,
     fs::File,
+    io::{BufWriter, Read, Seek, SeekFrom},
+    sync::atomic::{AtomicBool, Ordering},
+    sync::OnceLock,
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
-use typed_path::Utf8UnixPath as UnixPath;
+use typed_path::{Utf8UnixPath as UnixPath, Utf8UnixPathBuf as UnixPathBuf};
 
 #[cfg(target_os = "linux")]
 use std::os::unix::fs::MetadataExt;
@@ -18,9 +26,62 @@ use std::os::windows::fs::MetadataExt;
 pub trait FileSystem {
     fn mkdir(&mut self, path: &UnixPath) -> CResult<()>;
     fn list_dir(&mut self, path: &UnixPath) -> CResult<Vec<SyncFile>>;
+    // returns None if the path does not exist
+    fn stat(&mut self, path: &UnixPath) -> CResult<Option<SyncFile>>;
+    // results are in the same order as `paths`
+    fn stat_many(&mut self, paths: &[&UnixPath]) -> CResult<Vec<Option<SyncFile>>> {
+        paths.iter().map(|p| self.stat(p)).collect()
+    }
     fn rm(&mut self, path: &UnixPath) -> CResult<()>;
     fn rm_dir(&mut self, path: &UnixPath) -> CResult<()>;
     fn set_mtime(&mut self, path: &UnixPath, timestamp: u32) -> CResult<()>;
+    // hex sha256 of the file content
+    fn sha256(&mut self, _path: &UnixPath) -> CResult<String> {
+        Err("hashing is not supported by this file system".into())
+    }
+    // hex sha256 of the first `bytes` of the file followed by the last `bytes` of the rest,
+    // `size` being the file size
+    fn sha256_ends(&mut self, _path: &UnixPath, _size: u64, _bytes: u64) -> CResult<String> {
+        Err("hashing is not supported by this file system".into())
+    }
+    // results are in the same order as `paths`
+    fn sha256_many(&mut self, paths: &[&UnixPath]) -> CResult<Vec<String>> {
+        paths.iter().map(|p| self.sha256(p)).collect()
+    }
+    // copies a file to another place on the same file system
+    fn copy_within(&mut self, _from: &UnixPath, _to: &UnixPath) -> CResult<()> {
+        Err("copying within is not supported by this file system".into())
+    }
+    fn hard_link(&mut self, _existing: &UnixPath, _new: &UnixPath) -> CResult<()> {
+        Err("hard links are not supported by this file system".into())
+    }
+    // (device, inode) of each of `paths` that has more than one link, in the same order.
+    // file systems that know nothing about links have none
+    fn link_ids(&mut self, paths: &[&UnixPath]) -> CResult<Vec<Option<(u64, u64)>>> {
+        Ok(vec![None; paths.len()])
+    }
+    // `user.*` extended attributes as (name, value) pairs
+    fn get_xattrs(&mut self, _path: &UnixPath) -> CResult<Vec<(String, Vec<u8>)>> {
+        Err("extended attributes are not supported by this file system".into())
+    }
+    fn set_xattrs(&mut self, _path: &UnixPath, _attrs: &[(String, Vec<u8>)]) -> CResult<()> {
+        Err("extended attributes are not supported by this file system".into())
+    }
+    // (uid, gid)
+    fn get_owner(&mut self, _path: &UnixPath) -> CResult<(u32, u32)> {
+        Err("owners are not supported by this file system".into())
+    }
+    // `None` leaves the uid or gid as it is
+    fn set_owner(&mut self, _path: &UnixPath, _uid: Option<u32>, _gid: Option<u32>) -> CResult<()> {
+        Err("owners are not supported by this file system".into())
+    }
+    // permission bits
+    fn get_mode(&mut self, _path: &UnixPath) -> CResult<u32> {
+        Err("permissions are not supported by this file system".into())
+    }
+    fn set_mode(&mut self, _path: &UnixPath, _mode: u32) -> CResult<()> {
+        Err("permissions are not supported by this file system".into())
+    }
     fn get_all_files(&mut self, path: &UnixPath) -> CResult<(Vec<SyncFile>, Vec<SyncFile>)> {
         let mut fs = self.list_dir(path).annotate()?;
         let mut ffs = Vec::with_capacity(fs.len());
@@ -36,15 +97,147 @@ pub trait FileSystem {
                         dirs.push(f);
                     }
                 }
-                FileMode::Symlink => unreachable!("symlink: '{:?}'", f),
+                FileMode::Symlink => logw!(W002: "Skipping symlink {}", f.path),
             }
         }
         Ok((ffs, dirs))
     }
 }
 
+const STAT_MANY_CHUNK: usize = 256;
+
+// hex sha256 of local files on all cores, in the same order as `paths`. a slow disk is still
+// the limit, but an SSD or a page cache full of a backup is hashed several times faster
+pub fn sha256_local(paths: &[&UnixPath]) -> Vec<CResult<String>> {
+    paths.par_iter().map(|p| LocalFS.sha256(p)).collect()
+}
+
+// set by --no-write-device for the whole run, every AndroidFS operation that would modify the
+// device then fails instead
+static NO_WRITE_DEVICE: AtomicBool = AtomicBool::new(false);
+
+pub fn forbid_device_writes() {
+    NO_WRITE_DEVICE.store(true, Ordering::Relaxed);
+}
+
+pub fn check_device_writable(what: &str, path: &UnixPath) -> CResult<()> {
+    if NO_WRITE_DEVICE.load(Ordering::Relaxed) {
+        return Err(format!("--no-write-device: refusing to {what} '{path}' on the device").into());
+    }
+    Ok(())
+}
+
+// set by --file-timeout for the whole run: a pull that writes nothing for this long is given up
+static FILE_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+
+pub fn set_file_timeout(timeout: Duration) {
+    let _ = FILE_TIMEOUT.set(timeout);
+}
+
+pub(crate) fn file_timeout() -> Option<Duration> {
+    FILE_TIMEOUT.get().copied()
+}
+
+// whether `e` comes from a transfer given up by --file-timeout
+pub(crate) fn is_stalled(e: &(dyn Error + 'static)) -> bool {
+    let mut e = Some(e);
+    while let Some(err) = e {
+        let io = match err.downcast_ref::<AdbErr>() {
+            Some(AdbErr::IO(io)) => Some(io),
+            _ => err.downcast_ref::<std::io::Error>(),
+        };
+        if io.is_some_and(|io| io.kind() == std::io::ErrorKind::TimedOut) {
+            return true;
+        }
+        e = err.source();
+    }
+    false
+}
+
 pub struct AndroidFS {
     pub shell: AdbShell,
+    // `adb push -z` algorithm
+    pub push_compression: Option<&'static str>,
+    // the shell commands that work on this device
+    pub tools: DeviceTools,
+}
+
+impl AndroidFS {
+    fn adb_push(&mut self, from: &UnixPath, to: &UnixPath, timestamp: Option<u32>) -> CResult<()> {
+        check_device_writable("push", to).annotate()?;
+        let mut cmd = AdbCmd::new();
+        cmd.arg("push");
+        if let Some(algo) = self.push_compression {
+            cmd.args(["-z", algo]);
+        }
+        cmd.args([from.as_str(), to.as_str()]);
+        let _op = cmd.output().annotate()?;
+        if let Some(timestamp) = timestamp {
+            self.set_mtime(to, timestamp).annotate()?;
+        }
+        Ok(())
+    }
+
+    // a file through the sync service instead of an adb process. only the adb client can
+    // compress, `adb push -z` still goes through it
+    fn sync_push(&mut self, from: &UnixPath, to: &UnixPath, timestamp: Option<u32>) -> CResult<()> {
+        check_device_writable("push", to).annotate()?;
+        logv!("[SYNC] SEND {}", to);
+        let mut file = File::open(from.as_str()).annotate()?;
+        let meta = file.metadata().annotate()?;
+        // like `adb push`, the device file gets the local mtime unless another one is asked for
+        let mtime = timestamp.unwrap_or_else(|| {
+            meta.modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_secs() as u32)
+        });
+        adb::sync::with(|s| s.send(&mut file, to.as_str(), adb::sync::local_mode(&meta), mtime))
+            .annotate()?;
+        Ok(())
+    }
+
+    // listing goes through the sync service, so it does not need the shell session. LIST
+    // truncates sizes to 32 bits and silently leaves out what it cannot stat, LIS2 does neither
+    pub(crate) fn ls(path: &UnixPath) -> CResult<Vec<SyncFile>> {
+        let v2 = adb::has_ls_v2();
+        let entries = adb::sync::with(|s| {
+            if v2 {
+                s.list_v2(path.as_str())
+            } else {
+                s.list(path.as_str())
+            }
+        })
+        .annotate()?;
+        let mut files = Vec::with_capacity(entries.len());
+        for e in entries {
+            if e.name == "." || e.name == ".." {
+                continue;
+            }
+            let path = path.join(&e.name);
+            if e.error != 0 {
+                // EPERM and EACCES
+                if matches!(e.error, 1 | 13) {
+                    logw!(W003: "Skipping '{}': {}", path, adb::errno_name(e.error));
+                } else {
+                    logw!(W004: "Skipping '{}': {}", path, adb::errno_name(e.error));
+                }
+                continue;
+            }
+            let Some(mode) = FileMode::from_u32(e.mode) else {
+                logw!(W002: "Skipping special file {}", path);
+                continue;
+            };
+            files.push(SyncFile {
+                mode,
+                size: e.size,
+                timestamp: e.mtime.clamp(0, u32::MAX as i64) as u32,
+                name: e.name.into(),
+                path: path.into(),
+            });
+        }
+        Ok(files)
+    }
 }
 
 #[derive(Debug, Eq, Hash, PartialEq, Clone, Copy)]
@@ -55,32 +248,82 @@ pub enum FileMode {
 }
 
 impl FileMode {
-    pub fn from_u32(mode: u32) -> Self {
+    // None for fifos, sockets and device nodes, which are skipped
+    pub fn from_u32(mode: u32) -> Option<Self> {
         match mode >> 13 {
-            0b100 => Self::File,
-            0b010 => Self::Dir,
-            0b101 => Self::Symlink,
-            _ => unreachable!("file mode? {}", mode),
+            0b100 => Some(Self::File),
+            0b010 => Some(Self::Dir),
+            0b101 => Some(Self::Symlink),
+            _ => None,
         }
     }
 }
 
+// for a path given to stat that is not a file, directory or symlink
+fn special_file(path: &UnixPath) -> String {
+    format!("'{path}' is not a file, directory or symlink")
+}
+
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub struct SyncFile {
     pub mode: FileMode,
-    pub size: u32,
+    pub size: u64,
     pub timestamp: u32,
     pub name: Box<str>,
     pub path: Box<UnixPath>,
 }
 
-fn hex2u32(s: &str) -> u32 {
-    match u32::from_str_radix(s, 16) {
-        Ok(u) => u,
-        Err(e) => panic!("{e:?} ({s})"),
+// how many bytes `sha256_ends` reads from the start and from the end of a file
+fn ends(size: u64, bytes: u64) -> (u64, u64) {
+    let head = bytes.min(size);
+    (head, bytes.min(size - head))
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+// stat exits non-zero for missing paths, which only means they do not exist.
+// anything else (e.g. "Permission denied") is a real failure
+fn check_stat_errors(stderr: &str) -> CResult<()> {
+    match stderr
+        .lines()
+        .find(|l| !l.ends_with("No such file or directory"))
+    {
+        Some(err) => Err(err.to_string().into()),
+        None => Ok(()),
     }
 }
 
+// parses a line of `stat -c '%f %s %Y'` output, None for a special file. an mtime before
+// the epoch is clamped like in `AndroidFS::stat`
+pub(crate) fn parse_stat_line(line: &str, path: &UnixPath) -> CResult<Option<SyncFile>> {
+    let malformed = || format!("malformed stat output '{line}' for '{path}'");
+    let mut it = line.split(' ');
+    let mode = it
+        .next()
+        .and_then(|s| u32::from_str_radix(s, 16).ok())
+        .ok_or_else(malformed)?;
+    let size = it
+        .next()
+        .and_then(|s| s.parse::<u64>().ok())
+        .ok_or_else(malformed)?;
+    let mtime = it
+        .next()
+        .and_then(|s| s.trim_end().parse::<i64>().ok())
+        .ok_or_else(malformed)?;
+    let Some(mode) = FileMode::from_u32(mode) else {
+        return Ok(None);
+    };
+    Ok(Some(SyncFile {
+        mode,
+        size,
+        timestamp: mtime.clamp(0, u32::MAX as i64) as u32,
+        name: path.file_name().unwrap_or_default().into(),
+        path: path.to_path_buf().into_boxed_path(),
+    }))
+}
+
 pub trait FSCopyFrom<SRC: FileSystem>: FileSystem {
     fn copy(&mut self, from: &UnixPath, to: &UnixPath, timestamp: Option<u32>) -> CResult<()>;
 
@@ -92,46 +335,479 @@ pub trait FSCopyFrom<SRC: FileSystem>: FileSystem {
             match entry.mode {
                 FileMode::File => self.copy(&entry.path, &to_path, timestamp).annotate()?,
                 FileMode::Dir => self.copy_dir(&entry.path, &to_path, timestamp).annotate()?,
-                FileMode::Symlink => todo!(),
+                FileMode::Symlink => logw!(W002: "Skipping symlink {}", entry.path),
             }
         }
         Ok(())
     }
 }
 
-impl FSCopyFrom<LocalFS> for AndroidFS {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Transport {
+    Adb,
+    Local,
+    // only reachable through a `dav://`/`davs://` destination
+    #[value(skip)]
+    WebDav,
+    // only reachable through an `s3://` destination
+    #[value(skip)]
+    S3,
+}
+
+// object-safe facade so backends can be picked at runtime
+pub trait AnyFileSystem: FileSystem {
+    fn transport(&self) -> Transport;
+    fn copy_from(
+        &mut self,
+        src: Transport,
+        from: &UnixPath,
+        to: &UnixPath,
+        timestamp: Option<u32>,
+    ) -> CResult<()>;
+    fn copy_dir_from(
+        &mut self,
+        src: Transport,
+        from: &UnixPath,
+        to: &UnixPath,
+        timestamp: Option<u32>,
+    ) -> CResult<()>;
+}
+
+pub(crate) fn unsupported_pair(src: Transport, dest: Transport) -> CResult<()> {
+    Err(format!("copying from {src:?} to {dest:?} is not supported").into())
+}
+
+impl AnyFileSystem for AndroidFS {
+    fn transport(&self) -> Transport {
+        Transport::Adb
+    }
+
+    fn copy_from(
+        &mut self,
+        src: Transport,
+        from: &UnixPath,
+        to: &UnixPath,
+        timestamp: Option<u32>,
+    ) -> CResult<()> {
+        match src {
+            Transport::Local => <Self as FSCopyFrom<LocalFS>>::copy(self, from, to, timestamp),
+            src => unsupported_pair(src, Transport::Adb),
+        }
+    }
+
+    fn copy_dir_from(
+        &mut self,
+        src: Transport,
+        from: &UnixPath,
+        to: &UnixPath,
+        timestamp: Option<u32>,
+    ) -> CResult<()> {
+        match src {
+            Transport::Local => <Self as FSCopyFrom<LocalFS>>::copy_dir(self, from, to, timestamp),
+            src => unsupported_pair(src, Transport::Adb),
+        }
+    }
+}
+
+impl AnyFileSystem for LocalFS {
+    fn transport(&self) -> Transport {
+        Transport::Local
+    }
+
+    fn copy_from(
+        &mut self,
+        src: Transport,
+        from: &UnixPath,
+        to: &UnixPath,
+        timestamp: Option<u32>,
+    ) -> CResult<()> {
+        match src {
+            Transport::Adb => <Self as FSCopyFrom<AndroidFS>>::copy(self, from, to, timestamp),
+            Transport::Local => <Self as FSCopyFrom<LocalFS>>::copy(self, from, to, timestamp),
+            src => unsupported_pair(src, Transport::Local),
+        }
+    }
+
+    fn copy_dir_from(
+        &mut self,
+        src: Transport,
+        from: &UnixPath,
+        to: &UnixPath,
+        timestamp: Option<u32>,
+    ) -> CResult<()> {
+        match src {
+            Transport::Adb => <Self as FSCopyFrom<AndroidFS>>::copy_dir(self, from, to, timestamp),
+            Transport::Local => <Self as FSCopyFrom<LocalFS>>::copy_dir(self, from, to, timestamp),
+            src => unsupported_pair(src, Transport::Local),
+        }
+    }
+}
+
+impl FileSystem for Box<dyn AnyFileSystem> {
+    fn mkdir(&mut self, path: &UnixPath) -> CResult<()> {
+        (**self).mkdir(path)
+    }
+    fn list_dir(&mut self, path: &UnixPath) -> CResult<Vec<SyncFile>> {
+        (**self).list_dir(path)
+    }
+    fn stat(&mut self, path: &UnixPath) -> CResult<Option<SyncFile>> {
+        (**self).stat(path)
+    }
+    fn stat_many(&mut self, paths: &[&UnixPath]) -> CResult<Vec<Option<SyncFile>>> {
+        (**self).stat_many(paths)
+    }
+    fn rm(&mut self, path: &UnixPath) -> CResult<()> {
+        (**self).rm(path)
+    }
+    fn rm_dir(&mut self, path: &UnixPath) -> CResult<()> {
+        (**self).rm_dir(path)
+    }
+    fn set_mtime(&mut self, path: &UnixPath, timestamp: u32) -> CResult<()> {
+        (**self).set_mtime(path, timestamp)
+    }
+    fn sha256(&mut self, path: &UnixPath) -> CResult<String> {
+        (**self).sha256(path)
+    }
+    fn sha256_ends(&mut self, path: &UnixPath, size: u64, bytes: u64) -> CResult<String> {
+        (**self).sha256_ends(path, size, bytes)
+    }
+    fn sha256_many(&mut self, paths: &[&UnixPath]) -> CResult<Vec<String>> {
+        (**self).sha256_many(paths)
+    }
+    fn copy_within(&mut self, from: &UnixPath, to: &UnixPath) -> CResult<()> {
+        (**self).copy_within(from, to)
+    }
+    fn hard_link(&mut self, existing: &UnixPath, new: &UnixPath) -> CResult<()> {
+        (**self).hard_link(existing, new)
+    }
+    fn link_ids(&mut self, paths: &[&UnixPath]) -> CResult<Vec<Option<(u64, u64)>>> {
+        (**self).link_ids(paths)
+    }
+    fn get_xattrs(&mut self, path: &UnixPath) -> CResult<Vec<(String, Vec<u8>)>> {
+        (**self).get_xattrs(path)
+    }
+    fn set_xattrs(&mut self, path: &UnixPath, attrs: &[(String, Vec<u8>)]) -> CResult<()> {
+        (**self).set_xattrs(path, attrs)
+    }
+    fn get_owner(&mut self, path: &UnixPath) -> CResult<(u32, u32)> {
+        (**self).get_owner(path)
+    }
+    fn set_owner(&mut self, path: &UnixPath, uid: Option<u32>, gid: Option<u32>) -> CResult<()> {
+        (**self).set_owner(path, uid, gid)
+    }
+    fn get_mode(&mut self, path: &UnixPath) -> CResult<u32> {
+        (**self).get_mode(path)
+    }
+    fn set_mode(&mut self, path: &UnixPath, mode: u32) -> CResult<()> {
+        (**self).set_mode(path, mode)
+    }
+}
+
+// a runtime-chosen destination paired with the transport of the source it copies from,
+// so that `sink` can be driven by two `Box<dyn AnyFileSystem>`s
+pub struct DynDest<'a> {
+    pub fs: &'a mut dyn AnyFileSystem,
+    pub src: Transport,
+}
+
+impl FileSystem for DynDest<'_> {
+    fn mkdir(&mut self, path: &UnixPath) -> CResult<()> {
+        self.fs.mkdir(path)
+    }
+    fn list_dir(&mut self, path: &UnixPath) -> CResult<Vec<SyncFile>> {
+        self.fs.list_dir(path)
+    }
+    fn stat(&mut self, path: &UnixPath) -> CResult<Option<SyncFile>> {
+        self.fs.stat(path)
+    }
+    fn stat_many(&mut self, paths: &[&UnixPath]) -> CResult<Vec<Option<SyncFile>>> {
+        self.fs.stat_many(paths)
+    }
+    fn rm(&mut self, path: &UnixPath) -> CResult<()> {
+        self.fs.rm(path)
+    }
+    fn rm_dir(&mut self, path: &UnixPath) -> CResult<()> {
+        self.fs.rm_dir(path)
+    }
+    fn set_mtime(&mut self, path: &UnixPath, timestamp: u32) -> CResult<()> {
+        self.fs.set_mtime(path, timestamp)
+    }
+    fn sha256(&mut self, path: &UnixPath) -> CResult<String> {
+        self.fs.sha256(path)
+    }
+    fn sha256_ends(&mut self, path: &UnixPath, size: u64, bytes: u64) -> CResult<String> {
+        self.fs.sha256_ends(path, size, bytes)
+    }
+    fn sha256_many(&mut self, paths: &[&UnixPath]) -> CResult<Vec<String>> {
+        self.fs.sha256_many(paths)
+    }
+    fn copy_within(&mut self, from: &UnixPath, to: &UnixPath) -> CResult<()> {
+        self.fs.copy_within(from, to)
+    }
+    fn hard_link(&mut self, existing: &UnixPath, new: &UnixPath) -> CResult<()> {
+        self.fs.hard_link(existing, new)
+    }
+    fn link_ids(&mut self, paths: &[&UnixPath]) -> CResult<Vec<Option<(u64, u64)>>> {
+        self.fs.link_ids(paths)
+    }
+    fn get_xattrs(&mut self, path: &UnixPath) -> CResult<Vec<(String, Vec<u8>)>> {
+        self.fs.get_xattrs(path)
+    }
+    fn set_xattrs(&mut self, path: &UnixPath, attrs: &[(String, Vec<u8>)]) -> CResult<()> {
+        self.fs.set_xattrs(path, attrs)
+    }
+    fn get_owner(&mut self, path: &UnixPath) -> CResult<(u32, u32)> {
+        self.fs.get_owner(path)
+    }
+    fn set_owner(&mut self, path: &UnixPath, uid: Option<u32>, gid: Option<u32>) -> CResult<()> {
+        self.fs.set_owner(path, uid, gid)
+    }
+    fn get_mode(&mut self, path: &UnixPath) -> CResult<u32> {
+        self.fs.get_mode(path)
+    }
+    fn set_mode(&mut self, path: &UnixPath, mode: u32) -> CResult<()> {
+        self.fs.set_mode(path, mode)
+    }
+}
+
+impl FSCopyFrom<Box<dyn AnyFileSystem>> for DynDest<'_> {
     fn copy(&mut self, from: &UnixPath, to: &UnixPath, timestamp: Option<u32>) -> CResult<()> {
-        let mut cmd = AdbCmd::new();
-        cmd.args(["push", from.as_str(), to.as_str()]);
-        let _op = cmd.output().annotate()?;
-        if let Some(timestamp) = timestamp {
-            self.set_mtime(to, timestamp).annotate()?;
+        self.fs.copy_from(self.src, from, to, timestamp)
+    }
+
+    fn copy_dir(&mut self, from: &UnixPath, to: &UnixPath, timestamp: Option<u32>) -> CResult<()> {
+        self.fs.copy_dir_from(self.src, from, to, timestamp)
+    }
+}
+
+// a source whose files have to be written out to a local file before they can be copied,
+// like encrypted files or archive members
+pub trait Unpack: FileSystem {
+    fn unpack(&mut self, from: &UnixPath, to: &UnixPath) -> CResult<()>;
+}
+
+// destination for an `Unpack` source: every file is unpacked into a local temporary file
+// and copied from there
+pub struct Unpacking<U: Unpack> {
+    pub inner: Box<dyn AnyFileSystem>,
+    pub src: U,
+}
+
+impl<U: Unpack> FileSystem for Unpacking<U> {
+    fn mkdir(&mut self, path: &UnixPath) -> CResult<()> {
+        self.inner.mkdir(path)
+    }
+
+    fn list_dir(&mut self, path: &UnixPath) -> CResult<Vec<SyncFile>> {
+        self.inner.list_dir(path)
+    }
+
+    fn stat(&mut self, path: &UnixPath) -> CResult<Option<SyncFile>> {
+        self.inner.stat(path)
+    }
+
+    fn stat_many(&mut self, paths: &[&UnixPath]) -> CResult<Vec<Option<SyncFile>>> {
+        self.inner.stat_many(paths)
+    }
+
+    fn rm(&mut self, path: &UnixPath) -> CResult<()> {
+        self.inner.rm(path)
+    }
+
+    fn rm_dir(&mut self, path: &UnixPath) -> CResult<()> {
+        self.inner.rm_dir(path)
+    }
+
+    fn set_mtime(&mut self, path: &UnixPath, timestamp: u32) -> CResult<()> {
+        self.inner.set_mtime(path, timestamp)
+    }
+
+    fn sha256(&mut self, path: &UnixPath) -> CResult<String> {
+        self.inner.sha256(path)
+    }
+
+    fn sha256_ends(&mut self, path: &UnixPath, size: u64, bytes: u64) -> CResult<String> {
+        self.inner.sha256_ends(path, size, bytes)
+    }
+
+    fn sha256_many(&mut self, paths: &[&UnixPath]) -> CResult<Vec<String>> {
+        self.inner.sha256_many(paths)
+    }
+
+    fn copy_within(&mut self, from: &UnixPath, to: &UnixPath) -> CResult<()> {
+        self.inner.copy_within(from, to)
+    }
+
+    fn set_owner(&mut self, path: &UnixPath, uid: Option<u32>, gid: Option<u32>) -> CResult<()> {
+        self.inner.set_owner(path, uid, gid)
+    }
+}
+
+impl<U: Unpack> AnyFileSystem for Unpacking<U> {
+    fn transport(&self) -> Transport {
+        self.inner.transport()
+    }
+
+    fn copy_from(
+        &mut self,
+        src: Transport,
+        from: &UnixPath,
+        to: &UnixPath,
+        timestamp: Option<u32>,
+    ) -> CResult<()> {
+        if src != Transport::Local {
+            return unsupported_pair(src, self.inner.transport());
+        }
+        let name = format!(
+            "adb-sink-{}-{}",
+            std::process::id(),
+            from.file_name().unwrap()
+        );
+        let tmp = std::env::temp_dir().join(name);
+        let tmp = UnixPathBuf::from(tmp.to_str().unwrap());
+        let r = self
+            .src
+            .unpack(from, &tmp)
+            .and_then(|()| self.inner.copy_from(src, &tmp, to, timestamp))
+            // a queued forward transfer still reads the temporary file
+            .and_then(|()| forward::flush());
+        let _ = std::fs::remove_file(tmp.as_str());
+        r
+    }
+
+    fn copy_dir_from(
+        &mut self,
+        src: Transport,
+        from: &UnixPath,
+        to: &UnixPath,
+        timestamp: Option<u32>,
+    ) -> CResult<()> {
+        self.inner.mkdir(to).annotate()?;
+        for e in self.src.list_dir(from).annotate()? {
+            let to = to.join(&*e.name);
+            match e.mode {
+                FileMode::File => {
+                    let timestamp = timestamp.map(|_| e.timestamp);
+                    self.copy_from(src, &e.path, &to, timestamp).annotate()?
+                }
+                FileMode::Dir => self
+                    .copy_dir_from(src, &e.path, &to, timestamp)
+                    .annotate()?,
+                FileMode::Symlink => logw!(W002: "Skipping symlink {}", e.path),
+            }
         }
         Ok(())
     }
+}
+
+impl FSCopyFrom<LocalFS> for AndroidFS {
+    fn copy(&mut self, from: &UnixPath, to: &UnixPath, timestamp: Option<u32>) -> CResult<()> {
+        check_device_writable("push", to).annotate()?;
+        if delta::enabled() && delta::push(from, to, timestamp).annotate()? {
+            return Ok(());
+        }
+        if forward::port().is_some() {
+            return forward::push(from, to, timestamp);
+        }
+        if self.push_compression.is_some() {
+            return self.adb_push(from, to, timestamp);
+        }
+        self.sync_push(from, to, timestamp)
+    }
 
+    // whole directories are one adb command either way, so they do not go through the forward
     fn copy_dir(&mut self, from: &UnixPath, to: &UnixPath, timestamp: Option<u32>) -> CResult<()> {
-        <AndroidFS as FSCopyFrom<LocalFS>>::copy(self, from, to, timestamp).annotate()?;
-        Ok(())
+        if adb::over_usb() {
+            return copy_each::<LocalFS, _>(
+                self,
+                &mut |p| LocalFS.list_dir(p),
+                from,
+                to,
+                timestamp,
+            );
+        }
+        self.adb_push(from, to, timestamp)
     }
 }
 
 impl FSCopyFrom<AndroidFS> for LocalFS {
     fn copy(&mut self, from: &UnixPath, to: &UnixPath, timestamp: Option<u32>) -> CResult<()> {
-        let mut cmd = AdbCmd::new();
-        cmd.args(["pull"]);
-        if timestamp.is_some() {
-            cmd.arg("-a");
+        if let Some(access) = scoped::access_for(from) {
+            return scoped::pull(&access, from, to, timestamp);
         }
-        cmd.args([from.as_str(), to.as_str()]);
-        let _op = cmd.output().annotate()?;
-        Ok(())
+        if delta::enabled() && delta::pull(from, to, timestamp).annotate()? {
+            return Ok(());
+        }
+        if forward::port().is_some() {
+            return forward::pull(from, to, timestamp);
+        }
+        sync_pull(from, to, timestamp)
     }
 
+    // whole directories are one adb command either way, so they do not go through the forward
     fn copy_dir(&mut self, from: &UnixPath, to: &UnixPath, timestamp: Option<u32>) -> CResult<()> {
-        <LocalFS as FSCopyFrom<AndroidFS>>::copy(self, from, to, timestamp).annotate()?;
-        Ok(())
+        if let Some(access) = scoped::access_for(from) {
+            return scoped::pull_dir(&access, from, to, timestamp);
+        }
+        if adb::over_usb() {
+            return copy_each::<AndroidFS, _>(self, &mut AndroidFS::ls, from, to, timestamp);
+        }
+        adb_pull(from, to, timestamp)
+    }
+}
+
+// a directory file by file through the sync service, with `--usb` there is no adb binary to
+// copy it whole. like `adb pull -a`, each file keeps its own mtime when times are set
+fn copy_each<SRC: FileSystem, D: FSCopyFrom<SRC>>(
+    dest: &mut D,
+    list: &mut dyn FnMut(&UnixPath) -> CResult<Vec<SyncFile>>,
+    from: &UnixPath,
+    to: &UnixPath,
+    timestamp: Option<u32>,
+) -> CResult<()> {
+    dest.mkdir(to).annotate()?;
+    for entry in list(from).annotate()? {
+        let to_path = to.join(&*entry.name);
+        match entry.mode {
+            FileMode::File => {
+                let mtime = timestamp.map(|_| entry.timestamp);
+                dest.copy(&entry.path, &to_path, mtime).annotate()?
+            }
+            FileMode::Dir => {
+                copy_each::<SRC, D>(dest, list, &entry.path, &to_path, timestamp).annotate()?
+            }
+            FileMode::Symlink => logw!(W002: "Skipping symlink {}", entry.path),
+        }
+    }
+    Ok(())
+}
+
+fn adb_pull(from: &UnixPath, to: &UnixPath, timestamp: Option<u32>) -> CResult<()> {
+    let mut cmd = AdbCmd::new();
+    cmd.args(["pull"]);
+    if timestamp.is_some() {
+        cmd.arg("-a");
+    }
+    cmd.args([from.as_str(), to.as_str()]);
+    heartbeat::watch(to.as_str());
+    let _op = match file_timeout() {
+        Some(stall) => cmd.output_stalled(to.as_str(), stall).annotate()?,
+        None => cmd.output().annotate()?,
+    };
+    Ok(())
+}
+
+// a file through the sync service instead of an adb process
+fn sync_pull(from: &UnixPath, to: &UnixPath, timestamp: Option<u32>) -> CResult<()> {
+    logv!("[SYNC] RECV {}", from);
+    heartbeat::watch(to.as_str());
+    let mut out = BufWriter::new(File::create(to.as_str()).annotate()?);
+    adb::sync::with(|s| s.recv(from.as_str(), &mut out, file_timeout())).annotate()?;
+    out.into_inner().map_err(|e| e.into_error()).annotate()?;
+    if let Some(timestamp) = timestamp {
+        LocalFS.set_mtime(to, timestamp).annotate()?;
     }
+    Ok(())
 }
 
 impl FSCopyFrom<LocalFS> for LocalFS {
@@ -142,62 +818,341 @@ impl FSCopyFrom<LocalFS> for LocalFS {
 }
 
 impl FileSystem for AndroidFS {
-    fn mkdir(&mut self, _path: &UnixPath) -> CResult<()> {
+    fn mkdir(&mut self, path: &UnixPath) -> CResult<()> {
+        check_device_writable("create", path).annotate()?;
         // adb push already does this
         // self.shell.run(["mkdir", "-p", path.as_str()]).annotate()?;
         Ok(())
     }
 
     fn list_dir(&mut self, path: &UnixPath) -> CResult<Vec<SyncFile>> {
-        let op = AdbCmd::run_v(["ls", path.as_str()]).annotate()?;
-        let mut files = Vec::with_capacity(op.lines().count());
-        for line in op.lines() {
-            let (s, line) = line.split_once(' ').expect("ls output mode");
-            let mode = hex2u32(s);
+        if let Some(access) = scoped::access_for(path) {
+            return scoped::list(&access, path);
+        }
+        let files = AndroidFS::ls(path).annotate()?;
+        if files.is_empty() && scoped::is_scoped(path) {
+            return scoped::fallback_list(path);
+        }
+        Ok(files)
+    }
 
-            let (s, line) = line.split_once(' ').expect("ls output size");
-            let size = hex2u32(s);
+    // through the sync service, so it works without a `stat` applet too
+    fn stat(&mut self, path: &UnixPath) -> CResult<Option<SyncFile>> {
+        let Some(st) = adb::sync::with(|s| s.stat(path.as_str())).annotate()? else {
+            return Ok(None);
+        };
+        Ok(Some(SyncFile {
+            mode: FileMode::from_u32(st.mode).ok_or_else(|| special_file(path))?,
+            size: st.size,
+            timestamp: st.mtime.clamp(0, u32::MAX as i64) as u32,
+            name: path.file_name().unwrap_or_default().into(),
+            path: path.to_path_buf().into_boxed_path(),
+        }))
+    }
 
-            let (s, name) = line.split_once(' ').expect("ls output epoch");
-            if name == "." || name == ".." {
-                continue;
+    // one shell command per chunk instead of a request per path
+    fn stat_many(&mut self, paths: &[&UnixPath]) -> CResult<Vec<Option<SyncFile>>> {
+        let Some(stat) = self.tools.get("stat") else {
+            return paths.iter().map(|p| self.stat(p)).collect();
+        };
+        let mut found = HashMap::with_capacity(paths.len());
+        // keep each invocation well below the device's ARG_MAX
+        for chunk in paths.chunks(STAT_MANY_CHUNK) {
+            let quoted: Vec<String> = chunk.iter().map(|p| quote(p.as_str())).collect();
+            let op = self
+                .shell
+                .exec(
+                    [stat, "-c", "'%f %s %Y %n'"]
+                        .into_iter()
+                        .chain(quoted.iter().map(String::as_str)),
+                )
+                .annotate()?;
+            check_stat_errors(&op.stderr).annotate()?;
+            for line in op.stdout.lines() {
+                let Some(p) = line.splitn(4, ' ').nth(3) else {
+                    continue;
+                };
+                let fields = &line[..line.len() - p.len() - 1];
+                let path = UnixPath::new(p);
+                let sf = parse_stat_line(fields, path)
+                    .annotate()?
+                    .ok_or_else(|| special_file(path))?;
+                found.insert(p.to_string(), sf);
             }
-            let timestamp = hex2u32(s);
-            let path = path.join(name);
-            files.push(SyncFile {
-                mode: FileMode::from_u32(mode),
-                size,
-                timestamp,
-                name: name.into(),
-                path: path.into(),
-            });
         }
+        Ok(paths.iter().map(|p| found.remove(p.as_str())).collect())
+    }
 
-        Ok(files)
+    fn rm(&mut self, path: &UnixPath) -> CResult<()> {
+        check_device_writable("delete", path).annotate()?;
+        self.shell
+            .run(["rm", "-f", quote(path.as_str()).as_str()])
+            .annotate()?;
+        Ok(())
+    }
+
+    // with everything under it, like `LocalFS::rm_dir`
+    fn rm_dir(&mut self, path: &UnixPath) -> CResult<()> {
+        check_device_writable("delete", path).annotate()?;
+        self.shell
+            .run(["rm", "-rf", quote(path.as_str()).as_str()])
+            .annotate()?;
+        Ok(())
+    }
+
+    fn set_mtime(&mut self, path: &UnixPath, timestamp: u32) -> CResult<()> {
+        check_device_writable("set the mtime of", path).annotate()?;
+        let p = quote(path.as_str());
+        match self.tools.get("touch") {
+            Some(touch) => {
+                let ts = format!("@{timestamp}");
+                self.shell
+                    .run([touch, "-m", "-d", ts.as_str(), p.as_str()])
+                    .annotate()?;
+            }
+            // toolbox's touch only takes `-t YYYYMMDD.hhmmss` in local time
+            None => {
+                let (year, month, day, hour, min, sec) = utc(timestamp as u64);
+                let t = format!("{year:04}{month:02}{day:02}.{hour:02}{min:02}{sec:02}");
+                self.shell
+                    .run(["TZ=UTC0", "touch", "-m", "-t", t.as_str(), p.as_str()])
+                    .annotate()?;
+            }
+        }
+        Ok(())
+    }
+
+    // hashes have to match sha256 on the other side, a device without sha256sum cannot fall
+    // back to md5sum
+    fn sha256(&mut self, path: &UnixPath) -> CResult<String> {
+        let sha256sum = self.tools.require("sha256sum").annotate()?;
+        let op = self
+            .shell
+            .run([sha256sum, quote(path.as_str()).as_str()])
+            .annotate()?;
+        match op.split_whitespace().next() {
+            Some(h) if h.len() == 64 => Ok(h.to_string()),
+            _ => Err(format!("sha256sum failed for '{path}': {op}").into()),
+        }
+    }
+
+    fn sha256_ends(&mut self, path: &UnixPath, size: u64, bytes: u64) -> CResult<String> {
+        let (head, tail) = ends(size, bytes);
+        let p = quote(path.as_str());
+        let sha256sum = self.tools.require("sha256sum").annotate()?;
+        let head_cmd = self.tools.require("head").annotate()?;
+        let tail_cmd = self.tools.require("tail").annotate()?;
+        let op = self
+            .shell
+            .run([format!(
+                "{{ {head_cmd} -c {head} {p}; {tail_cmd} -c {tail} {p}; }} | {sha256sum}"
+            )])
+            .annotate()?;
+        match op.split_whitespace().next() {
+            Some(h) if h.len() == 64 => Ok(h.to_string()),
+            _ => Err(format!("sha256sum failed for '{path}': {op}").into()),
+        }
+    }
+
+    // one shell command per chunk instead of one per file, hashing on all of the device's cores
+    // when its xargs runs in parallel
+    fn sha256_many(&mut self, paths: &[&UnixPath]) -> CResult<Vec<String>> {
+        let sha256sum = self.tools.require("sha256sum").annotate()?;
+        let mut hashes = Vec::with_capacity(paths.len());
+        for chunk in paths.chunks(STAT_MANY_CHUNK) {
+            let quoted: Vec<String> = chunk.iter().map(|p| quote(p.as_str())).collect();
+            let cmd = match self.tools.get("xargs") {
+                Some(xargs) => format!(
+                    "printf '%s\\0' {} | {xargs} -0 -P \"$(nproc 2>/dev/null || echo 4)\" {sha256sum}",
+                    quoted.join(" ")
+                ),
+                None => format!("{sha256sum} {}", quoted.join(" ")),
+            };
+            let op = self.shell.run([cmd]).annotate()?;
+            // in whatever order the parallel runs finished
+            let by_path: HashMap<&str, &str> = op
+                .lines()
+                .filter_map(|l| l.split_once("  "))
+                .filter(|(h, _)| h.len() == 64)
+                .map(|(h, p)| (p, h))
+                .collect();
+            for p in chunk {
+                match by_path.get(p.as_str()) {
+                    Some(h) => hashes.push(h.to_string()),
+                    None => return Err(format!("sha256sum failed for '{p}': {op}").into()),
+                }
+            }
+        }
+        Ok(hashes)
     }
 
-    fn rm(&mut self, _path: &UnixPath) -> CResult<()> {
-        logw!("ignoring AndroidFS::rm");
+    // needs getfattr/setfattr on the device (toybox has them). most devices only let root
+    // read or set xattrs
+    fn get_xattrs(&mut self, path: &UnixPath) -> CResult<Vec<(String, Vec<u8>)>> {
+        let op = self
+            .shell
+            .run([
+                "getfattr",
+                "-d",
+                "-m",
+                "'^user\\.'",
+                "-e",
+                "hex",
+                quote(path.as_str()).as_str(),
+            ])
+            .annotate()?;
+        let mut attrs = Vec::new();
+        for line in op.lines() {
+            // `# file: <path>` header and blank separators
+            let Some((name, value)) = line.split_once('=') else {
+                continue;
+            };
+            let value = value.trim().trim_start_matches("0x");
+            let value = (0..value.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(value.get(i..i + 2).unwrap_or("0"), 16))
+                .collect::<Result<Vec<u8>, _>>()
+                .annotate()?;
+            attrs.push((name.to_string(), value));
+        }
+        Ok(attrs)
+    }
+
+    fn set_xattrs(&mut self, path: &UnixPath, attrs: &[(String, Vec<u8>)]) -> CResult<()> {
+        check_device_writable("set xattrs of", path).annotate()?;
+        for (name, value) in attrs {
+            self.shell
+                .run([
+                    "setfattr",
+                    "-n",
+                    quote(name).as_str(),
+                    "-v",
+                    format!("0x{}", hex(value)).as_str(),
+                    quote(path.as_str()).as_str(),
+                ])
+                .annotate()?;
+        }
         Ok(())
     }
 
-    fn rm_dir(&mut self, _path: &UnixPath) -> CResult<()> {
-        logw!("ignoring AndroidFS::rm_dir");
+    fn get_owner(&mut self, path: &UnixPath) -> CResult<(u32, u32)> {
+        let p = quote(path.as_str());
+        let op = match self.tools.get("stat") {
+            Some(stat) => self
+                .shell
+                .run([stat, "-c", "'%u %g'", p.as_str()])
+                .annotate()?,
+            // `drwxrwx--x 3 1000 1000 ...`, the third and fourth fields
+            None => {
+                let op = self.shell.run(["ls", "-ldn", p.as_str()]).annotate()?;
+                let fields: Vec<&str> = op.split_whitespace().skip(2).take(2).collect();
+                fields.join(" ")
+            }
+        };
+        let owner = op
+            .trim()
+            .split_once(' ')
+            .and_then(|(uid, gid)| Some((uid.parse().ok()?, gid.parse().ok()?)));
+        owner.ok_or_else(|| format!("stat failed for '{path}': {op}").into())
+    }
+
+    fn hard_link(&mut self, existing: &UnixPath, new: &UnixPath) -> CResult<()> {
+        check_device_writable("link", new).annotate()?;
+        self.shell
+            .run([
+                "ln",
+                quote(existing.as_str()).as_str(),
+                quote(new.as_str()).as_str(),
+            ])
+            .annotate()?;
         Ok(())
     }
 
-    fn set_mtime(&mut self, _path: &UnixPath, mut _timestamp: u32) -> CResult<()> {
-        // adb push already does this?
+    fn link_ids(&mut self, paths: &[&UnixPath]) -> CResult<Vec<Option<(u64, u64)>>> {
+        // listings have no inode numbers, hard links are copied as separate files then
+        let Some(stat) = self.tools.get("stat") else {
+            return Ok(vec![None; paths.len()]);
+        };
+        let mut found = HashMap::with_capacity(paths.len());
+        for chunk in paths.chunks(STAT_MANY_CHUNK) {
+            let quoted: Vec<String> = chunk.iter().map(|p| quote(p.as_str())).collect();
+            let op = self
+                .shell
+                .exec(
+                    [stat, "-c", "'%h %d %i %n'"]
+                        .into_iter()
+                        .chain(quoted.iter().map(String::as_str)),
+                )
+                .annotate()?;
+            check_stat_errors(&op.stderr).annotate()?;
+            for line in op.stdout.lines() {
+                let mut fields = line.splitn(4, ' ');
+                let id = (|| {
+                    let links: u64 = fields.next()?.parse().ok()?;
+                    let dev: u64 = fields.next()?.parse().ok()?;
+                    let ino: u64 = fields.next()?.parse().ok()?;
+                    Some((fields.next()?, (links > 1).then_some((dev, ino))))
+                })();
+                if let Some((p, id)) = id {
+                    found.insert(p.to_string(), id);
+                }
+            }
+        }
+        Ok(paths
+            .iter()
+            .map(|p| found.get(p.as_str()).copied().flatten())
+            .collect())
+    }
+
+    // needs root, `adb root` or a rooted adbd
+    fn set_owner(&mut self, path: &UnixPath, uid: Option<u32>, gid: Option<u32>) -> CResult<()> {
+        check_device_writable("change the owner of", path).annotate()?;
+        let id = |id: Option<u32>| id.map_or(String::new(), |id| id.to_string());
+        let owner = format!("{}:{}", id(uid), id(gid));
+        self.shell
+            .run(["chown", owner.as_str(), quote(path.as_str()).as_str()])
+            .annotate()?;
         Ok(())
-        // let timestamp = timestamp.to_string();
-        // let mut ts = String::with_capacity(1 + timestamp.len());
-        // ts.push('@');
-        // ts.push_str(&timestamp);
-        // adb_shell!(self.shell, "touch", "-m", "-d", ts, path)?;
     }
 }
 
 pub struct LocalFS;
+impl LocalFS {
+    // None for a special file
+    fn sync_file(
+        md: std::fs::Metadata,
+        name: Box<str>,
+        path: Box<UnixPath>,
+    ) -> CResult<Option<SyncFile>> {
+        let mode = if md.is_dir() {
+            FileMode::Dir
+        } else if md.is_file() {
+            FileMode::File
+        } else if md.is_symlink() {
+            FileMode::Symlink
+        } else {
+            return Ok(None);
+        };
+        #[cfg(target_os = "windows")]
+        let size = md.file_size();
+        #[cfg(target_os = "linux")]
+        let size = md.size();
+
+        Ok(Some(SyncFile {
+            mode,
+            size,
+            timestamp: md
+                .modified()
+                .annotate()?
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("get system time")
+                .as_secs() as u32,
+            name,
+            path,
+        }))
+    }
+}
+
 impl FileSystem for LocalFS {
     fn mkdir(&mut self, path: &UnixPath) -> CResult<()> {
         Ok(std::fs::create_dir_all(path.as_str()).annotate()?)
@@ -208,41 +1163,32 @@ impl FileSystem for LocalFS {
         for dir in std::fs::read_dir(path.as_str()).annotate()? {
             let dir = dir.annotate()?;
             let md = dir.metadata().annotate()?;
-            let mode = if md.is_dir() {
-                FileMode::Dir
-            } else if md.is_file() {
-                FileMode::File
-            } else if md.is_symlink() {
-                FileMode::Symlink
-            } else {
-                unreachable!("file mode?");
-            };
             let name = dir
                 .file_name()
                 .into_string()
                 .expect("file name is valid unicode");
             let path = path.join(&name);
-            #[cfg(target_os = "windows")]
-            let size = md.file_size() as u32;
-            #[cfg(target_os = "linux")]
-            let size = md.size() as u32;
-
-            fs.push(SyncFile {
-                mode,
-                size,
-                timestamp: md
-                    .modified()
-                    .annotate()?
-                    .duration_since(SystemTime::UNIX_EPOCH)
-                    .expect("get system time")
-                    .as_secs() as u32,
-                name: name.into_boxed_str(),
-                path: path.into_boxed_path(),
-            });
+            match LocalFS::sync_file(md, name.into_boxed_str(), path.clone().into_boxed_path())
+                .annotate()?
+            {
+                Some(sf) => fs.push(sf),
+                None => logw!(W002: "Skipping special file {}", path),
+            }
         }
         Ok(fs)
     }
 
+    fn stat(&mut self, path: &UnixPath) -> CResult<Option<SyncFile>> {
+        let md = match std::fs::symlink_metadata(path.as_str()) {
+            Ok(md) => md,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let name = path.file_name().unwrap_or_default().into();
+        let sf = LocalFS::sync_file(md, name, path.to_path_buf().into_boxed_path()).annotate()?;
+        Ok(Some(sf.ok_or_else(|| special_file(path))?))
+    }
+
     fn rm(&mut self, path: &UnixPath) -> CResult<()> {
         Ok(std::fs::remove_file(path.as_str()).annotate()?)
     }
@@ -257,4 +1203,197 @@ impl FileSystem for LocalFS {
             .annotate()?;
         Ok(())
     }
+
+    fn sha256(&mut self, path: &UnixPath) -> CResult<String> {
+        let mut f = File::open(path.as_str()).annotate()?;
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut f, &mut hasher).annotate()?;
+        Ok(hex(&hasher.finalize()))
+    }
+
+    fn sha256_many(&mut self, paths: &[&UnixPath]) -> CResult<Vec<String>> {
+        sha256_local(paths).into_iter().collect()
+    }
+
+    fn sha256_ends(&mut self, path: &UnixPath, size: u64, bytes: u64) -> CResult<String> {
+        let (head, tail) = ends(size, bytes);
+        let mut f = File::open(path.as_str()).annotate()?;
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut (&mut f).take(head), &mut hasher).annotate()?;
+        f.seek(SeekFrom::Start(size - tail)).annotate()?;
+        std::io::copy(&mut f.take(tail), &mut hasher).annotate()?;
+        Ok(hex(&hasher.finalize()))
+    }
+
+    fn copy_within(&mut self, from: &UnixPath, to: &UnixPath) -> CResult<()> {
+        std::fs::copy(from.as_str(), to.as_str()).annotate()?;
+        Ok(())
+    }
+
+    fn hard_link(&mut self, existing: &UnixPath, new: &UnixPath) -> CResult<()> {
+        Ok(std::fs::hard_link(existing.as_str(), new.as_str()).annotate()?)
+    }
+
+    #[cfg(unix)]
+    fn link_ids(&mut self, paths: &[&UnixPath]) -> CResult<Vec<Option<(u64, u64)>>> {
+        use std::os::unix::fs::MetadataExt;
+        paths
+            .iter()
+            .map(|p| {
+                let md = std::fs::symlink_metadata(p.as_str()).annotate()?;
+                Ok((md.nlink() > 1).then(|| (md.dev(), md.ino())))
+            })
+            .collect()
+    }
+
+    #[cfg(unix)]
+    fn set_owner(&mut self, path: &UnixPath, uid: Option<u32>, gid: Option<u32>) -> CResult<()> {
+        Ok(std::os::unix::fs::chown(path.as_str(), uid, gid).annotate()?)
+    }
+
+    #[cfg(unix)]
+    fn get_mode(&mut self, path: &UnixPath) -> CResult<u32> {
+        use std::os::unix::fs::PermissionsExt;
+        let md = std::fs::metadata(path.as_str()).annotate()?;
+        Ok(md.permissions().mode() & 0o7777)
+    }
+
+    #[cfg(unix)]
+    fn set_mode(&mut self, path: &UnixPath, mode: u32) -> CResult<()> {
+        use std::os::unix::fs::PermissionsExt;
+        let perms = std::fs::Permissions::from_mode(mode);
+        Ok(std::fs::set_permissions(path.as_str(), perms).annotate()?)
+    }
+
+    #[cfg(not(feature = "xattr"))]
+    fn get_xattrs(&mut self, _path: &UnixPath) -> CResult<Vec<(String, Vec<u8>)>> {
+        Err("adb-sink was built without the `xattr` feature".into())
+    }
+
+    #[cfg(not(feature = "xattr"))]
+    fn set_xattrs(&mut self, _path: &UnixPath, _attrs: &[(String, Vec<u8>)]) -> CResult<()> {
+        Err("adb-sink was built without the `xattr` feature".into())
+    }
+
+    #[cfg(feature = "xattr")]
+    fn get_xattrs(&mut self, path: &UnixPath) -> CResult<Vec<(String, Vec<u8>)>> {
+        let mut attrs = Vec::new();
+        for name in xattr::list(path.as_str()).annotate()? {
+            let Some(name) = name.to_str().filter(|n| n.starts_with("user.")) else {
+                continue;
+            };
+            if let Some(value) = xattr::get(path.as_str(), name).annotate()? {
+                attrs.push((name.to_string(), value));
+            }
+        }
+        Ok(attrs)
+    }
+
+    #[cfg(feature = "xattr")]
+    fn set_xattrs(&mut self, path: &UnixPath, attrs: &[(String, Vec<u8>)]) -> CResult<()> {
+        for (name, value) in attrs {
+            xattr::set(path.as_str(), name, value).annotate()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adb::{AdbStream, Transport};
+    use std::io::{Cursor, Write};
+    use std::sync::{Arc, Mutex};
+
+    // a device answering every shell command with `reply`, recording what was run
+    struct FakeShell {
+        cmds: Arc<Mutex<Vec<String>>>,
+        reply: Vec<u8>,
+    }
+
+    struct Reply(Cursor<Vec<u8>>);
+
+    impl Read for Reply {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Reply {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AdbStream for Reply {
+        fn set_read_timeout(&self, _timeout: Option<Duration>) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for FakeShell {
+        fn open(&self, service: &str) -> Result<Box<dyn AdbStream>, AdbErr> {
+            let cmd = service.strip_prefix("shell,v2,raw:").unwrap_or(service);
+            self.cmds.lock().unwrap().push(cmd.to_string());
+            Ok(Box::new(Reply(Cursor::new(self.reply.clone()))))
+        }
+        fn features(&self) -> Result<String, AdbErr> {
+            Ok(String::new())
+        }
+    }
+
+    // commands exiting with `status`, or a session that drops once they started without one
+    fn device(status: Option<u8>) -> (AndroidFS, Arc<Mutex<Vec<String>>>) {
+        let cmds = Arc::new(Mutex::new(Vec::new()));
+        let fake = FakeShell {
+            cmds: cmds.clone(),
+            // just the exit packet
+            reply: status.map_or(Vec::new(), |s| vec![3, 1, 0, 0, 0, s]),
+        };
+        let fs = AndroidFS {
+            shell: AdbShell::over(Box::new(fake)),
+            push_compression: None,
+            tools: DeviceTools::default(),
+        };
+        (fs, cmds)
+    }
+
+    #[test]
+    fn android_rm() {
+        let _ = crate::VERBOSE.set(false);
+        let (mut fs, cmds) = device(Some(0));
+        fs.rm(UnixPath::new("/sdcard/a b.txt")).unwrap();
+        fs.rm_dir(UnixPath::new("/sdcard/old")).unwrap();
+        assert_eq!(
+            *cmds.lock().unwrap(),
+            ["rm -f '/sdcard/a b.txt'", "rm -rf '/sdcard/old'"]
+        );
+        // what the device refuses to delete is an error
+        let (mut fs, _) = device(Some(1));
+        assert!(fs.rm(UnixPath::new("/system/x")).is_err());
+    }
+
+    #[test]
+    fn stat_line() {
+        let path = UnixPath::new("/sdcard/a");
+        let sf = parse_stat_line("81b0 12 -5", path).unwrap().unwrap();
+        assert_eq!((sf.mode, sf.size, sf.timestamp), (FileMode::File, 12, 0));
+        // a fifo
+        assert!(parse_stat_line("11b0 0 5", path).unwrap().is_none());
+        // what a newline in a name leaves of a line
+        assert!(parse_stat_line("b.txt 0 5", path).is_err());
+        assert!(parse_stat_line("81b0", path).is_err());
+    }
+
+    #[test]
+    fn no_replay_once_started() {
+        let _ = crate::VERBOSE.set(false);
+        let (mut fs, cmds) = device(None);
+        // the rm may have run before the session dropped
+        assert!(fs.rm(UnixPath::new("/sdcard/a")).is_err());
+        assert_eq!(cmds.lock().unwrap().len(), 1);
+    }
 }
@@ -0,0 +1,107 @@
+use crate::fs::{sha256_local, FileSystem, LocalFS};
+use crate::{is_verbose, logi, logv, logw, CResult};
+use chainerror::Context;
+use std::collections::BTreeMap;
+use std::path::Path;
+use typed_path::{Utf8UnixPath as UnixPath, Utf8UnixPathBuf as UnixPathBuf};
+
+// name of the database in the root of a pull destination. the format is that of
+// `sha256sum`, so `sha256sum -c` can check a backup too
+pub const HASH_DB: &str = ".adb-sink-hashes";
+
+// sha256 of every file pulled into a destination, keyed by the path relative to it
+pub struct HashDb {
+    root: UnixPathBuf,
+    hashes: BTreeMap<String, String>,
+}
+
+impl HashDb {
+    // a destination without a database starts an empty one
+    pub fn open(root: &UnixPath) -> CResult<Self> {
+        let mut hashes = BTreeMap::new();
+        let db = Path::new(root.as_str()).join(HASH_DB);
+        match std::fs::read_to_string(&db) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            r => {
+                for (i, line) in r.annotate()?.lines().enumerate() {
+                    let Some((hash, path)) = line.split_once("  ") else {
+                        return Err(
+                            format!("'{}' line {} is malformed", db.display(), i + 1).into()
+                        );
+                    };
+                    hashes.insert(path.to_string(), hash.to_string());
+                }
+            }
+        }
+        Ok(Self {
+            root: root.to_path_buf(),
+            hashes,
+        })
+    }
+
+    // hashes the just copied file `path` of `fs`
+    pub fn record<FS: FileSystem>(&mut self, fs: &mut FS, path: &UnixPath) -> CResult<()> {
+        let hash = fs.sha256(path).annotate()?;
+        self.hashes.insert(self.relative(path).to_string(), hash);
+        Ok(())
+    }
+
+    // forgets `path` and everything under it
+    pub fn remove(&mut self, path: &UnixPath) {
+        let path = self.relative(path).to_string();
+        let dir = format!("{path}/");
+        self.hashes
+            .retain(|p, _| *p != path && !p.starts_with(&dir));
+    }
+
+    fn relative<'a>(&self, path: &'a UnixPath) -> &'a str {
+        path.strip_prefix(&self.root)
+            .map_or(path.as_str(), |p| p.as_str())
+    }
+
+    pub fn save(&self) -> CResult<()> {
+        let mut out = String::new();
+        for (path, hash) in &self.hashes {
+            out.push_str(hash);
+            out.push_str("  ");
+            out.push_str(path);
+            out.push('\n');
+        }
+        // so that an interrupted write does not lose the old database
+        let root = Path::new(self.root.as_str());
+        let tmp = root.join(format!("{HASH_DB}.tmp"));
+        std::fs::write(&tmp, out).annotate()?;
+        std::fs::rename(&tmp, root.join(HASH_DB)).annotate()?;
+        Ok(())
+    }
+
+    // re-hashes every file of the database, returns how many are corrupt or missing
+    pub fn check(&self) -> CResult<usize> {
+        if self.hashes.is_empty() {
+            return Err(format!("No hashes recorded in '{}'", self.root).into());
+        }
+        let mut bad = 0;
+        let mut present = Vec::new();
+        for (path, hash) in &self.hashes {
+            let full = self.root.join(path);
+            match LocalFS.stat(&full).annotate()? {
+                None => {
+                    logw!(W006: "MISSING: {}", path);
+                    bad += 1;
+                }
+                Some(_) => present.push((path, hash, full)),
+            }
+        }
+        let fulls: Vec<&UnixPath> = present.iter().map(|(_, _, f)| f.as_path()).collect();
+        for ((path, hash, _), actual) in present.iter().zip(sha256_local(&fulls)) {
+            if actual.annotate()? != **hash {
+                logw!(W005: "CORRUPT: {}", path);
+                bad += 1;
+            } else {
+                logv!("OK: {}", path);
+            }
+        }
+        logi!("Checked {} files, {} bad", self.hashes.len(), bad);
+        Ok(bad)
+    }
+}
@@ -0,0 +1,75 @@
+// `--heartbeat SECS`: one status line every SECS, so CI jobs and log files show that a long
+// transfer is alive without a line per file
+//   HEARTBEAT: copying, 1204/5310 files (3.2 GiB), at /sdcard/DCIM/Camera/VID_0042.mp4
+
+use crate::{history, human_size, logi};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// where the run is, also drawn by `progress`
+#[derive(Clone)]
+pub(crate) struct State {
+    pub phase: &'static str,
+    // files and bytes the run is expected to copy, when known up front
+    pub total: Option<(u64, u64)>,
+    pub current: String,
+    pub size: u64,
+    // the local file the current transfer writes, whose size is how far it got
+    pub watch: Option<String>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    phase: "starting",
+    total: None,
+    current: String::new(),
+    size: 0,
+    watch: None,
+});
+
+pub(crate) fn phase(name: &'static str) {
+    STATE.lock().unwrap().phase = name;
+}
+
+pub(crate) fn total(files: u64, bytes: u64) {
+    STATE.lock().unwrap().total = Some((files, bytes));
+}
+
+pub(crate) fn current(path: &str, size: u64) {
+    let mut state = STATE.lock().unwrap();
+    state.current.clear();
+    state.current.push_str(path);
+    state.size = size;
+    state.watch = None;
+}
+
+// the current transfer writes the local file `path`
+pub(crate) fn watch(path: &str) {
+    STATE.lock().unwrap().watch = Some(path.to_string());
+}
+
+pub(crate) fn state() -> State {
+    STATE.lock().unwrap().clone()
+}
+
+// prints the status every `every` until the process exits
+pub fn start(every: Duration) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(every);
+        logi!("HEARTBEAT: {}", line());
+    });
+}
+
+fn line() -> String {
+    let (files, bytes) = history::copied();
+    let state = STATE.lock().unwrap();
+    let files = match state.total {
+        Some((total, _)) => format!("{files}/{total}"),
+        None => files.to_string(),
+    };
+    let mut line = format!("{}, {} files ({})", state.phase, files, human_size(bytes));
+    if !state.current.is_empty() {
+        line.push_str(", at ");
+        line.push_str(&state.current);
+    }
+    line
+}
@@ -0,0 +1,283 @@
+// a line per device run in the history file, so scheduled backups can be checked on without
+// reading logs: `adb-sink history` lists the past runs and how each job has been doing.
+// tab separated, appended to after every run
+//   start end job device ok|failed files bytes deleted error
+
+use crate::{human_size, logi, utc, CResult};
+use chainerror::Context;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static FILES: AtomicU64 = AtomicU64::new(0);
+static BYTES: AtomicU64 = AtomicU64::new(0);
+static DELETED: AtomicU64 = AtomicU64::new(0);
+static DEVICE: OnceLock<String> = OnceLock::new();
+
+thread_local! {
+    // what `counts` reports: files and bytes copied, entries deleted and files left unchanged
+    // by this thread, so syncs running side by side, like tests, each see their own
+    static COUNTS: Cell<[u64; 4]> = const { Cell::new([0; 4]) };
+}
+
+fn count(i: usize, n: u64) {
+    COUNTS.with(|c| {
+        let mut counts = c.get();
+        counts[i] += n;
+        c.set(counts);
+    });
+}
+
+pub(crate) fn count_copies(files: u64, bytes: u64) {
+    FILES.fetch_add(files, Ordering::Relaxed);
+    BYTES.fetch_add(bytes, Ordering::Relaxed);
+    count(0, files);
+    count(1, bytes);
+}
+
+// files and bytes copied so far
+pub(crate) fn copied() -> (u64, u64) {
+    (FILES.load(Ordering::Relaxed), BYTES.load(Ordering::Relaxed))
+}
+
+pub(crate) fn count_delete() {
+    DELETED.fetch_add(1, Ordering::Relaxed);
+    count(2, 1);
+}
+
+// a source file the destination already had
+pub(crate) fn count_unchanged() {
+    count(3, 1);
+}
+
+pub(crate) fn counts() -> [u64; 4] {
+    COUNTS.with(Cell::get)
+}
+
+// the serial of the device this run syncs with
+pub fn set_device(serial: &str) {
+    let _ = DEVICE.set(serial.to_string());
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Run {
+    pub start: u64,
+    pub end: u64,
+    pub job: String,
+    pub device: String,
+    pub ok: bool,
+    pub files: u64,
+    pub bytes: u64,
+    pub deleted: u64,
+    // the first line of the error of a failed run
+    pub error: String,
+}
+
+impl Run {
+    fn to_line(&self) -> String {
+        // a tab or newline in a job name or error would split the record
+        let clean = |s: &str| s.replace(['\t', '\n', '\r'], " ");
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            self.start,
+            self.end,
+            clean(&self.job),
+            clean(&self.device),
+            if self.ok { "ok" } else { "failed" },
+            self.files,
+            self.bytes,
+            self.deleted,
+            clean(&self.error)
+        )
+    }
+
+    fn parse(line: &str) -> Option<Self> {
+        let f: Vec<&str> = line.split('\t').collect();
+        let [start, end, job, device, status, files, bytes, deleted, error] = f[..] else {
+            return None;
+        };
+        Some(Self {
+            start: start.parse().ok()?,
+            end: end.parse().ok()?,
+            job: job.to_string(),
+            device: device.to_string(),
+            ok: status == "ok",
+            files: files.parse().ok()?,
+            bytes: bytes.parse().ok()?,
+            deleted: deleted.parse().ok()?,
+            error: error.to_string(),
+        })
+    }
+}
+
+// $ADB_SINK_HISTORY, else adb-sink/history in the user's state directory
+pub fn path() -> Option<PathBuf> {
+    if let Some(p) = std::env::var_os("ADB_SINK_HISTORY") {
+        return Some(PathBuf::from(p));
+    }
+    let dir = std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".local/state")))?;
+    Some(dir.join("adb-sink").join("history"))
+}
+
+// appends the run that started at `start` with what it counted, `error` if it failed
+pub fn record(job: &str, start: u64, error: Option<&str>) -> CResult<()> {
+    let path = path().ok_or("Could not find a state directory, set ADB_SINK_HISTORY")?;
+    let run = Run {
+        start,
+        end: now(),
+        job: job.to_string(),
+        device: DEVICE.get().cloned().unwrap_or_default(),
+        ok: error.is_none(),
+        files: FILES.load(Ordering::Relaxed),
+        bytes: BYTES.load(Ordering::Relaxed),
+        deleted: DELETED.load(Ordering::Relaxed),
+        error: error
+            .and_then(|e| e.lines().next())
+            .unwrap_or_default()
+            .to_string(),
+    };
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).annotate()?;
+    }
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .annotate()?
+        .write_all(run.to_line().as_bytes())
+        .annotate()?;
+    Ok(())
+}
+
+// the recorded runs, oldest first. lines that do not parse are skipped
+pub fn read() -> CResult<Vec<Run>> {
+    let Some(path) = path() else {
+        return Ok(Vec::new());
+    };
+    let s = match std::fs::read_to_string(&path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        r => r.annotate()?,
+    };
+    Ok(s.lines().filter_map(Run::parse).collect())
+}
+
+fn date(secs: u64) -> String {
+    let (year, month, day, hour, min, _) = utc(secs);
+    format!("{year:04}-{month:02}-{day:02} {hour:02}:{min:02}")
+}
+
+pub(crate) fn duration(secs: u64) -> String {
+    match secs {
+        s if s >= 24 * 3600 => format!("{}d{:02}h", s / (24 * 3600), s / 3600 % 24),
+        s if s >= 3600 => format!("{}h{:02}m", s / 3600, s / 60 % 60),
+        s if s >= 60 => format!("{}m{:02}s", s / 60, s % 60),
+        s => format!("{s}s"),
+    }
+}
+
+// `adb-sink history`: the last `limit` runs of `job`, or of all jobs, and a summary per job
+pub fn show(job: Option<&str>, limit: usize) -> CResult<()> {
+    let runs: Vec<Run> = read()
+        .annotate()?
+        .into_iter()
+        .filter(|r| job.is_none() || job == Some(r.job.as_str()))
+        .collect();
+    if runs.is_empty() {
+        logi!("No runs recorded yet");
+        return Ok(());
+    }
+    println!(
+        "{:<16}  {:>6}  {:<6}  {:>6}  {:>10}  {:>7}  JOB",
+        "STARTED (UTC)", "TOOK", "STATUS", "FILES", "SIZE", "DELETED"
+    );
+    for r in runs.iter().skip(runs.len().saturating_sub(limit)) {
+        println!(
+            "{:<16}  {:>6}  {:<6}  {:>6}  {:>10}  {:>7}  {}",
+            date(r.start),
+            duration(r.end.saturating_sub(r.start)),
+            if r.ok { "ok" } else { "FAILED" },
+            r.files,
+            human_size(r.bytes),
+            r.deleted,
+            r.job
+        );
+        if !r.ok {
+            println!("{:>18}{}", "", r.error);
+        }
+    }
+
+    let mut jobs: BTreeMap<&str, Vec<&Run>> = BTreeMap::new();
+    for r in &runs {
+        jobs.entry(&r.job).or_default().push(r);
+    }
+    println!();
+    let now = now();
+    for (job, runs) in jobs {
+        let failed = runs.iter().filter(|r| !r.ok).count();
+        let ok: Vec<&Run> = runs.iter().copied().filter(|r| r.ok).collect();
+        let last = match ok.last() {
+            Some(r) => format!("last success {} ago", duration(now.saturating_sub(r.end))),
+            None => "never succeeded".to_string(),
+        };
+        let avg = |f: fn(&Run) -> u64| ok.iter().map(|r| f(r)).sum::<u64>() / ok.len() as u64;
+        println!("{job}: {} runs, {failed} failed, {last}", runs.len());
+        if !ok.is_empty() {
+            println!(
+                "  a successful run copies {} files ({}) in {} on average",
+                avg(|r| r.files),
+                human_size(avg(|r| r.bytes)),
+                duration(avg(|r| r.end.saturating_sub(r.start)))
+            );
+        }
+        if runs.last().is_some_and(|r| !r.ok) {
+            println!("  the last run failed");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_round_trip() {
+        let run = Run {
+            start: 1714566600,
+            end: 1714566725,
+            job: "photos".to_string(),
+            device: "1A2B".to_string(),
+            ok: false,
+            files: 12,
+            bytes: 34567,
+            deleted: 1,
+            error: "No device\tfound".to_string(),
+        };
+        let back = Run::parse(run.to_line().trim_end_matches('\n')).unwrap();
+        assert_eq!(back.error, "No device found");
+        assert_eq!(
+            Run {
+                error: run.error.replace('\t', " "),
+                ..run
+            },
+            back
+        );
+        assert!(Run::parse("1\t2\tjob").is_none());
+        assert_eq!(duration(3725), "1h02m");
+        assert_eq!(duration(65), "1m05s");
+    }
+}
@@ -0,0 +1,533 @@
+// `adb-sink install APK|DIR`: installs APKs back onto the device, the other half of an APK
+// backup. a directory holding a base.apk is one app split into several APKs and goes through
+// `adb install-multiple`, any other directory is searched for such sets and single APKs. apps
+// the device already has at the same versionCode are skipped, which the AndroidManifest.xml
+// inside each APK says
+//   adb-sink install ~/backups/apks
+
+use crate::adb::AdbCmd;
+use crate::{logi, logw, CResult};
+use chainerror::Context;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+// the android:versionCode and android:versionCodeMajor attribute resource ids
+const VERSION_CODE: u32 = 0x0101021b;
+const VERSION_CODE_MAJOR: u32 = 0x01010576;
+
+pub fn install(path: &Path) -> CResult<()> {
+    let mut sets = Vec::new();
+    collect(path, &mut sets).annotate()?;
+    if sets.is_empty() {
+        return Err(format!("No APKs found in '{}'", path.display()).into());
+    }
+    let installed = installed_versions().annotate()?;
+    let (mut done, mut skipped, mut failed) = (0, 0, 0);
+    for apks in &sets {
+        let base = &apks[0];
+        let manifest = match read_manifest(base) {
+            Ok(m) => Some(m),
+            Err(e) => {
+                logw!(W016: "Could not read the manifest of '{}': {}", base.display(), e);
+                None
+            }
+        };
+        let name = match &manifest {
+            Some((package, _)) => package.clone(),
+            None => base.display().to_string(),
+        };
+        if let Some((package, version)) = &manifest {
+            if installed.get(package) == Some(version) {
+                logi!("SKIP (SAME VERSION {}): {}", version, package);
+                skipped += 1;
+                continue;
+            }
+        }
+        logi!("INSTALL ({} APKs): {}", apks.len(), name);
+        let mut cmd = AdbCmd::new();
+        cmd.arg(if apks.len() > 1 {
+            "install-multiple"
+        } else {
+            "install"
+        })
+        .arg("-r")
+        .args(apks);
+        let op = cmd.output().annotate()?;
+        if op.contains("Failure") || op.contains("failed to install") {
+            logw!(W016: "Installing {} failed: {}", name, op.trim());
+            failed += 1;
+        } else {
+            done += 1;
+        }
+    }
+    logi!(
+        "Installed {} apps, {} already were at the same version",
+        done,
+        skipped
+    );
+    if failed > 0 {
+        return Err(format!("{failed} of {} apps failed to install", sets.len()).into());
+    }
+    Ok(())
+}
+
+// the APK sets under `path`, each with its base APK first
+fn collect(path: &Path, sets: &mut Vec<Vec<PathBuf>>) -> CResult<()> {
+    let is_apk =
+        |p: &Path| p.extension().is_some_and(|e| e.eq_ignore_ascii_case("apk")) && p.is_file();
+    if !path.is_dir() {
+        if !is_apk(path) {
+            return Err(format!("'{}' is not an APK or a directory", path.display()).into());
+        }
+        sets.push(vec![path.to_path_buf()]);
+        return Ok(());
+    }
+    let mut entries: Vec<PathBuf> = std::fs::read_dir(path)
+        .annotate()?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<Result<_, _>>()
+        .annotate()?;
+    entries.sort();
+    let base = path.join("base.apk");
+    if base.is_file() {
+        let splits = entries.into_iter().filter(|p| is_apk(p) && *p != base);
+        sets.push(std::iter::once(base.clone()).chain(splits).collect());
+        return Ok(());
+    }
+    for p in entries {
+        if p.is_dir() {
+            collect(&p, sets).annotate()?;
+        } else if is_apk(&p) {
+            sets.push(vec![p]);
+        }
+    }
+    Ok(())
+}
+
+// package -> versionCode of the installed apps
+fn installed_versions() -> CResult<HashMap<String, u64>> {
+    let op = AdbCmd::run_v(["shell", "pm", "list", "packages", "--show-versioncode"]).annotate()?;
+    let versions: HashMap<String, u64> = op
+        .lines()
+        .filter_map(|l| {
+            let (package, version) = l.strip_prefix("package:")?.split_once(" versionCode:")?;
+            Some((package.to_string(), version.trim().parse().ok()?))
+        })
+        .collect();
+    // before Android 9 pm does not know the option, every app is then installed
+    if versions.is_empty() && op.lines().any(|l| l.starts_with("package:")) {
+        logw!(W021: "The device does not list versionCodes, installing every app");
+    }
+    Ok(versions)
+}
+
+// (package, versionCode) of an APK
+pub fn read_manifest(apk: &Path) -> CResult<(String, u64)> {
+    let xml = zip_member(apk, "AndroidManifest.xml").annotate()?;
+    parse_manifest(&xml).ok_or_else(|| "malformed AndroidManifest.xml".into())
+}
+
+fn u16_at(b: &[u8], i: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(b.get(i..i + 2)?.try_into().ok()?))
+}
+
+fn u32_at(b: &[u8], i: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(b.get(i..i + 4)?.try_into().ok()?))
+}
+
+// the uncompressed content of the member `name` of the zip file at `path`
+fn zip_member(path: &Path, name: &str) -> CResult<Vec<u8>> {
+    let bad = || format!("'{}' is not a valid APK", path.display());
+    let mut f = File::open(path).annotate()?;
+    let len = f.seek(SeekFrom::End(0)).annotate()?;
+    // the end of central directory record, followed by a comment of up to 64 KiB
+    let tail_len = len.min(22 + 0xffff);
+    f.seek(SeekFrom::Start(len - tail_len)).annotate()?;
+    let mut tail = vec![0; tail_len as usize];
+    f.read_exact(&mut tail).annotate()?;
+    let end = tail
+        .windows(4)
+        .rposition(|w| w == b"PK\x05\x06")
+        .ok_or_else(bad)?;
+    let (size, start) = (
+        u32_at(&tail, end + 12).ok_or_else(bad)?,
+        u32_at(&tail, end + 16).ok_or_else(bad)?,
+    );
+    let mut cd = vec![0; size as usize];
+    f.seek(SeekFrom::Start(start as u64)).annotate()?;
+    f.read_exact(&mut cd).annotate()?;
+
+    let mut p = 0;
+    while cd.get(p..p + 4) == Some(&b"PK\x01\x02"[..]) {
+        let field = |off| u16_at(&cd, p + off).map(usize::from).ok_or_else(bad);
+        let (name_len, extra_len, comment_len) = (field(28)?, field(30)?, field(32)?);
+        if cd.get(p + 46..p + 46 + name_len) != Some(name.as_bytes()) {
+            p += 46 + name_len + extra_len + comment_len;
+            continue;
+        }
+        let method = field(10)?;
+        let packed = u32_at(&cd, p + 20).ok_or_else(bad)?;
+        let local = u32_at(&cd, p + 42).ok_or_else(bad)? as u64;
+        // the data starts after the local header, whose extra field may differ
+        let mut h = [0; 30];
+        f.seek(SeekFrom::Start(local)).annotate()?;
+        f.read_exact(&mut h).annotate()?;
+        let offset = 30 + u16_at(&h, 26).unwrap() as u64 + u16_at(&h, 28).unwrap() as u64;
+        f.seek(SeekFrom::Start(local + offset)).annotate()?;
+        let mut data = vec![0; packed as usize];
+        f.read_exact(&mut data).annotate()?;
+        return match method {
+            0 => Ok(data),
+            8 => inflate(&data).ok_or_else(|| bad().into()),
+            _ => Err(format!(
+                "'{name}' in '{}' uses an unknown compression",
+                path.display()
+            )
+            .into()),
+        };
+    }
+    Err(format!("'{}' has no {name}", path.display()).into())
+}
+
+// raw deflate data (RFC 1951) decompressed, after zlib's puff
+fn inflate(data: &[u8]) -> Option<Vec<u8>> {
+    const LBASE: [u16; 29] = [
+        3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115,
+        131, 163, 195, 227, 258,
+    ];
+    const LEXT: [u8; 29] = [
+        0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+    ];
+    const DBASE: [u16; 30] = [
+        1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+        2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+    ];
+    const DEXT: [u8; 30] = [
+        0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12,
+        13, 13,
+    ];
+    // the order code length code lengths are stored in
+    const ORDER: [usize; 19] = [
+        16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+    ];
+
+    let mut r = Bits {
+        data,
+        pos: 0,
+        buf: 0,
+        count: 0,
+    };
+    let mut out = Vec::new();
+    loop {
+        let last = r.bits(1)? == 1;
+        let (lit, dist) = match r.bits(2)? {
+            0 => {
+                // stored, from the next byte on
+                r.buf = 0;
+                r.count = 0;
+                let len = u16_at(data, r.pos)? as usize;
+                out.extend_from_slice(data.get(r.pos + 4..r.pos + 4 + len)?);
+                r.pos += 4 + len;
+                if last {
+                    return Some(out);
+                }
+                continue;
+            }
+            1 => {
+                let mut lengths = [8u8; 288];
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+            }
+            2 => {
+                let nlen = r.bits(5)? as usize + 257;
+                let ndist = r.bits(5)? as usize + 1;
+                let ncode = r.bits(4)? as usize + 4;
+                let mut lengths = [0u8; 19];
+                for &i in &ORDER[..ncode] {
+                    lengths[i] = r.bits(3)? as u8;
+                }
+                let code = Huffman::new(&lengths);
+                let mut lengths = Vec::with_capacity(nlen + ndist);
+                while lengths.len() < nlen + ndist {
+                    let (len, repeat) = match code.decode(&mut r)? {
+                        sym @ 0..=15 => (sym as u8, 1),
+                        16 => (*lengths.last()?, 3 + r.bits(2)?),
+                        17 => (0, 3 + r.bits(3)?),
+                        _ => (0, 11 + r.bits(7)?),
+                    };
+                    lengths.extend(std::iter::repeat(len).take(repeat as usize));
+                }
+                if lengths.len() > nlen + ndist {
+                    return None;
+                }
+                (
+                    Huffman::new(&lengths[..nlen]),
+                    Huffman::new(&lengths[nlen..]),
+                )
+            }
+            _ => return None,
+        };
+        loop {
+            let sym = lit.decode(&mut r)? as usize;
+            if sym < 256 {
+                out.push(sym as u8);
+                continue;
+            } else if sym == 256 {
+                break;
+            }
+            let i = sym - 257;
+            let len = *LBASE.get(i)? as usize + r.bits(*LEXT.get(i)? as u32)? as usize;
+            let d = dist.decode(&mut r)? as usize;
+            let back = *DBASE.get(d)? as usize + r.bits(*DEXT.get(d)? as u32)? as usize;
+            let from = out.len().checked_sub(back)?;
+            // overlapping copies repeat what they just wrote
+            for k in 0..len {
+                out.push(out[from + k]);
+            }
+        }
+        if last {
+            return Some(out);
+        }
+    }
+}
+
+// deflate's bit stream, least significant bit first
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buf: u32,
+    count: u32,
+}
+
+impl Bits<'_> {
+    fn bits(&mut self, n: u32) -> Option<u32> {
+        while self.count < n {
+            self.buf |= (*self.data.get(self.pos)? as u32) << self.count;
+            self.pos += 1;
+            self.count += 8;
+        }
+        let v = self.buf & ((1 << n) - 1);
+        self.buf >>= n;
+        self.count -= n;
+        Some(v)
+    }
+}
+
+// a canonical Huffman code: how many codes there are of each length, and the symbols
+// ordered by code
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0u16; 16];
+        for &l in lengths {
+            counts[l as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut symbols: Vec<(u8, u16)> = (0..)
+            .zip(lengths)
+            .filter(|(_, &l)| l != 0)
+            .map(|(s, &l)| (l, s))
+            .collect();
+        symbols.sort();
+        Self {
+            counts,
+            symbols: symbols.into_iter().map(|(_, s)| s).collect(),
+        }
+    }
+
+    fn decode(&self, r: &mut Bits) -> Option<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= r.bits(1)? as i32;
+            let count = count as i32;
+            if code - first < count {
+                return self.symbols.get((index + code - first) as usize).copied();
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        None
+    }
+}
+
+// the package and versionCode attributes of the <manifest> element of a binary AndroidManifest.xml.
+// attributes are recognized by their resource id, as shrunk APKs may have no names for them
+fn parse_manifest(x: &[u8]) -> Option<(String, u64)> {
+    if u16_at(x, 0)? != 0x0003 {
+        return None;
+    }
+    let mut strings = Vec::new();
+    let mut ids = Vec::new();
+    let mut p = u16_at(x, 2)? as usize;
+    while p + 8 <= x.len() {
+        let (kind, header, size) = (u16_at(x, p)?, u16_at(x, p + 2)? as usize, u32_at(x, p + 4)?);
+        let chunk = x.get(p..p + size as usize)?;
+        match kind {
+            0x0001 => strings = string_pool(chunk)?,
+            0x0180 => {
+                ids = (8..chunk.len())
+                    .step_by(4)
+                    .filter_map(|i| u32_at(chunk, i))
+                    .collect()
+            }
+            // the first element is <manifest>
+            0x0102 => {
+                let (start, each, n) = (
+                    u16_at(chunk, 24)? as usize,
+                    u16_at(chunk, 26)? as usize,
+                    u16_at(chunk, 28)? as usize,
+                );
+                let (mut package, mut code, mut major) = (None, None, 0);
+                for a in (0..n).map(|i| header + start + i * each) {
+                    let name = u32_at(chunk, a + 4)? as usize;
+                    let (raw, kind, data) = (
+                        u32_at(chunk, a + 8)?,
+                        *chunk.get(a + 15)?,
+                        u32_at(chunk, a + 16)?,
+                    );
+                    let id = ids.get(name).copied();
+                    let named = |s: &str| strings.get(name).is_some_and(|n: &String| n == s);
+                    // 0x03 is a string, 0x10 and 0x11 decimal and hexadecimal integers
+                    if id == Some(VERSION_CODE) || (id.is_none() && named("versionCode")) {
+                        code = Some(data).filter(|_| kind == 0x10 || kind == 0x11);
+                    } else if id == Some(VERSION_CODE_MAJOR) {
+                        major = data;
+                    } else if id.is_none() && named("package") {
+                        let s = if raw != u32::MAX { raw } else { data };
+                        package = strings.get(s as usize).cloned();
+                    }
+                }
+                return Some((package?, (major as u64) << 32 | code? as u64));
+            }
+            _ => {}
+        }
+        if size == 0 {
+            return None;
+        }
+        p += size as usize;
+    }
+    None
+}
+
+// the strings of a string pool chunk, UTF-8 or UTF-16
+fn string_pool(c: &[u8]) -> Option<Vec<String>> {
+    let (header, n, flags, start) = (
+        u16_at(c, 2)? as usize,
+        u32_at(c, 8)? as usize,
+        u32_at(c, 16)?,
+        u32_at(c, 20)? as usize,
+    );
+    let utf8 = flags & 0x100 != 0;
+    let mut strings = Vec::with_capacity(n);
+    for i in 0..n {
+        let mut at = start + u32_at(c, header + i * 4)? as usize;
+        let s = if utf8 {
+            // the length in UTF-16 units, then in bytes, each in one or two bytes
+            let mut len = || {
+                let b = *c.get(at)? as usize;
+                at += 1;
+                if b & 0x80 == 0 {
+                    return Some(b);
+                }
+                at += 1;
+                Some((b & 0x7f) << 8 | *c.get(at - 1)? as usize)
+            };
+            len()?;
+            let bytes = len()?;
+            String::from_utf8_lossy(c.get(at..at + bytes)?).into_owned()
+        } else {
+            let mut len = u16_at(c, at)? as usize;
+            at += 2;
+            if len & 0x8000 != 0 {
+                len = (len & 0x7fff) << 16 | u16_at(c, at)? as usize;
+                at += 2;
+            }
+            let units: Vec<u16> = (0..len)
+                .map(|k| u16_at(c, at + k * 2))
+                .collect::<Option<_>>()?;
+            String::from_utf16_lossy(&units)
+        };
+        strings.push(s);
+    }
+    Some(strings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a binary manifest with a UTF-16 string pool and <manifest package versionCode>
+    fn manifest(package: &str, version: u32) -> Vec<u8> {
+        let names = ["versionCode", "package", "manifest", package];
+        let mut data = Vec::new();
+        let mut offsets = Vec::new();
+        for s in names {
+            offsets.extend((data.len() as u32).to_le_bytes());
+            data.extend((s.len() as u16).to_le_bytes());
+            data.extend(s.encode_utf16().flat_map(u16::to_le_bytes));
+            data.extend([0, 0]);
+        }
+        let mut pool = vec![1, 0, 28, 0];
+        pool.extend(((28 + offsets.len() + data.len()) as u32).to_le_bytes());
+        pool.extend((names.len() as u32).to_le_bytes());
+        pool.extend([0; 8]);
+        pool.extend(((28 + offsets.len()) as u32).to_le_bytes());
+        pool.extend([0; 4]);
+        pool.extend(offsets);
+        pool.extend(data);
+
+        // only versionCode has a resource id
+        let mut ids = vec![0x80, 0x01, 8, 0, 12, 0, 0, 0];
+        ids.extend(VERSION_CODE.to_le_bytes());
+
+        let attr = |name: u32, raw: u32, kind: u8, data: u32| {
+            let mut a = u32::MAX.to_le_bytes().to_vec();
+            a.extend(name.to_le_bytes());
+            a.extend(raw.to_le_bytes());
+            a.extend([8, 0, 0, kind]);
+            a.extend(data.to_le_bytes());
+            a
+        };
+        let mut element = vec![2, 1, 16, 0];
+        element.extend((36 + 2 * 20u32).to_le_bytes());
+        element.extend([0; 8]);
+        element.extend(u32::MAX.to_le_bytes());
+        element.extend(2u32.to_le_bytes());
+        element.extend([20, 0, 20, 0, 2, 0, 0, 0, 0, 0, 0, 0]);
+        element.extend(attr(0, u32::MAX, 0x10, version));
+        element.extend(attr(1, 3, 0x03, 3));
+
+        let mut x = vec![3, 0, 8, 0];
+        x.extend(((8 + pool.len() + ids.len() + element.len()) as u32).to_le_bytes());
+        x.extend(pool);
+        x.extend(ids);
+        x.extend(element);
+        x
+    }
+
+    #[test]
+    fn apk_manifest() {
+        assert_eq!(
+            parse_manifest(&manifest("com.example.app", 42)),
+            Some(("com.example.app".to_string(), 42))
+        );
+        assert_eq!(parse_manifest(b"<manifest/>"), None);
+
+        // raw deflate of the text, a fixed Huffman block with back references
+        let fixed = [
+            0x4b, 0x4c, 0x4a, 0x4e, 0x84, 0x21, 0x85, 0x8c, 0xd4, 0x9c, 0x9c, 0x7c, 0x08, 0x09,
+            0x00,
+        ];
+        assert_eq!(inflate(&fixed).unwrap(), b"abcabcabcabc hello hello");
+        let stored = [0x01, 0x03, 0x00, 0xfc, 0xff, b'a', b'b', b'c'];
+        assert_eq!(inflate(&stored).unwrap(), b"abc");
+    }
+}
@@ -1,15 +1,73 @@
 pub mod adb;
+pub mod archive;
 pub mod args;
+pub mod bwlimit;
+pub mod capture;
+pub mod config;
+pub mod cp;
+pub mod crypt;
+pub mod dedup;
+pub mod delta;
+pub mod expire;
+pub mod filter;
+pub mod forward;
 pub mod fs;
+pub mod hashdb;
+pub mod heartbeat;
+pub mod history;
+pub mod install;
+pub mod manifest;
+pub mod mediastore;
+pub mod owners;
+pub mod pause;
+pub mod perms;
+pub mod pipe;
+pub mod plan;
+pub mod preflight;
+pub mod progress;
+#[cfg(any(feature = "webdav", feature = "s3"))]
+mod remote;
+#[cfg(feature = "s3")]
+pub mod s3;
+pub mod scan;
+pub mod scoped;
+pub mod speedtest;
+pub mod store;
+pub mod systemd;
+pub mod template;
+pub mod timings;
+pub mod tools;
+pub mod transform;
 pub mod tree;
+pub mod warnings;
+#[cfg(feature = "watch")]
+pub mod watch;
+#[cfg(feature = "webdav")]
+pub mod webdav;
+pub mod winnames;
 
-use adb::AdbCmd;
+use adb::{is_server_wedged, quote, restart_server, AdbCmd, SERVER_RECOVERIES, SERVER_TIMEOUT};
+use args::Direction;
 use chainerror::Context;
-use fs::{FSCopyFrom, FileMode, FileSystem, SyncFile};
-use std::path::PathBuf;
+use config::config;
+use dedup::{DedupIndex, DedupMode};
+use filter::{Filter, Priority};
+use fs::{FSCopyFrom, FileMode, FileSystem, LocalFS, SyncFile};
+use hashdb::HashDb;
+use owners::OwnerDb;
+use perms::{Chmod, Chown};
+use plan::{Op, Plan};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::OnceLock;
-use tree::{build_tree, diff_trees};
-use typed_path::Utf8UnixPathBuf as UnixPathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use timings::Timings;
+use transform::{apply_all, flatten_name, Case, Transform};
+use tree::{build_tree, diff_trees, remap_tree, Node};
+use typed_path::{Utf8UnixPath as UnixPath, Utf8UnixPathBuf as UnixPathBuf};
+use winnames::{NameDb, WindowsNames};
 
 pub static VERBOSE: OnceLock<bool> = OnceLock::new();
 
@@ -19,19 +77,43 @@ pub fn is_verbose() -> bool {
 
 pub type CResult<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
+// set while stdout carries a file, see `pipe`
+static LOG_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+pub fn log_to_stderr() {
+    LOG_TO_STDERR.store(true, Ordering::Relaxed);
+}
+
+pub fn log_line(prefix: &str, args: std::fmt::Arguments) {
+    progress::interrupt(|| {
+        if LOG_TO_STDERR.load(Ordering::Relaxed) {
+            eprintln!("{prefix}{args}");
+        } else {
+            println!("{prefix}{args}");
+        }
+    });
+}
+
 #[macro_export]
 macro_rules! logi {
     ($($arg:tt)*) => {{
-		print!("[INFO] ");
-        println!($($arg)*);
+        $crate::log_line("[INFO] ", format_args!($($arg)*));
     }};
 }
 
 #[macro_export]
 macro_rules! logw {
+    // a warning with a code of `warnings`, counted for the summary and --fail-on-warning
+    ($code:ident: $($arg:tt)*) => {{
+        $crate::warnings::count(stringify!($code));
+        let _: &str = $crate::warnings::$code;
+        $crate::log_line(
+            concat!("[WARN ", stringify!($code), "] "),
+            format_args!($($arg)*),
+        );
+    }};
     ($($arg:tt)*) => {{
-		print!("[WARN] ");
-        println!($($arg)*);
+        $crate::log_line("[WARN] ", format_args!($($arg)*));
     }};
 }
 
@@ -39,29 +121,500 @@ macro_rules! logw {
 macro_rules! logv {
     ($($arg:tt)*) => {{
         if is_verbose() {
-            print!("[VERBOSE] ");
-            println!($($arg)*);
+            $crate::log_line("[VERBOSE] ", format_args!($($arg)*));
         }
     }};
 }
 
+// order of the copy queue. without one, files are copied in tree diff order and
+// new directories are copied in one go
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Order {
+    // many small files finish early, an interrupted run leaves fewer expensive gaps
+    SmallFirst,
+    LargeFirst,
+    Path,
+}
+
+// what to do with a changed destination file that is newer than its source, likely a local
+// edit the copy would lose
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OnConflict {
+    #[default]
+    Overwrite,
+    // keep the destination file, copy nothing
+    Skip,
+    // keep the destination file under a `.conflict-<mtime>` name, then copy
+    Rename,
+    // ask for each file, on a terminal
+    Ask,
+}
+
+// the name `path` is kept under by --on-conflict rename, its mtime before the extension:
+// `notes.conflict-20240501-123005.txt`
+fn conflict_name(path: &UnixPath, mtime: u32) -> UnixPathBuf {
+    let (year, month, day, hour, min, sec) = utc(mtime as u64);
+    let stamp = format!("conflict-{year:04}{month:02}{day:02}-{hour:02}{min:02}{sec:02}");
+    let name = path.file_name().unwrap_or_default();
+    let name = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => format!("{stem}.{stamp}.{ext}"),
+        _ => format!("{name}.{stamp}"),
+    };
+    path.with_file_name(name)
+}
+
+// how far source mtimes are ahead of those of the destination, e.g. a FAT sdcard storing
+// local time that is then read as UTC. the source's mtimes are moved back by it before they
+// are compared or copied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeOffset {
+    // in seconds
+    Fixed(i64),
+    // from the files both sides have, see `detect_time_offset`
+    Auto,
+}
+
+impl std::str::FromStr for TimeOffset {
+    type Err = String;
+
+    // `auto`, or `+HH:MM` / `-HH:MM`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "auto" {
+            return Ok(Self::Auto);
+        }
+        let err = || format!("time offset must be like '+02:00', '-05:30' or 'auto': '{s}'");
+        let (sign, hm) = match s.as_bytes().first() {
+            Some(b'+') => (1, &s[1..]),
+            Some(b'-') => (-1, &s[1..]),
+            _ => (1, s),
+        };
+        let (h, m) = hm.split_once(':').ok_or_else(err)?;
+        let h: i64 = h.parse().map_err(|_| err())?;
+        let m: i64 = m.parse().map_err(|_| err())?;
+        if h > 23 || m > 59 {
+            return Err(err());
+        }
+        Ok(Self::Fixed(sign * (h * 3600 + m * 60)))
+    }
+}
+
+// how many of the files a run copied to hash on both ends afterwards
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VerifySample {
+    Count(usize),
+    Percent(f64),
+}
+
+impl std::str::FromStr for VerifySample {
+    type Err = String;
+
+    // `N` or `P%`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("sample must be a number of files or a percentage like '5%': '{s}'");
+        match s.strip_suffix('%') {
+            Some(p) => match p.parse::<f64>() {
+                Ok(p) if p > 0.0 && p <= 100.0 => Ok(Self::Percent(p)),
+                _ => Err(err()),
+            },
+            None => match s.parse::<usize>() {
+                Ok(n) if n > 0 => Ok(Self::Count(n)),
+                _ => Err(err()),
+            },
+        }
+    }
+}
+
+impl VerifySample {
+    // how many of `copied` files to hash
+    fn of(self, copied: usize) -> usize {
+        match self {
+            Self::Count(n) => n.min(copied),
+            Self::Percent(p) => ((copied as f64 * p / 100.0).ceil() as usize).min(copied),
+        }
+    }
+}
+
+// `k` distinct indices below `n` picked at random, in ascending order. a different pick every
+// run, so repeated runs cover different files
+fn sample_indices(n: usize, k: usize) -> Vec<usize> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    let mut x = (nanos ^ (u64::from(std::process::id()) << 32)) | 1;
+    let mut all: Vec<usize> = (0..n).collect();
+    for i in 0..k {
+        // xorshift64
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        let j = i + (x % (n - i) as u64) as usize;
+        all.swap(i, j);
+    }
+    all.truncate(k);
+    all.sort_unstable();
+    all
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct SinkOpts {
+    pub delete_if_dne: bool,
+    // also delete destination files the filter excludes
+    pub delete_excluded: bool,
+    pub filter: Filter,
+    pub set_time: bool,
+    pub transforms: Vec<Transform>,
+    pub flatten: bool,
+    pub case: Case,
+    pub dedup: Option<DedupMode>,
+    pub order: Option<Order>,
+    // --first and --last patterns, applied after `order`
+    pub priority: Priority,
+    pub xattrs: bool,
+    pub prune_empty_dirs: bool,
+    // refuse to mirror an empty source over a non-empty destination
+    pub safe_delete: bool,
+    pub timings: bool,
+    // bytes hashed at both ends of files whose size and mtime match
+    pub spot_check: Option<u64>,
+    // record the sha256 of copied files in the destination's hash database
+    pub hash_db: bool,
+    // only fix mtimes of files whose content matches, copy and delete nothing
+    pub metadata_only: bool,
+    // recreate the source's parent directories under the destination, like rsync -R
+    pub relative: bool,
+    // sync the contents of the source directory into the destination, not a directory named
+    // like the source. the source of a restore is a snapshot or archive, its name means nothing
+    pub contents_only: bool,
+    // write `<dir>.sha256sums` next to the synced directory afterwards, the destination is local
+    pub write_manifest: bool,
+    // record the uid and gid of copied files in the destination's owner database
+    pub record_owners: bool,
+    // chown copied files to what the owner database next to the source has for them
+    pub restore_owners: bool,
+    // applied to every file and directory the sync creates
+    pub chmod: Chmod,
+    pub chown: Option<Chown>,
+    pub time_offset: Option<TimeOffset>,
+    // link files that are hard links of each other on the source instead of copying each
+    pub hard_links: bool,
+    // refuse runs that would copy or delete more files than this
+    pub max_files: Option<usize>,
+    // replace characters Windows does not allow in destination names with this, recording the
+    // original names in the destination's name database
+    pub windows_names: Option<WindowsNames>,
+    // give files the original names the name database next to the source has for them
+    pub restore_names: bool,
+    // write what the sync would do to this file instead of doing it, for `adb-sink apply`
+    pub plan_out: Option<PathBuf>,
+    // hash this many of the copied files on both ends once the run is done
+    pub verify_sample: Option<VerifySample>,
+    pub on_conflict: OnConflict,
+    // delete each source file once its copy on the destination hashes the same
+    pub remove_source_files: bool,
+    // copy files whose transfer stalled once more at the end of the run
+    pub retry_stalled: bool,
+    // only log the copies and deletes the sync would do
+    pub dry_run: bool,
+}
+
+// what a sync did, logged at the end of the run
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SinkReport {
+    // whether source and destination differed
+    pub changed: bool,
+    pub copied: u64,
+    pub bytes: u64,
+    pub deleted: u64,
+    // source files the destination already had up to date
+    pub skipped: u64,
+    // coded warnings, the errors a run goes on after
+    pub warnings: u64,
+    pub duration: Duration,
+}
+
+impl SinkReport {
+    pub fn log(&self) {
+        logi!(
+            "Copied {} files ({}), deleted {}, {} up to date, {} warnings, took {}",
+            self.copied,
+            human_size(self.bytes),
+            self.deleted,
+            self.skipped,
+            self.warnings,
+            history::duration(self.duration.as_secs())
+        );
+    }
+}
+
 pub fn sink<SRC: FileSystem, DEST: FileSystem + FSCopyFrom<SRC>>(
     src_fs: &mut SRC,
     dest_fs: &mut DEST,
     src_path: PathBuf,
     dst_path: PathBuf,
-    delete_if_dne: bool,
-    ignore_dirs: Vec<Box<str>>,
-    set_time: bool,
-) -> CResult<()> {
+    opts: SinkOpts,
+) -> CResult<SinkReport> {
+    let start = Instant::now();
+    let before = history::counts();
+    let warned = warnings::total();
+    let changed = sink_(src_fs, dest_fs, src_path, dst_path, opts).annotate()?;
+    let [copied, bytes, deleted, skipped] = history::counts();
+    Ok(SinkReport {
+        changed,
+        copied: copied - before[0],
+        bytes: bytes - before[1],
+        deleted: deleted - before[2],
+        skipped: skipped - before[3],
+        warnings: warnings::total() - warned,
+        duration: start.elapsed(),
+    })
+}
+
+// returns whether source and destination differed
+fn sink_<SRC: FileSystem, DEST: FileSystem + FSCopyFrom<SRC>>(
+    src_fs: &mut SRC,
+    dest_fs: &mut DEST,
+    src_path: PathBuf,
+    dst_path: PathBuf,
+    opts: SinkOpts,
+) -> CResult<bool> {
+    let SinkOpts {
+        delete_if_dne,
+        delete_excluded,
+        filter,
+        set_time,
+        transforms,
+        flatten,
+        case,
+        dedup,
+        order,
+        priority,
+        xattrs,
+        prune_empty_dirs,
+        safe_delete,
+        timings,
+        spot_check,
+        hash_db,
+        metadata_only,
+        relative,
+        contents_only,
+        write_manifest,
+        record_owners,
+        restore_owners,
+        chmod,
+        chown,
+        time_offset,
+        hard_links,
+        max_files,
+        windows_names,
+        restore_names,
+        plan_out,
+        verify_sample,
+        on_conflict,
+        remove_source_files,
+        retry_stalled,
+        dry_run,
+    } = opts;
+    let mut copier = Copier {
+        dedup: None,
+        xattrs,
+        timings: Timings::new(timings),
+        hash_db: None,
+        manifest: None,
+        owners: None,
+        restore_owners,
+        chmod,
+        chown,
+        restore_names,
+        names: None,
+        links: None,
+        verify_sample,
+        copied: Vec::new(),
+        on_conflict,
+        remove_source: remove_source_files,
+        stalled: Vec::new(),
+        retry_stalled,
+    };
     let source_file_name = src_path.file_name().unwrap().to_str().unwrap().to_string();
-    let dest_file_name = dst_path.file_name().unwrap().to_str().unwrap().to_string();
+    let dest_file_name = dst_path
+        .file_name()
+        .map(|n| n.to_str().unwrap().to_string())
+        .unwrap_or_default();
 
-    let src_path = UnixPathBuf::from(src_path.to_str().unwrap());
-    let dst_path = UnixPathBuf::from(dst_path.to_str().unwrap()).join(&source_file_name);
-    dest_fs.mkdir(&dst_path).annotate()?;
+    let src_path = src_path.to_str().unwrap();
+    let dst_parent = UnixPathBuf::from(dst_path.to_str().unwrap());
+    // where the source lands
+    let dst_base = if relative {
+        dst_parent.join(relative_dirs(src_path))
+    } else {
+        dst_parent.clone()
+    };
+    let src_path = UnixPathBuf::from(src_path.replacen("/./", "/", 1));
+    let Some(mut src_sf) = src_fs.stat(&src_path).annotate()? else {
+        return Err(format!("Source '{}' does not exist", src_path).into());
+    };
+    if hash_db {
+        copier.hash_db = Some(HashDb::open(&dst_parent).annotate()?);
+    }
+    if record_owners {
+        copier.owners = Some(OwnerDb::open(&dst_parent).annotate()?);
+    } else if restore_owners {
+        let root = src_path.parent().unwrap_or(src_path.as_path());
+        copier.owners = Some(OwnerDb::open(root).annotate()?);
+    }
+    if let Some(names) = windows_names {
+        let rep = names.replacement;
+        if winnames::is_invalid(rep) || rep == '/' || rep == '.' || rep == ' ' {
+            return Err(format!("--invalid-char-replacement cannot be '{rep}'").into());
+        }
+        copier.names = Some(NameDb::open(&dst_parent).annotate()?);
+    } else if restore_names {
+        let root = src_path.parent().unwrap_or(src_path.as_path());
+        let db = NameDb::open(root).annotate()?;
+        copier.names = (!db.is_empty()).then_some(db);
+    }
+    if src_sf.mode == FileMode::File {
+        if write_manifest {
+            return Err("--write-manifest needs a directory source".into());
+        }
+        if plan_out.is_some() {
+            return Err("--plan-out needs a directory source".into());
+        }
+        // one file is too few to detect an offset from
+        if let Some(TimeOffset::Fixed(offset)) = time_offset {
+            src_sf.timestamp = (src_sf.timestamp as i64 - offset).clamp(0, u32::MAX as i64) as u32;
+        }
+        let name = match &copier.names {
+            Some(db) if restore_names => {
+                let base = src_path.parent().unwrap_or(src_path.as_path());
+                db.restore(base, UnixPath::new(&source_file_name))
+                    .to_string()
+            }
+            _ => source_file_name,
+        };
+        let mut name = case.apply(apply_all(&transforms, &name));
+        if let (Some(db), Some(names)) = (&mut copier.names, windows_names) {
+            name = db.rename(&dst_base, &name, names);
+        }
+        let dst_path = dst_base.join(name);
+        if !dry_run {
+            dest_fs.mkdir(&dst_base).annotate()?;
+        }
+        let reason = match dest_fs.stat(&dst_path).annotate()? {
+            Some(dest_sf) if metadata_only => {
+                return fix_mtime(dest_fs, &dest_sf, &src_sf);
+            }
+            None if metadata_only => {
+                logv!("SKIP (METADATA ONLY): '{}'", src_sf.path);
+                return Ok(true);
+            }
+            None => "DNE",
+            // not through the conflict policy, renaming and asking change things
+            Some(dest_sf) if dry_run => {
+                match update_reason(src_fs, dest_fs, &dest_sf, &src_sf, spot_check).annotate()? {
+                    Some(reason) => reason,
+                    None => return Ok(false),
+                }
+            }
+            Some(dest_sf) => {
+                let reason = copier
+                    .copy_reason(src_fs, dest_fs, &dest_sf, &src_sf, spot_check)
+                    .annotate()?;
+                match reason {
+                    Some(reason) => reason,
+                    None => {
+                        history::count_unchanged();
+                        copier
+                            .remove_source(src_fs, dest_fs, &src_path, &dst_path, false)
+                            .annotate()?;
+                        return Ok(false);
+                    }
+                }
+            }
+        };
+        logi!("COPY FILE ({reason}): {} -> {}", src_path, dst_path);
+        if dry_run {
+            logi!("Dry run, nothing was changed");
+            return Ok(true);
+        }
+        heartbeat::phase("copying");
+        heartbeat::total(1, src_sf.size);
+        let timestamp = if set_time {
+            Some(src_sf.timestamp)
+        } else {
+            None
+        };
+        copy_file(
+            src_fs,
+            dest_fs,
+            &mut copier,
+            &src_path,
+            &dst_path,
+            timestamp,
+            src_sf.size,
+        )
+        .annotate()?;
+        copier.finish(src_fs, dest_fs).annotate()?;
+        return Ok(true);
+    }
+    let dst_path = if contents_only {
+        dst_base.clone()
+    } else {
+        dst_base.join(&source_file_name)
+    };
+    if !dry_run {
+        dest_fs.mkdir(&dst_path).annotate()?;
+    }
+    if write_manifest {
+        copier.manifest = Some(dst_path.clone());
+    }
+    let remapped =
+        flatten || !transforms.is_empty() || case != Case::Preserve || copier.names.is_some();
+    // excluded files are then missing only from the source tree, so they get deleted. a watch
+    // rerun still only touches the paths that changed
+    let no_filter = Filter {
+        only: filter.only.clone(),
+        ..Default::default()
+    };
+    let dest_filter = if delete_excluded { &no_filter } else { &filter };
 
-    let src_root = build_tree(
+    // nothing needs both whole trees (a sorted copy queue, hard link groups spanning
+    // directories), so transfers start with the first directory compared instead of after two
+    // full scans
+    if !remapped
+        && order.is_none()
+        && priority.is_empty()
+        && dedup.is_none()
+        && !prune_empty_dirs
+        && !metadata_only
+        && !safe_delete
+        && time_offset.is_none()
+        && !hard_links
+        && max_files.is_none()
+        && plan_out.is_none()
+        && !dry_run
+    {
+        let stream = Stream {
+            src_prefix: &src_path,
+            dst_prefix: &dst_path,
+            filter: &filter,
+            dest_filter,
+            set_time,
+            spot_check,
+            delete_if_dne,
+        };
+        // scanning and copying go together here, how many files there are is never known
+        heartbeat::phase("syncing");
+        let changed = stream
+            .dir(src_fs, dest_fs, &mut copier, &src_path, &dst_path)
+            .annotate()?;
+        copier.timings.phase("transfers");
+        copier.finish(src_fs, dest_fs).annotate()?;
+        return Ok(changed);
+    }
+
+    heartbeat::phase("scanning");
+    let mut src_root = build_tree(
         src_fs,
         SyncFile {
             mode: FileMode::Dir,
@@ -71,128 +624,1597 @@ pub fn sink<SRC: FileSystem, DEST: FileSystem + FSCopyFrom<SRC>>(
             path: src_path.clone().into_boxed_path(),
         },
         &src_path,
+        &filter,
     )
     .annotate()?;
-    let dest_root = build_tree(
-        dest_fs,
-        SyncFile {
-            mode: FileMode::Dir,
-            size: 0,
-            timestamp: 0,
-            name: dest_file_name.into_boxed_str(),
-            path: dst_path.clone().into_boxed_path(),
+    copier.timings.phase("source scan");
+    let dest_sf = SyncFile {
+        mode: FileMode::Dir,
+        size: 0,
+        timestamp: 0,
+        name: dest_file_name.into_boxed_str(),
+        path: dst_path.clone().into_boxed_path(),
+    };
+    // a dry run did not create the destination
+    let mut dest_root = if dry_run && dest_fs.stat(&dst_path).annotate()?.is_none() {
+        Node::new(dest_sf, &dst_path)
+    } else {
+        build_tree(dest_fs, dest_sf, &dst_path, dest_filter).annotate()?
+    };
+    tree::keep_marked(&src_root, &mut dest_root, dest_filter);
+    copier.timings.phase("destination scan");
+    let time_offset = match time_offset {
+        Some(TimeOffset::Fixed(offset)) => offset,
+        Some(TimeOffset::Auto) => match detect_time_offset(&src_root, &dest_root) {
+            Some(offset) => {
+                logi!(
+                    "Source mtimes are {} ahead of the destination's, correcting them",
+                    format_offset(offset)
+                );
+                offset
+            }
+            None => 0,
         },
-        &dst_path,
-    )
-    .annotate()?;
+        None => 0,
+    };
+    if time_offset != 0 {
+        tree::shift_times(&mut src_root, -time_offset);
+    }
+    if safe_delete && src_root.total_size().1 == 0 && dest_root.total_size().1 != 0 {
+        return Err(format!(
+            "Source '{}' has no files, refusing to mirror it over a non-empty destination",
+            src_path
+        )
+        .into());
+    }
+    let mut src_root = if remapped {
+        let mut taken = HashSet::new();
+        let names = &mut copier.names;
+        remap_tree(src_root, |p| {
+            let p = match names {
+                Some(db) if restore_names => db.restore(&src_path, p),
+                _ => p.to_path_buf(),
+            };
+            let p = case.apply(apply_all(&transforms, p.as_str()));
+            let p = if flatten {
+                flatten_name(&p, &mut taken)
+            } else {
+                p
+            };
+            match (names.as_mut(), windows_names) {
+                (Some(db), Some(win)) => UnixPathBuf::from(db.rename(&dst_path, &p, win)),
+                _ => UnixPathBuf::from(p),
+            }
+        })
+    } else {
+        src_root
+    };
+
+    if prune_empty_dirs {
+        tree::prune_empty_dirs(&mut src_root);
+    }
 
     let (dest_doesnt_have, src_doesnt_have, both_have_files) = diff_trees(&dest_root, &src_root);
+    let mut changed =
+        !dest_doesnt_have.is_empty() || (delete_if_dne && !src_doesnt_have.is_empty());
+    copier.timings.phase("diff");
+    // spot checks and conflicts may still spare some of the changed ones
+    let (new_bytes, new_files) = dest_doesnt_have
+        .iter()
+        .map(|n| n.total_size())
+        .fold((0, 0), |t, (bytes, files)| (t.0 + bytes, t.1 + files));
+    let changed_files: Vec<u64> = both_have_files
+        .iter()
+        .filter(|(d, s)| d.size != s.size || s.timestamp > d.timestamp)
+        .map(|(_, s)| s.size)
+        .collect();
+    heartbeat::total(
+        (new_files + changed_files.len()) as u64,
+        new_bytes + changed_files.iter().sum::<u64>(),
+    );
+    // a dry run shows what --max-files would refuse
+    if let (Some(max), false) = (max_files, metadata_only || dry_run) {
+        let deleted: &[&Node] = if delete_if_dne { &src_doesnt_have } else { &[] };
+        check_max_files(max, &src_path, &dest_doesnt_have, deleted, &both_have_files).annotate()?;
+    }
+    if let Some(out) = plan_out {
+        let deleted: &[&Node] = if delete_if_dne { &src_doesnt_have } else { &[] };
+        let plan = Plan::build(
+            src_fs,
+            dest_fs,
+            &src_path,
+            &dst_path,
+            set_time,
+            spot_check,
+            &dest_doesnt_have,
+            deleted,
+            &both_have_files,
+        )
+        .annotate()?;
+        std::fs::write(&out, plan.to_text()).annotate()?;
+        logi!(
+            "Wrote a plan of {} operations to '{}', nothing was changed. `adb-sink apply` does them",
+            plan.ops.len(),
+            out.display()
+        );
+        return Ok(!plan.ops.is_empty());
+    }
+    if dry_run {
+        let deleted: &[&Node] = if delete_if_dne { &src_doesnt_have } else { &[] };
+        print_deletion_plan(deleted);
+        let plan = Plan::build(
+            src_fs,
+            dest_fs,
+            &src_path,
+            &dst_path,
+            set_time,
+            spot_check,
+            &dest_doesnt_have,
+            deleted,
+            &both_have_files,
+        )
+        .annotate()?;
+        print_dry_run(&plan);
+        return Ok(!plan.ops.is_empty());
+    }
+    if hard_links {
+        copier.links = Some(Links::find(src_fs, &src_root, &both_have_files).annotate()?);
+        copier.timings.phase("hard links");
+    }
+    if let Some(mode) = dedup {
+        copier.dedup = Some(DedupIndex::build(dest_fs, &dst_parent, mode).annotate()?);
+        copier.timings.phase("dedup index");
+    }
+
+    if metadata_only {
+        for n in &dest_doesnt_have {
+            logv!("SKIP (METADATA ONLY): '{}'", n.sf.path);
+        }
+        for (dest_file, src_file) in &both_have_files {
+            changed |= fix_mtime(dest_fs, dest_file, src_file).annotate()?;
+        }
+        copier.timings.phase("mtimes");
+        copier.finish(src_fs, dest_fs).annotate()?;
+        return Ok(changed);
+    }
 
     if delete_if_dne {
+        heartbeat::phase("deleting");
+        print_deletion_plan(&src_doesnt_have);
         for n in &src_doesnt_have {
-            match n.sf.mode {
-                FileMode::File => {
-                    logi!("DEL FILE: '{}'", n.sf.path);
-                    dest_fs.rm(&n.sf.path)
+            delete_node(dest_fs, &mut copier, n).annotate()?;
+        }
+        copier.timings.phase("deletes");
+    }
+
+    heartbeat::phase("copying");
+    if order.is_some() || !priority.is_empty() {
+        // plan the whole copy set up front so it can be sorted
+        let mut queue: Vec<(&str, &SyncFile, UnixPathBuf)> = Vec::new();
+        for n in &dest_doesnt_have {
+            let mut files = Vec::new();
+            make_dirs(dest_fs, n, &dst_path, &mut files).annotate()?;
+            for f in files {
+                queue.push(("DNE", &f.sf, dst_path.join(&f.strip_path)));
+            }
+        }
+        for (dest_file, src_file) in &both_have_files {
+            match copier
+                .copy_reason(src_fs, dest_fs, dest_file, src_file, spot_check)
+                .annotate()?
+            {
+                Some(reason) => {
+                    changed = true;
+                    queue.push((reason, src_file, dest_file.path.to_path_buf()));
                 }
-                FileMode::Dir => {
-                    logi!("DEL DIR: '{}'", n.sf.path);
-                    dest_fs.rm_dir(&n.sf.path)
+                None => {
+                    history::count_unchanged();
+                    copier
+                        .remove_source(src_fs, dest_fs, &src_file.path, &dest_file.path, false)
+                        .annotate()?
                 }
-                FileMode::Symlink => todo!(),
             }
-            .annotate()?
         }
+        match order {
+            Some(Order::SmallFirst) => queue.sort_by_key(|(_, sf, _)| sf.size),
+            Some(Order::LargeFirst) => queue.sort_by_key(|(_, sf, _)| std::cmp::Reverse(sf.size)),
+            Some(Order::Path) => {
+                queue.sort_by(|(_, a, _), (_, b, _)| a.path.as_str().cmp(b.path.as_str()))
+            }
+            None => {}
+        }
+        // stable, files of the same rank keep the order above
+        if !priority.is_empty() {
+            queue.sort_by_cached_key(|(_, sf, _)| {
+                let rel = sf.path.strip_prefix(&src_path);
+                priority.rank(rel.map_or(sf.path.as_str(), |p| p.as_str()))
+            });
+        }
+        for (reason, sf, to) in queue {
+            let timestamp = if set_time { Some(sf.timestamp) } else { None };
+            if reason == "DNE" {
+                copy_new_file(src_fs, dest_fs, &mut copier, sf, &to, timestamp).annotate()?;
+            } else {
+                logi!("COPY FILE ({reason}): {} -> {}", sf.path, to);
+                copy_file(
+                    src_fs,
+                    dest_fs,
+                    &mut copier,
+                    &sf.path,
+                    &to,
+                    timestamp,
+                    sf.size,
+                )
+                .annotate()?;
+            }
+        }
+        copier.timings.phase("transfers");
+        copier.finish(src_fs, dest_fs).annotate()?;
+        return Ok(changed);
     }
 
+    // remapped directories have no counterpart on the source, pruned ones may have empty
+    // subdirectories there, `adb pull -a` would keep uncorrected mtimes and pull marked
+    // subdirectories whole
+    let per_file = remapped
+        || prune_empty_dirs
+        || (set_time && time_offset != 0)
+        || !filter.markers.is_empty();
     for n in &dest_doesnt_have {
-        let from = src_path.join(&n.strip_path);
-        let to = dst_path.join(&n.strip_path);
-        if ignore_dirs.iter().any(|g| n.strip_path.starts_with(&**g)) {
-            logi!("SKIP DIR (IGNORED): {}", from);
-            continue;
+        copy_new(
+            src_fs,
+            dest_fs,
+            &mut copier,
+            n,
+            &dst_path,
+            set_time,
+            per_file,
+        )
+        .annotate()?;
+    }
+
+    for (dest_file, src_file) in &both_have_files {
+        changed |= update_file(
+            src_fs,
+            dest_fs,
+            &mut copier,
+            dest_file,
+            src_file,
+            set_time,
+            spot_check,
+        )
+        .annotate()?;
+    }
+    copier.timings.phase("transfers");
+    copier.finish(src_fs, dest_fs).annotate()?;
+    Ok(changed)
+}
+
+// the state of a sync that diffs and transfers one directory at a time
+struct Stream<'a> {
+    src_prefix: &'a UnixPath,
+    dst_prefix: &'a UnixPath,
+    filter: &'a Filter,
+    dest_filter: &'a Filter,
+    set_time: bool,
+    spot_check: Option<u64>,
+    delete_if_dne: bool,
+}
+
+impl Stream<'_> {
+    // syncs the directory `src` into `dst`, descending into the directories both have.
+    // returns whether they differed
+    fn dir<SRC: FileSystem, DEST: FileSystem + FSCopyFrom<SRC>>(
+        &self,
+        src_fs: &mut SRC,
+        dest_fs: &mut DEST,
+        copier: &mut Copier,
+        src: &UnixPath,
+        dst: &UnixPath,
+    ) -> CResult<bool> {
+        let src_children =
+            tree::list_children(src_fs, src, self.src_prefix, self.filter).annotate()?;
+        let mut dest_children =
+            tree::list_children(dest_fs, dst, self.dst_prefix, self.dest_filter).annotate()?;
+        let is_marker =
+            |n: &Node| n.sf.mode == FileMode::File && self.dest_filter.is_marker(&n.sf.name);
+        // marked since the last run, see `tree::keep_marked`
+        if !src_children.is_empty() && src_children.values().all(is_marker) {
+            dest_children.retain(|_, n| is_marker(n));
         }
-        let timestamp = if set_time { Some(n.sf.timestamp) } else { None };
-        match n.sf.mode {
-            FileMode::File => {
-                logi!("COPY FILE (DNE): {} -> {}", from, to);
-                if cfg!(target_os = "windows") && n.sf.name.ends_with('.') {
-                    logw!(
-                        "Windows does not support file names ending with a dot: {}",
-                        n.sf.name
-                    );
+        let mut changed = false;
+        let mut deletes = Vec::new();
+        let mut src_children = src_children.into_iter().peekable();
+        let mut dest_children = dest_children.into_iter().peekable();
+        loop {
+            let order = match (src_children.peek(), dest_children.peek()) {
+                (Some((s, _)), Some((d, _))) => s.cmp(d),
+                (Some(_), None) => std::cmp::Ordering::Less,
+                (None, Some(_)) => std::cmp::Ordering::Greater,
+                (None, None) => break,
+            };
+            match order {
+                std::cmp::Ordering::Less => {
+                    let (_, n) = src_children.next().unwrap();
+                    changed = true;
+                    // only a new directory has to be scanned as a whole
+                    let n = match n.sf.mode {
+                        FileMode::Dir => {
+                            build_tree(src_fs, n.sf, self.src_prefix, self.filter).annotate()?
+                        }
+                        _ => n,
+                    };
+                    // `adb pull` of the whole directory would include marked ones
+                    copy_new(
+                        src_fs,
+                        dest_fs,
+                        copier,
+                        &n,
+                        self.dst_prefix,
+                        self.set_time,
+                        !self.filter.markers.is_empty(),
+                    )
+                    .annotate()?;
+                }
+                std::cmp::Ordering::Greater => {
+                    let (_, n) = dest_children.next().unwrap();
+                    if self.delete_if_dne {
+                        changed = true;
+                        deletes.push(match n.sf.mode {
+                            FileMode::Dir => {
+                                build_tree(dest_fs, n.sf, self.dst_prefix, self.dest_filter)
+                                    .annotate()?
+                            }
+                            _ => n,
+                        });
+                    }
+                }
+                std::cmp::Ordering::Equal => {
+                    let (_, s) = src_children.next().unwrap();
+                    let (_, d) = dest_children.next().unwrap();
+                    changed |= match (s.sf.mode, d.sf.mode) {
+                        (FileMode::File, FileMode::File) => update_file(
+                            src_fs,
+                            dest_fs,
+                            copier,
+                            &d.sf,
+                            &s.sf,
+                            self.set_time,
+                            self.spot_check,
+                        )
+                        .annotate()?,
+                        (FileMode::Dir, FileMode::Dir) => self
+                            .dir(src_fs, dest_fs, copier, &s.sf.path, &d.sf.path)
+                            .annotate()?,
+                        _ => {
+                            logw!(
+                                W010: "'{}' is a file on one side and a directory on the other, skipping",
+                                s.sf.path
+                            );
+                            false
+                        }
+                    };
                 }
-                dest_fs.copy(&from, &to, timestamp)
             }
-            FileMode::Dir => {
-                logi!("COPY DIR (DNE): {} -> {}", to, from);
-                dest_fs.copy_dir(&from, &to, timestamp)
+        }
+        if !deletes.is_empty() {
+            print_deletion_plan(&deletes.iter().collect::<Vec<_>>());
+            for n in &deletes {
+                delete_node(dest_fs, copier, n).annotate()?;
             }
-            FileMode::Symlink => todo!(),
         }
-        .annotate()?;
+        Ok(changed)
     }
+}
 
-    for (dest_file, src_file) in &both_have_files {
-        let reason = if dest_file.size != src_file.size {
-            "SIZE"
-        } else if src_file.timestamp > dest_file.timestamp {
-            "NEWER"
+// deletes a destination file or directory the source does not have. logged once it is gone,
+// so a device refusing the delete does not show up as deleted
+fn delete_node<DEST: FileSystem>(dest_fs: &mut DEST, copier: &mut Copier, n: &Node) -> CResult<()> {
+    match n.sf.mode {
+        FileMode::File => {
+            dest_fs.rm(&n.sf.path).annotate()?;
+            logi!("DEL FILE: '{}'", n.sf.path);
+        }
+        FileMode::Dir => {
+            dest_fs.rm_dir(&n.sf.path).annotate()?;
+            logi!("DEL DIR: '{}'", n.sf.path);
+        }
+        FileMode::Symlink => {
+            logw!(W002: "Skipping symlink {}", n.sf.path);
+            return Ok(());
+        }
+    }
+    history::count_delete();
+    if let Some(db) = &mut copier.hash_db {
+        db.remove(&n.sf.path);
+    }
+    if let (Some(db), false) = (&mut copier.owners, copier.restore_owners) {
+        db.remove(&n.sf.path);
+    }
+    if let (Some(db), false) = (&mut copier.names, copier.restore_names) {
+        db.remove(&n.sf.path);
+    }
+    Ok(())
+}
+
+// copies a file or directory the destination does not have to its place under `dst_path`.
+// `per_file` directories are copied file by file instead of with one `adb pull`
+fn copy_new<SRC: FileSystem, DEST: FileSystem + FSCopyFrom<SRC>>(
+    src_fs: &mut SRC,
+    dest_fs: &mut DEST,
+    copier: &mut Copier,
+    n: &Node,
+    dst_path: &UnixPath,
+    set_time: bool,
+    per_file: bool,
+) -> CResult<()> {
+    let from = &n.sf.path;
+    let to = dst_path.join(&n.strip_path);
+    let timestamp = if set_time { Some(n.sf.timestamp) } else { None };
+    match n.sf.mode {
+        FileMode::File => copy_new_file(src_fs, dest_fs, copier, &n.sf, &to, timestamp),
+        // deduplication, xattrs, the hash database, owners, permissions, hard links, removing
+        // sources and file timeouts have to look at every file too
+        FileMode::Dir
+            if per_file
+                || copier.remove_source
+                || fs::file_timeout().is_some()
+                || bwlimit::is_set()
+                || progress::enabled()
+                || copier.dedup.is_some()
+                || copier.xattrs
+                || copier.hash_db.is_some()
+                || copier.owners.is_some()
+                || copier.sets_perms()
+                || copier.links.is_some() =>
+        {
+            logi!("COPY DIR (DNE): {} -> {}", n.strip_path, to);
+            copy_files(src_fs, dest_fs, copier, n, dst_path, timestamp)
+        }
+        FileMode::Dir => {
+            pause::wait_if_paused();
+            copier.check_stop().annotate()?;
+            systemd::status(&format!("Copying {from}"));
+            heartbeat::current(from.as_str(), n.total_size().0);
+            logi!("COPY DIR (DNE): {} -> {}", from, to);
+            let start = Instant::now();
+            let r = dest_fs.copy_dir(from, &to, timestamp);
+            copier
+                .timings
+                .op(start.elapsed(), || format!("COPY DIR {from}"));
+            r.annotate()?;
+            let files = n.files();
+            history::count_copies(files.len() as u64, files.iter().map(|f| f.sf.size).sum());
+            forward::flush().annotate()?;
+            for f in files {
+                let to = dst_path.join(&f.strip_path);
+                capture::apply(dest_fs, &to).annotate()?;
+                copier.record_copy(&f.sf.path, &to);
+            }
+            Ok(())
+        }
+        FileMode::Symlink => {
+            logw!(W002: "Skipping symlink {}", n.sf.path);
+            Ok(())
+        }
+    }
+}
+
+// copies `src_file` over `dest_file` if it changed, returns whether it did
+fn update_file<SRC: FileSystem, DEST: FileSystem + FSCopyFrom<SRC>>(
+    src_fs: &mut SRC,
+    dest_fs: &mut DEST,
+    copier: &mut Copier,
+    dest_file: &SyncFile,
+    src_file: &SyncFile,
+    set_time: bool,
+    spot_check: Option<u64>,
+) -> CResult<bool> {
+    let Some(reason) = copier
+        .copy_reason(src_fs, dest_fs, dest_file, src_file, spot_check)
+        .annotate()?
+    else {
+        history::count_unchanged();
+        copier
+            .remove_source(src_fs, dest_fs, &src_file.path, &dest_file.path, false)
+            .annotate()?;
+        return Ok(false);
+    };
+    logi!(
+        "COPY FILE ({reason}): {} -> {}",
+        src_file.path,
+        dest_file.path
+    );
+    let timestamp = if set_time {
+        Some(src_file.timestamp)
+    } else {
+        None
+    };
+    copy_file(
+        src_fs,
+        dest_fs,
+        copier,
+        &src_file.path,
+        &dest_file.path,
+        timestamp,
+        src_file.size,
+    )
+    .annotate()?;
+    Ok(true)
+}
+
+// hashes every file under `src_path` and its copy under `dst_path`, returns how many copies
+// are missing or differ
+pub fn verify<SRC: FileSystem, DEST: FileSystem>(
+    src_fs: &mut SRC,
+    dest_fs: &mut DEST,
+    src_path: &UnixPath,
+    dst_path: &UnixPath,
+    filter: &Filter,
+) -> CResult<usize> {
+    let Some(src_sf) = src_fs.stat(src_path).annotate()? else {
+        return Err(format!("Source '{}' does not exist", src_path).into());
+    };
+    let root = build_tree(src_fs, src_sf, src_path, filter).annotate()?;
+    let files = root.files();
+    let mut bad = 0;
+    let tos: Vec<UnixPathBuf> = files.iter().map(|f| dst_path.join(&f.strip_path)).collect();
+    let mut present = Vec::new();
+    for (f, to) in files.iter().zip(&tos) {
+        match dest_fs.stat(to).annotate()? {
+            None => {
+                logw!(W006: "MISSING: {}", to);
+                bad += 1;
+            }
+            Some(_) => present.push((&*f.sf.path, to.as_path())),
+        }
+    }
+    // each side hashes all of its files in one go, local ones on all cores, device ones with
+    // a shell command per chunk instead of a round trip per file
+    let (froms, tos): (Vec<&UnixPath>, Vec<&UnixPath>) = present.into_iter().unzip();
+    let src_hashes = src_fs.sha256_many(&froms).annotate()?;
+    let dest_hashes = dest_fs.sha256_many(&tos).annotate()?;
+    for ((to, src_hash), dest_hash) in tos.iter().zip(src_hashes).zip(dest_hashes) {
+        if src_hash != dest_hash {
+            logw!(W005: "CORRUPT: {}", to);
+            bad += 1;
         } else {
-            logv!("SKIP: '{}'", src_file.path);
+            logv!("OK: {}", to);
+        }
+    }
+    logi!("Verified {} files, {} bad", files.len(), bad);
+    Ok(bad)
+}
+
+// parent directories of `src` for --relative. they start after a `/./` in it, else after
+// the shared storage root for device paths, else at the root
+fn relative_dirs(src: &str) -> &str {
+    const STORAGE_ROOTS: [&str; 3] = ["/sdcard/", "/storage/self/primary/", "/storage/emulated/0/"];
+    let path = match src.split_once("/./") {
+        Some((_, rest)) => rest,
+        None => STORAGE_ROOTS
+            .iter()
+            .find_map(|root| src.strip_prefix(root))
+            .unwrap_or(src),
+    };
+    let path = path.trim_matches('/');
+    path.rsplit_once('/').map_or("", |(dirs, _)| dirs)
+}
+
+// the first directory of `path`, relative to the root of the transfer
+fn top_dir(path: &str, is_dir: bool) -> &str {
+    match path.split_once('/') {
+        Some((top, _)) => top,
+        None if is_dir => path,
+        None => ".",
+    }
+}
+
+// refuses a run that would copy or delete more than `max` files, saying where they are.
+// updates are counted by size and mtime, --spot-check may find more
+fn check_max_files(
+    max: usize,
+    src_path: &UnixPath,
+    new: &[&Node],
+    deleted: &[&Node],
+    both_have: &[(&SyncFile, &SyncFile)],
+) -> CResult<()> {
+    // top directory -> (new, updated, deleted)
+    let mut by_dir: BTreeMap<&str, (usize, usize, usize)> = BTreeMap::new();
+    for n in new {
+        let is_dir = n.sf.mode == FileMode::Dir;
+        by_dir
+            .entry(top_dir(n.strip_path.as_str(), is_dir))
+            .or_default()
+            .0 += n.total_size().1;
+    }
+    for (dest_file, src_file) in both_have {
+        if dest_file.size != src_file.size || src_file.timestamp > dest_file.timestamp {
+            let rel = src_file.path.strip_prefix(src_path);
+            let rel = rel.map_or(src_file.path.as_str(), |p| p.as_str());
+            by_dir.entry(top_dir(rel, false)).or_default().1 += 1;
+        }
+    }
+    for n in deleted {
+        let is_dir = n.sf.mode == FileMode::Dir;
+        by_dir
+            .entry(top_dir(n.strip_path.as_str(), is_dir))
+            .or_default()
+            .2 += n.total_size().1;
+    }
+    let (new, updated, deleted) = by_dir
+        .values()
+        .fold((0, 0, 0), |t, c| (t.0 + c.0, t.1 + c.1, t.2 + c.2));
+    let total = new + updated + deleted;
+    if total <= max {
+        return Ok(());
+    }
+    let mut dirs: Vec<_> = by_dir.into_iter().collect();
+    dirs.sort_by_key(|(_, c)| std::cmp::Reverse(c.0 + c.1 + c.2));
+    // on stderr next to the error it explains, not in the log on stdout
+    progress::interrupt(|| {
+        eprintln!(
+            "[WARN] This run would copy {new} new files, update {updated} and delete {deleted}:"
+        );
+        for (dir, (new, updated, deleted)) in dirs.iter().take(10) {
+            eprintln!("[WARN]   {dir}/: {new} new, {updated} updated, {deleted} deleted");
+        }
+        if dirs.len() > 10 {
+            eprintln!("[WARN]   and {} more directories", dirs.len() - 10);
+        }
+    });
+    Err(format!(
+        "{total} files to transfer or delete is more than --max-files {max}, is the source right?"
+    )
+    .into())
+}
+
+// the copies and deletes of `plan` as a sync would log them, and what they add up to
+fn print_dry_run(plan: &Plan) {
+    let (mut copies, mut bytes, mut deletes) = (0, 0, 0);
+    for op in &plan.ops {
+        match op {
+            Op::Delete { path, dir, .. } => {
+                deletes += 1;
+                let kind = if *dir { "DIR" } else { "FILE" };
+                logi!("DEL {kind}: '{path}'");
+            }
+            Op::Mkdir { path } => logv!("MKDIR: '{}'", path),
+            Op::Copy {
+                reason,
+                from,
+                size,
+                to,
+                ..
+            } => {
+                copies += 1;
+                bytes += size;
+                logi!("COPY FILE ({reason}): {} -> {}", from, to);
+            }
+        }
+    }
+    logi!(
+        "Dry run, would copy {} files ({}) and delete {} entries, nothing was changed",
+        copies,
+        human_size(bytes),
+        deletes
+    );
+}
+
+// lists what the delete pass is about to remove, grouped by directory
+fn print_deletion_plan(nodes: &[&Node]) {
+    if nodes.is_empty() {
+        return;
+    }
+    let mut by_dir: BTreeMap<&str, Vec<(&str, u64, Option<usize>)>> = BTreeMap::new();
+    let mut total = 0;
+    for n in nodes {
+        let (bytes, files) = n.total_size();
+        total += bytes;
+        let dir = n.strip_path.parent().map_or("", |p| p.as_str());
+        let files = (n.sf.mode == FileMode::Dir).then_some(files);
+        by_dir
+            .entry(if dir.is_empty() { "." } else { dir })
+            .or_default()
+            .push((&*n.sf.name, bytes, files));
+    }
+    logi!(
+        "Deleting {} entries, {} reclaimable:",
+        nodes.len(),
+        human_size(total)
+    );
+    for (dir, mut entries) in by_dir {
+        logi!("  {}/", dir);
+        entries.sort_unstable();
+        for (name, bytes, files) in entries {
+            match files {
+                Some(files) => logi!("    {}/ ({} files, {})", name, files, human_size(bytes)),
+                None => logi!("    {} ({})", name, human_size(bytes)),
+            }
+        }
+    }
+}
+
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+// (year, month, day, hour, minute, second) of a unix timestamp in UTC, after
+// Howard Hinnant's civil_from_days
+pub(crate) fn utc(secs: u64) -> (i64, u32, u32, u32, u32, u32) {
+    let (days, rem) = (secs / 86400, secs % 86400);
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    let rem = rem as u32;
+    (year, month, day, rem / 3600, rem / 60 % 60, rem % 60)
+}
+
+// the inverse of `utc`, after days_from_civil
+pub(crate) fn unix_time(year: i64, month: u32, day: u32, hour: u32, min: u32, sec: u32) -> u64 {
+    let y = year - i64::from(month <= 2);
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (if month > 2 { month - 3 } else { month + 9 }) as i64;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    (days * 86400) as u64 + (hour * 3600 + min * 60 + sec) as u64
+}
+
+// why `src_file` has to be copied over `dest_file`, if at all. with `spot_check`, files
+// that look unchanged still get that many bytes at both ends compared, which catches
+// most truncation and corruption for a fraction of a full hash
+pub(crate) fn update_reason<SRC: FileSystem, DEST: FileSystem>(
+    src_fs: &mut SRC,
+    dest_fs: &mut DEST,
+    dest_file: &SyncFile,
+    src_file: &SyncFile,
+    spot_check: Option<u64>,
+) -> CResult<Option<&'static str>> {
+    if dest_file.size != src_file.size {
+        return Ok(Some("SIZE"));
+    } else if src_file.timestamp > dest_file.timestamp && !capture::keeps_time(&dest_file.path) {
+        return Ok(Some("NEWER"));
+    }
+    if let Some(bytes) = spot_check {
+        let size = src_file.size;
+        let src_hash = src_fs.sha256_ends(&src_file.path, size, bytes).annotate()?;
+        let dest_hash = dest_fs
+            .sha256_ends(&dest_file.path, size, bytes)
+            .annotate()?;
+        if src_hash != dest_hash {
+            return Ok(Some("SPOT CHECK"));
+        }
+    }
+    logv!("SKIP: '{}'", src_file.path);
+    Ok(None)
+}
+
+// the constant offset between the mtimes of same sized files on both sides, if most of those
+// whose mtimes differ at all differ by the same whole quarter hour of at most 14 hours, as a
+// timezone mixup does. FAT stores mtimes in 2 second steps, so they may be off by a bit more
+fn detect_time_offset(src: &Node, dest: &Node) -> Option<i64> {
+    let dest_files: HashMap<&UnixPath, &SyncFile> = dest
+        .files()
+        .into_iter()
+        .map(|n| (n.strip_path.as_path(), &n.sf))
+        .collect();
+    let mut offsets: HashMap<i64, usize> = HashMap::new();
+    let mut differing = 0;
+    for n in src.files() {
+        let Some(d) = dest_files.get(n.strip_path.as_path()) else {
             continue;
         };
-        logi!(
-            "COPY FILE ({reason}): {} -> {}",
-            src_file.path,
+        if d.size != n.sf.size || d.timestamp == n.sf.timestamp {
+            continue;
+        }
+        differing += 1;
+        let diff = n.sf.timestamp as i64 - d.timestamp as i64;
+        let quarters = (diff as f64 / 900.0).round() as i64;
+        if quarters != 0 && quarters.abs() <= 14 * 4 && (diff - quarters * 900).abs() <= 2 {
+            *offsets.entry(quarters * 900).or_default() += 1;
+        }
+    }
+    let (offset, count) = offsets.into_iter().max_by_key(|(_, count)| *count)?;
+    (count >= 10 && count * 10 >= differing * 9).then_some(offset)
+}
+
+fn format_offset(seconds: i64) -> String {
+    let sign = if seconds < 0 { '-' } else { '+' };
+    let minutes = seconds.abs() / 60;
+    format!("{sign}{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+// for --metadata-only: gives `dest_file` the mtime of `src_file`, or its capture time, if their
+// sizes match. returns whether the mtimes differed
+fn fix_mtime<DEST: FileSystem>(
+    dest_fs: &mut DEST,
+    dest_file: &SyncFile,
+    src_file: &SyncFile,
+) -> CResult<bool> {
+    if dest_file.size != src_file.size {
+        logw!(
+            W005: "'{}' differs in size from the source, leaving it alone",
             dest_file.path
         );
+        Ok(true)
+    } else if capture::keeps_time(&dest_file.path) {
+        capture::apply(dest_fs, &dest_file.path).annotate()?;
+        Ok(false)
+    } else if dest_file.timestamp != src_file.timestamp {
+        logi!("SET MTIME: {}", dest_file.path);
         dest_fs
-            .copy(
-                &src_file.path,
-                &dest_file.path,
-                if set_time {
-                    Some(src_file.timestamp)
-                } else {
-                    None
-                },
-            )
+            .set_mtime(&dest_file.path, src_file.timestamp)
             .annotate()?;
+        Ok(true)
+    } else {
+        logv!("SKIP: '{}'", src_file.path);
+        Ok(false)
+    }
+}
+
+// creates the destination directories of `n` and collects the files under it
+fn make_dirs<'n, DEST: FileSystem>(
+    dest_fs: &mut DEST,
+    n: &'n Node,
+    dst_path: &UnixPath,
+    files: &mut Vec<&'n Node>,
+) -> CResult<()> {
+    match n.sf.mode {
+        FileMode::File => files.push(n),
+        FileMode::Dir => {
+            dest_fs.mkdir(&dst_path.join(&n.strip_path)).annotate()?;
+            for e in n.entries.values() {
+                make_dirs(dest_fs, e, dst_path, files).annotate()?;
+            }
+        }
+        FileMode::Symlink => logw!(W002: "Skipping symlink {}", n.sf.path),
+    }
+    Ok(())
+}
+
+// copies a file the destination does not have, unless its content already
+// exists somewhere under the destination and deduplication is on
+fn copy_new_file<SRC: FileSystem, DEST: FileSystem + FSCopyFrom<SRC>>(
+    src_fs: &mut SRC,
+    dest_fs: &mut DEST,
+    copier: &mut Copier,
+    sf: &SyncFile,
+    to: &UnixPath,
+    timestamp: Option<u32>,
+) -> CResult<()> {
+    if let Some(existing) = copier.links.as_ref().and_then(|l| l.target(&sf.path)) {
+        logi!("LINK FILE (HARD LINK OF '{}'): {}", existing, to);
+        dest_fs.hard_link(&existing, to).annotate()?;
+        if let Some(db) = &mut copier.hash_db {
+            db.record(dest_fs, to).annotate()?;
+        }
+        copier
+            .carry_owner(src_fs, dest_fs, &sf.path, to)
+            .annotate()?;
+        copier
+            .remove_source(src_fs, dest_fs, &sf.path, to, true)
+            .annotate()?;
+        return Ok(());
+    }
+    if let Some(index) = &mut copier.dedup {
+        // candidates are hashed, so they have to be complete
+        forward::flush().annotate()?;
+        if let Some(existing) = index.find(src_fs, dest_fs, sf).annotate()? {
+            match index.mode {
+                DedupMode::Skip => {
+                    logi!("SKIP FILE (DUPLICATE OF '{}'): {}", existing, sf.path);
+                    return Ok(());
+                }
+                DedupMode::Hardlink => {
+                    logi!("LINK FILE (DUPLICATE): {} -> {}", existing, to);
+                    dest_fs.hard_link(&existing, to).annotate()?;
+                    copier.set_perms(dest_fs, to, false).annotate()?;
+                    if let Some(db) = &mut copier.hash_db {
+                        db.record(dest_fs, to).annotate()?;
+                    }
+                    return Ok(());
+                }
+                DedupMode::Copy => {
+                    logi!("COPY FILE (DUPLICATE): {} -> {}", existing, to);
+                    dest_fs.copy_within(&existing, to).annotate()?;
+                    if let Some(timestamp) = timestamp {
+                        dest_fs.set_mtime(to, timestamp).annotate()?;
+                    }
+                    index.add(to, sf.size);
+                    if let Some(db) = &mut copier.hash_db {
+                        db.record(dest_fs, to).annotate()?;
+                    }
+                    copier.set_perms(dest_fs, to, false).annotate()?;
+                    return Ok(());
+                }
+            }
+        }
+        index.add(to, sf.size);
+    }
+    logi!("COPY FILE (DNE): {} -> {}", sf.path, to);
+    copy_file(src_fs, dest_fs, copier, &sf.path, to, timestamp, sf.size).annotate()?;
+    if let Some(links) = &mut copier.links {
+        links.copied(&sf.path, to);
+    }
+    Ok(())
+}
+
+// hard link groups of the source, by the (device, inode) their files share
+struct Links {
+    ids: HashMap<UnixPathBuf, (u64, u64)>,
+    // where the first file of each group is on the destination
+    targets: HashMap<(u64, u64), UnixPathBuf>,
+}
+
+impl Links {
+    fn find<SRC: FileSystem>(
+        src_fs: &mut SRC,
+        src_root: &Node,
+        both_have_files: &[(&SyncFile, &SyncFile)],
+    ) -> CResult<Self> {
+        let files = src_root.files();
+        let paths: Vec<&UnixPath> = files.iter().map(|n| &*n.sf.path).collect();
+        let ids: HashMap<UnixPathBuf, (u64, u64)> = paths
+            .iter()
+            .zip(src_fs.link_ids(&paths).annotate()?)
+            .filter_map(|(p, id)| Some((p.to_path_buf(), id?)))
+            .collect();
+        let mut targets = HashMap::new();
+        // new files can link to group members the destination already has, unless those
+        // are about to be replaced
+        for (dest_file, src_file) in both_have_files {
+            let Some(id) = ids.get(&*src_file.path) else {
+                continue;
+            };
+            if dest_file.size == src_file.size && src_file.timestamp <= dest_file.timestamp {
+                targets
+                    .entry(*id)
+                    .or_insert_with(|| dest_file.path.to_path_buf());
+            }
+        }
+        logv!(
+            "{} files are hard links, of {} inodes",
+            ids.len(),
+            ids.values().collect::<HashSet<_>>().len()
+        );
+        Ok(Self { ids, targets })
+    }
+
+    // the destination file `from` should be a hard link of, if one of its group is there
+    fn target(&self, from: &UnixPath) -> Option<UnixPathBuf> {
+        self.targets.get(self.ids.get(from)?).cloned()
+    }
+
+    fn copied(&mut self, from: &UnixPath, to: &UnixPath) {
+        if let Some(id) = self.ids.get(from) {
+            self.targets.entry(*id).or_insert_with(|| to.to_path_buf());
+        }
+    }
+}
+
+// state of the per-file copy helpers for one run
+struct Copier {
+    dedup: Option<DedupIndex>,
+    xattrs: bool,
+    timings: Timings,
+    hash_db: Option<HashDb>,
+    // local directory to write a manifest of once everything is copied
+    manifest: Option<UnixPathBuf>,
+    // recorded to with --owner on pulls, read from on pushes
+    owners: Option<OwnerDb>,
+    restore_owners: bool,
+    chmod: Chmod,
+    chown: Option<Chown>,
+    restore_names: bool,
+    links: Option<Links>,
+    // original names of files renamed for Windows, recorded to on pulls, read from on pushes
+    names: Option<NameDb>,
+    verify_sample: Option<VerifySample>,
+    // source and destination of every copied file, only recorded with a `verify_sample`
+    copied: Vec<(UnixPathBuf, UnixPathBuf)>,
+    on_conflict: OnConflict,
+    remove_source: bool,
+    // copies given up by --file-timeout, tried once more at the end with `retry_stalled`
+    stalled: Vec<Stalled>,
+    retry_stalled: bool,
+}
+
+struct Stalled {
+    from: UnixPathBuf,
+    to: UnixPathBuf,
+    timestamp: Option<u32>,
+    size: u64,
+}
+
+impl Copier {
+    fn finish<SRC: FileSystem, DEST: FileSystem + FSCopyFrom<SRC>>(
+        &mut self,
+        src_fs: &mut SRC,
+        dest_fs: &mut DEST,
+    ) -> CResult<()> {
+        heartbeat::phase("finishing");
+        if self.retry_stalled && !self.stalled.is_empty() {
+            let stalled = std::mem::take(&mut self.stalled);
+            logi!("Retrying {} files that stalled", stalled.len());
+            for s in stalled {
+                logi!("RETRY FILE (STALLED): {} -> {}", s.from, s.to);
+                copy_file(src_fs, dest_fs, self, &s.from, &s.to, s.timestamp, s.size).annotate()?;
+            }
+        }
+        forward::flush().annotate()?;
+        self.save_dbs().annotate()?;
+        if let Some(root) = &self.manifest {
+            manifest::write(&mut LocalFS, root).annotate()?;
+        }
+        self.timings.report();
+        if let Some(sample) = self.verify_sample {
+            self.verify(src_fs, dest_fs, sample).annotate()?;
+        }
+        if let Some(first) = self.stalled.first() {
+            let n = self.stalled.len();
+            return Err(format!(
+                "{n} files stalled and were skipped, the first '{}'",
+                first.from
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    // `update_reason`, with --on-conflict applied to destination files newer than their source
+    fn copy_reason<SRC: FileSystem, DEST: FileSystem>(
+        &self,
+        src_fs: &mut SRC,
+        dest_fs: &mut DEST,
+        dest_file: &SyncFile,
+        src_file: &SyncFile,
+        spot_check: Option<u64>,
+    ) -> CResult<Option<&'static str>> {
+        let Some(reason) =
+            update_reason(src_fs, dest_fs, dest_file, src_file, spot_check).annotate()?
+        else {
+            return Ok(None);
+        };
+        if dest_file.timestamp <= src_file.timestamp {
+            return Ok(Some(reason));
+        }
+        let policy = match self.on_conflict {
+            OnConflict::Ask => ask_conflict(&dest_file.path).annotate()?,
+            policy => policy,
+        };
+        match policy {
+            OnConflict::Skip => {
+                logw!(
+                    W007: "CONFLICT (KEPT): '{}' is newer than the source",
+                    dest_file.path
+                );
+                Ok(None)
+            }
+            OnConflict::Rename => {
+                let kept = conflict_name(&dest_file.path, dest_file.timestamp);
+                logw!(
+                    W007: "CONFLICT (RENAMED): '{}' kept as '{}'",
+                    dest_file.path,
+                    kept
+                );
+                dest_fs.copy_within(&dest_file.path, &kept).annotate()?;
+                Ok(Some("CONFLICT"))
+            }
+            _ => Ok(Some(reason)),
+        }
+    }
+
+    // with --remove-source-files, deletes `from` once `to` hashes the same. a fresh copy that
+    // does not is an error, a file left alone as it differs is kept
+    fn remove_source<SRC: FileSystem, DEST: FileSystem>(
+        &self,
+        src_fs: &mut SRC,
+        dest_fs: &mut DEST,
+        from: &UnixPath,
+        to: &UnixPath,
+        copied: bool,
+    ) -> CResult<()> {
+        if !self.remove_source {
+            return Ok(());
+        }
+        forward::flush().annotate()?;
+        if src_fs.sha256(from).annotate()? != dest_fs.sha256(to).annotate()? {
+            if copied {
+                return Err(format!("'{to}' differs from its source after copying").into());
+            }
+            logw!(W005: "KEEP SOURCE (DIFFERS): {}", from);
+            return Ok(());
+        }
+        logv!("DEL SOURCE: '{}'", from);
+        src_fs.rm(from).annotate()?;
+        history::count_delete();
+        Ok(())
+    }
 
-        if cfg!(target_os = "windows") && dest_file.name.ends_with('.') {
-            logw!(
-                "Windows does not support file names ending with a dot: {}",
-                src_file.name
+    fn record_copy(&mut self, from: &UnixPath, to: &UnixPath) {
+        if self.verify_sample.is_some() {
+            self.copied.push((from.to_path_buf(), to.to_path_buf()));
+        }
+    }
+
+    // hashes a random sample of the copied files on both ends
+    fn verify<SRC: FileSystem, DEST: FileSystem>(
+        &self,
+        src_fs: &mut SRC,
+        dest_fs: &mut DEST,
+        sample: VerifySample,
+    ) -> CResult<()> {
+        let k = sample.of(self.copied.len());
+        if k == 0 {
+            return Ok(());
+        }
+        let picked: Vec<_> = sample_indices(self.copied.len(), k)
+            .into_iter()
+            .map(|i| &self.copied[i])
+            .collect();
+        let from: Vec<&UnixPath> = picked.iter().map(|(from, _)| from.as_path()).collect();
+        let to: Vec<&UnixPath> = picked.iter().map(|(_, to)| to.as_path()).collect();
+        let want = src_fs.sha256_many(&from).annotate()?;
+        let got = dest_fs.sha256_many(&to).annotate()?;
+        let bad: Vec<_> = to
+            .iter()
+            .zip(want.iter().zip(&got))
+            .filter(|(_, (w, g))| w != g)
+            .map(|(to, _)| to)
+            .collect();
+        for to in &bad {
+            logw!(W005: "DIFFERS: {}", to);
+        }
+        if !bad.is_empty() {
+            let n = bad.len();
+            return Err(format!("{n} of {k} sampled files differ from their source").into());
+        }
+        if k == self.copied.len() {
+            logi!("Verified all {} copied files", k);
+        } else {
+            // the rule of three: with no failure in k random picks, fewer than 3/k of the
+            // copies are bad at 95% confidence
+            logi!(
+                "Verified {} of {} copied files, so with 95% confidence under {:.1}% are bad",
+                k,
+                self.copied.len(),
+                300.0 / k as f64
             );
         }
+        Ok(())
+    }
+
+    // ends the sync before the next transfer once SIGTERM arrived, with the hash database saved
+    fn check_stop(&self) -> CResult<()> {
+        if !pause::stop_requested() {
+            return Ok(());
+        }
+        systemd::notify("STOPPING=1");
+        forward::flush().annotate()?;
+        self.save_dbs().annotate()?;
+        Err("Stopped by SIGTERM, every file copied so far is complete".into())
+    }
+
+    fn sets_perms(&self) -> bool {
+        !self.chmod.is_empty() || self.chown.is_some()
+    }
+
+    // --chmod and --chown of a file or directory the sync created
+    fn set_perms<DEST: FileSystem>(
+        &self,
+        dest_fs: &mut DEST,
+        to: &UnixPath,
+        is_dir: bool,
+    ) -> CResult<()> {
+        if !self.chmod.is_empty() {
+            let mode = dest_fs.get_mode(to).annotate()?;
+            let new = self.chmod.apply(mode, is_dir);
+            if new != mode {
+                logv!("CHMOD {:o}: {}", new, to);
+                dest_fs.set_mode(to, new).annotate()?;
+            }
+        }
+        if let Some(Chown { uid, gid }) = self.chown {
+            dest_fs.set_owner(to, uid, gid).annotate()?;
+        }
+        Ok(())
+    }
+
+    // the databases this run wrote to
+    fn save_dbs(&self) -> CResult<()> {
+        if let Some(db) = &self.hash_db {
+            db.save().annotate()?;
+        }
+        if let (Some(db), false) = (&self.owners, self.restore_owners) {
+            db.save().annotate()?;
+        }
+        // only written to on pulls, saving unchanged ones does nothing
+        if let Some(db) = &self.names {
+            db.save().annotate()?;
+        }
+        Ok(())
+    }
+
+    // with --owner, records the owner of `from` or gives `to` the recorded one
+    fn carry_owner<SRC: FileSystem, DEST: FileSystem>(
+        &mut self,
+        src_fs: &mut SRC,
+        dest_fs: &mut DEST,
+        from: &UnixPath,
+        to: &UnixPath,
+    ) -> CResult<()> {
+        let Some(db) = &mut self.owners else {
+            return Ok(());
+        };
+        if !self.restore_owners {
+            db.record(to, src_fs.get_owner(from).annotate()?);
+            return Ok(());
+        }
+        match db.get(from) {
+            Some((uid, gid)) => {
+                logv!("CHOWN {}:{}: {}", uid, gid, to);
+                dest_fs.set_owner(to, Some(uid), Some(gid)).annotate()?;
+            }
+            None => logw!(W018: "No owner recorded for '{}', leaving it as it is", from),
+        }
+        Ok(())
+    }
+}
+
+// what to do with the newer destination file `path`, asked on the terminal
+fn ask_conflict(path: &UnixPath) -> CResult<OnConflict> {
+    if !std::io::stdin().is_terminal() {
+        return Err("--on-conflict ask needs a terminal, pick skip, overwrite or rename".into());
+    }
+    loop {
+        print!("'{path}' is newer than the source. [s]kip, [o]verwrite or [r]ename it? ");
+        std::io::stdout().flush().annotate()?;
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer).annotate()? == 0 {
+            return Ok(OnConflict::Skip);
+        }
+        match answer.trim() {
+            "s" | "skip" => return Ok(OnConflict::Skip),
+            "o" | "overwrite" => return Ok(OnConflict::Overwrite),
+            "r" | "rename" => return Ok(OnConflict::Rename),
+            _ => {}
+        }
+    }
+}
+
+// copies one file, along with its extended attributes if enabled
+fn copy_file<SRC: FileSystem, DEST: FileSystem + FSCopyFrom<SRC>>(
+    src_fs: &mut SRC,
+    dest_fs: &mut DEST,
+    copier: &mut Copier,
+    from: &UnixPath,
+    to: &UnixPath,
+    timestamp: Option<u32>,
+    size: u64,
+) -> CResult<()> {
+    pause::wait_if_paused();
+    copier.check_stop().annotate()?;
+    systemd::status(&format!("Copying {from}"));
+    heartbeat::current(from.as_str(), size);
+    let start = Instant::now();
+    let r = dest_fs.copy(from, to, timestamp);
+    copier
+        .timings
+        .op(start.elapsed(), || format!("COPY {from}"));
+    match r {
+        Err(e) if fs::is_stalled(&*e) => {
+            logw!(W008: "STALLED: {} ({}), skipping it", from, e);
+            // the partial copy would look like a finished one to the next run
+            let _ = dest_fs.rm(to);
+            copier.stalled.push(Stalled {
+                from: from.to_path_buf(),
+                to: to.to_path_buf(),
+                timestamp,
+                size,
+            });
+            return Ok(());
+        }
+        r => r.annotate()?,
+    }
+    history::count_copies(1, size);
+    bwlimit::pace(size, start.elapsed());
+    copier.record_copy(from, to);
+    if copier.xattrs
+        || copier.hash_db.is_some()
+        || copier.owners.is_some()
+        || copier.sets_perms()
+        || capture::keeps_time(to)
+    {
+        // these need the copy to be complete
+        forward::flush().annotate()?;
+    }
+    capture::apply(dest_fs, to).annotate()?;
+    if copier.xattrs {
+        let attrs = src_fs.get_xattrs(from).annotate()?;
+        if !attrs.is_empty() {
+            logv!("SET XATTRS ({}): {}", attrs.len(), to);
+            dest_fs.set_xattrs(to, &attrs).annotate()?;
+        }
     }
+    if let Some(db) = &mut copier.hash_db {
+        db.record(dest_fs, to).annotate()?;
+    }
+    copier.carry_owner(src_fs, dest_fs, from, to).annotate()?;
+    copier.set_perms(dest_fs, to, false).annotate()?;
+    copier
+        .remove_source(src_fs, dest_fs, from, to, true)
+        .annotate()?;
     Ok(())
 }
 
-pub fn adb_connect() -> CResult<bool> {
-    let devices = AdbCmd::run_v(["devices"]).annotate()?;
-    match devices
+fn copy_files<SRC: FileSystem, DEST: FileSystem + FSCopyFrom<SRC>>(
+    src_fs: &mut SRC,
+    dest_fs: &mut DEST,
+    copier: &mut Copier,
+    dir: &Node,
+    dst_path: &UnixPath,
+    timestamp: Option<u32>,
+) -> CResult<()> {
+    dest_fs.mkdir(&dst_path.join(&dir.strip_path)).annotate()?;
+    for n in dir.entries.values() {
+        match n.sf.mode {
+            FileMode::File => {
+                let to = dst_path.join(&n.strip_path);
+                // each file gets its own mtime, `timestamp` only says whether to set them
+                let timestamp = timestamp.map(|_| n.sf.timestamp);
+                copy_new_file(src_fs, dest_fs, copier, &n.sf, &to, timestamp).annotate()?
+            }
+            _ => copy_files(src_fs, dest_fs, copier, n, dst_path, timestamp).annotate()?,
+        }
+    }
+    // after its files, a pushed directory only exists once there are files in it, and
+    // a --chmod without write permission would keep them out
+    let to = dst_path.join(&dir.strip_path);
+    if !dir.files().is_empty() {
+        copier
+            .carry_owner(src_fs, dest_fs, &dir.sf.path, &to)
+            .annotate()?;
+    }
+    copier.set_perms(dest_fs, &to, true).annotate()?;
+    Ok(())
+}
+
+// `adb devices`, restarting a stuck server a bounded number of times if `recover_server`
+fn adb_devices(recover_server: bool) -> CResult<String> {
+    let mut recoveries = 0;
+    loop {
+        match AdbCmd::new().arg("devices").output_timeout(SERVER_TIMEOUT) {
+            Err(e) if recover_server && recoveries < SERVER_RECOVERIES && is_server_wedged(&e) => {
+                recoveries += 1;
+                logw!(
+                    W014: "adb server is not responding ({e}), restarting it ({recoveries}/{SERVER_RECOVERIES})"
+                );
+                restart_server().annotate()?;
+            }
+            r => return Ok(r.annotate()?),
+        }
+    }
+}
+
+// `adb connect` to an explicit address, for --connect
+pub fn adb_connect_to(addr: &str) -> CResult<()> {
+    let op = AdbCmd::run_v(["connect", addr]).annotate()?;
+    if op.starts_with("connected to") || op.starts_with("already connected to") {
+        logi!("Connected to {}", addr);
+        Ok(())
+    } else {
+        Err(format!("Could not connect to '{}': {}", addr, op.trim_end()).into())
+    }
+}
+
+// puts the device into TCP mode and connects to it over WiFi. the device is picked like for any
+// other command, `serial` or the only one connected. returns the `ip:port` it is reachable at
+pub fn switch_to_wireless(port: u16, serial: Option<&str>) -> CResult<String> {
+    let Some(serial) = adb_connect(false, serial).annotate()? else {
+        return Err("No device connected".into());
+    };
+    let op = AdbCmd::run_v([
+        "-s", &serial, "shell", "ip", "-f", "inet", "addr", "show", "wlan0",
+    ])
+    .annotate()?;
+    let Some(ip) = op
         .lines()
-        .filter(|line| line.contains("\tdevice"))
-        .inspect(|line| logv!("{}", line))
-        .count()
-    {
-        0 => {
+        .find_map(|l| l.trim().strip_prefix("inet "))
+        .and_then(|l| l.split('/').next())
+    else {
+        return Err("Could not find the WLAN address of the device, is WiFi on?".into());
+    };
+    let addr = format!("{ip}:{port}");
+    logi!("Switching the device to TCP mode on {}", addr);
+    AdbCmd::run_v(["-s", &serial, "tcpip", &port.to_string()]).annotate()?;
+
+    // adbd restarts in TCP mode, which takes a moment
+    let mut attempt = 0;
+    loop {
+        std::thread::sleep(std::time::Duration::from_secs(2));
+        attempt += 1;
+        match adb_connect_to(&addr) {
+            Ok(()) => break,
+            Err(e) if attempt == 5 => return Err(e),
+            Err(_) => {}
+        }
+    }
+    let op = AdbCmd::run_v(["-s", &addr, "shell", "echo", "ok"]).annotate()?;
+    if op.trim() != "ok" {
+        return Err(format!(
+            "Connected to '{}' but the shell does not respond: {}",
+            addr, op
+        )
+        .into());
+    }
+    logi!("The USB cable can be unplugged now");
+    Ok(addr)
+}
+
+fn exists_on_device(path: &str) -> CResult<bool> {
+    let test = format!("if [ -e {} ]; then echo y; fi", adb::quote(path));
+    Ok(AdbCmd::run_v(["shell", test.as_str()]).annotate()?.trim() == "y")
+}
+
+// for `auto`: pull if `source` only exists on the device, push if it only exists locally.
+// a source that exists on both sides is decided by where `dest` exists
+pub fn detect_direction(source: &Path, dest: &Path) -> CResult<Direction> {
+    let on_device = |p: &Path| -> CResult<bool> {
+        match p.to_str() {
+            Some(s) if s.starts_with('/') => exists_on_device(s),
+            _ => Ok(false),
+        }
+    };
+    let direction = match (on_device(source).annotate()?, source.exists()) {
+        (true, false) => Direction::Pull,
+        (false, true) => Direction::Push,
+        (false, false) => {
+            return Err(format!("Source '{}' does not exist", source.display()).into())
+        }
+        (true, true) => match (on_device(dest).annotate()?, dest.exists()) {
+            (true, false) => Direction::Push,
+            (false, true) => Direction::Pull,
+            _ => {
+                return Err(format!(
+                    "'{}' exists both on the device and locally, use pull or push",
+                    source.display()
+                )
+                .into())
+            }
+        },
+    };
+    logi!(
+        "{} '{}' -> '{}'",
+        if direction == Direction::Pull {
+            "Pulling"
+        } else {
+            "Pushing"
+        },
+        source.display(),
+        dest.display()
+    );
+    Ok(direction)
+}
+
+// keeps the device screen on for the lifetime of the guard, the previous
+// `stay_on_while_plugged_in` setting is restored when it is dropped
+pub struct StayAwake {
+    previous: String,
+}
+
+impl StayAwake {
+    // a device on adb over WiFi is not on USB power, so it stays on with any charger there
+    pub fn enable(wireless: bool) -> CResult<Self> {
+        let previous = AdbCmd::run_v([
+            "shell",
+            "settings",
+            "get",
+            "global",
+            "stay_on_while_plugged_in",
+        ])
+        .annotate()?;
+        let previous = match previous.trim() {
+            p if p.parse::<u8>().is_ok() => p.to_string(),
+            // "null" when it was never set
+            _ => "0".to_string(),
+        };
+        let when = if wireless { "true" } else { "usb" };
+        AdbCmd::run_v(["shell", "svc", "power", "stayon", when]).annotate()?;
+        logi!("Keeping the device awake until done");
+        Ok(Self { previous })
+    }
+}
+
+impl Drop for StayAwake {
+    fn drop(&mut self) {
+        let setting = "stay_on_while_plugged_in";
+        if let Err(e) = AdbCmd::run_v([
+            "shell",
+            "settings",
+            "put",
+            "global",
+            setting,
+            &self.previous,
+        ]) {
+            logw!(W013: "Could not restore the device's {} setting: {}", setting, e);
+        }
+    }
+}
+
+// posts a notification on the device, e.g. that a long sync is done. best effort, `cmd
+// notification post` needs Android 10
+pub fn notify_device(text: &str) {
+    let cmd = format!("cmd notification post -t adb-sink adb-sink {}", quote(text));
+    match AdbCmd::run_v(["shell", cmd.as_str()]) {
+        Ok(op) if op.contains("Exception") || op.contains("Unknown command") => {
+            logw!(W013: "Could not post a notification on the device: {}", op.trim())
+        }
+        Ok(_) => {}
+        Err(e) => logw!(W013: "Could not post a notification on the device: {}", e),
+    }
+}
+
+// (serial, state) of every device the adb server knows about
+pub fn adb_device_list(recover_server: bool) -> CResult<Vec<(String, String)>> {
+    Ok(adb_devices(recover_server)
+        .annotate()?
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(serial, state)| (serial.to_string(), state.trim().to_string()))
+        .collect())
+}
+
+// `serial` restricts the search to that device, which also allows several to be connected.
+// returns the serial of the device found
+pub fn adb_connect(recover_server: bool, serial: Option<&str>) -> CResult<Option<String>> {
+    let devices: Vec<String> = adb_device_list(recover_server)
+        .annotate()?
+        .into_iter()
+        .filter(|(s, state)| state == "device" && serial.is_none_or(|serial| s == serial))
+        .map(|(s, _)| s)
+        .inspect(|s| logv!("{}", config().describe(s)))
+        .collect();
+    match devices.as_slice() {
+        [] => {
             #[cfg(feature = "mdns")]
-            if let Some((ip, port)) = mdns_discover() {
-                logi!("Discovered device {} {}. Trying to connect...", ip, port);
-                if AdbCmd::run_v(["connect", &format!("{}:{}", ip, port)])
-                    .annotate()?
-                    .starts_with("connected to")
-                {
-                    return Ok(true);
+            if serial.is_none() {
+                if let Some((ip, port)) = mdns_discover() {
+                    logi!("Discovered device {} {}. Trying to connect...", ip, port);
+                    let addr = format!("{}:{}", ip, port);
+                    if AdbCmd::run_v(["connect", &addr])
+                        .annotate()?
+                        .starts_with("connected to")
+                    {
+                        return Ok(Some(addr));
+                    }
                 }
             }
-            Ok(false)
+            Ok(None)
         }
-        1 => Ok(true),
-        n if n > 1 => panic!("more than 1 device connected"),
-        _ => unreachable!(),
+        [device] => {
+            logi!("Using device {}", config().describe(device));
+            Ok(Some(device.clone()))
+        }
+        _ => Err("More than 1 device connected, pick one with -s/--serial".into()),
     }
 }
 
@@ -219,3 +2241,312 @@ fn mdns_discover() -> Option<(std::net::Ipv4Addr, u16)> {
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    // a fresh directory for one test, removed again when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let n = NEXT.fetch_add(1, Ordering::Relaxed);
+            let dir =
+                std::env::temp_dir().join(format!("adb-sink-{name}-{}-{n}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl std::ops::Deref for TempDir {
+        type Target = Path;
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn delete_keeps_excluded() {
+        let _ = VERBOSE.set(false);
+        let tmp = TempDir::new("excl");
+        let (src, dst) = (tmp.join("from"), tmp.join("to"));
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::create_dir_all(dst.join("from/cache")).unwrap();
+        std::fs::write(src.join("keep.txt"), "a").unwrap();
+        std::fs::write(dst.join("from/stale.txt"), "b").unwrap();
+        std::fs::write(dst.join("from/cache/x.bin"), "c").unwrap();
+
+        let opts = || SinkOpts {
+            delete_if_dne: true,
+            filter: Filter {
+                rules: vec!["- cache/".parse().unwrap()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        sink(&mut LocalFS, &mut LocalFS, src.clone(), dst.clone(), opts()).unwrap();
+        assert!(dst.join("from/keep.txt").exists());
+        assert!(!dst.join("from/stale.txt").exists());
+        // never scanned on the source, so it must not look like it was deleted there
+        assert!(dst.join("from/cache/x.bin").exists());
+
+        sink(
+            &mut LocalFS,
+            &mut LocalFS,
+            src,
+            dst.clone(),
+            SinkOpts {
+                delete_excluded: true,
+                ..opts()
+            },
+        )
+        .unwrap();
+        assert!(!dst.join("from/cache").exists());
+    }
+
+    #[test]
+    fn exclude_if_present() {
+        let _ = VERBOSE.set(false);
+        let tmp = TempDir::new("marker");
+        let (src, dst) = (tmp.join("from"), tmp.join("to"));
+        std::fs::create_dir_all(src.join("thumbs")).unwrap();
+        std::fs::create_dir_all(src.join("new/cache")).unwrap();
+        std::fs::create_dir_all(dst.join("from/thumbs")).unwrap();
+        std::fs::write(src.join("thumbs/CACHEDIR.TAG"), "tag").unwrap();
+        std::fs::write(src.join("thumbs/1.jpg"), "a").unwrap();
+        std::fs::write(src.join("new/cache/CACHEDIR.TAG"), "tag").unwrap();
+        std::fs::write(src.join("new/cache/x.bin"), "b").unwrap();
+        std::fs::write(src.join("new/a.txt"), "c").unwrap();
+        // from before the directory was marked
+        std::fs::write(dst.join("from/thumbs/0.jpg"), "d").unwrap();
+
+        for order in [None, Some(Order::Path)] {
+            let opts = SinkOpts {
+                delete_if_dne: true,
+                filter: Filter {
+                    markers: vec!["CACHEDIR.TAG".to_string()],
+                    ..Default::default()
+                },
+                order,
+                ..Default::default()
+            };
+            sink(&mut LocalFS, &mut LocalFS, src.clone(), dst.clone(), opts).unwrap();
+            assert!(dst.join("from/new/a.txt").exists());
+            assert!(dst.join("from/new/cache/CACHEDIR.TAG").exists());
+            assert!(!dst.join("from/new/cache/x.bin").exists());
+            assert!(!dst.join("from/thumbs/1.jpg").exists());
+            assert!(dst.join("from/thumbs/0.jpg").exists());
+        }
+    }
+
+    #[test]
+    fn on_conflict() {
+        let _ = VERBOSE.set(false);
+        assert_eq!(
+            conflict_name(UnixPath::new("/b/notes.txt"), 1714566605),
+            UnixPath::new("/b/notes.conflict-20240501-123005.txt")
+        );
+        assert_eq!(
+            conflict_name(UnixPath::new("/b/.profile"), 0),
+            UnixPath::new("/b/.profile.conflict-19700101-000000")
+        );
+
+        let tmp = TempDir::new("conflict");
+        let (src, dst) = (tmp.join("from"), tmp.join("to"));
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::create_dir_all(dst.join("from")).unwrap();
+        let write = |path: &Path, content: &str, mtime: u64| {
+            std::fs::write(path, content).unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(UNIX_EPOCH + std::time::Duration::from_secs(mtime))
+                .unwrap();
+        };
+        write(&src.join("a.txt"), "old", 1000);
+        write(&dst.join("from/a.txt"), "edited", 2000);
+
+        let opts = |on_conflict| SinkOpts {
+            on_conflict,
+            ..Default::default()
+        };
+        sink(
+            &mut LocalFS,
+            &mut LocalFS,
+            src.clone(),
+            dst.clone(),
+            opts(OnConflict::Skip),
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dst.join("from/a.txt")).unwrap(),
+            "edited"
+        );
+        sink(
+            &mut LocalFS,
+            &mut LocalFS,
+            src.clone(),
+            dst.clone(),
+            opts(OnConflict::Rename),
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dst.join("from/a.txt")).unwrap(),
+            "old"
+        );
+        let kept = dst.join("from/a.conflict-19700101-003320.txt");
+        assert_eq!(std::fs::read_to_string(kept).unwrap(), "edited");
+    }
+
+    #[test]
+    fn dry_run() {
+        let _ = VERBOSE.set(false);
+        let tmp = TempDir::new("dry-run");
+        let (src, dst) = (tmp.join("from"), tmp.join("to"));
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        std::fs::create_dir_all(dst.join("from")).unwrap();
+        std::fs::write(src.join("sub/new.txt"), "new").unwrap();
+        std::fs::write(dst.join("from/gone.txt"), "gone").unwrap();
+
+        let opts = || SinkOpts {
+            delete_if_dne: true,
+            dry_run: true,
+            ..Default::default()
+        };
+        let report = sink(&mut LocalFS, &mut LocalFS, src.clone(), dst.clone(), opts()).unwrap();
+        assert!(report.changed);
+        assert_eq!((report.copied, report.deleted), (0, 0));
+        assert!(dst.join("from/gone.txt").exists());
+        assert!(!dst.join("from/sub").exists());
+        // nor is a missing destination created
+        let elsewhere = tmp.join("elsewhere");
+        sink(
+            &mut LocalFS,
+            &mut LocalFS,
+            src.clone(),
+            elsewhere.clone(),
+            opts(),
+        )
+        .unwrap();
+        assert!(!elsewhere.exists());
+    }
+
+    #[test]
+    fn remove_source_files() {
+        let _ = VERBOSE.set(false);
+        let tmp = TempDir::new("move");
+        let (src, dst) = (tmp.join("from"), tmp.join("to"));
+        std::fs::create_dir_all(src.join("new")).unwrap();
+        std::fs::create_dir_all(dst.join("from")).unwrap();
+        std::fs::write(src.join("new/a.txt"), "a").unwrap();
+        std::fs::write(src.join("same.txt"), "b").unwrap();
+        std::fs::write(src.join("edited.txt"), "c").unwrap();
+        std::fs::write(dst.join("from/same.txt"), "b").unwrap();
+        // same size and newer, so not copied, but not the same either
+        std::fs::write(dst.join("from/edited.txt"), "d").unwrap();
+
+        let opts = SinkOpts {
+            remove_source_files: true,
+            ..Default::default()
+        };
+        sink(&mut LocalFS, &mut LocalFS, src.clone(), dst.clone(), opts).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dst.join("from/new/a.txt")).unwrap(),
+            "a"
+        );
+        assert!(!src.join("new/a.txt").exists());
+        assert!(!src.join("same.txt").exists());
+        assert!(src.join("edited.txt").exists());
+    }
+
+    #[test]
+    fn verify_sample() {
+        assert_eq!("50".parse(), Ok(VerifySample::Count(50)));
+        assert_eq!("2.5%".parse(), Ok(VerifySample::Percent(2.5)));
+        assert!("0".parse::<VerifySample>().is_err());
+        assert!("150%".parse::<VerifySample>().is_err());
+        assert_eq!(VerifySample::Count(50).of(10), 10);
+        assert_eq!(VerifySample::Percent(1.0).of(250), 3);
+
+        let picked = sample_indices(100, 10);
+        assert_eq!(picked.len(), 10);
+        assert!(picked.windows(2).all(|w| w[0] < w[1]));
+        assert!(picked.iter().all(|&i| i < 100));
+        assert_eq!(sample_indices(5, 5), [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn time_offset() {
+        let _ = VERBOSE.set(false);
+        assert_eq!("+02:00".parse(), Ok(TimeOffset::Fixed(7200)));
+        assert_eq!("-05:30".parse(), Ok(TimeOffset::Fixed(-19800)));
+        assert_eq!("auto".parse(), Ok(TimeOffset::Auto));
+        assert!("2h".parse::<TimeOffset>().is_err());
+
+        let tmp = TempDir::new("offset");
+        let (src, dst) = (tmp.join("from"), tmp.join("to"));
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::create_dir_all(dst.join("from")).unwrap();
+        for i in 0..12 {
+            let name = format!("IMG_{i}.jpg");
+            std::fs::write(src.join(&name), "a").unwrap();
+            std::fs::write(dst.join("from").join(&name), "a").unwrap();
+            let mtime = 1_700_000_000 + i * 60;
+            LocalFS
+                .set_mtime(
+                    &UnixPathBuf::from(src.join(&name).to_str().unwrap()),
+                    mtime + 3600,
+                )
+                .unwrap();
+            LocalFS
+                .set_mtime(
+                    &UnixPathBuf::from(dst.join("from").join(&name).to_str().unwrap()),
+                    mtime,
+                )
+                .unwrap();
+        }
+        let opts = |time_offset| SinkOpts {
+            time_offset,
+            ..Default::default()
+        };
+        let auto = Some(TimeOffset::Auto);
+        assert!(
+            !sink(
+                &mut LocalFS,
+                &mut LocalFS,
+                src.clone(),
+                dst.clone(),
+                opts(auto)
+            )
+            .unwrap()
+            .changed
+        );
+        let fixed = Some(TimeOffset::Fixed(3600));
+        assert!(
+            !sink(
+                &mut LocalFS,
+                &mut LocalFS,
+                src.clone(),
+                dst.clone(),
+                opts(fixed)
+            )
+            .unwrap()
+            .changed
+        );
+        // every file looks newer without the correction
+        let report = sink(&mut LocalFS, &mut LocalFS, src, dst, opts(None)).unwrap();
+        assert!(report.changed);
+        assert_eq!((report.copied, report.skipped), (12, 0));
+    }
+}
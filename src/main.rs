@@ -1,76 +1,963 @@
-use adb_sink::adb::{AdbCmd, AdbErr, AdbShell};
-use adb_sink::args::{Cli, SubCmds};
-use adb_sink::fs::{AndroidFS, LocalFS};
-use adb_sink::{adb_connect, sink, CResult};
+use adb_sink::adb::{push_compression, AdbCmd, AdbErr, AdbShell};
+use adb_sink::archive::{self, Archive, ArchiveFS};
+use adb_sink::args::{Cli, ConfigCmd, Direction, SubCmds, SyncArgs};
+use adb_sink::capture;
+use adb_sink::config::{config, Config, DeviceSettings, CONFIG};
+use adb_sink::cp;
+use adb_sink::crypt::EncryptedDir;
+use adb_sink::expire::{self, Expire};
+use adb_sink::filter::{Filter, FilterRule, Priority};
+use adb_sink::forward::Forward;
+use adb_sink::fs::{AndroidFS, AnyFileSystem, DynDest, FileSystem, LocalFS, Transport, Unpacking};
+use adb_sink::hashdb::HashDb;
+use adb_sink::history;
+use adb_sink::install;
+use adb_sink::mediastore::{MediaStoreFS, MEDIA_TEMP_PATTERNS};
+use adb_sink::pipe;
+use adb_sink::plan::{self, Plan};
+use adb_sink::preflight::Preflight;
+use adb_sink::scan::{self, Scan};
+use adb_sink::speedtest::speedtest;
+use adb_sink::store::{self, Retention, Snapshot, SnapshotFS};
+use adb_sink::systemd;
+use adb_sink::template::{self, Vars};
+use adb_sink::tools::DeviceTools;
+use adb_sink::tree::build_tree;
+use adb_sink::warnings;
+#[cfg(feature = "watch")]
+use adb_sink::watch::{self, Watcher};
+use adb_sink::winnames::WindowsNames;
+use adb_sink::{
+    adb_connect, adb_connect_to, adb_device_list, detect_direction, logi, logw, notify_device,
+    sink, switch_to_wireless, verify, CResult, SinkOpts, StayAwake,
+};
 use chainerror::Context;
 use clap::Parser;
+use std::path::PathBuf;
 use std::process::ExitCode;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use typed_path::Utf8UnixPathBuf as UnixPathBuf;
 
-fn run(args: Cli) -> CResult<()> {
-    match AdbCmd::run_v(["start-server"]) {
-        Ok(_) => {}
-        Err(AdbErr::IO(e)) if e.kind() == std::io::ErrorKind::NotFound => {
-            panic!("adb binary not found")
+// also returns the serial of the device, for its settings
+fn device_fs(
+    transport: Transport,
+    mediastore: Option<Option<String>>,
+    recover_server: bool,
+    compress: bool,
+    connect: Option<&str>,
+    device: Option<&str>,
+    preflight: &Preflight,
+) -> CResult<(Box<dyn AnyFileSystem>, Option<String>)> {
+    match transport {
+        Transport::Adb => {
+            match AdbCmd::run_v(["start-server"]) {
+                Ok(_) => {}
+                Err(AdbErr::IO(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                    panic!("adb binary not found")
+                }
+                Err(AdbErr::Adb(e)) if e.starts_with("* daemon not running") => {}
+                Err(e) => panic!("{}", e),
+            }
+            let serial = match connect {
+                Some(addr) => {
+                    adb_connect_to(addr).annotate()?;
+                    Some(addr.to_string())
+                }
+                None => adb_connect(recover_server, device).annotate()?,
+            };
+            let mut shell = AdbShell::new().annotate()?;
+            shell.recover_server = recover_server;
+            preflight.check(&mut shell).annotate()?;
+            let tools = DeviceTools::probe(&mut shell).annotate()?;
+            let push_compression = if compress { push_compression() } else { None };
+            if let Some(algo) = push_compression {
+                logi!("Compressing pushes with {}", algo);
+            }
+            let android_fs = AndroidFS {
+                shell,
+                push_compression,
+                tools,
+            };
+            let fs: Box<dyn AnyFileSystem> = match mediastore {
+                Some(selection) => Box::new(MediaStoreFS::new(android_fs, selection)),
+                None => Box::new(android_fs),
+            };
+            Ok((fs, serial))
         }
-        Err(AdbErr::Adb(e)) if e.starts_with("* daemon not running") => {}
-        Err(e) => panic!("{}", e),
+        Transport::Local if mediastore.is_some() => {
+            Err("--mediastore needs the adb transport".into())
+        }
+        Transport::Local => Ok((Box::new(LocalFS), None)),
+        t => Err(format!("{t:?} can not be used as the device transport").into()),
     }
-    adb_connect().annotate()?;
+}
 
-    let mut local_fs = LocalFS;
-    let mut android_fs = AndroidFS {
-        shell: AdbShell::new().annotate()?,
-    };
+// pull destinations may point at network storage instead of a local directory
+fn dest_fs(dest: PathBuf) -> CResult<(Box<dyn AnyFileSystem>, PathBuf)> {
+    let _url = dest.to_str().unwrap_or_default();
+    #[cfg(feature = "webdav")]
+    if _url.starts_with("dav://") || _url.starts_with("davs://") {
+        let (fs, path) = adb_sink::webdav::WebDavFS::from_url(_url).annotate()?;
+        return Ok((Box::new(fs), PathBuf::from(path.as_str())));
+    }
+    #[cfg(feature = "s3")]
+    if _url.starts_with("s3://") {
+        let (fs, path) = adb_sink::s3::S3FS::from_url(_url).annotate()?;
+        return Ok((Box::new(fs), PathBuf::from(path.as_str())));
+    }
+    Ok((Box::new(LocalFS), dest))
+}
 
+// the device settings' filter rules and patterns go after the command line's
+fn sink_opts(sync: SyncArgs, settings: DeviceSettings) -> SinkOpts {
+    // every other file is excluded after the --include-ext rules
+    let only_ext = !sync.include_ext.is_empty();
+    SinkOpts {
+        delete_if_dne: sync.delete_if_dne,
+        delete_excluded: sync.delete_excluded,
+        filter: Filter {
+            rules: sync
+                .ignore_dir
+                .into_iter()
+                .chain(sync.filter)
+                .chain(sync.exclude)
+                .chain(sync.exclude_ext)
+                .chain(sync.include_ext)
+                .chain(std::iter::once(FilterRule::other_files()).filter(|_| only_ext))
+                .chain(settings.filter)
+                .chain(
+                    MEDIA_TEMP_PATTERNS
+                        .iter()
+                        .filter(|_| !sync.include_pending)
+                        .map(|p| format!("- {p}").parse().expect("valid pattern")),
+                )
+                .map(|r: FilterRule| {
+                    if sync.ignore_case_filters {
+                        r.ignore_case()
+                    } else {
+                        r
+                    }
+                })
+                .collect(),
+            markers: sync.exclude_if_present,
+            only: Vec::new(),
+        },
+        transforms: sync.transform,
+        flatten: sync.flatten,
+        order: sync.order,
+        priority: Priority {
+            first: sync.first.into_iter().chain(settings.first).collect(),
+            last: sync.last.into_iter().chain(settings.last).collect(),
+        },
+        xattrs: sync.xattrs,
+        prune_empty_dirs: sync.prune_empty_dirs,
+        timings: sync.timings,
+        spot_check: sync.spot_check.map(|kib| kib * 1024),
+        metadata_only: sync.metadata_only,
+        relative: sync.relative,
+        time_offset: sync.time_offset,
+        hard_links: sync.hard_links,
+        max_files: sync.max_files,
+        plan_out: sync.plan_out,
+        dry_run: sync.dry_run,
+        verify_sample: sync.verify_sample,
+        on_conflict: sync.on_conflict,
+        ..Default::default()
+    }
+}
+
+// `push --watch`, until interrupted: every burst of changes under `source` is synced with
+// only the changed paths compared. a failed sync is reported and the next change tried again
+#[cfg(feature = "watch")]
+fn watch_push(
+    local_fs: &mut Box<dyn AnyFileSystem>,
+    device_fs: &mut dyn AnyFileSystem,
+    watcher: &Watcher,
+    source: PathBuf,
+    dest: PathBuf,
+    opts: SinkOpts,
+) -> CResult<bool> {
+    logi!("Watching '{}' for changes", source.display());
+    loop {
+        let only = watcher.changes(watch::DEBOUNCE).annotate()?;
+        match only.as_slice() {
+            [] => logi!("'{}' changed", source.display()),
+            [path] => logi!("'{}' changed", path),
+            paths => logi!("{} paths changed", paths.len()),
+        }
+        let opts = SinkOpts {
+            filter: Filter {
+                only,
+                ..opts.filter.clone()
+            },
+            // removing the files in scope is not an empty source, the first sync checked that
+            safe_delete: false,
+            ..opts.clone()
+        };
+        let mut dest_fs = DynDest {
+            fs: &mut *device_fs,
+            src: Transport::Local,
+        };
+        match sink(local_fs, &mut dest_fs, source.clone(), dest.clone(), opts) {
+            Ok(report) => report.log(),
+            Err(e) => logw!(W022: "Sync failed, waiting for the next change: {:?}", e),
+        }
+    }
+}
+
+fn config_cmd(cmd: &ConfigCmd) -> CResult<()> {
+    let default = || Config::path().ok_or("Could not find a config directory, set ADB_SINK_CONFIG");
+    match cmd {
+        ConfigCmd::Init => adb_sink::config::init(&default()?),
+        ConfigCmd::Validate { path } => {
+            let path = match path {
+                Some(p) => p.clone(),
+                None => default()?,
+            };
+            if !path.exists() {
+                return Err(format!("'{}' does not exist", path.display()).into());
+            }
+            let problems = Config::load_from(&path).annotate()?.validate();
+            for p in &problems {
+                eprintln!("{}: {}", path.display(), p);
+            }
+            match problems.len() {
+                0 => {
+                    logi!("'{}' is valid", path.display());
+                    Ok(())
+                }
+                n => Err(format!("{n} problems in '{}'", path.display()).into()),
+            }
+        }
+    }
+}
+
+// whether the trees differed
+fn run(args: Cli) -> CResult<bool> {
+    let (subcmd, mirror) = match args.subcmd {
+        SubCmds::Mirror(ma) => (ma.into_subcmd(), true),
+        subcmd => (subcmd, false),
+    };
+    let plan = match &subcmd {
+        SubCmds::Apply(aa) => Some(Plan::read(&aa.plan).annotate()?),
+        _ => None,
+    };
+    // cp and apply work either way
+    let direction = match (&subcmd, &plan) {
+        (SubCmds::Cp(ca), _) => Some(detect_direction(&ca.source, &ca.dest).annotate()?),
+        (_, Some(p)) => Some(
+            detect_direction(
+                std::path::Path::new(&p.source),
+                std::path::Path::new(&p.dest),
+            )
+            .annotate()?,
+        ),
+        _ => None,
+    };
+    if args.no_write_device
+        && !matches!(subcmd, SubCmds::Pull(_))
+        && direction != Some(Direction::Pull)
+    {
+        return Err("--no-write-device only allows pulls".into());
+    }
     {
-        let p = match &args.subcmd {
-            SubCmds::Pull(pa) => &pa.source,
-            SubCmds::Push(pa) => &pa.source,
+        let p = match &subcmd {
+            SubCmds::Pull(pa) => Some(&pa.source),
+            // stdin
+            SubCmds::Push(pa) if pipe::is_stdio(&pa.source) => None,
+            SubCmds::Push(pa) => Some(&pa.source),
+            SubCmds::Restore(ra) => Some(&ra.source),
+            SubCmds::Scan(sa) => Some(&sa.source),
+            // local paths may be relative
+            SubCmds::Cp(_) => None,
+            SubCmds::Apply(_) => None,
+            _ => unreachable!("converted above"),
         };
-        if !(p.starts_with("/") || p.is_absolute()) {
+        if p.is_some_and(|p| !(p.starts_with("/") || p.is_absolute())) {
             return Err("Source path must be absolute".into());
         }
     }
 
-    match args.subcmd {
-        SubCmds::Pull(pa) => sink(
-            &mut android_fs,
-            &mut local_fs,
-            pa.source,
-            match pa.dest {
+    let (mediastore, compress) = match &subcmd {
+        SubCmds::Pull(pa) => (pa.mediastore.clone(), false),
+        SubCmds::Push(pa) => (None, !pa.no_compress),
+        SubCmds::Restore(_) | SubCmds::Cp(_) | SubCmds::Scan(_) | SubCmds::Apply(_) => (None, true),
+        _ => unreachable!("converted above"),
+    };
+    let (mut device_fs, serial) = device_fs(
+        args.transport,
+        mediastore,
+        args.recover_server,
+        compress,
+        args.connect.as_deref(),
+        args.device.as_deref(),
+        &Preflight {
+            min_battery: args.min_battery,
+            max_thermal: args.max_thermal,
+            only_when_charging: args.only_when_charging,
+            only_on_wifi: args.only_on_wifi,
+            wait: args.preflight_wait,
+        },
+    )
+    .annotate()?;
+    let device_transport = device_fs.transport();
+    if let Some(serial) = &serial {
+        history::set_device(serial);
+    }
+    systemd::notify("READY=1\nSTATUS=Syncing");
+    // restores the setting when dropped at the end of the run
+    let _awake = match &serial {
+        Some(serial) if args.stay_awake => {
+            Some(StayAwake::enable(serial.contains(':')).annotate()?)
+        }
+        _ => None,
+    };
+    let _forward = match device_transport {
+        Transport::Adb if args.forward => Some(Forward::start(args.channels.into()).annotate()?),
+        _ => None,
+    };
+    let settings = match &serial {
+        Some(serial) => config().device_settings(serial).annotate()?,
+        None => Default::default(),
+    };
+    let mut local_fs: Box<dyn AnyFileSystem> = Box::new(LocalFS);
+
+    let changed = match subcmd {
+        SubCmds::Pull(pa) if pa.dest.as_deref().is_some_and(pipe::is_stdio) => {
+            let source = UnixPathBuf::from(pa.source.to_str().unwrap());
+            pipe::pull_to_stdout(&mut device_fs, &source).annotate()?;
+            return Ok(true);
+        }
+        SubCmds::Push(pa) if pipe::is_stdio(&pa.source) => {
+            let dest = UnixPathBuf::from(pa.dest.to_str().unwrap());
+            pipe::push_from_stdin(&mut device_fs, &dest).annotate()?;
+            return Ok(true);
+        }
+        SubCmds::Pull(pa) => {
+            if pa.expire_source.is_some() && (mirror || args.no_write_device) {
+                return Err("--expire-source does not work with mirror or --no-write-device".into());
+            }
+            if let Some(secs) = pa.file_timeout {
+                if args.forward {
+                    return Err("--file-timeout does not work with --forward".into());
+                }
+                adb_sink::fs::set_file_timeout(Duration::from_secs(secs));
+            }
+            if pa.remove_source_files && (mirror || args.no_write_device) {
+                return Err(
+                    "--remove-source-files does not work with mirror or --no-write-device".into(),
+                );
+            }
+            if pa.sync.dry_run
+                && (pa.store
+                    || !pa.encrypt.is_empty()
+                    || pa.to_archive.is_some()
+                    || pa.expire_source.is_some()
+                    || pa.remove_source_files)
+            {
+                return Err("--dry-run does not work with --store, --encrypt, --to-archive, --expire-source or --remove-source-files".into());
+            }
+            // a plan only holds copies and deletes
+            if pa.sync.plan_out.is_some()
+                && (pa.dedup.is_some()
+                    || pa.hash_db
+                    || pa.write_manifest
+                    || pa.chmod.is_some()
+                    || pa.chown.is_some()
+                    || pa.store
+                    || !pa.encrypt.is_empty()
+                    || pa.to_archive.is_some())
+            {
+                return Err("--plan-out does not work with --dedup, --hash-db, --write-manifest, --chmod, --chown, --store, --encrypt or --to-archive".into());
+            }
+            let vars = Vars {
+                serial: serial.as_deref(),
+                nickname: serial.as_deref().and_then(|s| config().nickname(s)),
+                source: &pa.source,
+                now: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+            };
+            if let Some(archive) = &pa.to_archive {
+                let archive = template::expand_path(archive, &vars).annotate()?;
+                let source = pa.source.to_str().unwrap().trim_end_matches('/');
+                archive::pull_to_archive(
+                    &mut device_fs,
+                    &UnixPathBuf::from(source),
+                    &archive,
+                    pa.split_size,
+                    &sink_opts(pa.sync, settings).filter,
+                )
+                .annotate()?;
+                return Ok(true);
+            }
+            let dest = match &pa.dest {
                 Some(dest) if dest == std::path::Path::new(".") => {
                     std::env::current_dir().expect("get current dir")
                 }
-                Some(dest) => dest,
-                None => std::env::current_dir().expect("get current dir"),
-            },
-            pa.delete_if_dne,
-            pa.ignore_dir,
-            pa.set_times,
-        ),
-        SubCmds::Push(pa) => sink(
-            &mut local_fs,
-            &mut android_fs,
-            pa.source,
-            pa.dest,
-            pa.delete_if_dne,
-            pa.ignore_dir,
-            false,
-        ),
+                Some(dest) => template::expand_path(dest, &vars).annotate()?,
+                None => match &settings.pull_dest {
+                    Some(dest) => {
+                        let dest = template::expand_path(dest, &vars).annotate()?;
+                        logi!("Pulling into '{}' from the device settings", dest.display());
+                        dest
+                    }
+                    None => std::env::current_dir().expect("get current dir"),
+                },
+            };
+            let (mut dest_fs, dest) = dest_fs(dest).annotate()?;
+            if pa.hash_db && dest_fs.transport() != Transport::Local {
+                return Err("--hash-db needs a local destination".into());
+            }
+            if pa.write_manifest && dest_fs.transport() != Transport::Local {
+                return Err("--write-manifest needs a local destination".into());
+            }
+            if pa.sync.owner && dest_fs.transport() != Transport::Local {
+                return Err("--owner needs a local destination".into());
+            }
+            if (pa.chmod.is_some() || pa.chown.is_some()) && dest_fs.transport() != Transport::Local
+            {
+                return Err("--chmod and --chown need a local destination".into());
+            }
+            if pa.capture_times {
+                if dest_fs.transport() != Transport::Local {
+                    return Err("--capture-times needs a local destination".into());
+                }
+                capture::enable();
+            }
+            if !pa.encrypt.is_empty() {
+                if dest_fs.transport() != Transport::Local {
+                    return Err("--encrypt needs a local destination".into());
+                }
+                dest_fs = Box::new(EncryptedDir::new(pa.encrypt, None));
+            }
+            if pa.store {
+                if dest_fs.transport() != Transport::Local {
+                    return Err("--store needs a local destination".into());
+                }
+                let source = pa.source.to_str().unwrap();
+                return store::snapshot(
+                    &mut device_fs,
+                    &mut DynDest {
+                        fs: &mut *dest_fs,
+                        src: device_transport,
+                    },
+                    &UnixPathBuf::from(source.trim_end_matches('/')),
+                    &dest,
+                    &sink_opts(pa.sync, settings).filter,
+                );
+            }
+            let opts = SinkOpts {
+                set_time: pa.set_times,
+                case: pa.case,
+                dedup: pa.dedup,
+                safe_delete: mirror,
+                hash_db: pa.hash_db,
+                write_manifest: pa.write_manifest,
+                record_owners: pa.sync.owner,
+                chmod: pa.chmod.unwrap_or_default(),
+                chown: pa.chown,
+                windows_names: (cfg!(windows) || pa.windows_names).then_some(WindowsNames {
+                    replacement: pa.invalid_char_replacement,
+                    trailing_dot: pa.trailing_dot,
+                }),
+                remove_source_files: pa.remove_source_files,
+                retry_stalled: pa.retry_stalled,
+                ..sink_opts(pa.sync, settings)
+            };
+            let filter = opts.filter.clone();
+            let report = sink(
+                &mut device_fs,
+                &mut DynDest {
+                    fs: &mut *dest_fs,
+                    src: device_transport,
+                },
+                pa.source.clone(),
+                dest.clone(),
+                opts,
+            )
+            .annotate()?;
+            report.log();
+            if let Some(days) = pa.expire_source {
+                let source = pa.source.to_str().unwrap().trim_end_matches('/');
+                let name = pa.source.file_name().unwrap().to_str().unwrap();
+                expire::expire_source(
+                    &mut device_fs,
+                    &mut dest_fs,
+                    &UnixPathBuf::from(source),
+                    &UnixPathBuf::from(dest.to_str().unwrap()).join(name),
+                    &filter,
+                    &Expire {
+                        days,
+                        dry_run: pa.expire_dry_run,
+                        yes: pa.yes,
+                    },
+                )
+                .annotate()?;
+            }
+            Ok(report.changed)
+        }
+        SubCmds::Push(pa) => {
+            // also `backup.tar/DCIM`, a directory inside an archive
+            let archive = pa.source.ancestors().find(|a| archive::exists(a));
+            if pa.sync.plan_out.is_some() && (archive.is_some() || pa.decrypt.is_some()) {
+                return Err("--plan-out does not work with archives or --decrypt".into());
+            }
+            #[cfg(not(feature = "watch"))]
+            if pa.watch {
+                return Err("adb-sink was built without the `watch` feature".into());
+            }
+            // started before the first sync, so what changes during it is pushed afterwards
+            #[cfg(feature = "watch")]
+            let watcher = if pa.watch {
+                if archive.is_some()
+                    || pa.decrypt.is_some()
+                    || pa.sync.plan_out.is_some()
+                    || pa.sync.flatten
+                    || !pa.sync.transform.is_empty()
+                {
+                    return Err(
+                        "--watch does not work with archives, --decrypt, --plan-out, --flatten or --transform"
+                            .into(),
+                    );
+                }
+                Some(Watcher::new(&pa.source).annotate()?)
+            } else {
+                None
+            };
+            let mut source = pa.source.clone();
+            let mut device_fs: Box<dyn AnyFileSystem> = match (pa.decrypt, archive) {
+                (Some(_), Some(_)) => return Err("--decrypt does not work with archives".into()),
+                (None, Some(path)) => {
+                    let archive = Rc::new(Archive::open(path).annotate()?);
+                    if path == pa.source {
+                        source = archive.top_dir().annotate()?;
+                    }
+                    local_fs = Box::new(ArchiveFS(archive.clone()));
+                    Box::new(Unpacking {
+                        inner: device_fs,
+                        src: ArchiveFS(archive),
+                    })
+                }
+                (Some(identity), None) => {
+                    local_fs = Box::new(EncryptedDir::new(Vec::new(), Some(identity.clone())));
+                    Box::new(Unpacking {
+                        inner: device_fs,
+                        src: EncryptedDir::new(Vec::new(), Some(identity)),
+                    })
+                }
+                (None, None) => device_fs,
+            };
+            let opts = SinkOpts {
+                safe_delete: mirror,
+                restore_owners: pa.sync.owner,
+                restore_names: true,
+                ..sink_opts(pa.sync, settings)
+            };
+            #[cfg(feature = "watch")]
+            let rerun = watcher.as_ref().map(|_| opts.clone());
+            let report = sink(
+                &mut local_fs,
+                &mut DynDest {
+                    fs: &mut *device_fs,
+                    src: Transport::Local,
+                },
+                source.clone(),
+                pa.dest.clone(),
+                opts,
+            )
+            .annotate()?;
+            report.log();
+            #[cfg(feature = "watch")]
+            if let (Some(watcher), Some(opts)) = (watcher, rerun) {
+                return watch_push(&mut local_fs, &mut *device_fs, &watcher, source, pa.dest, opts);
+            }
+            Ok(report.changed)
+        }
+        SubCmds::Restore(ra) => {
+            if ra.sync.owner {
+                return Err("--owner does not work with restore, backups keep no owners".into());
+            }
+            if ra.sync.plan_out.is_some() {
+                return Err("--plan-out does not work with restore".into());
+            }
+            if ra.sync.dry_run && ra.verify {
+                return Err("--verify does not work with --dry-run".into());
+            }
+            let (source, mut device_fs): (PathBuf, Box<dyn AnyFileSystem>) =
+                if archive::exists(&ra.source) {
+                    if ra.snapshot.is_some() {
+                        return Err("--snapshot only works with a snapshot store".into());
+                    }
+                    let archive = Rc::new(Archive::open(&ra.source).annotate()?);
+                    local_fs = Box::new(ArchiveFS(archive.clone()));
+                    let unpacking = Unpacking {
+                        inner: device_fs,
+                        src: ArchiveFS(archive.clone()),
+                    };
+                    (archive.top_dir().annotate()?, Box::new(unpacking))
+                } else {
+                    let snapshot = Snapshot::open(&ra.source, ra.snapshot.as_deref()).annotate()?;
+                    let snapshot = Rc::new(snapshot);
+                    local_fs = Box::new(SnapshotFS(snapshot.clone()));
+                    let unpacking = Unpacking {
+                        inner: device_fs,
+                        src: SnapshotFS(snapshot.clone()),
+                    };
+                    (snapshot.root(), Box::new(unpacking))
+                };
+            let opts = SinkOpts {
+                set_time: true,
+                contents_only: true,
+                ..sink_opts(ra.sync, settings)
+            };
+            let filter = opts.filter.clone();
+            let report = sink(
+                &mut local_fs,
+                &mut DynDest {
+                    fs: &mut *device_fs,
+                    src: Transport::Local,
+                },
+                source.clone(),
+                ra.dest.clone(),
+                opts,
+            )
+            .annotate()?;
+            report.log();
+            if ra.verify {
+                let bad = verify(
+                    &mut local_fs,
+                    &mut device_fs,
+                    &UnixPathBuf::from(source.to_str().unwrap()),
+                    &UnixPathBuf::from(ra.dest.to_str().unwrap()),
+                    &filter,
+                )
+                .annotate()?;
+                if bad > 0 {
+                    return Err(format!("{bad} restored files are missing or differ").into());
+                }
+            }
+            Ok(report.changed)
+        }
+        SubCmds::Scan(sa) => {
+            let source = UnixPathBuf::from(sa.source.to_str().unwrap().trim_end_matches('/'));
+            let Some(sf) = device_fs.stat(&source).annotate()? else {
+                return Err(format!("Source '{}' does not exist", source).into());
+            };
+            let root = build_tree(&mut device_fs, sf, &source, &Filter::default()).annotate()?;
+            let json = Scan::of(&root, serial).to_json();
+            match &sa.output {
+                Some(path) => {
+                    std::fs::write(path, json).annotate()?;
+                    logi!("Wrote the scan to '{}'", path.display());
+                }
+                None => print!("{json}"),
+            }
+            Ok(false)
+        }
+        SubCmds::Cp(ca) => {
+            let direction = direction.expect("detected above");
+            cp::cp(
+                &mut device_fs,
+                direction,
+                &ca.source,
+                &ca.dest,
+                !ca.no_verify,
+            )
+            .map(|()| true)
+        }
+        SubCmds::Apply(_) => {
+            let plan = plan.expect("read above");
+            plan::apply(&mut device_fs, direction.expect("detected above"), &plan)
+        }
+        _ => unreachable!("converted above"),
     }
     .annotate()?;
-    Ok(())
+    Ok(changed)
 }
 
 fn main() -> ExitCode {
-    let args = Cli::parse();
+    let mut args = Cli::parse();
     adb_sink::VERBOSE.set(args.verbose).unwrap();
+    if matches!(&args.subcmd, SubCmds::Pull(pa) if pa.dest.as_deref().is_some_and(pipe::is_stdio)) {
+        // stdout is the file
+        adb_sink::log_to_stderr();
+    }
+    if args.no_write_device {
+        adb_sink::fs::forbid_device_writes();
+    }
+    adb_sink::pause::install_signal_handlers();
+    if let Some(name) = &args.generate_unit {
+        let argv: Vec<String> = std::env::args().collect();
+        return match systemd::generate_units(name, &args.on_calendar, &argv) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("ERROR: {:?}", e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+    if let SubCmds::Config(cmd) = &args.subcmd {
+        return match config_cmd(cmd) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("ERROR: {:?}", e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+    match Config::load() {
+        Ok(c) => CONFIG.set(c).unwrap(),
+        Err(e) => {
+            eprintln!("ERROR: {:?}", e);
+            return ExitCode::FAILURE;
+        }
+    }
+    if let SubCmds::Run(ra) = args.subcmd {
+        match config().profile(&ra.profile) {
+            Ok(profile) => args.subcmd = ra.into_subcmd(profile),
+            Err(e) => {
+                eprintln!("ERROR: {}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    args.device = args
+        .device
+        .or_else(|| std::env::var("ANDROID_SERIAL").ok())
+        .map(|d| config().serial(&d).to_string());
+    if let SubCmds::Wireless(wa) = &args.subcmd {
+        let addr = match switch_to_wireless(wa.port, args.device.as_deref()) {
+            Ok(addr) => addr,
+            Err(e) => {
+                eprintln!("ERROR: {:?}", e);
+                return ExitCode::FAILURE;
+            }
+        };
+        let SubCmds::Wireless(wa) = args.subcmd else {
+            unreachable!()
+        };
+        match wa.then {
+            Some(then) => {
+                args.subcmd = then.into();
+                args.connect = Some(addr);
+            }
+            None => return ExitCode::SUCCESS,
+        }
+    }
+    // nicknames are accepted wherever adb takes a serial
+    let mut adb_args = args.adb_arg.clone();
+    for i in 1..adb_args.len() {
+        if adb_args[i - 1] == "-s" {
+            adb_args[i] = config().serial(&adb_args[i]).to_string();
+        }
+    }
+    if let Some(serial) = args.connect.as_ref().or(args.device.as_ref()) {
+        // so that a USB device plugged in at the same time is not picked instead
+        adb_args.extend(["-s".to_string(), serial.clone()]);
+    }
+    adb_sink::adb::ADB_ARGS.set(adb_args).unwrap();
+    #[cfg(feature = "usb")]
+    if args.usb {
+        if let Err(e) = adb_sink::adb::usb::open() {
+            eprintln!("ERROR: {}", e);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    if let SubCmds::Auto(aa) = args.subcmd {
+        match detect_direction(&aa.source, &aa.dest) {
+            Ok(direction) => args.subcmd = aa.into_subcmd(direction),
+            Err(e) => {
+                eprintln!("ERROR: {:?}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    if let SubCmds::BitrotCheck(ba) = &args.subcmd {
+        let root = UnixPathBuf::from(ba.backup_dir.to_str().unwrap());
+        return match HashDb::open(&root).and_then(|db| db.check()) {
+            Ok(0) => ExitCode::SUCCESS,
+            Ok(_) => ExitCode::FAILURE,
+            Err(e) => {
+                eprintln!("ERROR: {:?}", e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+    if let SubCmds::VerifyManifest(va) = &args.subcmd {
+        return match adb_sink::manifest::verify(&va.dir, &va.manifest) {
+            Ok(0) => ExitCode::SUCCESS,
+            Ok(_) => ExitCode::FAILURE,
+            Err(e) => {
+                eprintln!("ERROR: {:?}", e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+    if let SubCmds::Checkout(ca) = &args.subcmd {
+        return match store::checkout(&ca.store, ca.snapshot.as_deref(), &ca.dest, ca.link) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("ERROR: {:?}", e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+    if let SubCmds::SnapshotDiff(sa) = &args.subcmd {
+        let scans = Scan::read(&sa.a).and_then(|a| Ok((a, Scan::read(&sa.b)?)));
+        return match scans {
+            Ok((a, b)) => {
+                let differed = scan::diff(&a, &b);
+                if differed && args.exit_code {
+                    ExitCode::from(1)
+                } else {
+                    ExitCode::SUCCESS
+                }
+            }
+            Err(e) => {
+                eprintln!("ERROR: {:?}", e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+    if let SubCmds::History(ha) = &args.subcmd {
+        return match history::show(ha.job.as_deref(), ha.limit) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("ERROR: {:?}", e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+    if let SubCmds::Prune(pa) = &args.subcmd {
+        let retention = Retention {
+            daily: pa.keep_daily.unwrap_or(0),
+            weekly: pa.keep_weekly.unwrap_or(0),
+            monthly: pa.keep_monthly.unwrap_or(0),
+        };
+        return match store::prune(&pa.store, retention, pa.dry_run) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("ERROR: {:?}", e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+    if let SubCmds::Devices = args.subcmd {
+        return match adb_device_list(args.recover_server) {
+            Ok(devices) => {
+                for (serial, state) in devices {
+                    match config().nickname(&serial) {
+                        Some(nick) => println!("{serial}\t{state}\t{nick}"),
+                        None => println!("{serial}\t{state}"),
+                    }
+                }
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                eprintln!("ERROR: {:?}", e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+    if let SubCmds::Install(ia) = &args.subcmd {
+        if args.no_write_device {
+            eprintln!("ERROR: --no-write-device does not allow installing");
+            return ExitCode::FAILURE;
+        }
+        let r = match args.connect.as_deref() {
+            Some(addr) => adb_connect_to(addr),
+            None => adb_connect(args.recover_server, args.device.as_deref()).map(drop),
+        }
+        .and_then(|()| install::install(&ia.path));
+        return match r {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("ERROR: {:?}", e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+    if let SubCmds::Speedtest(sa) = &args.subcmd {
+        let connected = match args.connect.as_deref() {
+            Some(addr) => adb_connect_to(addr),
+            None => adb_connect(args.recover_server, args.device.as_deref()).map(drop),
+        };
+        let r = connected.and_then(|()| {
+            let _forward = match args.forward {
+                true => Some(Forward::start(args.channels.into())?),
+                false => None,
+            };
+            speedtest(sa.size, sa.rounds)
+        });
+        return match r {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("ERROR: {:?}", e);
+                ExitCode::FAILURE
+            }
+        };
+    }
 
-    match run(args) {
-        Ok(()) => ExitCode::SUCCESS,
+    let exit_code = args.exit_code;
+    let disconnect = args.connect.clone().filter(|_| args.disconnect);
+    let device_notify = args.device_notify;
+    let job = args.job.clone().unwrap_or_else(|| {
+        let argv: Vec<String> = std::env::args().skip(1).collect();
+        argv.join(" ")
+    });
+    let start = history::now();
+    if let Some(schedule) = args.bwlimit.clone() {
+        adb_sink::bwlimit::set(schedule);
+    }
+    if let Some(secs) = args.heartbeat {
+        adb_sink::heartbeat::start(Duration::from_secs(secs));
+    }
+    if args.delta {
+        adb_sink::delta::enable();
+    }
+    if args.progress {
+        adb_sink::progress::start();
+    }
+    let fail_on_warning = args.fail_on_warning.clone();
+    let mut res = run(args);
+    adb_sink::progress::stop();
+    warnings::summary();
+    if let (Ok(_), Some(codes)) = (&res, fail_on_warning) {
+        if let Err(e) = warnings::check(&codes) {
+            res = Err(e);
+        }
+    }
+    let error = res.as_ref().err().map(|e| e.to_string());
+    if let Err(e) = history::record(&job, start, error.as_deref()) {
+        logw!(W013: "Could not record the run in the history: {}", e);
+    }
+    if device_notify {
+        notify_device(&match &res {
+            Ok(true) => "Sync finished".to_string(),
+            Ok(false) => "Sync finished, everything was up to date".to_string(),
+            Err(e) => format!(
+                "Sync failed: {}",
+                e.to_string().lines().next().unwrap_or("")
+            ),
+        });
+    }
+    if let Some(addr) = disconnect {
+        let _ = AdbCmd::run_v(["disconnect", addr.as_str()]);
+    }
+    match res {
+        Ok(true) if exit_code => ExitCode::from(1),
+        Ok(_) => ExitCode::SUCCESS,
         Err(e) => {
             eprintln!("ERROR: {:?}", e);
-            ExitCode::FAILURE
+            if exit_code {
+                ExitCode::from(2)
+            } else {
+                ExitCode::FAILURE
+            }
         }
     }
 }
@@ -88,9 +975,10 @@ mod tests {
             &mut LocalFS,
             PathBuf::from_str(r"test-from").unwrap(),
             PathBuf::from_str(r"test-to").unwrap(),
-            true,
-            Vec::new(),
-            false,
+            SinkOpts {
+                delete_if_dne: true,
+                ..Default::default()
+            },
         )
         .unwrap();
     }
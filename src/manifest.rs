@@ -0,0 +1,271 @@
+// `sha256sum` compatible manifests of a pulled tree, written next to it as `<dir>.sha256sums`.
+// every `hash  path` line is preceded by a `# size mtime` comment, which sha256sum skips, so
+// `sha256sum -c DCIM.sha256sums` works from the destination directory
+
+use crate::filter::Filter;
+use crate::fs::{sha256_local, FileMode, FileSystem, LocalFS};
+use crate::tree::build_tree;
+use crate::{is_verbose, logi, logv, logw, CResult};
+use chainerror::Context;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use typed_path::{Utf8UnixPath as UnixPath, Utf8UnixPathBuf as UnixPathBuf};
+
+pub const SUFFIX: &str = ".sha256sums";
+
+// one file of a manifest. plain sha256sum files have no sizes and mtimes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub hash: String,
+    pub size: Option<u64>,
+    pub timestamp: Option<u32>,
+    // relative to the manifest's directory
+    pub path: String,
+}
+
+pub fn path_for(root: &UnixPath) -> PathBuf {
+    PathBuf::from(format!("{root}{SUFFIX}"))
+}
+
+// names with a backslash or newline are escaped, and their line starts with a backslash
+fn format(entries: &[Entry]) -> String {
+    let mut out = String::new();
+    for e in entries {
+        if let (Some(size), Some(timestamp)) = (e.size, e.timestamp) {
+            out.push_str(&format!("# {size} {timestamp}\n"));
+        }
+        if e.path.contains(['\\', '\n']) {
+            let path = e.path.replace('\\', "\\\\").replace('\n', "\\n");
+            out.push_str(&format!("\\{}  {}\n", e.hash, path));
+        } else {
+            out.push_str(&format!("{}  {}\n", e.hash, e.path));
+        }
+    }
+    out
+}
+
+fn parse(s: &str) -> Result<Vec<Entry>, usize> {
+    let mut entries = Vec::new();
+    let mut meta = None;
+    for (i, line) in s.lines().enumerate() {
+        if let Some(comment) = line.strip_prefix('#') {
+            let mut fields = comment.split_whitespace();
+            meta = match (fields.next(), fields.next()) {
+                (Some(size), Some(mtime)) => size.parse().ok().zip(mtime.parse().ok()),
+                _ => None,
+            };
+            continue;
+        }
+        let (line, escaped) = match line.strip_prefix('\\') {
+            Some(line) => (line, true),
+            None => (line, false),
+        };
+        // ` *` marks binary mode in sha256sum's output
+        let Some((hash, path)) = line.split_once("  ").or_else(|| line.split_once(" *")) else {
+            return Err(i + 1);
+        };
+        if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(i + 1);
+        }
+        let path = if escaped {
+            let mut out = String::new();
+            let mut chars = path.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => match chars.next() {
+                        Some('n') => out.push('\n'),
+                        Some('\\') => out.push('\\'),
+                        _ => return Err(i + 1),
+                    },
+                    c => out.push(c),
+                }
+            }
+            out
+        } else {
+            path.to_string()
+        };
+        let (size, timestamp) = meta.take().unzip();
+        entries.push(Entry {
+            hash: hash.to_ascii_lowercase(),
+            size,
+            timestamp,
+            path,
+        });
+    }
+    Ok(entries)
+}
+
+pub fn read(path: &Path) -> CResult<Vec<Entry>> {
+    let s = std::fs::read_to_string(path).annotate()?;
+    parse(&s).map_err(|line| format!("'{}' line {} is malformed", path.display(), line).into())
+}
+
+// hashes every file under `root` into `<root>.sha256sums`. files whose size and mtime match
+// the previous manifest are not hashed again
+pub fn write<FS: FileSystem>(fs: &mut FS, root: &UnixPath) -> CResult<()> {
+    let manifest = path_for(root);
+    let previous = if manifest.exists() {
+        read(&manifest).annotate()?
+    } else {
+        Vec::new()
+    };
+    let known: HashMap<&str, &Entry> = previous.iter().map(|e| (e.path.as_str(), e)).collect();
+    let Some(root_sf) = fs.stat(root).annotate()? else {
+        return Err(format!("'{}' does not exist", root).into());
+    };
+    let name = root_sf.name.to_string();
+    let tree = build_tree(fs, root_sf, root, &Filter::default()).annotate()?;
+    let mut files = tree.files();
+    files.sort_unstable_by(|a, b| a.strip_path.as_str().cmp(b.strip_path.as_str()));
+    let mut entries = Vec::with_capacity(files.len());
+    // indices of the entries whose files have to be hashed, all in one go
+    let mut unknown = Vec::new();
+    for f in &files {
+        let path = format!("{}/{}", name, f.strip_path);
+        let (size, timestamp) = (Some(f.sf.size), Some(f.sf.timestamp));
+        let hash = match known.get(path.as_str()) {
+            Some(e) if e.size == size && e.timestamp == timestamp => e.hash.clone(),
+            _ => {
+                logv!("HASH: {}", f.sf.path);
+                unknown.push(entries.len());
+                String::new()
+            }
+        };
+        entries.push(Entry {
+            hash,
+            size,
+            timestamp,
+            path,
+        });
+    }
+    let paths: Vec<&UnixPath> = unknown.iter().map(|&i| &*files[i].sf.path).collect();
+    for (i, hash) in unknown.into_iter().zip(fs.sha256_many(&paths).annotate()?) {
+        entries[i].hash = hash;
+    }
+    let tmp = PathBuf::from(format!("{}.tmp", manifest.display()));
+    std::fs::write(&tmp, format(&entries)).annotate()?;
+    std::fs::rename(&tmp, &manifest).annotate()?;
+    logi!(
+        "Wrote manifest '{}' of {} files",
+        manifest.display(),
+        entries.len()
+    );
+    Ok(())
+}
+
+// re-hashes the files of `manifest` under `dir`, returns how many are missing or corrupt.
+// files in the manifest's top directories that it does not list are reported as extra, they
+// may just be newer than the manifest so they do not count as bad
+pub fn verify(dir: &Path, manifest: &Path) -> CResult<usize> {
+    let entries = read(manifest).annotate()?;
+    if entries.is_empty() {
+        return Err(format!("No files listed in '{}'", manifest.display()).into());
+    }
+    let dir = UnixPathBuf::from(dir.to_str().unwrap());
+    let mut bad = 0;
+    let mut present = Vec::new();
+    for e in &entries {
+        let path = dir.join(&e.path);
+        match LocalFS.stat(&path).annotate()? {
+            None => {
+                logw!(W006: "MISSING: {}", e.path);
+                bad += 1;
+            }
+            Some(sf) if e.size.is_some_and(|size| size != sf.size) => {
+                logw!(W005: "CORRUPT (SIZE): {}", e.path);
+                bad += 1;
+            }
+            Some(_) => present.push((e, path)),
+        }
+    }
+    let paths: Vec<&UnixPath> = present.iter().map(|(_, p)| p.as_path()).collect();
+    for ((e, _), hash) in present.iter().zip(sha256_local(&paths)) {
+        if hash.annotate()? != e.hash {
+            logw!(W005: "CORRUPT: {}", e.path);
+            bad += 1;
+        } else {
+            logv!("OK: {}", e.path);
+        }
+    }
+
+    let listed: HashSet<&str> = entries.iter().map(|e| e.path.as_str()).collect();
+    let tops: BTreeSet<&str> = entries
+        .iter()
+        .filter_map(|e| e.path.split_once('/').map(|(top, _)| top))
+        .collect();
+    let mut extra = 0;
+    for top in tops {
+        let root = dir.join(top);
+        let Some(sf) = LocalFS.stat(&root).annotate()? else {
+            continue;
+        };
+        if sf.mode != FileMode::Dir {
+            continue;
+        }
+        let tree = build_tree(&mut LocalFS, sf, &root, &Filter::default()).annotate()?;
+        for f in tree.files() {
+            let path = format!("{}/{}", top, f.strip_path);
+            if !listed.contains(path.as_str()) {
+                logw!(W017: "EXTRA: {}", path);
+                extra += 1;
+            }
+        }
+    }
+    logi!(
+        "Checked {} files, {} bad, {} extra",
+        entries.len(),
+        bad,
+        extra
+    );
+    Ok(bad)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let hash = "ab".repeat(32);
+        let entries = vec![
+            Entry {
+                hash: hash.clone(),
+                size: Some(3),
+                timestamp: Some(1_700_000_000),
+                path: "DCIM/a b.jpg".to_string(),
+            },
+            Entry {
+                hash: hash.clone(),
+                size: Some(0),
+                timestamp: Some(0),
+                path: "DCIM/odd\\name\n.jpg".to_string(),
+            },
+        ];
+        let s = format(&entries);
+        assert!(s.contains("\\".to_string() + &hash + "  DCIM/odd\\\\name\\n.jpg\n"));
+        assert_eq!(parse(&s).unwrap(), entries);
+        // plain sha256sum output, binary mode too
+        let plain = parse(&format!("{hash}  a\n{hash} *b\n")).unwrap();
+        assert_eq!(plain[1].path, "b");
+        assert_eq!(plain[0].size, None);
+        assert_eq!(parse("not a manifest\n"), Err(1));
+    }
+
+    #[test]
+    fn verify_dir() {
+        let _ = crate::VERBOSE.set(false);
+        let dir = std::env::temp_dir().join(format!("adb-sink-manifest-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("DCIM")).unwrap();
+        std::fs::write(dir.join("DCIM/a"), b"a").unwrap();
+        std::fs::write(dir.join("DCIM/b"), b"b").unwrap();
+        let root = UnixPathBuf::from(dir.join("DCIM").to_str().unwrap());
+        write(&mut LocalFS, &root).unwrap();
+        let manifest = path_for(&root);
+        assert_eq!(verify(&dir, &manifest).unwrap(), 0);
+        std::fs::write(dir.join("DCIM/a"), b"x").unwrap();
+        std::fs::remove_file(dir.join("DCIM/b")).unwrap();
+        std::fs::write(dir.join("DCIM/c"), b"c").unwrap();
+        assert_eq!(verify(&dir, &manifest).unwrap(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,221 @@
+use crate::adb::quote;
+use crate::fs::{AndroidFS, AnyFileSystem, FileMode, FileSystem, SyncFile, Transport};
+use crate::{logi, CResult};
+use chainerror::Context;
+use std::collections::{HashMap, HashSet};
+use typed_path::{Utf8UnixPath as UnixPath, Utf8UnixPathBuf as UnixPathBuf};
+
+// MTP "association" objects, i.e. directories
+const FORMAT_DIR: u32 = 0x3001;
+
+// MediaStore names files that are still being written `.pending-<expiry>-<name>` and files
+// in the trash `.trashed-<expiry>-<name>`. pulling a pending file gives a truncated copy
+pub const MEDIA_TEMP_PATTERNS: [&str; 2] = [".pending-*", ".trashed-*"];
+
+// enumerates files through the MediaStore content provider instead of `adb ls`,
+// which also sees media that scoped storage hides from a plain listing.
+// everything but listing is delegated to `AndroidFS`
+pub struct MediaStoreFS {
+    pub inner: AndroidFS,
+    // extra sql selection, ANDed with the path filter
+    pub selection: Option<String>,
+    // directory -> entries, filled by the first `list_dir` call
+    index: Option<(UnixPathBuf, HashMap<UnixPathBuf, Vec<SyncFile>>)>,
+}
+
+impl MediaStoreFS {
+    pub fn new(inner: AndroidFS, selection: Option<String>) -> Self {
+        Self {
+            inner,
+            selection,
+            index: None,
+        }
+    }
+
+    fn query(&mut self, root: &UnixPath) -> CResult<HashMap<UnixPathBuf, Vec<SyncFile>>> {
+        // MediaStore stores canonical paths (/storage/emulated/0/..), not /sdcard/..
+        let canon = self
+            .inner
+            .shell
+            .run(["realpath", quote(root.as_str()).as_str()])
+            .annotate()?;
+        let canon = canon.trim_end();
+        let mut selection = format!(
+            "_data LIKE {} AND format != {FORMAT_DIR}",
+            sql_quote(&format!("{}/%", canon.trim_end_matches('/')))
+        );
+        if let Some(extra) = &self.selection {
+            selection.push_str(&format!(" AND ({extra})"));
+        }
+        let op = self
+            .inner
+            .shell
+            .run([
+                "content",
+                "query",
+                "--uri",
+                "content://media/external/file",
+                "--projection",
+                "_data:_size:date_modified",
+                "--where",
+                quote(&selection).as_str(),
+            ])
+            .annotate()?;
+
+        let mut index: HashMap<UnixPathBuf, Vec<SyncFile>> = HashMap::new();
+        let mut dirs = HashSet::new();
+        let mut count = 0;
+        for line in op.lines() {
+            let Some((data, size, mtime)) = parse_row(line) else {
+                continue;
+            };
+            let Some(rel) = data.strip_prefix(canon).map(|r| r.trim_start_matches('/')) else {
+                continue;
+            };
+            let path = root.join(rel);
+            let parent = path.parent().expect("under root").to_path_buf();
+            add_dirs(&mut index, &mut dirs, root, &parent);
+            index.entry(parent).or_default().push(SyncFile {
+                mode: FileMode::File,
+                size,
+                timestamp: mtime,
+                name: path.file_name().unwrap_or_default().into(),
+                path: path.into_boxed_path(),
+            });
+            count += 1;
+        }
+        logi!("MediaStore returned {} files under '{}'", count, root);
+        Ok(index)
+    }
+}
+
+// makes every directory between `root` and `dir` show up in its parent's listing
+fn add_dirs(
+    index: &mut HashMap<UnixPathBuf, Vec<SyncFile>>,
+    dirs: &mut HashSet<UnixPathBuf>,
+    root: &UnixPath,
+    dir: &UnixPath,
+) {
+    if dir == root || !dir.starts_with(root) || !dirs.insert(dir.to_path_buf()) {
+        return;
+    }
+    let parent = dir.parent().expect("under root");
+    add_dirs(index, dirs, root, parent);
+    index
+        .entry(parent.to_path_buf())
+        .or_default()
+        .push(SyncFile {
+            mode: FileMode::Dir,
+            size: 0,
+            timestamp: 0,
+            name: dir.file_name().unwrap_or_default().into(),
+            path: dir.to_path_buf().into_boxed_path(),
+        });
+}
+
+fn sql_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+// "Row: 0 _data=/storage/emulated/0/DCIM/a, b.jpg, _size=123, date_modified=1700000000"
+fn parse_row(line: &str) -> Option<(&str, u64, u32)> {
+    let rest = line.strip_prefix("Row: ")?;
+    let rest = rest.split_once(' ')?.1.strip_prefix("_data=")?;
+    let (data, rest) = rest.rsplit_once(", _size=")?;
+    let (size, mtime) = rest.split_once(", date_modified=")?;
+    // NULL for pending/unscanned entries
+    let size = size.parse().ok()?;
+    let mtime = mtime.trim_end().parse().ok()?;
+    Some((data, size, mtime))
+}
+
+impl FileSystem for MediaStoreFS {
+    fn mkdir(&mut self, path: &UnixPath) -> CResult<()> {
+        self.inner.mkdir(path)
+    }
+
+    fn list_dir(&mut self, path: &UnixPath) -> CResult<Vec<SyncFile>> {
+        let under_root = matches!(&self.index, Some((root, _)) if path.starts_with(root));
+        if !under_root {
+            let index = self.query(path).annotate()?;
+            self.index = Some((path.to_path_buf(), index));
+        }
+        let (_, index) = self.index.as_mut().expect("just built");
+        Ok(index.remove(&path.to_path_buf()).unwrap_or_default())
+    }
+
+    fn stat(&mut self, path: &UnixPath) -> CResult<Option<SyncFile>> {
+        self.inner.stat(path)
+    }
+
+    fn stat_many(&mut self, paths: &[&UnixPath]) -> CResult<Vec<Option<SyncFile>>> {
+        self.inner.stat_many(paths)
+    }
+
+    fn rm(&mut self, path: &UnixPath) -> CResult<()> {
+        self.inner.rm(path)
+    }
+
+    fn rm_dir(&mut self, path: &UnixPath) -> CResult<()> {
+        self.inner.rm_dir(path)
+    }
+
+    fn set_mtime(&mut self, path: &UnixPath, timestamp: u32) -> CResult<()> {
+        self.inner.set_mtime(path, timestamp)
+    }
+
+    fn sha256(&mut self, path: &UnixPath) -> CResult<String> {
+        self.inner.sha256(path)
+    }
+    fn sha256_many(&mut self, paths: &[&UnixPath]) -> CResult<Vec<String>> {
+        self.inner.sha256_many(paths)
+    }
+    fn copy_within(&mut self, from: &UnixPath, to: &UnixPath) -> CResult<()> {
+        self.inner.copy_within(from, to)
+    }
+    fn sha256_ends(&mut self, path: &UnixPath, size: u64, bytes: u64) -> CResult<String> {
+        self.inner.sha256_ends(path, size, bytes)
+    }
+
+    fn get_xattrs(&mut self, path: &UnixPath) -> CResult<Vec<(String, Vec<u8>)>> {
+        self.inner.get_xattrs(path)
+    }
+
+    fn set_xattrs(&mut self, path: &UnixPath, attrs: &[(String, Vec<u8>)]) -> CResult<()> {
+        self.inner.set_xattrs(path, attrs)
+    }
+
+    fn get_owner(&mut self, path: &UnixPath) -> CResult<(u32, u32)> {
+        self.inner.get_owner(path)
+    }
+
+    fn link_ids(&mut self, paths: &[&UnixPath]) -> CResult<Vec<Option<(u64, u64)>>> {
+        self.inner.link_ids(paths)
+    }
+}
+
+impl AnyFileSystem for MediaStoreFS {
+    fn transport(&self) -> Transport {
+        Transport::Adb
+    }
+
+    fn copy_from(
+        &mut self,
+        src: Transport,
+        from: &UnixPath,
+        to: &UnixPath,
+        timestamp: Option<u32>,
+    ) -> CResult<()> {
+        self.inner.copy_from(src, from, to, timestamp)
+    }
+
+    fn copy_dir_from(
+        &mut self,
+        src: Transport,
+        from: &UnixPath,
+        to: &UnixPath,
+        timestamp: Option<u32>,
+    ) -> CResult<()> {
+        self.inner.copy_dir_from(src, from, to, timestamp)
+    }
+}
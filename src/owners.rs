@@ -0,0 +1,105 @@
+use crate::CResult;
+use chainerror::Context;
+use std::collections::BTreeMap;
+use std::path::Path;
+use typed_path::{Utf8UnixPath as UnixPath, Utf8UnixPathBuf as UnixPathBuf};
+
+// name of the database in the root of a `pull --owner` destination, `uid:gid  path` lines.
+// `push --owner` gives the files back their owners from it, Android ignores app data that
+// belongs to the wrong uid
+pub const OWNER_DB: &str = ".adb-sink-owners";
+
+// uid and gid of every pulled file and directory, keyed by the path relative to the root
+pub struct OwnerDb {
+    root: UnixPathBuf,
+    owners: BTreeMap<String, (u32, u32)>,
+}
+
+impl OwnerDb {
+    // a root without a database starts an empty one
+    pub fn open(root: &UnixPath) -> CResult<Self> {
+        let mut owners = BTreeMap::new();
+        let db = Path::new(root.as_str()).join(OWNER_DB);
+        match std::fs::read_to_string(&db) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            r => {
+                for (i, line) in r.annotate()?.lines().enumerate() {
+                    let owner = line.split_once("  ").and_then(|(owner, path)| {
+                        let (uid, gid) = owner.split_once(':')?;
+                        Some((path, (uid.parse().ok()?, gid.parse().ok()?)))
+                    });
+                    let Some((path, owner)) = owner else {
+                        return Err(
+                            format!("'{}' line {} is malformed", db.display(), i + 1).into()
+                        );
+                    };
+                    owners.insert(path.to_string(), owner);
+                }
+            }
+        }
+        Ok(Self {
+            root: root.to_path_buf(),
+            owners,
+        })
+    }
+
+    pub fn record(&mut self, path: &UnixPath, owner: (u32, u32)) {
+        self.owners.insert(self.relative(path).to_string(), owner);
+    }
+
+    pub fn get(&self, path: &UnixPath) -> Option<(u32, u32)> {
+        self.owners.get(self.relative(path)).copied()
+    }
+
+    // forgets `path` and everything under it
+    pub fn remove(&mut self, path: &UnixPath) {
+        let path = self.relative(path).to_string();
+        let dir = format!("{path}/");
+        self.owners
+            .retain(|p, _| *p != path && !p.starts_with(&dir));
+    }
+
+    fn relative<'a>(&self, path: &'a UnixPath) -> &'a str {
+        path.strip_prefix(&self.root)
+            .map_or(path.as_str(), |p| p.as_str())
+    }
+
+    pub fn save(&self) -> CResult<()> {
+        let mut out = String::new();
+        for (path, (uid, gid)) in &self.owners {
+            out.push_str(&format!("{uid}:{gid}  {path}\n"));
+        }
+        // so that an interrupted write does not lose the old database
+        let root = Path::new(self.root.as_str());
+        let tmp = root.join(format!("{OWNER_DB}.tmp"));
+        std::fs::write(&tmp, out).annotate()?;
+        std::fs::rename(&tmp, root.join(OWNER_DB)).annotate()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let dir = std::env::temp_dir().join(format!("adb-sink-owners-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let root = UnixPathBuf::from(dir.to_str().unwrap());
+        let mut db = OwnerDb::open(&root).unwrap();
+        db.record(&root.join("com.app/files/a b"), (10123, 10123));
+        db.record(&root.join("com.app/cache"), (10123, 20123));
+        db.save().unwrap();
+
+        let mut db = OwnerDb::open(&root).unwrap();
+        assert_eq!(
+            db.get(&root.join("com.app/files/a b")),
+            Some((10123, 10123))
+        );
+        assert_eq!(db.get(&root.join("com.app/cache")), Some((10123, 20123)));
+        db.remove(&root.join("com.app/files"));
+        assert_eq!(db.get(&root.join("com.app/files/a b")), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
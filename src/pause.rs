@@ -0,0 +1,53 @@
+use crate::logi;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+// set by SIGUSR1, cleared by SIGUSR2
+static PAUSED: AtomicBool = AtomicBool::new(false);
+// set by SIGTERM, the sync stops before its next transfer
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+pub fn install_signal_handlers() {
+    extern "C" fn pause(_: libc::c_int) {
+        PAUSED.store(true, Ordering::Relaxed);
+    }
+    extern "C" fn resume(_: libc::c_int) {
+        PAUSED.store(false, Ordering::Relaxed);
+    }
+    // a second SIGTERM does not wait for the transfer
+    extern "C" fn stop(_: libc::c_int) {
+        if STOPPING.swap(true, Ordering::Relaxed) {
+            unsafe { libc::_exit(143) };
+        }
+        PAUSED.store(false, Ordering::Relaxed);
+    }
+    // SAFETY: the handlers only touch atomics or exit
+    unsafe {
+        libc::signal(libc::SIGUSR1, pause as libc::sighandler_t);
+        libc::signal(libc::SIGUSR2, resume as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, stop as libc::sighandler_t);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn install_signal_handlers() {}
+
+pub fn stop_requested() -> bool {
+    STOPPING.load(Ordering::Relaxed)
+}
+
+// blocks while paused. called before every transfer, so a running one is finished first
+pub fn wait_if_paused() {
+    if !PAUSED.load(Ordering::Relaxed) {
+        return;
+    }
+    logi!(
+        "Paused, send SIGUSR2 to process {} to resume",
+        std::process::id()
+    );
+    while PAUSED.load(Ordering::Relaxed) && !stop_requested() {
+        std::thread::sleep(Duration::from_millis(250));
+    }
+    logi!("Resuming");
+}
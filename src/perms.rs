@@ -0,0 +1,210 @@
+use std::str::FromStr;
+
+// rsync style --chmod: comma separated rules, each for directories only with a leading `D`,
+// files only with `F`, else both. a rule is an octal mode or symbolic `[ugoa]*[-+=][rwxX]*`
+// changes, applied in order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Chmod {
+    rules: Vec<ChmodRule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ChmodRule {
+    dirs: bool,
+    files: bool,
+    change: Change,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Change {
+    Octal(u32),
+    // `who` and `perms` are masks of the permission bits, `X` is only x for directories or
+    // files that are executable for someone
+    Symbolic {
+        who: u32,
+        op: char,
+        perms: u32,
+        x_if_dir: bool,
+    },
+}
+
+impl FromStr for Chmod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rules = Vec::new();
+        for rule in s.split(',') {
+            let (dirs, files, change) = match rule.as_bytes().first() {
+                Some(b'D') => (true, false, &rule[1..]),
+                Some(b'F') => (false, true, &rule[1..]),
+                _ => (true, true, rule),
+            };
+            rules.push(ChmodRule {
+                dirs,
+                files,
+                change: parse_change(change).ok_or_else(|| format!("bad chmod rule '{rule}'"))?,
+            });
+        }
+        Ok(Self { rules })
+    }
+}
+
+fn parse_change(s: &str) -> Option<Change> {
+    if !s.is_empty() && s.bytes().all(|b| (b'0'..=b'7').contains(&b)) {
+        return u32::from_str_radix(s, 8)
+            .ok()
+            .filter(|m| *m <= 0o7777)
+            .map(Change::Octal);
+    }
+    let op_at = s.find(['+', '-', '='])?;
+    let mut who = 0;
+    for c in s[..op_at].chars() {
+        who |= match c {
+            'u' => 0o700,
+            'g' => 0o070,
+            'o' => 0o007,
+            'a' => 0o777,
+            _ => return None,
+        };
+    }
+    if who == 0 {
+        who = 0o777;
+    }
+    let mut perms = 0;
+    let mut x_if_dir = false;
+    for c in s[op_at + 1..].chars() {
+        perms |= match c {
+            'r' => 0o444,
+            'w' => 0o222,
+            'x' => 0o111,
+            'X' => {
+                x_if_dir = true;
+                0
+            }
+            _ => return None,
+        };
+    }
+    Some(Change::Symbolic {
+        who,
+        op: s[op_at..].chars().next()?,
+        perms,
+        x_if_dir,
+    })
+}
+
+impl Chmod {
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    // the permission bits `mode` becomes
+    pub fn apply(&self, mut mode: u32, is_dir: bool) -> u32 {
+        for rule in &self.rules {
+            if !(is_dir && rule.dirs || !is_dir && rule.files) {
+                continue;
+            }
+            mode = match rule.change {
+                Change::Octal(m) => m,
+                Change::Symbolic {
+                    who,
+                    op,
+                    perms,
+                    x_if_dir,
+                } => {
+                    let x = if x_if_dir && (is_dir || mode & 0o111 != 0) {
+                        0o111
+                    } else {
+                        0
+                    };
+                    let bits = (perms | x) & who;
+                    match op {
+                        '+' => mode | bits,
+                        '-' => mode & !bits,
+                        _ => mode & !who | bits,
+                    }
+                }
+            };
+        }
+        mode
+    }
+}
+
+// --chown `user:group`, `user` or `:group`, by name or number
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Chown {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl FromStr for Chown {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (user, group) = s.split_once(':').unwrap_or((s, ""));
+        let uid = match user {
+            "" => None,
+            u => Some(u.parse().or_else(|_| lookup(u, false))?),
+        };
+        let gid = match group {
+            "" => None,
+            g => Some(g.parse().or_else(|_| lookup(g, true))?),
+        };
+        if uid.is_none() && gid.is_none() {
+            return Err("--chown needs a user, a group or both".to_string());
+        }
+        Ok(Self { uid, gid })
+    }
+}
+
+#[cfg(unix)]
+fn lookup(name: &str, group: bool) -> Result<u32, String> {
+    let c_name = std::ffi::CString::new(name).map_err(|e| e.to_string())?;
+    // SAFETY: the returned entries are only read right away, before any other lookup
+    let id = unsafe {
+        if group {
+            let gr = libc::getgrnam(c_name.as_ptr());
+            (!gr.is_null()).then(|| (*gr).gr_gid)
+        } else {
+            let pw = libc::getpwnam(c_name.as_ptr());
+            (!pw.is_null()).then(|| (*pw).pw_uid)
+        }
+    };
+    let kind = if group { "group" } else { "user" };
+    id.ok_or_else(|| format!("no {kind} named '{name}'"))
+}
+
+#[cfg(not(unix))]
+fn lookup(name: &str, _group: bool) -> Result<u32, String> {
+    Err(format!("'{name}' is not a number, names only work on unix"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chmod_rules() {
+        let c: Chmod = "D755,F644".parse().unwrap();
+        assert_eq!(c.apply(0o700, true), 0o755);
+        assert_eq!(c.apply(0o600, false), 0o644);
+
+        let c: Chmod = "go-w,Fa+r,Du=rwx,g=rX".parse().unwrap();
+        assert_eq!(c.apply(0o666, false), 0o644);
+        assert_eq!(c.apply(0o777, true), 0o755);
+        // X only sets x on files that already have it for someone
+        assert_eq!(c.apply(0o700, false), 0o754);
+
+        assert!("Fu+q".parse::<Chmod>().is_err());
+        assert!("8".parse::<Chmod>().is_err());
+        assert!("".parse::<Chmod>().is_err());
+    }
+
+    #[test]
+    fn chown_ids() {
+        let c: Chown = "1000:100".parse().unwrap();
+        assert_eq!((c.uid, c.gid), (Some(1000), Some(100)));
+        let c: Chown = ":100".parse().unwrap();
+        assert_eq!((c.uid, c.gid), (None, Some(100)));
+        assert!(":".parse::<Chown>().is_err());
+    }
+}
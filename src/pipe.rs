@@ -0,0 +1,78 @@
+// `-` as the destination of a pull or the source of a push: one file streamed between the
+// device and a pipe, without a temporary copy on either side
+//   adb-sink pull /sdcard/dump.bin - | xxd | less
+//   gzip -dc db.gz | adb-sink push - /sdcard/db
+
+use crate::adb::{quote, AdbCmd};
+use crate::fs::{check_device_writable, AnyFileSystem, FileMode, FileSystem};
+use crate::{human_size, logi, CResult};
+use chainerror::Context;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::process::Stdio;
+use typed_path::Utf8UnixPath as UnixPath;
+
+pub fn is_stdio(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+// writes the device file `path` to stdout. a reader that stops early, like `head`, is no error
+pub fn pull_to_stdout(device_fs: &mut Box<dyn AnyFileSystem>, path: &UnixPath) -> CResult<()> {
+    let Some(sf) = device_fs.stat(path).annotate()? else {
+        return Err(format!("Source '{path}' does not exist").into());
+    };
+    if sf.mode != FileMode::File {
+        return Err(format!("'{path}' is not a file, only single files stream to stdout").into());
+    }
+    let mut child = AdbCmd::new()
+        .args(["exec-out", "cat", quote(path.as_str()).as_str()])
+        .spawn()
+        .annotate()?;
+    let out = child.stdout.as_mut().expect("stdout piped");
+    let copied = match std::io::copy(out, &mut std::io::stdout().lock()) {
+        Err(e) if e.kind() == ErrorKind::BrokenPipe => {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Ok(());
+        }
+        r => r.annotate()?,
+    };
+    if !child.wait().annotate()?.success() {
+        return Err("adb exec-out failed".into());
+    }
+    // `cat` errors do not reach us through exec-out, a short read is how they show
+    if copied != sf.size {
+        return Err(format!("got {copied} of the {} bytes of '{path}'", sf.size).into());
+    }
+    logi!("Streamed '{}' ({}) to stdout", path, human_size(copied));
+    Ok(())
+}
+
+// writes stdin to the device file `path`, replacing it
+pub fn push_from_stdin(device_fs: &mut Box<dyn AnyFileSystem>, path: &UnixPath) -> CResult<()> {
+    check_device_writable("write stdin to", path).annotate()?;
+    if device_fs
+        .stat(path)
+        .annotate()?
+        .is_some_and(|sf| sf.mode == FileMode::Dir)
+    {
+        return Err(format!("'{path}' is a directory, give the name of the file to write").into());
+    }
+    let cat = format!("cat > {}", quote(path.as_str()));
+    let status = AdbCmd::new()
+        .args(["exec-in", cat.as_str()])
+        .stdin(Stdio::inherit())
+        .spawn()
+        .annotate()?
+        .wait()
+        .annotate()?;
+    if !status.success() {
+        return Err("adb exec-in failed".into());
+    }
+    // exec-in does not pass on the exit status of `cat`, the file has to be there
+    let Some(sf) = device_fs.stat(path).annotate()? else {
+        return Err(format!("could not write '{path}' on the device").into());
+    };
+    logi!("Wrote {} from stdin to '{}'", human_size(sf.size), path);
+    Ok(())
+}
@@ -0,0 +1,434 @@
+// `--plan-out`: the operations a sync would do, written to a file instead of being done, and
+// `adb-sink apply` doing exactly those once they were reviewed. a plan keeps the size and mtime
+// every path had on both sides, apply refuses to start when any of them changed since
+//   adb-sink plan 1
+//   source	/sdcard/DCIM
+//   dest	/home/me/backup/DCIM
+//   set-time	true
+//   delete	<file|dir>	<size>	<mtime>	<dest>
+//   mkdir	<dest>
+//   copy	<reason>	<size>	<mtime>	<source>	<dest>	<dest size>	<dest mtime>
+// a new file's dest size and mtime are `-`. tabs, newlines and backslashes in paths are escaped
+
+use crate::args::Direction;
+use crate::fs::{AnyFileSystem, FileMode, FileSystem, LocalFS, SyncFile};
+use crate::tree::Node;
+use crate::{history, logi, logw, update_reason, CResult};
+use chainerror::Context;
+use std::path::Path;
+use typed_path::{Utf8UnixPath as UnixPath, Utf8UnixPathBuf as UnixPathBuf};
+
+const HEADER: &str = "adb-sink plan 1";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Delete {
+        path: String,
+        dir: bool,
+        size: u64,
+        mtime: u32,
+    },
+    Mkdir {
+        path: String,
+    },
+    Copy {
+        reason: String,
+        from: String,
+        size: u64,
+        mtime: u32,
+        to: String,
+        // size and mtime of the file it replaces
+        was: Option<(u64, u32)>,
+    },
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Plan {
+    pub source: String,
+    pub dest: String,
+    pub set_time: bool,
+    pub ops: Vec<Op>,
+}
+
+impl Plan {
+    // what a sync of `src_path` into `dst_path` would do, from its diff
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn build<SRC: FileSystem, DEST: FileSystem>(
+        src_fs: &mut SRC,
+        dest_fs: &mut DEST,
+        src_path: &UnixPath,
+        dst_path: &UnixPath,
+        set_time: bool,
+        spot_check: Option<u64>,
+        dest_doesnt_have: &[&Node],
+        deleted: &[&Node],
+        both_have_files: &[(&SyncFile, &SyncFile)],
+    ) -> CResult<Self> {
+        let mut ops = Vec::new();
+        for n in deleted {
+            ops.push(Op::Delete {
+                path: n.sf.path.to_string(),
+                dir: n.sf.mode == FileMode::Dir,
+                size: n.sf.size,
+                mtime: n.sf.timestamp,
+            });
+        }
+        fn add_new(n: &Node, dst_path: &UnixPath, ops: &mut Vec<Op>) {
+            let to = dst_path.join(&n.strip_path).to_string();
+            match n.sf.mode {
+                FileMode::File => ops.push(Op::Copy {
+                    reason: "DNE".to_string(),
+                    from: n.sf.path.to_string(),
+                    size: n.sf.size,
+                    mtime: n.sf.timestamp,
+                    to,
+                    was: None,
+                }),
+                FileMode::Dir => {
+                    ops.push(Op::Mkdir { path: to });
+                    for e in n.entries.values() {
+                        add_new(e, dst_path, ops);
+                    }
+                }
+                FileMode::Symlink => logw!(W002: "Skipping symlink {}", n.sf.path),
+            }
+        }
+        for n in dest_doesnt_have {
+            add_new(n, dst_path, &mut ops);
+        }
+        for (dest_file, src_file) in both_have_files {
+            if let Some(reason) =
+                update_reason(src_fs, dest_fs, dest_file, src_file, spot_check).annotate()?
+            {
+                ops.push(Op::Copy {
+                    reason: reason.to_string(),
+                    from: src_file.path.to_string(),
+                    size: src_file.size,
+                    mtime: src_file.timestamp,
+                    to: dest_file.path.to_string(),
+                    was: Some((dest_file.size, dest_file.timestamp)),
+                });
+            }
+        }
+        Ok(Self {
+            source: src_path.to_string(),
+            dest: dst_path.to_string(),
+            set_time,
+            ops,
+        })
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut out = format!("{HEADER}\n");
+        out.push_str(&format!("source\t{}\n", escape(&self.source)));
+        out.push_str(&format!("dest\t{}\n", escape(&self.dest)));
+        out.push_str(&format!("set-time\t{}\n", self.set_time));
+        for op in &self.ops {
+            let line = match op {
+                Op::Delete {
+                    path,
+                    dir,
+                    size,
+                    mtime,
+                } => {
+                    let kind = if *dir { "dir" } else { "file" };
+                    format!("delete\t{kind}\t{size}\t{mtime}\t{}", escape(path))
+                }
+                Op::Mkdir { path } => format!("mkdir\t{}", escape(path)),
+                Op::Copy {
+                    reason,
+                    from,
+                    size,
+                    mtime,
+                    to,
+                    was,
+                } => {
+                    let (was_size, was_mtime) = match was {
+                        Some((s, m)) => (s.to_string(), m.to_string()),
+                        None => ("-".to_string(), "-".to_string()),
+                    };
+                    format!(
+                        "copy\t{reason}\t{size}\t{mtime}\t{}\t{}\t{was_size}\t{was_mtime}",
+                        escape(from),
+                        escape(to)
+                    )
+                }
+            };
+            out.push_str(&line);
+            out.push('\n');
+        }
+        out
+    }
+
+    pub fn read(path: &Path) -> CResult<Self> {
+        let s = std::fs::read_to_string(path).annotate()?;
+        Self::parse(&s).map_err(|line| {
+            format!(
+                "'{}' line {line} is malformed, is it a plan?",
+                path.display()
+            )
+            .into()
+        })
+    }
+
+    // the line number of the first bad line on errors
+    fn parse(s: &str) -> Result<Self, usize> {
+        let mut lines = s.lines().enumerate();
+        if lines.next().map(|(_, l)| l) != Some(HEADER) {
+            return Err(1);
+        }
+        let mut plan = Plan::default();
+        for (i, line) in lines {
+            let f: Vec<&str> = line.split('\t').collect();
+            let op = (|| {
+                match f.as_slice() {
+                    ["source", p] => plan.source = unescape(p),
+                    ["dest", p] => plan.dest = unescape(p),
+                    ["set-time", b] => plan.set_time = b.parse().ok()?,
+                    ["delete", kind, size, mtime, p] => {
+                        return Some(Some(Op::Delete {
+                            path: unescape(p),
+                            dir: match *kind {
+                                "dir" => true,
+                                "file" => false,
+                                _ => return None,
+                            },
+                            size: size.parse().ok()?,
+                            mtime: mtime.parse().ok()?,
+                        }))
+                    }
+                    ["mkdir", p] => return Some(Some(Op::Mkdir { path: unescape(p) })),
+                    ["copy", reason, size, mtime, from, to, was_size, was_mtime] => {
+                        let was = match (*was_size, *was_mtime) {
+                            ("-", "-") => None,
+                            (s, m) => Some((s.parse().ok()?, m.parse().ok()?)),
+                        };
+                        return Some(Some(Op::Copy {
+                            reason: reason.to_string(),
+                            from: unescape(from),
+                            size: size.parse().ok()?,
+                            mtime: mtime.parse().ok()?,
+                            to: unescape(to),
+                            was,
+                        }));
+                    }
+                    _ => return None,
+                }
+                Some(None)
+            })();
+            match op {
+                Some(Some(op)) => plan.ops.push(op),
+                Some(None) => {}
+                None => return Err(i + 1),
+            }
+        }
+        if plan.source.is_empty() || plan.dest.is_empty() {
+            return Err(1);
+        }
+        Ok(plan)
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some(c) => out.push(c),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+// what changed on either side since the plan was made, one line per path
+fn drift(
+    src_fs: &mut dyn AnyFileSystem,
+    dest_fs: &mut dyn AnyFileSystem,
+    plan: &Plan,
+) -> CResult<Vec<String>> {
+    let same = |sf: &Option<SyncFile>, size: u64, mtime: u32| {
+        sf.as_ref()
+            .is_some_and(|sf| sf.mode == FileMode::File && sf.size == size && sf.timestamp == mtime)
+    };
+    let (mut from, mut to) = (Vec::new(), Vec::new());
+    for op in &plan.ops {
+        match op {
+            Op::Delete { path, .. } => to.push(UnixPathBuf::from(path.as_str())),
+            Op::Mkdir { .. } => {}
+            Op::Copy { from: f, to: t, .. } => {
+                from.push(UnixPathBuf::from(f.as_str()));
+                to.push(UnixPathBuf::from(t.as_str()));
+            }
+        }
+    }
+    let from_refs: Vec<&UnixPath> = from.iter().map(|p| p.as_path()).collect();
+    let to_refs: Vec<&UnixPath> = to.iter().map(|p| p.as_path()).collect();
+    let mut src_stats = src_fs.stat_many(&from_refs).annotate()?.into_iter();
+    let mut dest_stats = dest_fs.stat_many(&to_refs).annotate()?.into_iter();
+    let mut drifted = Vec::new();
+    for op in &plan.ops {
+        match op {
+            Op::Delete {
+                path,
+                dir,
+                size,
+                mtime,
+            } => {
+                let sf = dest_stats.next().flatten();
+                let ok = if *dir {
+                    sf.is_some_and(|sf| sf.mode == FileMode::Dir)
+                } else {
+                    same(&sf, *size, *mtime)
+                };
+                if !ok {
+                    drifted.push(format!(
+                        "{path} changed since the plan, it was to be deleted"
+                    ));
+                }
+            }
+            Op::Mkdir { .. } => {}
+            Op::Copy {
+                from,
+                size,
+                mtime,
+                to,
+                was,
+                ..
+            } => {
+                if !same(&src_stats.next().flatten(), *size, *mtime) {
+                    drifted.push(format!("{from} changed since the plan"));
+                }
+                let dest = dest_stats.next().flatten();
+                let ok = match was {
+                    Some((size, mtime)) => same(&dest, *size, *mtime),
+                    None => dest.is_none(),
+                };
+                if !ok {
+                    drifted.push(format!("{to} changed since the plan"));
+                }
+            }
+        }
+    }
+    Ok(drifted)
+}
+
+// does what `plan` lists, after checking that nothing it touches changed since it was made
+pub fn apply(
+    device_fs: &mut Box<dyn AnyFileSystem>,
+    direction: Direction,
+    plan: &Plan,
+) -> CResult<bool> {
+    let mut local_fs: Box<dyn AnyFileSystem> = Box::new(LocalFS);
+    let (src_fs, dest_fs) = match direction {
+        Direction::Pull => (device_fs, &mut local_fs),
+        Direction::Push => (&mut local_fs, device_fs),
+    };
+    let drifted = drift(&mut **src_fs, &mut **dest_fs, plan).annotate()?;
+    if !drifted.is_empty() {
+        for d in drifted.iter().take(10) {
+            logw!("{}", d);
+        }
+        return Err(format!(
+            "{} paths changed since the plan was made, make a new one",
+            drifted.len()
+        )
+        .into());
+    }
+
+    let src_transport = src_fs.transport();
+    for op in &plan.ops {
+        match op {
+            Op::Delete { path, dir, .. } => {
+                let path = UnixPath::new(path);
+                if *dir {
+                    logi!("DEL DIR: '{}'", path);
+                    dest_fs.rm_dir(path).annotate()?;
+                } else {
+                    logi!("DEL FILE: '{}'", path);
+                    dest_fs.rm(path).annotate()?;
+                }
+                history::count_delete();
+            }
+            Op::Mkdir { path } => dest_fs.mkdir(UnixPath::new(path)).annotate()?,
+            Op::Copy {
+                reason,
+                from,
+                size,
+                mtime,
+                to,
+                ..
+            } => {
+                logi!("COPY FILE ({reason}): {} -> {}", from, to);
+                let timestamp = plan.set_time.then_some(*mtime);
+                dest_fs
+                    .copy_from(
+                        src_transport,
+                        UnixPath::new(from),
+                        UnixPath::new(to),
+                        timestamp,
+                    )
+                    .annotate()?;
+                history::count_copies(1, *size);
+            }
+        }
+    }
+    logi!("Applied {} operations", plan.ops.len());
+    Ok(!plan.ops.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_round_trip() {
+        let plan = Plan {
+            source: "/sdcard/DCIM".to_string(),
+            dest: "/b/DCIM".to_string(),
+            set_time: true,
+            ops: vec![
+                Op::Delete {
+                    path: "/b/DCIM/old".to_string(),
+                    dir: true,
+                    size: 0,
+                    mtime: 1,
+                },
+                Op::Mkdir {
+                    path: "/b/DCIM/new".to_string(),
+                },
+                Op::Copy {
+                    reason: "DNE".to_string(),
+                    from: "/sdcard/DCIM/new/a\tb\\c\n".to_string(),
+                    size: 5_000_000_000,
+                    mtime: 1714560000,
+                    to: "/b/DCIM/new/a\tb\\c\n".to_string(),
+                    was: None,
+                },
+                Op::Copy {
+                    reason: "SIZE".to_string(),
+                    from: "/sdcard/DCIM/x".to_string(),
+                    size: 3,
+                    mtime: 2,
+                    to: "/b/DCIM/x".to_string(),
+                    was: Some((4, 2)),
+                },
+            ],
+        };
+        assert_eq!(Plan::parse(&plan.to_text()), Ok(plan));
+        assert_eq!(Plan::parse("adb-sink plan 1\nsource\t/a\nbogus\n"), Err(3));
+        assert_eq!(Plan::parse("something else\n"), Err(1));
+    }
+}
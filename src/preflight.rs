@@ -0,0 +1,187 @@
+use crate::adb::AdbShell;
+use crate::{is_verbose, logi, logv, logw, CResult};
+use chainerror::Context;
+use clap::ValueEnum;
+use std::time::Duration;
+
+const WAIT_INTERVAL: Duration = Duration::from_secs(60);
+
+// android's PowerManager thermal status levels, coolest first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Thermal {
+    None,
+    Light,
+    Moderate,
+    Severe,
+    Critical,
+    Emergency,
+    Shutdown,
+}
+
+// device checks done before a sync starts, long transfers on a hot or nearly dead phone
+// tend to fail halfway
+#[derive(Debug, Default)]
+pub struct Preflight {
+    pub min_battery: Option<u8>,
+    pub max_thermal: Option<Thermal>,
+    // for scheduled runs, which should not drain the battery or use mobile data
+    pub only_when_charging: bool,
+    pub only_on_wifi: bool,
+    // wait for the checks to pass instead of refusing
+    pub wait: bool,
+}
+
+impl Preflight {
+    pub fn check(&self, shell: &mut AdbShell) -> CResult<()> {
+        if self.min_battery.is_none()
+            && self.max_thermal.is_none()
+            && !self.only_when_charging
+            && !self.only_on_wifi
+        {
+            return Ok(());
+        }
+        let mut waited = false;
+        loop {
+            match self.problem(shell).annotate()? {
+                None if waited => {
+                    logi!("Preflight checks passed, starting");
+                    return Ok(());
+                }
+                None => return Ok(()),
+                Some(p) if self.wait => {
+                    logw!(W012: "{}, checking again in {}s", p, WAIT_INTERVAL.as_secs());
+                    waited = true;
+                    std::thread::sleep(WAIT_INTERVAL);
+                }
+                Some(p) => {
+                    return Err(
+                        format!("{p}, not starting (--preflight-wait waits instead)").into(),
+                    )
+                }
+            }
+        }
+    }
+
+    // why the sync should not start yet
+    fn problem(&self, shell: &mut AdbShell) -> CResult<Option<String>> {
+        if self.min_battery.is_some() || self.only_when_charging {
+            let (percent, charging) = battery(shell).annotate()?;
+            logv!("Battery at {}%, charging: {}", percent, charging);
+            if self.only_when_charging && !charging {
+                return Ok(Some(format!(
+                    "Battery is at {percent}% and not charging (--only-when-charging)"
+                )));
+            }
+            match self.min_battery {
+                Some(min) if percent < min as u32 && !charging => {
+                    return Ok(Some(format!(
+                        "Battery is at {percent}% and not charging, below --min-battery {min}%"
+                    )))
+                }
+                _ => {}
+            }
+        }
+        if self.only_on_wifi {
+            let op = shell.run(["dumpsys", "connectivity"]).annotate()?;
+            match default_transport(&op) {
+                None => logw!(
+                    W012: "Could not read the default network of the device, skipping --only-on-wifi"
+                ),
+                Some(t) if t != "WIFI" => {
+                    return Ok(Some(format!(
+                        "Device is not on WiFi but on {t} (--only-on-wifi)"
+                    )))
+                }
+                Some(t) => logv!("Default network transport {}", t),
+            }
+        }
+        if let Some(max) = self.max_thermal {
+            let op = shell.run(["dumpsys", "thermalservice"]).annotate()?;
+            let status = op
+                .lines()
+                .find_map(|l| l.trim().strip_prefix("Thermal Status:"))
+                .and_then(|v| v.trim().parse::<usize>().ok())
+                .and_then(|v| Thermal::value_variants().get(v).copied());
+            match status {
+                // the thermal service is android 10+
+                None => {
+                    logw!(
+                        W012: "Could not read the thermal status of the device, skipping that check"
+                    )
+                }
+                Some(status) if status > max => {
+                    return Ok(Some(format!(
+                        "Device thermal status is {status:?}, above --max-thermal {max:?}"
+                    )))
+                }
+                Some(status) => logv!("Thermal status {:?}", status),
+            }
+        }
+        Ok(None)
+    }
+}
+
+// (percent, charging) from `dumpsys battery`
+fn battery(shell: &mut AdbShell) -> CResult<(u32, bool)> {
+    let op = shell.run(["dumpsys", "battery"]).annotate()?;
+    let field = |name: &str| {
+        op.lines()
+            .find_map(|l| l.trim().strip_prefix(name)?.strip_prefix(':'))
+            .and_then(|v| v.trim().parse::<u32>().ok())
+    };
+    let (Some(level), scale) = (field("level"), field("scale").unwrap_or(100)) else {
+        return Err("Could not read the battery level from `dumpsys battery`".into());
+    };
+    // BatteryManager.BATTERY_STATUS_CHARGING and _FULL
+    let charging = matches!(field("status"), Some(2 | 5));
+    Ok((level * 100 / scale.max(1), charging))
+}
+
+// the transport (WIFI, CELLULAR, ETHERNET, ..) of the default network in `dumpsys connectivity`
+// output, NONE without one. None when the output is not understood
+fn default_transport(op: &str) -> Option<String> {
+    let id = op
+        .lines()
+        .find_map(|l| l.trim().strip_prefix("Active default network:"))?
+        .trim();
+    if id == "none" {
+        return Some("NONE".to_string());
+    }
+    let network = format!("network{{{id}}}");
+    let agent = op
+        .lines()
+        .find(|l| l.contains("NetworkAgentInfo") && l.contains(&network))?;
+    // `Transports: WIFI` in the capabilities, `type: WIFI` before android 9
+    ["Transports: ", "type: "].iter().find_map(|key| {
+        let rest = &agent[agent.find(key)? + key.len()..];
+        let t: String = rest
+            .chars()
+            .take_while(|c| c.is_ascii_uppercase() || *c == '_')
+            .collect();
+        (!t.is_empty()).then_some(t)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transport() {
+        let op = "Active default network: 101\n\
+                  NetworkAgentInfo{network{100}  handle{1} ni{CELLULAR CONNECTED} \
+                  nc{[ Transports: CELLULAR Capabilities: INTERNET]}}\n\
+                  NetworkAgentInfo{network{101}  handle{2} ni{WIFI CONNECTED} \
+                  nc{[ Transports: WIFI Capabilities: INTERNET&NOT_METERED]}}\n";
+        assert_eq!(default_transport(op).as_deref(), Some("WIFI"));
+        let old = "Active default network: 100\n\
+                   NetworkAgentInfo{ ni{[type: MOBILE[LTE], state: CONNECTED/CONNECTED]}  \
+                   network{100}  lp{...}}\n";
+        assert_eq!(default_transport(old).as_deref(), Some("MOBILE"));
+        assert_eq!(
+            default_transport("Active default network: none\n").as_deref(),
+            Some("NONE")
+        );
+        assert_eq!(default_transport("Current state:\n"), None);
+    }
+}
@@ -0,0 +1,151 @@
+// `--progress`: a status line at the bottom of the terminal, redrawn a few times a second, with
+// how much of the run is copied, the transfer rate, the time left and how far the current file
+// got. adb prints no progress when its output is read by another program, so a pull is
+// followed by the size of the file it writes. pushes advance a file at a time
+//   [ 43%] 1.2 GiB of 2.8 GiB, 23.4 MiB/s, 1m10s left | VID_0042.mp4 61%
+
+use crate::history::{self, duration};
+use crate::{heartbeat, human_size};
+use std::collections::VecDeque;
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const REDRAW: Duration = Duration::from_millis(250);
+// the rate is averaged over this long
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+// whether the line is on screen, log lines clear it first
+static DRAWN: Mutex<bool> = Mutex::new(false);
+
+// draws the line until the process exits. only on a terminal, logs get --heartbeat
+pub fn start() {
+    if !std::io::stderr().is_terminal() {
+        return;
+    }
+    ENABLED.store(true, Ordering::Relaxed);
+    std::thread::spawn(|| {
+        let mut samples: VecDeque<(Instant, u64)> = VecDeque::new();
+        loop {
+            std::thread::sleep(REDRAW);
+            let state = heartbeat::state();
+            let done = history::copied().1 + partial(&state);
+            let now = Instant::now();
+            samples.push_back((now, done));
+            while samples.len() > 2 && now - samples[0].0 > RATE_WINDOW {
+                samples.pop_front();
+            }
+            let (t0, b0) = samples[0];
+            let secs = (now - t0).as_secs_f64();
+            let rate = if secs > 0.0 {
+                (done.saturating_sub(b0) as f64 / secs) as u64
+            } else {
+                0
+            };
+            let line = line(&state, done, rate);
+            let mut drawn = DRAWN.lock().unwrap();
+            if !enabled() {
+                return;
+            }
+            eprint!("\r\x1b[2K{line}");
+            let _ = std::io::stderr().flush();
+            *drawn = true;
+        }
+    });
+}
+
+pub(crate) fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// takes the line off the screen for good, before the last words of the run
+pub fn stop() {
+    interrupt(|| ENABLED.store(false, Ordering::Relaxed));
+}
+
+// runs `print` with the line taken off the screen, it comes back with the next redraw
+pub(crate) fn interrupt(print: impl FnOnce()) {
+    if !enabled() {
+        return print();
+    }
+    let mut drawn = DRAWN.lock().unwrap();
+    if *drawn {
+        eprint!("\r\x1b[2K");
+        *drawn = false;
+    }
+    print();
+}
+
+// bytes of the current file written so far, if they can be seen
+fn partial(state: &heartbeat::State) -> u64 {
+    let Some(watch) = &state.watch else {
+        return 0;
+    };
+    let written = std::fs::metadata(watch).map_or(0, |m| m.len());
+    // once it is complete the file is counted as copied
+    if written < state.size {
+        written
+    } else {
+        0
+    }
+}
+
+fn line(state: &heartbeat::State, done: u64, rate: u64) -> String {
+    let mut line = match state.total {
+        Some((_, total)) if total > 0 => {
+            let left = match total.saturating_sub(done).checked_div(rate) {
+                Some(secs) => format!(", {} left", duration(secs)),
+                None => String::new(),
+            };
+            format!(
+                "[{:>3}%] {} of {}, {}/s{left}",
+                (done * 100 / total).min(100),
+                human_size(done),
+                human_size(total),
+                human_size(rate),
+            )
+        }
+        _ => format!("{}, {}/s", human_size(done), human_size(rate)),
+    };
+    if state.current.is_empty() {
+        return line;
+    }
+    let name = state.current.rsplit('/').next().unwrap_or_default();
+    // long names would wrap the line
+    let name: String = match name.char_indices().nth(40) {
+        Some((at, _)) => format!("{}..", &name[..at]),
+        None => name.to_string(),
+    };
+    line.push_str(" | ");
+    line.push_str(&name);
+    let written = partial(state);
+    if written > 0 {
+        line.push_str(&format!(" {}%", written * 100 / state.size));
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_line() {
+        let mut state = heartbeat::State {
+            phase: "copying",
+            total: Some((10, 4 << 30)),
+            current: "/sdcard/DCIM/Camera/VID_0042.mp4".to_string(),
+            size: 100,
+            watch: None,
+        };
+        assert_eq!(
+            line(&state, 1 << 30, 16 << 20),
+            "[ 25%] 1.0 GiB of 4.0 GiB, 16.0 MiB/s, 3m12s left | VID_0042.mp4"
+        );
+        state.total = None;
+        state.current.clear();
+        assert_eq!(line(&state, 2048, 0), "2.0 KiB, 0 B/s");
+    }
+}
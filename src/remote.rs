@@ -0,0 +1,189 @@
+// helpers shared by the network storage backends (webdav, s3)
+
+use crate::adb::{quote, AdbCmd};
+use crate::fs::{AndroidFS, FileSystem, LocalFS, SyncFile, Transport};
+use crate::CResult;
+use chainerror::Context;
+use std::io::Read;
+use std::process::Child;
+use typed_path::Utf8UnixPath as UnixPath;
+
+// `adb exec-out cat` child whose stdout is the file content
+struct ExecOut(Child);
+impl Read for ExecOut {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.stdout.as_mut().expect("stdout piped").read(buf)
+    }
+}
+impl Drop for ExecOut {
+    fn drop(&mut self) {
+        let _ = self.0.wait();
+    }
+}
+
+// opens `path` on the source side for streaming, with its length when it is cheap to know
+pub(crate) fn open_source(
+    src: Transport,
+    path: &UnixPath,
+) -> CResult<(Box<dyn Read + Send>, Option<u64>)> {
+    match src {
+        Transport::Local => {
+            let f = std::fs::File::open(path.as_str()).annotate()?;
+            let len = f.metadata().annotate()?.len();
+            Ok((Box::new(f), Some(len)))
+        }
+        Transport::Adb => {
+            let child = AdbCmd::new()
+                .args(["exec-out", "cat", quote(path.as_str()).as_str()])
+                .spawn()
+                .annotate()?;
+            Ok((Box::new(ExecOut(child)), None))
+        }
+        src => Err(format!("cannot stream from {src:?}").into()),
+    }
+}
+
+pub(crate) fn list_source(src: Transport, path: &UnixPath) -> CResult<Vec<SyncFile>> {
+    match src {
+        Transport::Local => LocalFS.list_dir(path),
+        Transport::Adb => AndroidFS::ls(path),
+        src => Err(format!("cannot list {src:?} as a source").into()),
+    }
+}
+
+// returns the inner text of every element with the local name `name`, ignoring namespace prefixes.
+// good enough for the flat responses of PROPFIND and ListObjectsV2, not a general xml parser
+pub(crate) fn xml_elements<'x>(xml: &'x str, name: &str) -> Vec<&'x str> {
+    let mut out = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let Some(end) = rest.find('>') else {
+            break;
+        };
+        let tag = &rest[..end];
+        rest = &rest[end + 1..];
+        if tag.starts_with(['/', '?', '!']) {
+            continue;
+        }
+        let qname = tag
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default();
+        if qname.rsplit(':').next() != Some(name) {
+            continue;
+        }
+        if tag.ends_with('/') {
+            out.push("");
+            continue;
+        }
+        let close = format!("</{qname}>");
+        if let Some(e) = rest.find(&close) {
+            out.push(&rest[..e]);
+            rest = &rest[e + close.len()..];
+        }
+    }
+    out
+}
+
+pub(crate) fn xml_first<'x>(xml: &'x str, name: &str) -> Option<&'x str> {
+    xml_elements(xml, name).into_iter().next()
+}
+
+pub(crate) fn xml_unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+// percent-encodes everything but RFC 3986 unreserved characters (and '/' unless `encode_slash`)
+pub(crate) fn uri_encode(s: &str, encode_slash: bool) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(b as char)
+            }
+            b'/' if !encode_slash => out.push('/'),
+            b => out.push_str(&format!("%{b:02X}")),
+        }
+    }
+    out
+}
+
+pub(crate) fn uri_decode(s: &str) -> String {
+    let b = s.as_bytes();
+    let mut out = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        if b[i] == b'%' && i + 2 < b.len() {
+            let h = std::str::from_utf8(&b[i + 1..i + 3]).unwrap_or_default();
+            if let Ok(v) = u8::from_str_radix(h, 16) {
+                out.push(v);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(b[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// days since 1970-01-01 for a proleptic gregorian date (Howard Hinnant's algorithm)
+pub(crate) fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let m = m as i64;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+pub(crate) fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    let z = z + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d)
+}
+
+fn hms_secs(hms: &str) -> Option<i64> {
+    let mut it = hms.split(':').map(|p| p.parse::<i64>().ok());
+    Some(it.next()?? * 3600 + it.next()?? * 60 + it.next()??)
+}
+
+// "Tue, 15 Nov 1994 12:45:26 GMT"
+pub(crate) fn parse_http_date(s: &str) -> Option<u32> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let mut it = s.split_whitespace().skip(1);
+    let d = it.next()?.parse().ok()?;
+    let mon = it.next()?;
+    let m = MONTHS.iter().position(|x| *x == mon)? as u32 + 1;
+    let y = it.next()?.parse().ok()?;
+    let secs = days_from_civil(y, m, d) * 86400 + hms_secs(it.next()?)?;
+    u32::try_from(secs).ok()
+}
+
+// "2009-10-12T17:50:30.000Z"
+pub(crate) fn parse_iso8601(s: &str) -> Option<u32> {
+    let (date, time) = s.split_once('T')?;
+    let mut it = date.split('-');
+    let y = it.next()?.parse().ok()?;
+    let m = it.next()?.parse().ok()?;
+    let d = it.next()?.parse().ok()?;
+    let time = time.trim_end_matches('Z');
+    let time = time.split_once('.').map_or(time, |(t, _)| t);
+    let secs = days_from_civil(y, m, d) * 86400 + hms_secs(time)?;
+    u32::try_from(secs).ok()
+}
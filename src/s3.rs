@@ -0,0 +1,329 @@
+use crate::fs::{hex, unsupported_pair, AnyFileSystem, FileMode, FileSystem, SyncFile, Transport};
+use crate::remote::{
+    civil_from_days, list_source, open_source, parse_http_date, parse_iso8601, uri_encode,
+    xml_elements, xml_first, xml_unescape,
+};
+use crate::{logw, CResult};
+use chainerror::Context;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::time::{SystemTime, UNIX_EPOCH};
+use typed_path::{Utf8UnixPath as UnixPath, Utf8UnixPathBuf as UnixPathBuf};
+
+type HmacSha256 = Hmac<Sha256>;
+
+// path-style S3 client. directories do not exist in S3, they are the common prefixes of keys
+pub struct S3FS {
+    agent: ureq::Agent,
+    // scheme://host[:port]
+    endpoint: String,
+    host: String,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("hmac takes any key length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn key_of(path: &UnixPath) -> &str {
+    path.as_str().trim_start_matches('/')
+}
+
+impl S3FS {
+    // `s3://bucket/prefix`. endpoint, region and credentials come from the usual AWS_* variables
+    pub fn from_url(url: &str) -> CResult<(Self, UnixPathBuf)> {
+        let Some(rest) = url.strip_prefix("s3://") else {
+            return Err(format!("not an s3 url: '{url}'").into());
+        };
+        let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        let region = std::env::var("AWS_REGION")
+            .or_else(|_| std::env::var("AWS_DEFAULT_REGION"))
+            .unwrap_or_else(|_| "us-east-1".to_string());
+        let endpoint = std::env::var("AWS_ENDPOINT_URL")
+            .unwrap_or_else(|_| format!("https://s3.{region}.amazonaws.com"));
+        let endpoint = endpoint.trim_end_matches('/').to_string();
+        let host = endpoint
+            .split_once("://")
+            .map_or(endpoint.as_str(), |(_, h)| h)
+            .to_string();
+        let access_key = std::env::var("AWS_ACCESS_KEY_ID").annotate()?;
+        let secret_key = std::env::var("AWS_SECRET_ACCESS_KEY").annotate()?;
+        Ok((
+            Self {
+                agent: ureq::AgentBuilder::new().build(),
+                endpoint,
+                host,
+                bucket: bucket.to_string(),
+                region,
+                access_key,
+                secret_key,
+            },
+            UnixPathBuf::from(format!("/{prefix}")),
+        ))
+    }
+
+    // builds a SigV4 signed request. the payload is not signed (UNSIGNED-PAYLOAD)
+    fn request(&self, method: &str, key: &str, query: &[(&str, &str)]) -> ureq::Request {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("get system time")
+            .as_secs() as i64;
+        let (y, m, d) = civil_from_days(now.div_euclid(86400));
+        let secs = now.rem_euclid(86400);
+        let date = format!("{y:04}{m:02}{d:02}");
+        let amz_date = format!(
+            "{date}T{:02}{:02}{:02}Z",
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60
+        );
+
+        let uri = format!("/{}/{}", self.bucket, uri_encode(key, false));
+        let mut query: Vec<(String, String)> = query
+            .iter()
+            .map(|(k, v)| (uri_encode(k, true), uri_encode(v, true)))
+            .collect();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(k, v)| format!("{k}={v}"))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let canonical_request = format!(
+            "{method}\n{uri}\n{query}\nhost:{}\nx-amz-content-sha256:UNSIGNED-PAYLOAD\nx-amz-date:{amz_date}\n\nhost;x-amz-content-sha256;x-amz-date\nUNSIGNED-PAYLOAD",
+            self.host
+        );
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let key = hmac(format!("AWS4{}", self.secret_key).as_bytes(), &date);
+        let key = hmac(&key, &self.region);
+        let key = hmac(&key, "s3");
+        let key = hmac(&key, "aws4_request");
+        let signature = hex(&hmac(&key, &string_to_sign));
+
+        let url = if query.is_empty() {
+            format!("{}{uri}", self.endpoint)
+        } else {
+            format!("{}{uri}?{query}", self.endpoint)
+        };
+        self.agent
+            .request(method, &url)
+            .set("x-amz-date", &amz_date)
+            .set("x-amz-content-sha256", "UNSIGNED-PAYLOAD")
+            .set(
+                "Authorization",
+                &format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={signature}",
+                    self.access_key
+                ),
+            )
+    }
+
+    // one ListObjectsV2 listing, following continuation tokens. returns (files, dir prefixes)
+    fn list(&self, prefix: &str, delimiter: bool) -> CResult<(Vec<SyncFile>, Vec<String>)> {
+        let mut files = Vec::new();
+        let mut dirs = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", prefix)];
+            if delimiter {
+                query.push(("delimiter", "/"));
+            }
+            if let Some(token) = &token {
+                query.push(("continuation-token", token.as_str()));
+            }
+            let xml = self
+                .request("GET", "", &query)
+                .call()
+                .annotate()?
+                .into_string()
+                .annotate()?;
+            for c in xml_elements(&xml, "Contents") {
+                let Some(key) = xml_first(c, "Key").map(xml_unescape) else {
+                    continue;
+                };
+                let path = UnixPathBuf::from(format!("/{key}"));
+                files.push(SyncFile {
+                    mode: FileMode::File,
+                    size: xml_first(c, "Size")
+                        .and_then(|s| s.parse::<u64>().ok())
+                        .unwrap_or(0),
+                    timestamp: xml_first(c, "LastModified")
+                        .and_then(parse_iso8601)
+                        .unwrap_or(0),
+                    name: path.file_name().unwrap_or_default().into(),
+                    path: path.into_boxed_path(),
+                });
+            }
+            for p in xml_elements(&xml, "CommonPrefixes") {
+                if let Some(p) = xml_first(p, "Prefix") {
+                    dirs.push(xml_unescape(p));
+                }
+            }
+            token = xml_first(&xml, "NextContinuationToken").map(xml_unescape);
+            if token.is_none() {
+                break;
+            }
+        }
+        Ok((files, dirs))
+    }
+}
+
+fn dir_prefix(path: &UnixPath) -> String {
+    match key_of(path).trim_end_matches('/') {
+        "" => String::new(),
+        k => format!("{k}/"),
+    }
+}
+
+impl FileSystem for S3FS {
+    fn mkdir(&mut self, _path: &UnixPath) -> CResult<()> {
+        // prefixes come into existence with their first object
+        Ok(())
+    }
+
+    fn list_dir(&mut self, path: &UnixPath) -> CResult<Vec<SyncFile>> {
+        let prefix = dir_prefix(path);
+        let (mut files, dirs) = self.list(&prefix, true).annotate()?;
+        // "directory marker" objects some tools create
+        files.retain(|f| key_of(&f.path) != prefix);
+        for d in dirs {
+            let path = UnixPathBuf::from(format!("/{}", d.trim_end_matches('/')));
+            files.push(SyncFile {
+                mode: FileMode::Dir,
+                size: 0,
+                timestamp: 0,
+                name: path.file_name().unwrap_or_default().into(),
+                path: path.into_boxed_path(),
+            });
+        }
+        Ok(files)
+    }
+
+    fn stat(&mut self, path: &UnixPath) -> CResult<Option<SyncFile>> {
+        let name: Box<str> = path.file_name().unwrap_or_default().into();
+        let key = key_of(path);
+        if !key.is_empty() {
+            match self.request("HEAD", key, &[]).call() {
+                Ok(r) => {
+                    return Ok(Some(SyncFile {
+                        mode: FileMode::File,
+                        size: r
+                            .header("Content-Length")
+                            .and_then(|s| s.parse::<u64>().ok())
+                            .unwrap_or(0),
+                        timestamp: r
+                            .header("Last-Modified")
+                            .and_then(parse_http_date)
+                            .unwrap_or(0),
+                        name,
+                        path: path.to_path_buf().into_boxed_path(),
+                    }))
+                }
+                Err(ureq::Error::Status(404, _)) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let dir = SyncFile {
+            mode: FileMode::Dir,
+            size: 0,
+            timestamp: 0,
+            name,
+            path: path.to_path_buf().into_boxed_path(),
+        };
+        if key.is_empty() {
+            return Ok(Some(dir));
+        }
+        let (files, dirs) = self.list(&dir_prefix(path), true).annotate()?;
+        Ok((!files.is_empty() || !dirs.is_empty()).then_some(dir))
+    }
+
+    fn rm(&mut self, path: &UnixPath) -> CResult<()> {
+        self.request("DELETE", key_of(path), &[])
+            .call()
+            .annotate()?;
+        Ok(())
+    }
+
+    fn rm_dir(&mut self, path: &UnixPath) -> CResult<()> {
+        let (files, _) = self.list(&dir_prefix(path), false).annotate()?;
+        for f in files {
+            self.rm(&f.path).annotate()?;
+        }
+        Ok(())
+    }
+
+    fn set_mtime(&mut self, _path: &UnixPath, _timestamp: u32) -> CResult<()> {
+        // stored as object metadata at upload time, changing it would mean a server side copy
+        Ok(())
+    }
+}
+
+impl AnyFileSystem for S3FS {
+    fn transport(&self) -> Transport {
+        Transport::S3
+    }
+
+    fn copy_from(
+        &mut self,
+        src: Transport,
+        from: &UnixPath,
+        to: &UnixPath,
+        timestamp: Option<u32>,
+    ) -> CResult<()> {
+        if !matches!(src, Transport::Adb | Transport::Local) {
+            return unsupported_pair(src, Transport::S3);
+        }
+        let (mut reader, len) = open_source(src, from).annotate()?;
+        let mut req = self.request("PUT", key_of(to), &[]);
+        if let Some(timestamp) = timestamp {
+            req = req.set("x-amz-meta-mtime", &timestamp.to_string());
+        }
+        match len {
+            Some(len) => {
+                req.set("Content-Length", &len.to_string())
+                    .send(reader)
+                    .annotate()?;
+            }
+            None => {
+                // a plain PUT needs a content length, so unsized streams are buffered
+                let mut buf = Vec::new();
+                reader.read_to_end(&mut buf).annotate()?;
+                req.send_bytes(&buf).annotate()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn copy_dir_from(
+        &mut self,
+        src: Transport,
+        from: &UnixPath,
+        to: &UnixPath,
+        timestamp: Option<u32>,
+    ) -> CResult<()> {
+        for entry in list_source(src, from).annotate()? {
+            let to_path = to.join(&*entry.name);
+            match entry.mode {
+                FileMode::File => self
+                    .copy_from(src, &entry.path, &to_path, timestamp)
+                    .annotate()?,
+                FileMode::Dir => self
+                    .copy_dir_from(src, &entry.path, &to_path, timestamp)
+                    .annotate()?,
+                FileMode::Symlink => logw!(W002: "Skipping symlink {}", entry.path),
+            }
+        }
+        Ok(())
+    }
+}
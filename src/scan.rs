@@ -0,0 +1,357 @@
+// saved tree scans: `adb-sink scan` writes the files under a path with their sizes and mtimes
+// as json, `adb-sink snapshot-diff` compares two of them without touching a device
+//   {"root": "/sdcard/DCIM", "device": "1A2B3C4D", "scanned": 1714566600,
+//    "files": [{"path": "Camera/IMG_1.jpg", "size": 123, "mtime": 1714560000}, ...]}
+
+use crate::tree::Node;
+use crate::{logi, CResult};
+use chainerror::Context;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanFile {
+    pub size: u64,
+    pub mtime: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct Scan {
+    pub root: String,
+    pub device: Option<String>,
+    pub scanned: u64,
+    // by path relative to the root
+    pub files: BTreeMap<String, ScanFile>,
+}
+
+impl Scan {
+    pub fn of(root: &Node, device: Option<String>) -> Self {
+        let files = root
+            .files()
+            .into_iter()
+            .map(|n| {
+                let f = ScanFile {
+                    size: n.sf.size,
+                    mtime: n.sf.timestamp,
+                };
+                (n.strip_path.to_string(), f)
+            })
+            .collect();
+        Self {
+            root: root.sf.path.to_string(),
+            device,
+            scanned: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            files,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut out = format!("{{\"root\": {}, ", quote(&self.root));
+        if let Some(device) = &self.device {
+            out.push_str(&format!("\"device\": {}, ", quote(device)));
+        }
+        out.push_str(&format!("\"scanned\": {}, \"files\": [", self.scanned));
+        for (i, (path, f)) in self.files.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            out.push_str(&format!(
+                "\n  {{\"path\": {}, \"size\": {}, \"mtime\": {}}}",
+                quote(path),
+                f.size,
+                f.mtime
+            ));
+        }
+        out.push_str("\n]}\n");
+        out
+    }
+
+    pub fn read(path: &Path) -> CResult<Self> {
+        let s = std::fs::read_to_string(path).annotate()?;
+        Self::from_json(&s).map_err(|e| format!("'{}': {e}", path.display()).into())
+    }
+
+    fn from_json(s: &str) -> Result<Self, String> {
+        let mut p = Parser {
+            s: s.as_bytes(),
+            at: 0,
+        };
+        let value = p.value()?;
+        p.ws();
+        if p.at != p.s.len() {
+            return Err(p.err("trailing characters"));
+        }
+        let Json::Obj(fields) = value else {
+            return Err("not a scan, the top level is no object".to_string());
+        };
+        let mut scan = Scan::default();
+        for (key, value) in fields {
+            match (key.as_str(), value) {
+                ("root", Json::Str(s)) => scan.root = s,
+                ("device", Json::Str(s)) => scan.device = Some(s),
+                ("scanned", Json::Num(n)) => scan.scanned = n,
+                ("files", Json::Arr(files)) => {
+                    for f in files {
+                        let (path, f) = scan_file(f)?;
+                        scan.files.insert(path, f);
+                    }
+                }
+                // written by a later version
+                _ => {}
+            }
+        }
+        Ok(scan)
+    }
+}
+
+fn scan_file(f: Json) -> Result<(String, ScanFile), String> {
+    let Json::Obj(fields) = f else {
+        return Err("a file is no object".to_string());
+    };
+    let (mut path, mut size, mut mtime) = (None, None, None);
+    for (key, value) in fields {
+        match (key.as_str(), value) {
+            ("path", Json::Str(s)) => path = Some(s),
+            ("size", Json::Num(n)) => size = Some(n),
+            ("mtime", Json::Num(n)) => mtime = u32::try_from(n).ok(),
+            _ => {}
+        }
+    }
+    match (path, size, mtime) {
+        (Some(path), Some(size), Some(mtime)) => Ok((path, ScanFile { size, mtime })),
+        (path, ..) => Err(format!(
+            "file '{}' needs a path, a size and an mtime",
+            path.unwrap_or_default()
+        )),
+    }
+}
+
+fn quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// the json scans need: numbers are whole and not negative
+enum Json {
+    Null,
+    Bool,
+    Num(u64),
+    Str(String),
+    Arr(Vec<Json>),
+    Obj(Vec<(String, Json)>),
+}
+
+struct Parser<'a> {
+    s: &'a [u8],
+    at: usize,
+}
+
+impl Parser<'_> {
+    fn err(&self, what: &str) -> String {
+        format!("{what} at byte {}", self.at)
+    }
+
+    fn ws(&mut self) {
+        while self.s.get(self.at).is_some_and(|b| b.is_ascii_whitespace()) {
+            self.at += 1;
+        }
+    }
+
+    fn eat(&mut self, b: u8) -> bool {
+        self.ws();
+        let found = self.s.get(self.at) == Some(&b);
+        if found {
+            self.at += 1;
+        }
+        found
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json, String> {
+        if self.s[self.at..].starts_with(word.as_bytes()) {
+            self.at += word.len();
+            Ok(value)
+        } else {
+            Err(self.err("unexpected word"))
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.ws();
+        match self.s.get(self.at) {
+            Some(b'{') => {
+                self.at += 1;
+                let mut fields = Vec::new();
+                if self.eat(b'}') {
+                    return Ok(Json::Obj(fields));
+                }
+                loop {
+                    self.ws();
+                    let key = self.string()?;
+                    if !self.eat(b':') {
+                        return Err(self.err("expected ':'"));
+                    }
+                    fields.push((key, self.value()?));
+                    if self.eat(b'}') {
+                        return Ok(Json::Obj(fields));
+                    }
+                    if !self.eat(b',') {
+                        return Err(self.err("expected ',' or '}'"));
+                    }
+                }
+            }
+            Some(b'[') => {
+                self.at += 1;
+                let mut items = Vec::new();
+                if self.eat(b']') {
+                    return Ok(Json::Arr(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    if self.eat(b']') {
+                        return Ok(Json::Arr(items));
+                    }
+                    if !self.eat(b',') {
+                        return Err(self.err("expected ',' or ']'"));
+                    }
+                }
+            }
+            Some(b'"') => Ok(Json::Str(self.string()?)),
+            Some(b'0'..=b'9') => {
+                let start = self.at;
+                while self.s.get(self.at).is_some_and(u8::is_ascii_digit) {
+                    self.at += 1;
+                }
+                let n = std::str::from_utf8(&self.s[start..self.at]).expect("digits");
+                n.parse()
+                    .map(Json::Num)
+                    .map_err(|_| self.err("number too big"))
+            }
+            Some(b't') => self.literal("true", Json::Bool),
+            Some(b'f') => self.literal("false", Json::Bool),
+            Some(b'n') => self.literal("null", Json::Null),
+            _ => Err(self.err("unexpected character")),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        if self.s.get(self.at) != Some(&b'"') {
+            return Err(self.err("expected a string"));
+        }
+        self.at += 1;
+        let mut out = Vec::new();
+        loop {
+            let Some(&b) = self.s.get(self.at) else {
+                return Err(self.err("unterminated string"));
+            };
+            self.at += 1;
+            match b {
+                b'"' => break,
+                b'\\' => {
+                    let Some(&e) = self.s.get(self.at) else {
+                        return Err(self.err("unterminated string"));
+                    };
+                    self.at += 1;
+                    match e {
+                        b'n' => out.push(b'\n'),
+                        b't' => out.push(b'\t'),
+                        b'r' => out.push(b'\r'),
+                        b'u' => {
+                            let hex = self.s.get(self.at..self.at + 4);
+                            let c = hex
+                                .and_then(|h| std::str::from_utf8(h).ok())
+                                .and_then(|h| u32::from_str_radix(h, 16).ok())
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| self.err("bad \\u escape"))?;
+                            self.at += 4;
+                            out.extend_from_slice(c.to_string().as_bytes());
+                        }
+                        e => out.push(e),
+                    }
+                }
+                b => out.push(b),
+            }
+        }
+        String::from_utf8(out).map_err(|_| self.err("string is not utf-8"))
+    }
+}
+
+// prints what changed from scan `a` to scan `b`, returns whether anything did
+pub fn diff(a: &Scan, b: &Scan) -> bool {
+    let describe = |s: &Scan| match &s.device {
+        Some(d) => format!("'{}' of {d}", s.root),
+        None => format!("'{}'", s.root),
+    };
+    logi!("Comparing {} with {}", describe(a), describe(b));
+    let (mut added, mut removed, mut changed) = (0, 0, 0);
+    for (path, fa) in &a.files {
+        match b.files.get(path) {
+            None => {
+                println!("- {path}");
+                removed += 1;
+            }
+            Some(fb) if fb != fa => {
+                let mut what = Vec::new();
+                if fa.size != fb.size {
+                    what.push(format!("size {} -> {}", fa.size, fb.size));
+                }
+                if fa.mtime != fb.mtime {
+                    what.push(format!("mtime {} -> {}", fa.mtime, fb.mtime));
+                }
+                println!("~ {path} ({})", what.join(", "));
+                changed += 1;
+            }
+            Some(_) => {}
+        }
+    }
+    for path in b.files.keys().filter(|p| !a.files.contains_key(*p)) {
+        println!("+ {path}");
+        added += 1;
+    }
+    logi!("{} added, {} removed, {} changed", added, removed, changed);
+    added + removed + changed > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_round_trip() {
+        let mut scan = Scan {
+            root: "/sdcard/DCIM".to_string(),
+            device: Some("1A2B".to_string()),
+            scanned: 1714566600,
+            ..Default::default()
+        };
+        for (path, size) in [("a \"b\"\\c\n.jpg", 1), ("Camera/é.jpg", 2)] {
+            let f = ScanFile {
+                size,
+                mtime: 1714560000,
+            };
+            scan.files.insert(path.to_string(), f);
+        }
+        let back = Scan::from_json(&scan.to_json()).unwrap();
+        assert_eq!(back.root, scan.root);
+        assert_eq!(back.device, scan.device);
+        assert_eq!(back.scanned, scan.scanned);
+        assert_eq!(back.files, scan.files);
+        assert!(Scan::from_json("{\"files\": [{\"path\": \"x\"}]}").is_err());
+        assert!(Scan::from_json("{\"root\": \"/\"} x").is_err());
+    }
+}
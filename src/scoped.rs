@@ -0,0 +1,205 @@
+// Android 11+ scoped storage: `Android/data` and `Android/obb` of the shared storage can be
+// hidden from `adb ls` and `adb pull`, which then see empty directories. an empty listing there
+// is double checked through the shell, `run-as` of the owning package or `su`, and files of
+// directories only one of those can read are streamed through it
+
+use crate::adb::{quote, AdbCmd};
+use crate::fs::{parse_stat_line, FileMode, FileSystem, LocalFS, SyncFile};
+use crate::{logw, CResult};
+use chainerror::Context;
+use std::process::Stdio;
+use std::sync::Mutex;
+use typed_path::{Utf8UnixPath as UnixPath, Utf8UnixPathBuf as UnixPathBuf};
+
+const STORAGE_ROOTS: [&str; 3] = ["/sdcard/", "/storage/self/primary/", "/storage/emulated/0/"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Access {
+    // `adb shell` can read what the sync service does not show
+    Shell,
+    // the debuggable app owning the directory
+    RunAs(String),
+    Root,
+}
+
+impl Access {
+    // `cmd` run with this access
+    fn wrap(&self, cmd: &str) -> String {
+        match self {
+            Access::Shell => cmd.to_string(),
+            Access::RunAs(package) => format!("run-as {} sh -c {}", quote(package), quote(cmd)),
+            Access::Root => format!("su -c {}", quote(cmd)),
+        }
+    }
+}
+
+// directories that needed another access, by path
+static ACCESS: Mutex<Vec<(UnixPathBuf, Access)>> = Mutex::new(Vec::new());
+
+// `Android/data/<package>/..` -> (`data`, Some(package)), `Android/obb` -> (`obb`, None)
+fn scoped_part(path: &UnixPath) -> Option<(&str, Option<&str>)> {
+    let rest = STORAGE_ROOTS
+        .iter()
+        .find_map(|root| path.as_str().strip_prefix(root))?;
+    let mut parts = rest.split('/');
+    if parts.next() != Some("Android") {
+        return None;
+    }
+    match parts.next() {
+        Some(dir @ ("data" | "obb")) => Some((dir, parts.next().filter(|p| !p.is_empty()))),
+        _ => None,
+    }
+}
+
+pub fn is_scoped(path: &UnixPath) -> bool {
+    scoped_part(path).is_some()
+}
+
+// the access registered for `path` or a directory above it
+pub fn access_for(path: &UnixPath) -> Option<Access> {
+    let access = ACCESS.lock().unwrap();
+    access
+        .iter()
+        .filter(|(dir, _)| path.starts_with(dir))
+        .max_by_key(|(dir, _)| dir.as_str().len())
+        .map(|(_, a)| a.clone())
+}
+
+// `stat` of every entry of `path`, Err with the error output when it could not be read
+fn try_list(access: &Access, path: &UnixPath) -> CResult<Result<Vec<SyncFile>, String>> {
+    let cmd = format!(
+        "find {} -mindepth 1 -maxdepth 1 -exec stat -c '%f %s %Y %n' {{}} +",
+        quote(path.as_str())
+    );
+    let cmd = format!("{} 2>&1", access.wrap(&cmd));
+    let op = AdbCmd::run_v(["shell", cmd.as_str()]).annotate()?;
+    let mut files = Vec::new();
+    for line in op.lines() {
+        let mut fields = line.splitn(4, ' ');
+        let stat = (|| {
+            u32::from_str_radix(fields.next()?, 16).ok()?;
+            fields.next()?.parse::<u64>().ok()?;
+            fields.next()?.parse::<i64>().ok()?;
+            fields.next()
+        })();
+        match stat {
+            Some(p) => {
+                let fields = &line[..line.len() - p.len() - 1];
+                match parse_stat_line(fields, UnixPath::new(p)).annotate()? {
+                    Some(sf) => files.push(sf),
+                    None => logw!(W002: "Skipping special file {}", p),
+                }
+            }
+            None => return Ok(Err(line.to_string())),
+        }
+    }
+    Ok(Ok(files))
+}
+
+pub fn list(access: &Access, path: &UnixPath) -> CResult<Vec<SyncFile>> {
+    match try_list(access, path).annotate()? {
+        Ok(files) => Ok(files),
+        Err(e) => Err(format!("Could not list '{path}' as {access:?}: {e}").into()),
+    }
+}
+
+// called when `adb ls` shows nothing under a scoped directory. finds out whether it really is
+// empty, with whichever access can read it. never returns an empty listing for a directory
+// that could not be read, a mirror would delete the backup of it
+pub fn fallback_list(path: &UnixPath) -> CResult<Vec<SyncFile>> {
+    let Some((dir, package)) = scoped_part(path) else {
+        return Ok(Vec::new());
+    };
+    let mut tried = vec![Access::Shell];
+    tried.extend(package.map(|p| Access::RunAs(p.to_string())));
+    tried.push(Access::Root);
+    for access in tried {
+        let Ok(files) = try_list(&access, path).annotate()? else {
+            continue;
+        };
+        if !files.is_empty() {
+            logw!(
+                W020: "'{}' is hidden from adb by scoped storage, reading it through {:?}",
+                path,
+                access
+            );
+            ACCESS.lock().unwrap().push((path.to_path_buf(), access));
+        }
+        return Ok(files);
+    }
+    Err(format!(
+        "'{path}' cannot be read: Android 11 and later hide Android/{dir} from adb. Skip it with \
+        `-i Android/{dir}`, or use a rooted device or a debuggable app"
+    )
+    .into())
+}
+
+// streams `from` into the local `to` with `access`, for files `adb pull` cannot see
+pub fn pull(
+    access: &Access,
+    from: &UnixPath,
+    to: &UnixPath,
+    timestamp: Option<u32>,
+) -> CResult<()> {
+    let cmd = access.wrap(&format!("cat {}", quote(from.as_str())));
+    let out = std::fs::File::create(to.as_str()).annotate()?;
+    let status = AdbCmd::new()
+        .args(["exec-out", cmd.as_str()])
+        .stdout(Stdio::from(out))
+        .spawn()
+        .annotate()?
+        .wait()
+        .annotate()?;
+    if !status.success() {
+        return Err(format!("Could not read '{from}' as {access:?}").into());
+    }
+    if let Some(timestamp) = timestamp {
+        LocalFS.set_mtime(to, timestamp).annotate()?;
+    }
+    Ok(())
+}
+
+// like `adb pull` of a whole directory, file by file
+pub fn pull_dir(
+    access: &Access,
+    from: &UnixPath,
+    to: &UnixPath,
+    timestamp: Option<u32>,
+) -> CResult<()> {
+    LocalFS.mkdir(to).annotate()?;
+    for e in list(access, from).annotate()? {
+        let to = to.join(&*e.name);
+        match e.mode {
+            FileMode::File => {
+                pull(access, &e.path, &to, timestamp.map(|_| e.timestamp)).annotate()?
+            }
+            FileMode::Dir => pull_dir(access, &e.path, &to, timestamp).annotate()?,
+            FileMode::Symlink => logw!(W002: "Skipping symlink {}", e.path),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scoped_paths() {
+        fn p(s: &str) -> Option<(&str, Option<&str>)> {
+            scoped_part(UnixPath::new(s))
+        }
+        assert_eq!(p("/sdcard/Android/data"), Some(("data", None)));
+        assert_eq!(
+            p("/sdcard/Android/data/com.app/files"),
+            Some(("data", Some("com.app")))
+        );
+        assert_eq!(
+            p("/storage/emulated/0/Android/obb/com.game"),
+            Some(("obb", Some("com.game")))
+        );
+        assert_eq!(p("/sdcard/Android/media"), None);
+        assert_eq!(p("/sdcard/DCIM"), None);
+        assert_eq!(p("/sdcard"), None);
+    }
+}
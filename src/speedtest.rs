@@ -0,0 +1,174 @@
+// `adb-sink speedtest`: shell latency and transfer rates of the ways files can move, to pick
+// transport flags and to tell a slow cable from a slow device
+
+use crate::adb::{quote, AdbCmd, AdbShell};
+use crate::fs::check_device_writable;
+use crate::{forward, human_size, logi, CResult};
+use chainerror::Context;
+use std::io::Write;
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use typed_path::{Utf8UnixPath as UnixPath, Utf8UnixPathBuf as UnixPathBuf};
+
+const REMOTE: &str = "/data/local/tmp/adb-sink-speedtest";
+
+// incompressible, so `adb push -z` and compressed links do not flatter the result
+fn fill(out: &mut impl Write, size: u64) -> std::io::Result<()> {
+    let mut x: u64 = 0x9e3779b97f4a7c15;
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut left = size;
+    while left > 0 {
+        for chunk in buf.chunks_mut(8) {
+            // xorshift64
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            chunk.copy_from_slice(&x.to_le_bytes()[..chunk.len()]);
+        }
+        let n = left.min(buf.len() as u64) as usize;
+        out.write_all(&buf[..n])?;
+        left -= n as u64;
+    }
+    Ok(())
+}
+
+fn rate(size: u64, took: Duration) -> String {
+    let per_sec = size as f64 / took.as_secs_f64().max(0.001);
+    format!("{}/s", human_size(per_sec as u64))
+}
+
+fn report(what: &str, result: String) {
+    println!("  {what:<28} {result}");
+}
+
+// average of `rounds` runs of `f`
+fn latency(rounds: u32, mut f: impl FnMut() -> CResult<()>) -> CResult<String> {
+    let start = Instant::now();
+    for _ in 0..rounds {
+        f().annotate()?;
+    }
+    let avg = start.elapsed() / rounds;
+    Ok(format!("{:.1} ms", avg.as_secs_f64() * 1000.0))
+}
+
+// times `f`, which moves `size` bytes
+fn throughput(size: u64, f: impl FnOnce() -> CResult<()>) -> CResult<String> {
+    let start = Instant::now();
+    f().annotate()?;
+    Ok(rate(size, start.elapsed()))
+}
+
+fn wait(mut child: std::process::Child, what: &str) -> CResult<()> {
+    if !child.wait().annotate()?.success() {
+        return Err(format!("{what} failed").into());
+    }
+    Ok(())
+}
+
+pub fn speedtest(size: u64, rounds: u32) -> CResult<()> {
+    check_device_writable("write the test file", UnixPath::new(REMOTE)).annotate()?;
+    let local = std::env::temp_dir().join(format!("adb-sink-speedtest-{}", std::process::id()));
+    let local_path = local.to_str().unwrap().to_string();
+    fill(&mut std::fs::File::create(&local).annotate()?, size).annotate()?;
+    let r = run(&local_path, size, rounds);
+    let _ = std::fs::remove_file(&local);
+    let _ = AdbCmd::run_v(["shell", "rm", "-f", REMOTE]);
+    r
+}
+
+fn run(local: &str, size: u64, rounds: u32) -> CResult<()> {
+    logi!(
+        "Testing with {} files, {} rounds of latency",
+        human_size(size),
+        rounds
+    );
+    println!("latency:");
+    let mut shell = AdbShell::new().annotate()?;
+    report(
+        "shell command (adb server)",
+        latency(rounds, || {
+            shell.run(["true"]).annotate()?;
+            Ok(())
+        })
+        .annotate()?,
+    );
+    report(
+        "adb shell process",
+        latency(rounds, || {
+            AdbCmd::run_v(["shell", "true"]).annotate()?;
+            Ok(())
+        })
+        .annotate()?,
+    );
+
+    println!("write to the device:");
+    report(
+        "adb push",
+        throughput(size, || {
+            AdbCmd::run_v(["push", local, REMOTE]).annotate()?;
+            Ok(())
+        })
+        .annotate()?,
+    );
+    report(
+        "exec-in stream",
+        throughput(size, || {
+            let cat = format!("cat > {}", quote(REMOTE));
+            let child = AdbCmd::new()
+                .args(["exec-in", cat.as_str()])
+                .stdin(Stdio::from(std::fs::File::open(local).annotate()?))
+                .spawn()
+                .annotate()?;
+            wait(child, "adb exec-in")
+        })
+        .annotate()?,
+    );
+
+    println!("read from the device:");
+    let back = format!("{local}.back");
+    report(
+        "adb pull",
+        throughput(size, || {
+            AdbCmd::run_v(["pull", REMOTE, back.as_str()]).annotate()?;
+            Ok(())
+        })
+        .annotate()?,
+    );
+    let _ = std::fs::remove_file(&back);
+    report(
+        "exec-out stream",
+        throughput(size, || {
+            let child = AdbCmd::new()
+                .args(["exec-out", "cat", REMOTE])
+                .stdout(Stdio::null())
+                .spawn()
+                .annotate()?;
+            wait(child, "adb exec-out")
+        })
+        .annotate()?,
+    );
+
+    if forward::port().is_some() {
+        println!("through --forward:");
+        let (from, to) = (UnixPathBuf::from(local), UnixPathBuf::from(REMOTE));
+        report(
+            "write",
+            throughput(size, || {
+                forward::push(&from, &to, None).annotate()?;
+                forward::flush()
+            })
+            .annotate()?,
+        );
+        let back = UnixPathBuf::from(back.as_str());
+        report(
+            "read",
+            throughput(size, || {
+                forward::pull(&to, &back, None).annotate()?;
+                forward::flush()
+            })
+            .annotate()?,
+        );
+        let _ = std::fs::remove_file(back.as_str());
+    }
+    Ok(())
+}
@@ -0,0 +1,482 @@
+use crate::filter::Filter;
+use crate::fs::{
+    AnyFileSystem, FSCopyFrom, FileMode, FileSystem, LocalFS, SyncFile, Transport, Unpack,
+};
+use crate::tree::{build_tree, VirtualTree};
+use crate::{human_size, is_verbose, logi, logv, logw, unix_time, utc, CResult};
+use chainerror::Context;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use typed_path::{Utf8UnixPath as UnixPath, Utf8UnixPathBuf as UnixPathBuf};
+
+// content addressed backup layout:
+//   blobs/ab/abcdef...       file contents, named by their sha256
+//   snapshots/<time>.manifest one `hash\tsize\tmtime\tpath` line per file of a run
+// identical content is stored once, however many snapshots or devices have it
+
+// one file of a snapshot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub hash: String,
+    pub size: u64,
+    pub timestamp: u32,
+    pub path: String,
+}
+
+fn blob_path(store: &Path, hash: &str) -> PathBuf {
+    store.join("blobs").join(&hash[..2]).join(hash)
+}
+
+// snapshot names sort by time, oldest first
+pub fn list_snapshots(store: &Path) -> CResult<Vec<String>> {
+    let mut names = Vec::new();
+    match std::fs::read_dir(store.join("snapshots")) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        r => {
+            for e in r.annotate()? {
+                let name = e.annotate()?.file_name();
+                if let Some(n) = name.to_str().and_then(|n| n.strip_suffix(".manifest")) {
+                    names.push(n.to_string());
+                }
+            }
+        }
+    }
+    names.sort_unstable();
+    Ok(names)
+}
+
+pub fn read_manifest(store: &Path, snapshot: &str) -> CResult<Vec<Entry>> {
+    let path = store.join("snapshots").join(format!("{snapshot}.manifest"));
+    let s = std::fs::read_to_string(&path).annotate()?;
+    let mut entries = Vec::new();
+    for (i, line) in s.lines().enumerate() {
+        let mut fields = line.splitn(4, '\t');
+        let entry = (|| {
+            Some(Entry {
+                hash: fields.next()?.to_string(),
+                size: fields.next()?.parse().ok()?,
+                timestamp: fields.next()?.parse().ok()?,
+                path: fields.next()?.to_string(),
+            })
+        })();
+        match entry {
+            Some(e) if e.hash.len() == 64 => entries.push(e),
+            _ => return Err(format!("'{}' line {} is malformed", path.display(), i + 1).into()),
+        }
+    }
+    Ok(entries)
+}
+
+fn write_manifest(store: &Path, snapshot: &str, entries: &[Entry]) -> CResult<()> {
+    let dir = store.join("snapshots");
+    std::fs::create_dir_all(&dir).annotate()?;
+    let mut out = String::new();
+    for e in entries {
+        out.push_str(&format!(
+            "{}\t{}\t{}\t{}\n",
+            e.hash, e.size, e.timestamp, e.path
+        ));
+    }
+    let tmp = dir.join(format!("{snapshot}.manifest.tmp"));
+    std::fs::write(&tmp, out).annotate()?;
+    std::fs::rename(&tmp, dir.join(format!("{snapshot}.manifest"))).annotate()?;
+    Ok(())
+}
+
+// "2024-05-01T12-30-00", utc
+fn snapshot_name(t: SystemTime) -> String {
+    let secs = t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (year, month, day, hour, min, sec) = utc(secs);
+    format!("{year:04}-{month:02}-{day:02}T{hour:02}-{min:02}-{sec:02}")
+}
+
+// pulls `src_path` into the store as a new snapshot. files unchanged since the last snapshot
+// are not hashed again, content the store already has is not transferred.
+// returns whether the snapshot differs from the last one
+pub fn snapshot<SRC: FileSystem, DEST: FileSystem + FSCopyFrom<SRC>>(
+    src_fs: &mut SRC,
+    dest_fs: &mut DEST,
+    src_path: &UnixPath,
+    store: &Path,
+    filter: &Filter,
+) -> CResult<bool> {
+    let Some(src_sf) = src_fs.stat(src_path).annotate()? else {
+        return Err(format!("Source '{}' does not exist", src_path).into());
+    };
+    if src_sf.mode != FileMode::Dir {
+        return Err(format!("Source '{}' is not a directory", src_path).into());
+    }
+    let root = build_tree(src_fs, src_sf, src_path, filter).annotate()?;
+    let mut files = root.files();
+    files.sort_unstable_by(|a, b| a.strip_path.as_str().cmp(b.strip_path.as_str()));
+
+    let snapshots = list_snapshots(store).annotate()?;
+    let previous = match snapshots.last() {
+        Some(s) => read_manifest(store, s).annotate()?,
+        None => Vec::new(),
+    };
+    let known: HashMap<&str, &Entry> = previous.iter().map(|e| (e.path.as_str(), e)).collect();
+
+    let tmp = store.join("tmp");
+    std::fs::create_dir_all(&tmp).annotate()?;
+    let tmp_file = UnixPathBuf::from(tmp.join("incoming").to_str().unwrap());
+    let (mut new_blobs, mut new_bytes) = (0, 0);
+    let mut entries = Vec::with_capacity(files.len());
+    for f in files {
+        let (size, timestamp) = (f.sf.size, f.sf.timestamp);
+        let path = f.strip_path.as_str();
+        let hash = match known.get(path) {
+            Some(e) if e.size == size && e.timestamp == timestamp => e.hash.clone(),
+            _ => src_fs.sha256(&f.sf.path).annotate()?,
+        };
+        let blob = blob_path(store, &hash);
+        if blob.exists() {
+            logv!("SKIP (STORED): {}", f.sf.path);
+        } else {
+            logi!("STORE: {} -> {}", f.sf.path, &hash[..12]);
+            dest_fs.copy(&f.sf.path, &tmp_file, None).annotate()?;
+            // the file may have changed between hashing and pulling
+            let got = LocalFS.sha256(&tmp_file).annotate()?;
+            if got != hash {
+                return Err(format!("'{}' changed while it was being stored", f.sf.path).into());
+            }
+            std::fs::create_dir_all(blob.parent().unwrap()).annotate()?;
+            std::fs::rename(tmp_file.as_str(), &blob).annotate()?;
+            new_blobs += 1;
+            new_bytes += size;
+        }
+        entries.push(Entry {
+            hash,
+            size,
+            timestamp,
+            path: path.to_string(),
+        });
+    }
+
+    let changed = entries != previous;
+    let mut name = snapshot_name(SystemTime::now());
+    if snapshots.last() == Some(&name) {
+        // two runs within a second
+        name = snapshot_name(SystemTime::now() + Duration::from_secs(1));
+    }
+    write_manifest(store, &name, &entries).annotate()?;
+    logi!(
+        "Snapshot {}: {} files, {} new blobs ({})",
+        name,
+        entries.len(),
+        new_blobs,
+        human_size(new_bytes)
+    );
+    Ok(changed)
+}
+
+// the given snapshot, or the latest
+fn pick_snapshot(store: &Path, snapshot: Option<&str>) -> CResult<String> {
+    match snapshot {
+        Some(s) => Ok(s.to_string()),
+        None => match list_snapshots(store).annotate()?.pop() {
+            Some(s) => Ok(s),
+            None => Err(format!("'{}' has no snapshots", store.display()).into()),
+        },
+    }
+}
+
+// writes the files of `snapshot` (the latest without one) out as a normal tree under `dest`.
+// `link` hard links the blobs instead of copying them, so the tree must not be modified
+pub fn checkout(store: &Path, snapshot: Option<&str>, dest: &Path, link: bool) -> CResult<()> {
+    let name = pick_snapshot(store, snapshot).annotate()?;
+    let entries = read_manifest(store, &name).annotate()?;
+    for e in &entries {
+        let to = dest.join(&e.path);
+        std::fs::create_dir_all(to.parent().unwrap()).annotate()?;
+        let blob = blob_path(store, &e.hash);
+        logv!("CHECKOUT: {}", e.path);
+        if link {
+            std::fs::hard_link(&blob, &to).annotate()?;
+        } else {
+            std::fs::copy(&blob, &to).annotate()?;
+            let to = UnixPathBuf::from(to.to_str().unwrap());
+            LocalFS.set_mtime(&to, e.timestamp).annotate()?;
+        }
+    }
+    logi!("Checked out {} files of snapshot {}", entries.len(), name);
+    Ok(())
+}
+
+// which snapshots `prune` keeps: the latest of each of the last `daily` days, `weekly` weeks
+// and `monthly` months that have one. the latest snapshot is always kept
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Retention {
+    pub daily: usize,
+    pub weekly: usize,
+    pub monthly: usize,
+}
+
+// days since the epoch of a snapshot name, see `snapshot_name`
+fn snapshot_day(name: &str) -> Option<i64> {
+    let mut date = name.get(..10)?.split('-');
+    let year = date.next()?.parse().ok()?;
+    let month = date.next()?.parse().ok()?;
+    let day = date.next()?.parse().ok()?;
+    Some((unix_time(year, month, day, 0, 0, 0) / 86400) as i64)
+}
+
+// whether to keep each of `snapshots`, oldest first. snapshots with names that are not times
+// were not made by adb-sink and are kept
+fn retained(snapshots: &[String], retention: Retention) -> Vec<bool> {
+    let mut keep = vec![false; snapshots.len()];
+    // (how many to keep, day -> period, the last period kept)
+    let mut rules: [(usize, fn(i64) -> i64, Option<i64>); 3] = [
+        (retention.daily, |d| d, None),
+        // weeks start on monday, the epoch was a thursday
+        (retention.weekly, |d| (d + 3).div_euclid(7), None),
+        (
+            retention.monthly,
+            |d| {
+                let (year, month, ..) = utc(d as u64 * 86400);
+                year * 12 + month as i64
+            },
+            None,
+        ),
+    ];
+    for (i, name) in snapshots.iter().enumerate().rev() {
+        let Some(day) = snapshot_day(name) else {
+            keep[i] = true;
+            continue;
+        };
+        for (left, period, last) in &mut rules {
+            let p = period(day);
+            if *left > 0 && *last != Some(p) {
+                keep[i] = true;
+                *left -= 1;
+                *last = Some(p);
+            }
+        }
+    }
+    if let Some(latest) = keep.last_mut() {
+        *latest = true;
+    }
+    keep
+}
+
+// deletes the snapshots `retention` does not keep, then the blobs no remaining snapshot has.
+// manifests go first, so an interrupted prune never leaves a snapshot with missing blobs.
+// checkouts made with `--link` share the blobs' data and keep it, only the store's names of it
+// are removed. must not run while a `pull --store` writes to the same store
+pub fn prune(store: &Path, retention: Retention, dry_run: bool) -> CResult<()> {
+    let snapshots = list_snapshots(store).annotate()?;
+    let keep = retained(&snapshots, retention);
+    let mut referenced = HashSet::new();
+    for (name, keep) in snapshots.iter().zip(&keep) {
+        if *keep {
+            logv!("KEEP: {}", name);
+            for e in read_manifest(store, name).annotate()? {
+                referenced.insert(e.hash);
+            }
+            continue;
+        }
+        logi!("PRUNE SNAPSHOT: {}", name);
+        if !dry_run {
+            let manifest = store.join("snapshots").join(format!("{name}.manifest"));
+            std::fs::remove_file(manifest).annotate()?;
+        }
+    }
+
+    let (mut blobs, mut bytes) = (0, 0);
+    let dirs = match std::fs::read_dir(store.join("blobs")) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        r => r.annotate()?.collect::<Result<Vec<_>, _>>().annotate()?,
+    };
+    for dir in dirs {
+        for blob in std::fs::read_dir(dir.path()).annotate()? {
+            let blob = blob.annotate()?;
+            let name = blob.file_name();
+            if name.to_str().is_some_and(|h| referenced.contains(h)) {
+                continue;
+            }
+            logv!("PRUNE BLOB: {}", name.to_string_lossy());
+            blobs += 1;
+            bytes += blob.metadata().annotate()?.len();
+            if !dry_run {
+                std::fs::remove_file(blob.path()).annotate()?;
+            }
+        }
+    }
+    let pruned = keep.iter().filter(|k| !**k).count();
+    if dry_run {
+        logw!("Dry run, nothing was deleted");
+    }
+    logi!(
+        "Pruned {} of {} snapshots, {} blobs ({})",
+        pruned,
+        snapshots.len(),
+        blobs,
+        human_size(bytes)
+    );
+    Ok(())
+}
+
+// a snapshot as a tree of its files, rooted at `<store>/snapshots/<name>`
+pub struct Snapshot {
+    store: PathBuf,
+    // files with their hashes
+    files: VirtualTree<String>,
+}
+
+impl Snapshot {
+    pub fn open(store: &Path, snapshot: Option<&str>) -> CResult<Self> {
+        let name = pick_snapshot(store, snapshot).annotate()?;
+        let entries = read_manifest(store, &name).annotate()?;
+        let root = store.join("snapshots").join(&name);
+        let root = UnixPathBuf::from(root.to_str().unwrap());
+        let mtime = entries.iter().map(|e| e.timestamp).max().unwrap_or(0);
+        logi!("Snapshot {}: {} files", name, entries.len());
+        let mut files = VirtualTree::new(root, mtime);
+        for e in entries {
+            files.insert(&e.path, FileMode::File, e.size, e.timestamp, e.hash);
+        }
+        Ok(Self {
+            store: store.to_path_buf(),
+            files,
+        })
+    }
+
+    pub fn root(&self) -> PathBuf {
+        PathBuf::from(self.files.root.as_str())
+    }
+}
+
+// read-only file system view of a snapshot, for restoring it
+pub struct SnapshotFS(pub Rc<Snapshot>);
+
+fn read_only() -> CResult<()> {
+    Err("snapshots are read-only".into())
+}
+
+impl FileSystem for SnapshotFS {
+    fn mkdir(&mut self, _path: &UnixPath) -> CResult<()> {
+        read_only()
+    }
+
+    fn list_dir(&mut self, path: &UnixPath) -> CResult<Vec<SyncFile>> {
+        Ok(self.0.files.list_dir(path))
+    }
+
+    fn stat(&mut self, path: &UnixPath) -> CResult<Option<SyncFile>> {
+        Ok(self.0.files.stat(path))
+    }
+
+    fn rm(&mut self, _path: &UnixPath) -> CResult<()> {
+        read_only()
+    }
+
+    fn rm_dir(&mut self, _path: &UnixPath) -> CResult<()> {
+        read_only()
+    }
+
+    fn set_mtime(&mut self, _path: &UnixPath, _timestamp: u32) -> CResult<()> {
+        read_only()
+    }
+
+    // blobs are verified when stored, their name is their hash
+    fn sha256(&mut self, path: &UnixPath) -> CResult<String> {
+        match self.0.files.get(path) {
+            Some((_, hash)) => Ok(hash.clone()),
+            None => Err(format!("'{path}' is not in the snapshot").into()),
+        }
+    }
+}
+
+impl AnyFileSystem for SnapshotFS {
+    fn transport(&self) -> Transport {
+        Transport::Local
+    }
+
+    fn copy_from(
+        &mut self,
+        _src: Transport,
+        _from: &UnixPath,
+        _to: &UnixPath,
+        _timestamp: Option<u32>,
+    ) -> CResult<()> {
+        read_only()
+    }
+
+    fn copy_dir_from(
+        &mut self,
+        _src: Transport,
+        _from: &UnixPath,
+        _to: &UnixPath,
+        _timestamp: Option<u32>,
+    ) -> CResult<()> {
+        read_only()
+    }
+}
+
+impl Unpack for SnapshotFS {
+    fn unpack(&mut self, from: &UnixPath, to: &UnixPath) -> CResult<()> {
+        let hash = self.sha256(from).annotate()?;
+        std::fs::copy(blob_path(&self.0.store, &hash), to.as_str()).annotate()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_names() {
+        assert_eq!(snapshot_name(UNIX_EPOCH), "1970-01-01T00-00-00");
+        let t = UNIX_EPOCH + Duration::from_secs(1709210096);
+        assert_eq!(snapshot_name(t), "2024-02-29T12-34-56");
+    }
+
+    #[test]
+    fn retention() {
+        let snapshots: Vec<String> = [
+            "2024-01-15T10-00-00",
+            "2024-02-10T10-00-00",
+            "2024-02-26T10-00-00",
+            "2024-02-28T09-00-00",
+            "2024-02-28T21-00-00",
+            "2024-02-29T08-00-00",
+            "2024-03-01T08-00-00",
+            "2024-03-01T20-00-00",
+            "handmade",
+        ]
+        .map(String::from)
+        .to_vec();
+        let kept = |retention| {
+            let keep = retained(&snapshots, retention);
+            let names = snapshots.iter().zip(keep).filter(|(_, k)| *k);
+            names.map(|(n, _)| n.as_str()).collect::<Vec<_>>()
+        };
+        let daily = Retention {
+            daily: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            kept(daily),
+            ["2024-02-29T08-00-00", "2024-03-01T20-00-00", "handmade"]
+        );
+        // 2024-02-26 is a monday, it starts the week of the latest snapshots
+        let weekly_monthly = Retention {
+            weekly: 2,
+            monthly: 3,
+            ..Default::default()
+        };
+        assert_eq!(
+            kept(weekly_monthly),
+            [
+                "2024-01-15T10-00-00",
+                "2024-02-10T10-00-00",
+                "2024-02-29T08-00-00",
+                "2024-03-01T20-00-00",
+                "handmade"
+            ]
+        );
+    }
+}
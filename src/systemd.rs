@@ -0,0 +1,151 @@
+// running under systemd: sd_notify readiness and status, and user units that run a sync on a
+// timer, generated from the command line they were asked for with
+
+use crate::{logi, CResult};
+use chainerror::Context;
+use std::path::PathBuf;
+
+// sends `state`, e.g. `READY=1` or `STATUS=...`, to the service manager. does nothing when
+// not started by systemd as a Type=notify service
+#[cfg(target_os = "linux")]
+pub fn notify(state: &str) {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let path = path.to_string_lossy();
+    // `@name` is an abstract socket
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name),
+        None => SocketAddr::from_pathname(&*path),
+    };
+    let sent = addr.and_then(|addr| UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr));
+    if let Err(e) = sent {
+        crate::logw!(W013: "Could not notify systemd: {}", e);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn notify(_state: &str) {}
+
+pub fn status(text: &str) {
+    notify(&format!("STATUS={text}"));
+}
+
+// ExecStart= quoting: specifiers and variables are escaped, arguments with spaces quoted
+fn quote(arg: &str) -> String {
+    let escaped = arg
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    if escaped.is_empty() || escaped.contains([' ', '\t', '\'', ';']) || escaped != arg {
+        format!("\"{escaped}\"")
+    } else {
+        escaped
+    }
+}
+
+// the arguments without --generate-unit and --on-calendar, which only concern the unit
+fn command_line(args: &[String]) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--generate-unit" | "--on-calendar" => {
+                args.next();
+            }
+            a if a.starts_with("--generate-unit=") || a.starts_with("--on-calendar=") => {}
+            _ => out.push(arg.clone()),
+        }
+    }
+    out
+}
+
+fn unit_dir() -> CResult<PathBuf> {
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))
+        .ok_or("Could not find the systemd user unit directory, set XDG_CONFIG_HOME")?;
+    Ok(dir.join("systemd").join("user"))
+}
+
+// writes `<name>.service`, running adb-sink with `args` (the whole command line of this run),
+// and `<name>.timer`, starting it on `calendar`
+pub fn generate_units(name: &str, calendar: &str, args: &[String]) -> CResult<()> {
+    let exe = std::env::current_exe().annotate()?;
+    let cwd = std::env::current_dir().annotate()?;
+    let mut cmd = command_line(&args[1..]);
+    // so that the runs of the unit are recorded in the history under its name
+    if !cmd.iter().any(|a| a == "--job" || a.starts_with("--job=")) {
+        cmd.splice(0..0, ["--job".to_string(), name.to_string()]);
+    }
+    let exec: Vec<String> = std::iter::once(exe.to_string_lossy().to_string())
+        .chain(cmd)
+        .map(|a| quote(&a))
+        .collect();
+    // only adb-sink gets SIGTERM, it finishes the file being transferred and saves its state.
+    // with the default KillMode the adb doing the transfer would be killed along with it
+    let service = format!(
+        "[Unit]\n\
+        Description=adb-sink {name}\n\
+        \n\
+        [Service]\n\
+        Type=notify\n\
+        NotifyAccess=main\n\
+        KillMode=mixed\n\
+        TimeoutStopSec=120\n\
+        WorkingDirectory={}\n\
+        ExecStart={}\n",
+        quote(&cwd.to_string_lossy()),
+        exec.join(" ")
+    );
+    let timer = format!(
+        "[Unit]\n\
+        Description=Run adb-sink {name} {calendar}\n\
+        \n\
+        [Timer]\n\
+        OnCalendar={calendar}\n\
+        Persistent=true\n\
+        \n\
+        [Install]\n\
+        WantedBy=timers.target\n"
+    );
+    let dir = unit_dir().annotate()?;
+    std::fs::create_dir_all(&dir).annotate()?;
+    for (ext, unit) in [("service", service), ("timer", timer)] {
+        let path = dir.join(format!("{name}.{ext}"));
+        std::fs::write(&path, unit).annotate()?;
+        logi!("Wrote '{}'", path.display());
+    }
+    logi!(
+        "Enable it with: systemctl --user daemon-reload && systemctl --user enable --now {}.timer",
+        name
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exec_start() {
+        let args: Vec<String> = [
+            "--generate-unit",
+            "photos",
+            "--on-calendar=hourly",
+            "pull",
+            "/sdcard/DCIM",
+            "my backup/100%",
+        ]
+        .map(String::from)
+        .to_vec();
+        let exec: Vec<String> = command_line(&args).iter().map(|a| quote(a)).collect();
+        assert_eq!(exec, ["pull", "/sdcard/DCIM", "\"my backup/100%%\""]);
+        assert_eq!(quote(""), "\"\"");
+        assert_eq!(quote("$HOME"), "\"$$HOME\"");
+    }
+}
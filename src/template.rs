@@ -0,0 +1,124 @@
+// tokens in destination paths, so one config serves several phones and dated folders need no
+// script: `pull /sdcard/DCIM ~/backups/{model}/{date}`. device properties are only read when
+// a path asks for them
+
+use crate::adb::AdbCmd;
+use crate::{utc, CResult};
+use chainerror::Context;
+use std::path::{Path, PathBuf};
+
+const TOKENS: &str = "{serial}, {nickname}, {model}, {manufacturer}, {device}, {android}, \
+                      {date}, {time}, {source_name}";
+
+pub struct Vars<'a> {
+    pub serial: Option<&'a str>,
+    pub nickname: Option<&'a str>,
+    pub source: &'a Path,
+    // seconds since the epoch, every token of a run sees the same time
+    pub now: u64,
+}
+
+impl Vars<'_> {
+    fn get(&self, token: &str) -> CResult<Option<String>> {
+        let (year, month, day, hour, min, sec) = utc(self.now);
+        let value = match token {
+            "serial" => self.device(token)?.to_string(),
+            "nickname" => self.nickname.unwrap_or(self.device(token)?).to_string(),
+            "model" => self.getprop(token, "ro.product.model")?,
+            "manufacturer" => self.getprop(token, "ro.product.manufacturer")?,
+            "device" => self.getprop(token, "ro.product.device")?,
+            "android" => self.getprop(token, "ro.build.version.release")?,
+            // utc, like snapshot names
+            "date" => format!("{year:04}-{month:02}-{day:02}"),
+            "time" => format!("{hour:02}{min:02}{sec:02}"),
+            "source_name" => self
+                .source
+                .file_name()
+                .map_or("root".into(), |n| n.to_string_lossy())
+                .into_owned(),
+            _ => return Ok(None),
+        };
+        Ok(Some(value))
+    }
+
+    fn device(&self, token: &str) -> CResult<&str> {
+        self.serial
+            .ok_or_else(|| format!("{{{token}}} needs an adb device").into())
+    }
+
+    fn getprop(&self, token: &str, prop: &str) -> CResult<String> {
+        self.device(token).annotate()?;
+        let value = AdbCmd::run_v(["shell", "getprop", prop]).annotate()?;
+        Ok(value.trim().to_string())
+    }
+}
+
+// `template` with its tokens replaced, `{{` and `}}` are literal braces. values cannot add
+// directories, their slashes become underscores
+pub fn expand(template: &str, vars: &Vars) -> CResult<String> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(at) = rest.find(['{', '}']) {
+        out.push_str(&rest[..at]);
+        let tail = &rest[at..];
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            out.push_str(&tail[..1]);
+            rest = &tail[2..];
+            continue;
+        }
+        let token = tail[1..]
+            .find('}')
+            .filter(|_| tail.starts_with('{'))
+            .map(|end| &tail[1..end + 1]);
+        let Some(token) = token else {
+            return Err(format!("unbalanced brace in '{template}', use {{{{ and }}}}").into());
+        };
+        let Some(value) = vars.get(token).annotate()? else {
+            return Err(
+                format!("unknown token {{{token}}} in '{template}', known are {TOKENS}").into(),
+            );
+        };
+        let value = value.trim().replace('/', "_");
+        out.push_str(if value.is_empty() { "unknown" } else { &value });
+        rest = &tail[token.len() + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+// `path` expanded, as it was when it has no tokens
+pub fn expand_path(path: &Path, vars: &Vars) -> CResult<PathBuf> {
+    match path.to_str() {
+        Some(s) if s.contains(['{', '}']) => Ok(PathBuf::from(expand(s, vars).annotate()?)),
+        _ => Ok(path.to_path_buf()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens() {
+        let vars = Vars {
+            serial: Some("1A2B"),
+            nickname: None,
+            source: Path::new("/sdcard/DCIM/"),
+            // 2024-05-01 12:30:05
+            now: 1714566605,
+        };
+        assert_eq!(
+            expand("/b/{nickname}/{source_name}-{date}T{time}", &vars).unwrap(),
+            "/b/1A2B/DCIM-2024-05-01T123005"
+        );
+        assert_eq!(expand("/b/{{x}}", &vars).unwrap(), "/b/{x}");
+        assert!(expand("/b/{bogus}", &vars).is_err());
+        assert!(expand("/b/{date", &vars).is_err());
+        assert!(expand("/b/x}", &vars).is_err());
+        let local = Vars {
+            serial: None,
+            ..vars
+        };
+        assert!(expand("/b/{serial}", &local).is_err());
+    }
+}
@@ -0,0 +1,59 @@
+use crate::logi;
+use std::time::{Duration, Instant};
+
+// how many of the slowest operations are reported
+const SLOWEST: usize = 10;
+
+// wall time of each sync phase and of the slowest single operations, for --timings
+pub struct Timings {
+    enabled: bool,
+    mark: Instant,
+    phases: Vec<(&'static str, Duration)>,
+    slowest: Vec<(Duration, String)>,
+}
+
+impl Timings {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            mark: Instant::now(),
+            phases: Vec::new(),
+            slowest: Vec::new(),
+        }
+    }
+
+    // ends the running phase, naming it `name`
+    pub fn phase(&mut self, name: &'static str) {
+        let now = Instant::now();
+        self.phases.push((name, now - self.mark));
+        self.mark = now;
+    }
+
+    // `desc` is only built for operations that make it into the list
+    pub fn op(&mut self, took: Duration, desc: impl FnOnce() -> String) {
+        if !self.enabled
+            || (self.slowest.len() == SLOWEST && self.slowest.last().is_some_and(|l| l.0 >= took))
+        {
+            return;
+        }
+        let i = self.slowest.partition_point(|(d, _)| *d >= took);
+        self.slowest.insert(i, (took, desc()));
+        self.slowest.truncate(SLOWEST);
+    }
+
+    pub fn report(&self) {
+        if !self.enabled {
+            return;
+        }
+        logi!("Timings:");
+        for (name, took) in &self.phases {
+            println!("  {:<18} {:>9.2?}", name, took);
+        }
+        if !self.slowest.is_empty() {
+            println!("  slowest operations:");
+            for (took, desc) in &self.slowest {
+                println!("    {:>9.2?}  {}", took, desc);
+            }
+        }
+    }
+}
@@ -0,0 +1,90 @@
+// which flavor of each shell command the device has. toybox replaced toolbox in Android 6,
+// older devices have toolbox without `stat`, `head -c` or `touch -d`, and rooted ones often
+// carry busybox next to either. the probe runs once per session and picks, per applet, the
+// first of the plain command, `toybox <applet>` and `busybox <applet>` that takes the flags
+// adb-sink passes
+
+use crate::adb::AdbShell;
+use crate::{is_verbose, logv, CResult};
+use chainerror::Context;
+use std::collections::HashMap;
+
+// a command per applet that only succeeds when it understands those flags, `$c` is how the
+// applet is called
+const CHECKS: [(&str, &str); 6] = [
+    ("stat", "$c -c %s /"),
+    ("touch", "$c -c -m -d @0 /data/local/tmp/.adb-sink-probe"),
+    ("sha256sum", "echo | $c"),
+    ("head", "echo | $c -c 1"),
+    ("tail", "echo | $c -c 1"),
+    ("xargs", "echo | $c -0 -P 2 true"),
+];
+
+#[derive(Debug, Clone, Default)]
+pub struct DeviceTools {
+    // applet -> how to call it, missing when no flavor on the device works
+    commands: HashMap<&'static str, String>,
+}
+
+impl DeviceTools {
+    pub fn probe(shell: &mut AdbShell) -> CResult<Self> {
+        let script: String = CHECKS
+            .iter()
+            .map(|(applet, check)| {
+                format!(
+                    "for p in '' toybox busybox; do c=\"${{p:+$p }}{applet}\"; \
+                     {check} >/dev/null 2>&1 && {{ echo \"{applet}:$c\"; break; }}; done; "
+                )
+            })
+            .collect();
+        let op = shell.exec([script]).annotate()?;
+        let tools = Self::parse(&op.stdout);
+        for (applet, _) in CHECKS {
+            match tools.commands.get(applet) {
+                Some(c) if c != applet => logv!("Using `{}` on the device", c),
+                Some(_) => {}
+                None => logv!("The device has no usable `{}`, falling back", applet),
+            }
+        }
+        Ok(tools)
+    }
+
+    fn parse(out: &str) -> Self {
+        let commands = out
+            .lines()
+            .filter_map(|l| l.split_once(':'))
+            .filter_map(|(applet, c)| {
+                let (applet, _) = CHECKS.iter().find(|(a, _)| *a == applet)?;
+                Some((*applet, c.trim().to_string()))
+            })
+            .collect();
+        Self { commands }
+    }
+
+    // how to call `applet`, None when the device has no flavor of it that works
+    pub fn get(&self, applet: &str) -> Option<&str> {
+        self.commands.get(applet).map(String::as_str)
+    }
+
+    // like `get`, for commands that have no fallback
+    pub fn require(&self, applet: &str) -> CResult<&str> {
+        self.get(applet).ok_or_else(|| {
+            format!("the device has no `{applet}`, neither toybox nor busybox provides one").into()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probe_output() {
+        let tools = DeviceTools::parse("stat:busybox stat\nsha256sum:sha256sum\nbogus:x\n");
+        assert_eq!(tools.get("stat"), Some("busybox stat"));
+        assert_eq!(tools.get("sha256sum"), Some("sha256sum"));
+        assert_eq!(tools.get("touch"), None);
+        assert_eq!(tools.get("bogus"), None);
+        assert!(tools.require("head").is_err());
+    }
+}
@@ -0,0 +1,175 @@
+use regex::Regex;
+use std::collections::HashSet;
+use std::str::FromStr;
+
+// a sed style `s/pattern/replacement/flags` rule, applied to destination paths like `tar --transform`.
+// the first character after `s` is the delimiter. flags: `g` replace all matches, `i` ignore case
+#[derive(Debug, Clone)]
+pub struct Transform {
+    re: Regex,
+    rep: String,
+    global: bool,
+}
+
+impl FromStr for Transform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chars = s.chars();
+        if chars.next() != Some('s') {
+            return Err(format!(
+                "transform must look like s/pattern/replacement/: '{s}'"
+            ));
+        }
+        let delim = chars
+            .next()
+            .ok_or_else(|| format!("missing delimiter: '{s}'"))?;
+
+        let mut parts = vec![String::new()];
+        let mut escaped = false;
+        for c in chars {
+            if escaped {
+                if c != delim {
+                    parts.last_mut().unwrap().push('\\');
+                }
+                parts.last_mut().unwrap().push(c);
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == delim {
+                parts.push(String::new());
+            } else {
+                parts.last_mut().unwrap().push(c);
+            }
+        }
+        let [pattern, rep, flags]: [String; 3] = parts
+            .try_into()
+            .map_err(|_| format!("transform must have exactly 3 parts: '{s}'"))?;
+
+        let mut global = false;
+        let mut ignore_case = false;
+        for f in flags.chars() {
+            match f {
+                'g' => global = true,
+                'i' => ignore_case = true,
+                f => return Err(format!("unknown transform flag '{f}'")),
+            }
+        }
+        let pattern = if ignore_case {
+            format!("(?i){pattern}")
+        } else {
+            pattern
+        };
+        Ok(Self {
+            re: Regex::new(&pattern).map_err(|e| e.to_string())?,
+            rep: sed_to_regex_replacement(&rep),
+            global,
+        })
+    }
+}
+
+// sed uses `&` and `\N` for captures, the regex crate uses `${0}` and `${N}`
+fn sed_to_regex_replacement(rep: &str) -> String {
+    let mut out = String::with_capacity(rep.len());
+    let mut chars = rep.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(d) if d.is_ascii_digit() => {
+                    out.push_str("${");
+                    out.push(d);
+                    out.push('}');
+                }
+                Some('$') => out.push_str("$$"),
+                Some(c) => out.push(c),
+                None => out.push('\\'),
+            },
+            '&' => out.push_str("${0}"),
+            '$' => out.push_str("$$"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+impl Transform {
+    pub fn apply(&self, s: &str) -> String {
+        if self.global {
+            self.re.replace_all(s, self.rep.as_str()).into_owned()
+        } else {
+            self.re.replace(s, self.rep.as_str()).into_owned()
+        }
+    }
+}
+
+// rules are applied in the order given, each to the output of the previous one
+pub fn apply_all(transforms: &[Transform], s: &str) -> String {
+    transforms
+        .iter()
+        .fold(s.to_string(), |acc, t| t.apply(&acc))
+}
+
+// letter case of destination names, for case-insensitive targets
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Case {
+    Lower,
+    Upper,
+    #[default]
+    Preserve,
+}
+
+impl Case {
+    pub fn apply(self, s: String) -> String {
+        match self {
+            Self::Lower => s.to_lowercase(),
+            Self::Upper => s.to_uppercase(),
+            Self::Preserve => s,
+        }
+    }
+}
+
+// file name of `path`, suffixed with " (N)" before the extension if it is already `taken`
+pub fn flatten_name(path: &str, taken: &mut HashSet<String>) -> String {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let (stem, ext) = match name.rfind('.') {
+        Some(i) if i > 0 => name.split_at(i),
+        _ => (name, ""),
+    };
+    let mut candidate = name.to_string();
+    let mut n = 1;
+    while !taken.insert(candidate.clone()) {
+        candidate = format!("{stem} ({n}){ext}");
+        n += 1;
+    }
+    candidate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transform_rules() {
+        let t: Transform = "s/^Camera\\///".parse().unwrap();
+        assert_eq!(t.apply("Camera/IMG_1.jpg"), "IMG_1.jpg");
+
+        let t: Transform = "s/ /_/g".parse().unwrap();
+        assert_eq!(t.apply("a b c.txt"), "a_b_c.txt");
+
+        let t: Transform = "s|(\\w+)\\.JPG|\\1.jpg|i".parse().unwrap();
+        assert_eq!(t.apply("dir/photo.jpg"), "dir/photo.jpg");
+        assert_eq!(t.apply("dir/photo.JPG"), "dir/photo.jpg");
+
+        assert!("s/a/b".parse::<Transform>().is_err());
+        assert!("s/a/b/x".parse::<Transform>().is_err());
+    }
+
+    #[test]
+    fn flatten_collisions() {
+        let mut taken = HashSet::new();
+        assert_eq!(flatten_name("a/doc.pdf", &mut taken), "doc.pdf");
+        assert_eq!(flatten_name("b/doc.pdf", &mut taken), "doc (1).pdf");
+        assert_eq!(flatten_name("c/doc.pdf", &mut taken), "doc (2).pdf");
+        assert_eq!(flatten_name("c/.hidden", &mut taken), ".hidden");
+    }
+}
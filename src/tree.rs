@@ -1,43 +1,63 @@
 use crate::{
+    filter::Filter,
     fs::{FileMode, FileSystem, SyncFile},
-    CResult,
+    is_verbose, logi, logv, logw, CResult,
 };
 use chainerror::Context;
-use std::collections::HashSet;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
-use std::hash::Hash;
+use std::time::{Duration, Instant};
 use typed_path::{Utf8UnixPath as UnixPath, Utf8UnixPathBuf as UnixPathBuf};
 
-#[derive(Eq, Debug)]
+#[derive(Debug)]
 pub struct Node {
     pub sf: SyncFile,
-    pub entries: HashSet<Node>,
+    // children by the last component of their `strip_path`, which for remapped trees is not
+    // the name they have on the source. sorted, so trees are walked in the same order each run
+    pub entries: BTreeMap<Box<str>, Node>,
     pub strip_path: UnixPathBuf,
 }
-impl PartialEq for Node {
-    fn eq(&self, other: &Self) -> bool {
-        self.strip_path == other.strip_path
-    }
-}
-impl Hash for Node {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.strip_path.hash(state);
-    }
-}
 
 impl Node {
     pub fn new(sf: SyncFile, prefix: &UnixPath) -> Self {
         let strip_path = sf.path.strip_prefix(prefix).unwrap().to_path_buf();
         Self {
             sf,
-            entries: HashSet::new(),
+            entries: BTreeMap::new(),
             strip_path,
         }
     }
 
+    // (bytes, files) of everything under this node
+    pub fn total_size(&self) -> (u64, usize) {
+        match self.sf.mode {
+            FileMode::File => (self.sf.size, 1),
+            _ => self.entries.values().fold((0, 0), |(bytes, files), e| {
+                let (b, f) = e.total_size();
+                (bytes + b, files + f)
+            }),
+        }
+    }
+
+    // every file under this node
+    pub fn files(&self) -> Vec<&Node> {
+        fn collect<'n>(n: &'n Node, files: &mut Vec<&'n Node>) {
+            for e in n.entries.values() {
+                match e.sf.mode {
+                    FileMode::File => files.push(e),
+                    _ => collect(e, files),
+                }
+            }
+        }
+        let mut files = Vec::new();
+        collect(self, &mut files);
+        files
+    }
+
     pub fn print_node(&self) {
-        fn print_node_(depth: usize, entries: &HashSet<Node>) {
-            for n in entries {
+        fn print_node_(depth: usize, entries: &BTreeMap<Box<str>, Node>) {
+            for n in entries.values() {
                 for _ in 0..depth * 2 {
                     print!("  ");
                 }
@@ -49,30 +69,312 @@ impl Node {
     }
 }
 
-pub fn build_tree<FS: FileSystem>(fs: &mut FS, sf: SyncFile, prefix: &UnixPath) -> CResult<Node> {
-    fn build_tree_<FS: FileSystem>(fs: &mut FS, root: &mut Node, prefix: &UnixPath) -> CResult<()> {
-        for entry in fs.list_dir(&root.sf.path).annotate()? {
-            let mode = entry.mode;
-            let mut node = Node::new(entry, prefix);
+// an in-memory tree of files whose data lives elsewhere, like the members of an archive or the
+// files of a snapshot manifest. each file carries a `T` telling where its data is
+pub struct VirtualTree<T> {
+    pub root: UnixPathBuf,
+    entries: HashMap<UnixPathBuf, (SyncFile, T)>,
+    children: HashMap<UnixPathBuf, Vec<UnixPathBuf>>,
+}
+
+impl<T: Default> VirtualTree<T> {
+    pub fn new(root: UnixPathBuf, mtime: u32) -> Self {
+        let sf = SyncFile {
+            mode: FileMode::Dir,
+            size: 0,
+            timestamp: mtime,
+            name: root.file_name().unwrap_or_default().into(),
+            path: root.clone().into_boxed_path(),
+        };
+        Self {
+            entries: HashMap::from([(root.clone(), (sf, T::default()))]),
+            children: HashMap::new(),
+            root,
+        }
+    }
+
+    // `name` is relative to the root. missing parents are added as directories, neither
+    // archives nor manifests need to list every directory
+    pub fn insert(&mut self, name: &str, mode: FileMode, size: u64, mtime: u32, value: T) {
+        let path = self.root.join(name);
+        let parent = path.parent().expect("under the root").to_path_buf();
+        if !self.entries.contains_key(&parent) {
+            let parent_name = parent
+                .strip_prefix(&self.root)
+                .unwrap()
+                .as_str()
+                .to_string();
+            self.insert(&parent_name, FileMode::Dir, 0, mtime, T::default());
+        }
+        if !self.entries.contains_key(&path) {
+            self.children.entry(parent).or_default().push(path.clone());
+        }
+        let sf = SyncFile {
+            mode,
+            size,
+            timestamp: mtime,
+            name: path.file_name().unwrap_or_default().into(),
+            path: path.clone().into_boxed_path(),
+        };
+        self.entries.insert(path, (sf, value));
+    }
+}
+
+impl<T> VirtualTree<T> {
+    pub fn get(&self, path: &UnixPath) -> Option<&(SyncFile, T)> {
+        self.entries.get(path)
+    }
+
+    pub fn stat(&self, path: &UnixPath) -> Option<SyncFile> {
+        self.get(path).map(|(sf, _)| sf.clone())
+    }
+
+    pub fn list_dir(&self, path: &UnixPath) -> Vec<SyncFile> {
+        let children = self.children.get(path).map_or(&[][..], Vec::as_slice);
+        children.iter().map(|c| self.entries[c].0.clone()).collect()
+    }
+
+    // the root's only entry, when that is a directory
+    pub fn single_dir(&self) -> Option<&UnixPath> {
+        match self.children.get(&self.root).map(Vec::as_slice) {
+            Some([top]) if self.entries[top].0.mode == FileMode::Dir => Some(top),
+            _ => None,
+        }
+    }
+}
+
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(2);
+
+// periodic "still scanning" output, so big trees do not look like a hang
+struct ScanProgress<'a> {
+    root: &'a UnixPath,
+    entries: usize,
+    dirs: usize,
+    last: Instant,
+    printed: bool,
+}
+
+impl ScanProgress<'_> {
+    fn tick(&mut self, is_dir: bool) {
+        self.entries += 1;
+        if is_dir {
+            self.dirs += 1;
+        }
+        if self.last.elapsed() >= PROGRESS_INTERVAL {
+            self.last = Instant::now();
+            self.printed = true;
+            logi!(
+                "Scanning '{}': {} entries in {} dirs...",
+                self.root,
+                thousands(self.entries),
+                thousands(self.dirs)
+            );
+        }
+    }
+}
+
+// 12345 -> "12,345"
+fn thousands(n: usize) -> String {
+    let digits = n.to_string();
+    let mut out = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            out.push(',');
+        }
+        out.push(c);
+    }
+    out
+}
+
+// the entries of the directory `path` as childless nodes, without those `filter` excludes.
+// a directory with a marker file lists as only that, which is copied like borg's
+// --keep-exclude-tags so the destination directory is left alone too
+pub fn list_children<FS: FileSystem>(
+    fs: &mut FS,
+    path: &UnixPath,
+    prefix: &UnixPath,
+    filter: &Filter,
+) -> CResult<BTreeMap<Box<str>, Node>> {
+    let mut children = BTreeMap::new();
+    let mut entries = fs.list_dir(path).annotate()?;
+    if entries
+        .iter()
+        .any(|e| e.mode == FileMode::File && filter.is_marker(&e.name))
+    {
+        logv!("SKIP (MARKED): {}", path);
+        entries.retain(|e| e.mode == FileMode::File && filter.is_marker(&e.name));
+    }
+    for entry in entries {
+        let node = Node::new(entry, prefix);
+        let is_dir = node.sf.mode == FileMode::Dir;
+        if filter.is_excluded(node.strip_path.as_str(), is_dir) {
+            logv!("SKIP (EXCLUDED): {}", node.sf.path);
+            continue;
+        }
+        children.insert(node.sf.name.clone(), node);
+    }
+    Ok(children)
+}
+
+// entries `filter` excludes are left out, excluded directories are not descended into
+pub fn build_tree<FS: FileSystem>(
+    fs: &mut FS,
+    sf: SyncFile,
+    prefix: &UnixPath,
+    filter: &Filter,
+) -> CResult<Node> {
+    fn build_tree_<FS: FileSystem>(
+        fs: &mut FS,
+        root: &mut Node,
+        prefix: &UnixPath,
+        filter: &Filter,
+        progress: &mut ScanProgress,
+    ) -> CResult<()> {
+        for (name, mut node) in list_children(fs, &root.sf.path, prefix, filter).annotate()? {
+            let mode = node.sf.mode;
+            progress.tick(mode == FileMode::Dir);
             match mode {
                 FileMode::File => {
-                    root.entries.insert(node);
+                    root.entries.insert(name, node);
                 }
                 FileMode::Dir => {
-                    build_tree_(fs, &mut node, prefix).annotate()?;
-                    root.entries.insert(node);
+                    build_tree_(fs, &mut node, prefix, filter, progress).annotate()?;
+                    root.entries.insert(name, node);
                 }
-                FileMode::Symlink => todo!("symlinks not supported"),
+                FileMode::Symlink => logw!(W002: "Skipping symlink {}", node.sf.path),
             }
         }
         Ok(())
     }
 
     let mut root = Node::new(sf, prefix);
-    build_tree_(fs, &mut root, prefix).annotate()?;
+    let mut progress = ScanProgress {
+        root: prefix,
+        entries: 0,
+        dirs: 0,
+        last: Instant::now(),
+        printed: false,
+    };
+    build_tree_(fs, &mut root, prefix, filter, &mut progress).annotate()?;
+    if progress.printed {
+        logi!(
+            "Scanned '{}': {} entries in {} dirs",
+            prefix,
+            thousands(progress.entries),
+            thousands(progress.dirs)
+        );
+    }
     Ok(root)
 }
 
+// moves the mtime of everything under `n` by `seconds`
+pub fn shift_times(n: &mut Node, seconds: i64) {
+    for e in n.entries.values_mut() {
+        e.sf.timestamp = (e.sf.timestamp as i64 + seconds).clamp(0, u32::MAX as i64) as u32;
+        shift_times(e, seconds);
+    }
+}
+
+// drops directories that have no files anywhere below them
+pub fn prune_empty_dirs(n: &mut Node) {
+    n.entries.retain(|_, e| {
+        if e.sf.mode == FileMode::Dir {
+            prune_empty_dirs(e);
+            if e.entries.is_empty() {
+                logv!("SKIP DIR (EMPTY): {}", e.sf.path);
+                return false;
+            }
+        }
+        true
+    });
+}
+
+// re-homes every file of `root` at the relative path `f` maps it to (empty to drop it).
+// directories are recreated from the new file paths, so a remapped directory node
+// does not correspond to a directory on the source anymore
+pub fn remap_tree(root: Node, mut f: impl FnMut(&UnixPath) -> UnixPathBuf) -> Node {
+    fn collect_files(n: Node, files: &mut Vec<Node>) {
+        for e in n.entries.into_values() {
+            match e.sf.mode {
+                FileMode::File => files.push(e),
+                _ => collect_files(e, files),
+            }
+        }
+    }
+
+    fn insert(parent: &mut Node, file: Node, comps: &[&str]) {
+        if let [name] = comps {
+            if let Some(old) = parent.entries.insert((*name).into(), file) {
+                logw!(
+                    W009: "'{}' maps to the same destination as another file, replacing it",
+                    old.sf.path
+                );
+            }
+            return;
+        }
+        let path = parent.sf.path.join(comps[0]).into_boxed_path();
+        let strip_path = parent.strip_path.join(comps[0]);
+        let dir = parent
+            .entries
+            .entry(comps[0].into())
+            .or_insert_with(|| Node {
+                sf: SyncFile {
+                    mode: FileMode::Dir,
+                    size: 0,
+                    timestamp: 0,
+                    name: comps[0].into(),
+                    path,
+                },
+                entries: BTreeMap::new(),
+                strip_path,
+            });
+        insert(dir, file, &comps[1..]);
+    }
+
+    let mut files = Vec::new();
+    let mut new_root = Node {
+        sf: root.sf.clone(),
+        entries: BTreeMap::new(),
+        strip_path: root.strip_path.clone(),
+    };
+    collect_files(root, &mut files);
+    // so that stateful mappings (like flattening) are stable across runs
+    files.sort_unstable_by(|a, b| a.sf.path.as_str().cmp(b.sf.path.as_str()));
+    for mut file in files {
+        let strip_path = f(&file.strip_path);
+        let comps: Vec<&str> = strip_path
+            .as_str()
+            .split('/')
+            .filter(|c| !c.is_empty())
+            .collect();
+        if comps.is_empty() {
+            logw!(W019: "transform left nothing of '{}', skipping", file.sf.path);
+            continue;
+        }
+        file.strip_path = UnixPathBuf::from(comps.join("/"));
+        insert(&mut new_root, file, &comps);
+    }
+    new_root
+}
+
+// directories the source lists as only their marker (see `list_children`) hide the rest of
+// what the destination has in them, so a directory marked since the last run is not emptied
+pub fn keep_marked(src: &Node, dest: &mut Node, filter: &Filter) {
+    let is_marker = |n: &Node| n.sf.mode == FileMode::File && filter.is_marker(&n.sf.name);
+    if filter.markers.is_empty() {
+        return;
+    }
+    if !src.entries.is_empty() && src.entries.values().all(is_marker) {
+        dest.entries.retain(|_, n| is_marker(n));
+        return;
+    }
+    for (name, s) in &src.entries {
+        if let (FileMode::Dir, Some(d)) = (s.sf.mode, dest.entries.get_mut(name)) {
+            keep_marked(s, d, filter);
+        }
+    }
+}
+
 pub fn diff_trees<'n>(
     root1: &'n Node,
     root2: &'n Node,
@@ -88,16 +390,28 @@ pub fn diff_trees<'n>(
         n2_doesnt_have: &mut Vec<&'n Node>,
         both_have: &mut Vec<(&'n SyncFile, &'n SyncFile)>,
     ) {
-        n1_doesnt_have.extend(n2.entries.difference(&n1.entries));
-        n2_doesnt_have.extend(n1.entries.difference(&n2.entries));
-        for n in HashSet::intersection(&n1.entries, &n2.entries) {
-            // i just checked their intersection so..
-            let n1c = n1.entries.get(n).unwrap();
-            let n2c = n2.entries.get(n).unwrap();
-            if n.sf.mode == FileMode::File {
-                both_have.push((&n1c.sf, &n2c.sf));
+        // both children lists are sorted by name, so one merge walk over them finds what
+        // only one side has and the pairs both have
+        let mut e1 = n1.entries.iter().peekable();
+        let mut e2 = n2.entries.iter().peekable();
+        loop {
+            let order = match (e1.peek(), e2.peek()) {
+                (Some((k1, _)), Some((k2, _))) => k1.cmp(k2),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => break,
+            };
+            match order {
+                Ordering::Less => n2_doesnt_have.push(e1.next().unwrap().1),
+                Ordering::Greater => n1_doesnt_have.push(e2.next().unwrap().1),
+                Ordering::Equal => {
+                    let (n1c, n2c) = (e1.next().unwrap().1, e2.next().unwrap().1);
+                    if n1c.sf.mode == FileMode::File {
+                        both_have.push((&n1c.sf, &n2c.sf));
+                    }
+                    diff_trees_(n1c, n2c, n1_doesnt_have, n2_doesnt_have, both_have);
+                }
             }
-            diff_trees_(n1c, n2c, n1_doesnt_have, n2_doesnt_have, both_have);
         }
     }
 
@@ -113,3 +427,51 @@ pub fn diff_trees<'n>(
     );
     (n1_doesnt_have, n2_doesnt_have, both_have)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(files: &[&str]) -> Node {
+        let root = UnixPath::new("/r");
+        let mut n = Node::new(
+            SyncFile {
+                mode: FileMode::Dir,
+                size: 0,
+                timestamp: 0,
+                name: "r".into(),
+                path: root.to_path_buf().into_boxed_path(),
+            },
+            root,
+        );
+        for f in files {
+            let path = root.join(f);
+            let file = Node::new(
+                SyncFile {
+                    mode: FileMode::File,
+                    size: 1,
+                    timestamp: 0,
+                    name: path.file_name().unwrap().into(),
+                    path: path.clone().into_boxed_path(),
+                },
+                root,
+            );
+            n.entries.insert((*f).into(), file);
+        }
+        // nests the files under their directories
+        remap_tree(n, |p| p.to_path_buf())
+    }
+
+    #[test]
+    fn diff_sorted() {
+        let a = tree(&["b/x", "b/y", "c", "a b/z"]);
+        let b = tree(&["b/y", "c", "d/w"]);
+        let (a_lacks, b_lacks, both) = diff_trees(&a, &b);
+        let names =
+            |v: Vec<&Node>| -> Vec<String> { v.iter().map(|n| n.strip_path.to_string()).collect() };
+        assert_eq!(names(a_lacks), ["d"]);
+        assert_eq!(names(b_lacks), ["a b", "b/x"]);
+        let both: Vec<&str> = both.iter().map(|(s, _)| s.path.as_str()).collect();
+        assert_eq!(both, ["/r/b/y", "/r/c"]);
+    }
+}
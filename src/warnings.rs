@@ -0,0 +1,122 @@
+// stable codes for the warnings of a run, `logw!(W003: ...)`, so scripts can tell them apart.
+// the run ends with a count per code, and `--fail-on-warning` fails it when some occurred.
+// codes are never renumbered, new ones go at the end
+
+use crate::{logi, CResult};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+pub const W001: &str = "name-sanitized";
+pub const W002: &str = "skipped-special-file";
+pub const W003: &str = "permission-denied";
+pub const W004: &str = "unreadable";
+pub const W005: &str = "content-mismatch";
+pub const W006: &str = "missing";
+pub const W007: &str = "conflict";
+pub const W008: &str = "stalled";
+pub const W009: &str = "name-collision";
+pub const W010: &str = "type-mismatch";
+pub const W011: &str = "changed-while-copying";
+pub const W012: &str = "preflight";
+pub const W013: &str = "housekeeping-failed";
+pub const W014: &str = "connection-lost";
+pub const W015: &str = "kept-on-device";
+pub const W016: &str = "install-failed";
+pub const W017: &str = "extra-file";
+pub const W018: &str = "no-owner";
+pub const W019: &str = "empty-name";
+pub const W020: &str = "scoped-storage";
+pub const W021: &str = "no-version-codes";
+pub const W022: &str = "sync-failed";
+
+pub const CODES: [(&str, &str); 22] = [
+    ("W001", W001),
+    ("W002", W002),
+    ("W003", W003),
+    ("W004", W004),
+    ("W005", W005),
+    ("W006", W006),
+    ("W007", W007),
+    ("W008", W008),
+    ("W009", W009),
+    ("W010", W010),
+    ("W011", W011),
+    ("W012", W012),
+    ("W013", W013),
+    ("W014", W014),
+    ("W015", W015),
+    ("W016", W016),
+    ("W017", W017),
+    ("W018", W018),
+    ("W019", W019),
+    ("W020", W020),
+    ("W021", W021),
+    ("W022", W022),
+];
+
+static COUNTS: Mutex<BTreeMap<&'static str, u64>> = Mutex::new(BTreeMap::new());
+
+pub fn count(code: &'static str) {
+    *COUNTS.lock().unwrap().entry(code).or_default() += 1;
+}
+
+// the code of `W003` or `permission-denied`
+pub fn parse_code(s: &str) -> Result<&'static str, String> {
+    CODES
+        .iter()
+        .find(|(code, name)| code.eq_ignore_ascii_case(s) || *name == s)
+        .map(|(code, _)| *code)
+        .ok_or_else(|| format!("unknown warning code '{s}', see the README for the list"))
+}
+
+// how many coded warnings there were so far
+pub fn total() -> u64 {
+    COUNTS.lock().unwrap().values().sum()
+}
+
+// logs how many warnings of each code the run had
+pub fn summary() {
+    let counts = COUNTS.lock().unwrap();
+    if counts.is_empty() {
+        return;
+    }
+    let per_code: Vec<String> = CODES
+        .iter()
+        .filter_map(|(code, name)| Some(format!("{code} {name}: {}", counts.get(code)?)))
+        .collect();
+    logi!("Warnings: {}", per_code.join(", "));
+}
+
+// with `--fail-on-warning`, an error when a warning of `codes` occurred, of any code when empty
+pub fn check(codes: &[&str]) -> CResult<()> {
+    let counts = COUNTS.lock().unwrap();
+    let failing: Vec<&str> = counts
+        .keys()
+        .copied()
+        .filter(|c| codes.is_empty() || codes.contains(c))
+        .collect();
+    if failing.is_empty() {
+        return Ok(());
+    }
+    Err(format!("Failing on warnings {}", failing.join(", ")).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warning_codes() {
+        assert_eq!(parse_code("w003"), Ok("W003"));
+        assert_eq!(parse_code("permission-denied"), Ok("W003"));
+        assert!(parse_code("W999").is_err());
+        for (i, (code, _)) in CODES.iter().enumerate() {
+            assert_eq!(*code, format!("W{:03}", i + 1));
+        }
+        count("W008");
+        count("W008");
+        assert!(check(&["W016"]).is_ok());
+        assert!(check(&["W008"]).is_err());
+        assert!(check(&[]).is_err());
+    }
+}
@@ -0,0 +1,101 @@
+// `push --watch`: after the first sync, the source is watched and every burst of changes is
+// pushed as soon as it settles, only the changed paths are compared again
+//   img/a.png written, img/b.png removed  ->  sync with the filter's `only` = [img/a.png, img/b.png]
+
+use crate::CResult;
+use chainerror::Context;
+use notify::{EventKind, RecursiveMode, Watcher as _};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+// how long the source has to stay quiet before a burst of changes is synced, editors and build
+// tools write several times in a row
+pub const DEBOUNCE: Duration = Duration::from_millis(500);
+
+pub struct Watcher {
+    // stops watching when dropped
+    _watcher: notify::RecommendedWatcher,
+    events: Receiver<notify::Result<notify::Event>>,
+    root: PathBuf,
+}
+
+impl Watcher {
+    pub fn new(root: &Path) -> CResult<Self> {
+        let (tx, events) = std::sync::mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx).annotate()?;
+        watcher.watch(root, RecursiveMode::Recursive).annotate()?;
+        Ok(Self {
+            _watcher: watcher,
+            events,
+            root: root.to_path_buf(),
+        })
+    }
+
+    // waits for changes under the root and until none came for `debounce`. returns the changed
+    // paths relative to the root, empty when the root itself changed
+    pub fn changes(&self, debounce: Duration) -> CResult<Vec<String>> {
+        let mut changed = Vec::new();
+        loop {
+            let event = if changed.is_empty() {
+                self.events.recv().map_err(|_| "the file watcher stopped")?
+            } else {
+                match self.events.recv_timeout(debounce) {
+                    Ok(event) => event,
+                    Err(RecvTimeoutError::Timeout) => return Ok(outermost(changed)),
+                    Err(RecvTimeoutError::Disconnected) => {
+                        return Err("the file watcher stopped".into())
+                    }
+                }
+            };
+            let event = event.annotate()?;
+            if matches!(event.kind, EventKind::Access(_)) {
+                continue;
+            }
+            for path in event.paths {
+                let Ok(rel) = path.strip_prefix(&self.root) else {
+                    continue;
+                };
+                let rel = rel.components().map(|c| c.as_os_str().to_string_lossy());
+                changed.push(rel.collect::<Vec<_>>().join("/"));
+            }
+        }
+    }
+}
+
+// without the paths under another one, which is synced whole anyway. an empty path, the root,
+// leaves nothing
+fn outermost(mut paths: Vec<String>) -> Vec<String> {
+    if paths.iter().any(String::is_empty) {
+        return Vec::new();
+    }
+    paths.sort();
+    paths.dedup();
+    let mut out: Vec<String> = Vec::new();
+    for p in paths {
+        let under = out.iter().any(|o| {
+            p.strip_prefix(o.as_str())
+                .is_some_and(|r| r.starts_with('/'))
+        });
+        if !under {
+            out.push(p);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outermost_paths() {
+        let paths = |p: &[&str]| p.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            outermost(paths(&["img/a.png", "img", "img/a.png", "res/x", "img2/b"])),
+            paths(&["img", "img2/b", "res/x"])
+        );
+        assert_eq!(outermost(paths(&["a/c", "a-b", "a"])), paths(&["a", "a-b"]));
+        assert!(outermost(paths(&["a", ""])).is_empty());
+    }
+}
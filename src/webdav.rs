@@ -0,0 +1,215 @@
+use crate::fs::{unsupported_pair, AnyFileSystem, FileMode, FileSystem, SyncFile, Transport};
+use crate::remote::{
+    list_source, open_source, parse_http_date, uri_decode, uri_encode, xml_elements, xml_first,
+    xml_unescape,
+};
+use crate::{logw, CResult};
+use base64::Engine;
+use chainerror::Context;
+use typed_path::{Utf8UnixPath as UnixPath, Utf8UnixPathBuf as UnixPathBuf};
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:"><d:prop><d:resourcetype/><d:getcontentlength/><d:getlastmodified/></d:prop></d:propfind>"#;
+
+pub struct WebDavFS {
+    agent: ureq::Agent,
+    // scheme://host[:port]
+    base: String,
+    auth: Option<String>,
+}
+
+impl WebDavFS {
+    // `dav://host[:port]/path` (plain http) or `davs://host[:port]/path` (https).
+    // credentials are read from ADB_SINK_WEBDAV_USER and ADB_SINK_WEBDAV_PASSWORD
+    pub fn from_url(url: &str) -> CResult<(Self, UnixPathBuf)> {
+        let (scheme, rest) = if let Some(rest) = url.strip_prefix("davs://") {
+            ("https", rest)
+        } else if let Some(rest) = url.strip_prefix("dav://") {
+            ("http", rest)
+        } else {
+            return Err(format!("not a webdav url: '{url}'").into());
+        };
+        let (host, path) = match rest.split_once('/') {
+            Some((host, path)) => (host, format!("/{path}")),
+            None => (rest, "/".to_string()),
+        };
+        let auth = match (
+            std::env::var("ADB_SINK_WEBDAV_USER"),
+            std::env::var("ADB_SINK_WEBDAV_PASSWORD"),
+        ) {
+            (Ok(user), Ok(pass)) => Some(format!(
+                "Basic {}",
+                base64::engine::general_purpose::STANDARD.encode(format!("{user}:{pass}"))
+            )),
+            _ => None,
+        };
+        Ok((
+            Self {
+                agent: ureq::AgentBuilder::new().build(),
+                base: format!("{scheme}://{host}"),
+                auth,
+            },
+            UnixPathBuf::from(path),
+        ))
+    }
+
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        let req = self
+            .agent
+            .request(method, &format!("{}{}", self.base, uri_encode(path, false)));
+        match &self.auth {
+            Some(auth) => req.set("Authorization", auth),
+            None => req,
+        }
+    }
+
+    fn propfind(&self, path: &UnixPath, depth: &str) -> CResult<Option<String>> {
+        match self
+            .request("PROPFIND", path.as_str())
+            .set("Depth", depth)
+            .set("Content-Type", "application/xml")
+            .send_string(PROPFIND_BODY)
+        {
+            Ok(r) => Ok(Some(r.into_string().annotate()?)),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn parse_response(&self, resp: &str) -> Option<SyncFile> {
+        let href = uri_decode(&xml_unescape(xml_first(resp, "href")?));
+        // some servers answer with absolute urls
+        let href = match href.find("://") {
+            Some(i) => href[i + 3..]
+                .find('/')
+                .map_or("/".to_string(), |j| href[i + 3 + j..].to_string()),
+            None => href,
+        };
+        let path = match href.trim_end_matches('/') {
+            "" => "/",
+            p => p,
+        };
+        let path = UnixPathBuf::from(path);
+        let mode = if xml_first(resp, "collection").is_some() {
+            FileMode::Dir
+        } else {
+            FileMode::File
+        };
+        let size = xml_first(resp, "getcontentlength")
+            .and_then(|s| s.trim().parse::<u64>().ok())
+            .unwrap_or(0);
+        let timestamp = xml_first(resp, "getlastmodified")
+            .and_then(|s| parse_http_date(s.trim()))
+            .unwrap_or(0);
+        Some(SyncFile {
+            mode,
+            size,
+            timestamp,
+            name: path.file_name().unwrap_or_default().into(),
+            path: path.into_boxed_path(),
+        })
+    }
+}
+
+impl FileSystem for WebDavFS {
+    fn mkdir(&mut self, path: &UnixPath) -> CResult<()> {
+        let mut cur = String::new();
+        for part in path.as_str().split('/').filter(|p| !p.is_empty()) {
+            cur.push('/');
+            cur.push_str(part);
+            match self.request("MKCOL", &cur).call() {
+                // 405: already exists
+                Ok(_) | Err(ureq::Error::Status(405, _)) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    fn list_dir(&mut self, path: &UnixPath) -> CResult<Vec<SyncFile>> {
+        let Some(xml) = self.propfind(path, "1").annotate()? else {
+            return Err(format!("'{path}' does not exist").into());
+        };
+        let this = path.as_str().trim_end_matches('/');
+        Ok(xml_elements(&xml, "response")
+            .into_iter()
+            .filter_map(|resp| self.parse_response(resp))
+            .filter(|sf| sf.path.as_str().trim_end_matches('/') != this)
+            .collect())
+    }
+
+    fn stat(&mut self, path: &UnixPath) -> CResult<Option<SyncFile>> {
+        let Some(xml) = self.propfind(path, "0").annotate()? else {
+            return Ok(None);
+        };
+        Ok(xml_first(&xml, "response").and_then(|resp| self.parse_response(resp)))
+    }
+
+    fn rm(&mut self, path: &UnixPath) -> CResult<()> {
+        self.request("DELETE", path.as_str()).call().annotate()?;
+        Ok(())
+    }
+
+    fn rm_dir(&mut self, path: &UnixPath) -> CResult<()> {
+        // DELETE on a collection is recursive
+        self.request("DELETE", path.as_str()).call().annotate()?;
+        Ok(())
+    }
+
+    fn set_mtime(&mut self, _path: &UnixPath, _timestamp: u32) -> CResult<()> {
+        // getlastmodified is protected, the mtime is sent with the PUT instead
+        Ok(())
+    }
+}
+
+impl AnyFileSystem for WebDavFS {
+    fn transport(&self) -> Transport {
+        Transport::WebDav
+    }
+
+    fn copy_from(
+        &mut self,
+        src: Transport,
+        from: &UnixPath,
+        to: &UnixPath,
+        timestamp: Option<u32>,
+    ) -> CResult<()> {
+        if !matches!(src, Transport::Adb | Transport::Local) {
+            return unsupported_pair(src, Transport::WebDav);
+        }
+        let (reader, len) = open_source(src, from).annotate()?;
+        let mut req = self.request("PUT", to.as_str());
+        if let Some(len) = len {
+            req = req.set("Content-Length", &len.to_string());
+        }
+        if let Some(timestamp) = timestamp {
+            // understood by nextcloud/owncloud, ignored elsewhere
+            req = req.set("X-OC-Mtime", &timestamp.to_string());
+        }
+        req.send(reader).annotate()?;
+        Ok(())
+    }
+
+    fn copy_dir_from(
+        &mut self,
+        src: Transport,
+        from: &UnixPath,
+        to: &UnixPath,
+        timestamp: Option<u32>,
+    ) -> CResult<()> {
+        self.mkdir(to).annotate()?;
+        for entry in list_source(src, from).annotate()? {
+            let to_path = to.join(&*entry.name);
+            match entry.mode {
+                FileMode::File => self
+                    .copy_from(src, &entry.path, &to_path, timestamp)
+                    .annotate()?,
+                FileMode::Dir => self
+                    .copy_dir_from(src, &entry.path, &to_path, timestamp)
+                    .annotate()?,
+                FileMode::Symlink => logw!(W002: "Skipping symlink {}", entry.path),
+            }
+        }
+        Ok(())
+    }
+}
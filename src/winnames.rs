@@ -0,0 +1,238 @@
+use crate::{logw, CResult};
+use chainerror::Context;
+use std::collections::BTreeMap;
+use std::path::Path;
+use typed_path::{Utf8UnixPath as UnixPath, Utf8UnixPathBuf as UnixPathBuf};
+
+// name of the database in the root of a pull destination with Windows names, `path<TAB>name`
+// lines: the original name of every file or directory whose name had to change, by its new
+// path. new paths cannot hold tabs, they are control characters. a push from there gives the
+// files their names back
+pub const NAME_DB: &str = ".adb-sink-names";
+
+// not allowed in Windows file names, along with control characters
+const INVALID: [char; 8] = ['<', '>', ':', '"', '\\', '|', '?', '*'];
+
+pub fn is_invalid(c: char) -> bool {
+    INVALID.contains(&c) || c.is_ascii_control()
+}
+
+// what to do with names ending in dots or spaces, which Windows silently drops
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TrailingDot {
+    // append the replacement character, `name.` becomes `name._`
+    #[default]
+    Marker,
+    // drop them, unless nothing would be left
+    Strip,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WindowsNames {
+    pub replacement: char,
+    pub trailing_dot: TrailingDot,
+}
+
+impl WindowsNames {
+    // `path` with every name in it made valid on Windows
+    pub fn sanitize(&self, path: &str) -> String {
+        path.split('/')
+            .map(|name| self.sanitize_name(name))
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    fn sanitize_name(&self, name: &str) -> String {
+        let mut name: String = name
+            .chars()
+            .map(|c| if is_invalid(c) { self.replacement } else { c })
+            .collect();
+        // `.` and `..` are fine
+        if !name.ends_with(['.', ' ']) || name == "." || name == ".." {
+            return name;
+        }
+        let stripped = name.trim_end_matches(['.', ' ']);
+        match self.trailing_dot {
+            TrailingDot::Strip if !stripped.is_empty() => stripped.to_string(),
+            _ => {
+                name.push(self.replacement);
+                name
+            }
+        }
+    }
+}
+
+// original names of renamed entries, keyed by their path relative to the root
+pub struct NameDb {
+    root: UnixPathBuf,
+    names: BTreeMap<String, String>,
+    changed: bool,
+}
+
+impl NameDb {
+    // a root without a database starts an empty one
+    pub fn open(root: &UnixPath) -> CResult<Self> {
+        let mut names = BTreeMap::new();
+        let db = Path::new(root.as_str()).join(NAME_DB);
+        match std::fs::read_to_string(&db) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            r => {
+                for (i, line) in r.annotate()?.lines().enumerate() {
+                    let Some((path, name)) = line.split_once('\t') else {
+                        return Err(
+                            format!("'{}' line {} is malformed", db.display(), i + 1).into()
+                        );
+                    };
+                    names.insert(path.to_string(), unescape(name));
+                }
+            }
+        }
+        Ok(Self {
+            root: root.to_path_buf(),
+            names,
+            changed: false,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    // `path`, under the root, was named `original` on the device. returns whether that was not
+    // recorded yet
+    pub fn record(&mut self, path: &UnixPath, original: &str) -> bool {
+        let path = self.relative(path).to_string();
+        if self.names.get(&path).map(String::as_str) == Some(original) {
+            return false;
+        }
+        self.names.insert(path, original.to_string());
+        self.changed = true;
+        true
+    }
+
+    // `path` made valid on Windows, recording the original name of each entry under `base`
+    // that changed
+    pub fn rename(&mut self, base: &UnixPath, path: &str, names: WindowsNames) -> String {
+        let new = names.sanitize(path);
+        if new != path {
+            let mut at = base.to_path_buf();
+            for (comp, original) in new.split('/').zip(path.split('/')) {
+                at.push(comp);
+                if comp != original && self.record(&at, original) {
+                    logw!(W001: "RENAMED: '{}' is stored as '{}'", original, at);
+                }
+            }
+        }
+        new
+    }
+
+    // `path` with the original names of the entries `base` and everything after it lead to
+    pub fn restore(&self, base: &UnixPath, path: &UnixPath) -> UnixPathBuf {
+        let mut at = base.to_path_buf();
+        let mut out = UnixPathBuf::new();
+        for comp in path.as_str().split('/').filter(|c| !c.is_empty()) {
+            at.push(comp);
+            match self.names.get(self.relative(&at)) {
+                Some(original) => out.push(original),
+                None => out.push(comp),
+            }
+        }
+        out
+    }
+
+    // forgets `path` and everything under it
+    pub fn remove(&mut self, path: &UnixPath) {
+        let path = self.relative(path).to_string();
+        let dir = format!("{path}/");
+        let before = self.names.len();
+        self.names.retain(|p, _| *p != path && !p.starts_with(&dir));
+        self.changed |= self.names.len() != before;
+    }
+
+    fn relative<'a>(&self, path: &'a UnixPath) -> &'a str {
+        path.strip_prefix(&self.root)
+            .map_or(path.as_str(), |p| p.as_str())
+    }
+
+    // only writes a database when something was renamed, most pulls need none
+    pub fn save(&self) -> CResult<()> {
+        if !self.changed {
+            return Ok(());
+        }
+        let mut out = String::new();
+        for (path, name) in &self.names {
+            out.push_str(&format!("{path}\t{}\n", escape(name)));
+        }
+        // so that an interrupted write does not lose the old database
+        let root = Path::new(self.root.as_str());
+        let tmp = root.join(format!("{NAME_DB}.tmp"));
+        std::fs::write(&tmp, out).annotate()?;
+        std::fs::rename(&tmp, root.join(NAME_DB)).annotate()?;
+        Ok(())
+    }
+}
+
+// original names may hold newlines
+fn escape(name: &str) -> String {
+    name.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some(c) => out.push(c),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut names = WindowsNames {
+            replacement: '_',
+            trailing_dot: TrailingDot::Marker,
+        };
+        assert_eq!(
+            names.sanitize("Screenshot 2024-05-01 10:30:00.png"),
+            "Screenshot 2024-05-01 10_30_00.png"
+        );
+        assert_eq!(names.sanitize("etc./notes. "), "etc._/notes. _");
+        names.trailing_dot = TrailingDot::Strip;
+        assert_eq!(names.sanitize("etc./notes. /..."), "etc/notes/..._");
+        let dir = std::env::temp_dir().join(format!("adb-sink-names-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let root = UnixPathBuf::from(dir.to_str().unwrap());
+        let mut db = NameDb::open(&root).unwrap();
+        let base = root.join("DCIM");
+        assert_eq!(db.rename(&base, "a:b/c?.txt", names), "a_b/c_.txt");
+        db.record(&root.join("DCIM/n_"), "n\\\n");
+        db.save().unwrap();
+
+        let mut db = NameDb::open(&root).unwrap();
+        let base = root.join("DCIM");
+        assert_eq!(
+            db.restore(&base, UnixPath::new("a_b/c_.txt")).as_str(),
+            "a:b/c?.txt"
+        );
+        assert_eq!(db.restore(&base, UnixPath::new("n_")).as_str(), "n\\\n");
+        assert_eq!(db.restore(&base, UnixPath::new("x_y")).as_str(), "x_y");
+        db.remove(&root.join("DCIM/a_b"));
+        assert_eq!(
+            db.restore(&base, UnixPath::new("a_b/c_.txt")).as_str(),
+            "a_b/c_.txt"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}